@@ -13,6 +13,7 @@
  ---------
 */
 
+use std::fmt;
 use std::io;
 
 #[derive(Debug)]
@@ -22,6 +23,16 @@ pub enum FileLoadError {
     IOError(io::Error),
 }
 
+impl fmt::Display for FileLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileLoadError::CustomError(err) => write!(f, "{}", err),
+            FileLoadError::StdError(err) => write!(f, "{}", err),
+            FileLoadError::IOError(err) => write!(f, "{}", err),
+        }
+    }
+}
+
 impl From<io::Error> for FileLoadError {
     fn from(error: io::Error) -> Self {
         FileLoadError::IOError(error)
@@ -13,11 +13,15 @@
  ---------
 */
 
+use std::collections::BTreeSet;
+
+use super::control_output::host_println;
 use super::common_actions_linux;
 use super::common_actions_unix;
 
-use super::control_actions::{ActionProvider, ActionError, ControlAction, GenericError, SystemDetailsResult};
+use super::control_actions::{ActionProvider, ActionError, ActionResult, ControlAction, ControlActionType, GenericError, SystemDetailsResult};
 use super::control_common::{ControlSession, ControlSessionParams};
+use super::control_facts::HostFacts;
 use super::terminal_helpers_linux;
 
 pub struct AProviderLinuxDebian {
@@ -44,7 +48,15 @@ impl ActionProvider for AProviderLinuxDebian {
         Some(&self.session_params)
     }
 
-    fn generic_command(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn supported_actions(&self) -> BTreeSet<ControlActionType> {
+        use ControlActionType::*;
+        BTreeSet::from([GenericCommand, AddUser, CreateDirectory, RemoveDirectory, InstallPackages, RemovePackages,
+                        SystemCtl, Firewall, EditFile, CopyPath, RemoveFile, DownloadFile, TransmitFile, ReceiveFile,
+                        CreateSymlink, SetTimeZone, DisableSwap, CreateFile, AddGroup, SetHostname,
+                        CreateSystemdService, ConfigureSSH, AddPackageRepo])
+    }
+
+    fn generic_command(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
         common_actions_unix::generic_command(self, connection, action)
     }
 
@@ -53,19 +65,23 @@ impl ActionProvider for AProviderLinuxDebian {
         common_actions_linux::get_system_details(self, connection)
     }
 
-    fn add_user(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn get_host_facts(&self, connection: &mut ControlSession) -> Result<HostFacts, GenericError> {
+        common_actions_linux::get_host_facts(self, connection)
+    }
+
+    fn add_user(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
         common_actions_linux::add_user(self, connection, action)
     }
 
-    fn create_directory(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn create_directory(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
         common_actions_unix::create_directory(self, connection, action)
     }
 
-    fn remove_directory(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn remove_directory(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
         common_actions_unix::remove_directory(self, connection, action)
     }
 
-    fn install_packages(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn install_packages(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
         // use apt-get, because the commands for that will apparently be much more stable, compared to apt
         // which might change as it's designed to be more user-facing...
 
@@ -101,7 +117,7 @@ impl ActionProvider for AProviderLinuxDebian {
         self.perform_apt_package_install(&apt_get_install_params, connection)
     }
 
-    fn remove_packages(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn remove_packages(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
         // use apt-get, because the commands for that will apparently be much more stable, compared to apt
         // which might change as it's designed to be more user-facing...
 
@@ -129,7 +145,7 @@ impl ActionProvider for AProviderLinuxDebian {
         if wait_for_apt_get_lockfile {
             let mut try_count = 0;
             while try_count < 20 {
-                connection.conn.send_command(&self.post_process_command("pidof apt-get"));
+                connection.conn.send_command(&self.post_process_command("pidof apt-get"))?;
 
                 if !connection.conn.had_command_response() {
                     // it's likely no longer running, so we can continue...
@@ -137,7 +153,7 @@ impl ActionProvider for AProviderLinuxDebian {
                 }
 
                 // TODO: only print this once eventually, but might be useful like this for the moment...
-                println!("Waiting for existing apt-get to finish before removing packages...");
+                host_println!("Waiting for existing apt-get to finish before removing packages...");
 
                 // sleep a bit to give things a chance...
                 std::thread::sleep(std::time::Duration::from_secs(20));
@@ -147,7 +163,7 @@ impl ActionProvider for AProviderLinuxDebian {
         }
 
         let apt_get_command = format!("export DEBIAN_FRONTEND=noninteractive; apt-get -y remove {}", packages_string);
-        connection.conn.send_command(&self.post_process_command(&apt_get_command));
+        connection.conn.send_command(&self.post_process_command(&apt_get_command))?;
 
         let ignore_failure = action.params.get_value_as_bool("ignoreFailure").unwrap_or(false);
 
@@ -158,75 +174,75 @@ impl ActionProvider for AProviderLinuxDebian {
             }
         }
 
-        Ok(())
+        Ok(ActionResult::Changed)
     }
 
-    fn systemctrl(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn systemctrl(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
         common_actions_linux::systemctrl(self, connection, action)
     }
 
-    fn firewall(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn firewall(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
         // debian doesn't need ufw firewall enabled first before adding rules
         common_actions_linux::firewall(self, connection, action, false)
     }
 
-    fn edit_file(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn edit_file(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
         common_actions_unix::edit_file(self, connection, action)
     }
 
-    fn copy_path(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn copy_path(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
         common_actions_unix::copy_path(self, connection, action)
     }
 
-    fn remove_file(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn remove_file(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
         common_actions_unix::remove_file(self, connection, action)
     }
 
-    fn download_file(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn download_file(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
         common_actions_unix::download_file(self, connection, action)
     }
 
-    fn transmit_file(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn transmit_file(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
         common_actions_unix::transmit_file(self, connection, action)
     }
 
-    fn receive_file(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn receive_file(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
         common_actions_unix::receive_file(self, connection, action)
     }
 
-    fn create_symlink(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn create_symlink(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
         common_actions_unix::create_symlink(self, connection, action)
     }
 
-    fn set_time_zone(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn set_time_zone(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
         common_actions_linux::set_time_zone(self, connection, action)
     }
 
-    fn disable_swap(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn disable_swap(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
         common_actions_linux::disable_swap(self, connection, action)
     }
 
-    fn create_file(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn create_file(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
         common_actions_unix::create_file(self, connection, action)
     }
 
-    fn add_group(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn add_group(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
         common_actions_linux::add_group(self, connection, action)
     }
 
-    fn set_hostname(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn set_hostname(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
         common_actions_linux::set_hostname(self, connection, action)
     }
 
-    fn create_systemd_service(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn create_systemd_service(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
         common_actions_linux::create_systemd_service(self, connection, action)
     }
 
-    fn configure_ssh(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn configure_ssh(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
         common_actions_unix::configure_ssh(self, connection, action)
     }
 
-    fn add_package_repo(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn add_package_repo(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
 
         // check that we have required params first
         let repo_type = action.get_required_string_param("type")?;
@@ -266,7 +282,7 @@ impl ActionProvider for AProviderLinuxDebian {
             let key_install_cmd = format!("curl -1sLf '{}' | gpg {}--dearmor -o /usr/share/keyrings/{}-archive-keyring.gpg",
                 key_url, overwrite_existing, local_file_prefix);
 
-            connection.conn.send_command(&self.post_process_command(&key_install_cmd));
+            connection.conn.send_command(&self.post_process_command(&key_install_cmd))?;
 
             // TODO: might have to chmod it to 644 in some future cases (sudo?)?
 
@@ -280,7 +296,7 @@ impl ActionProvider for AProviderLinuxDebian {
             let source_list_def_install_cmd = format!("curl -1sLf '{}' | tee /etc/apt/sources.list.d/{}.list",
                 source_list_def_url, local_file_prefix);
             
-            connection.conn.send_command(&self.post_process_command(&source_list_def_install_cmd));
+            connection.conn.send_command(&self.post_process_command(&source_list_def_install_cmd))?;
 
             // again, this command is not amazingly robust to detecting validate issues with, although it does output
             // the contents of the file if downloaded, but it prints nothing if it fails.
@@ -289,7 +305,7 @@ impl ActionProvider for AProviderLinuxDebian {
             // out if it succeeded or not...
             
             let stat_command = format!("stat /etc/apt/sources.list.d/{}.list", local_file_prefix);
-            connection.conn.send_command(&self.post_process_command(&stat_command));
+            connection.conn.send_command(&self.post_process_command(&stat_command))?;
             if let Some(strerr) = connection.conn.get_previous_stderr_response() {
                 return Err(ActionError::FailedOther(format!("Error accessing remote file path: {}", strerr)));
             }
@@ -320,10 +336,10 @@ impl ActionProvider for AProviderLinuxDebian {
         let update_packages = action.params.get_value_as_bool("updatePackages").unwrap_or(true);
         if update_packages {
             let apt_get_command = "apt-get -y update".to_string();
-            connection.conn.send_command(&self.post_process_command(&apt_get_command));
+            connection.conn.send_command(&self.post_process_command(&apt_get_command))?;
         }
         
-        Ok(())
+        Ok(ActionResult::Changed)
     }
 }
 
@@ -359,12 +375,30 @@ impl AptGetInstallParams {
 // actions
 impl AProviderLinuxDebian {
     fn perform_apt_package_install(&self, params: &AptGetInstallParams,
-        connection: &mut ControlSession) -> Result<(), ActionError> {
+        connection: &mut ControlSession) -> Result<ActionResult, ActionError> {
 
         // use apt-get, because the commands for that will apparently be much more stable, compared to apt
         // which might change as it's designed to be more user-facing...
-    
-        let packages_string = params.packages_to_install.join(" ");
+
+        // see which of the packages are already installed, so we only install the ones which aren't,
+        // and can skip everything (including updating) if they're all installed already.
+        // dpkg-query doesn't need the lock, so this is fine to do before waiting for any other apt-get runs
+        // to finish...
+        let dpkg_query_command = format!("dpkg-query -W -f='${{Package}} ${{Status}}\\n' {}", params.packages_to_install.join(" "));
+        let installed_packages: Vec<String> = common_actions_unix::probe_command_any_stdout(self, connection, &dpkg_query_command)?
+            .lines()
+            .filter(|line| line.ends_with(" install ok installed"))
+            .filter_map(|line| line.split_whitespace().next().map(|package| package.to_string()))
+            .collect();
+
+        let packages_to_install: Vec<&String> = params.packages_to_install.iter()
+            .filter(|package| !installed_packages.contains(package))
+            .collect();
+        if packages_to_install.is_empty() {
+            return Ok(ActionResult::Unchanged);
+        }
+
+        let packages_string = packages_to_install.iter().map(|package| package.as_str()).collect::<Vec<&str>>().join(" ");
     
         // with some providers (Vultr), apt-get runs automatically just after the instance first starts,
         // so we can't run apt-get manually, as the lock file is locked, so wait until apt-get has stopped running
@@ -373,7 +407,7 @@ impl AProviderLinuxDebian {
         if wait_for_apt_get_lockfile {
             let mut try_count = 0;
             while try_count < 20 {
-                connection.conn.send_command(&self.post_process_command("pidof apt-get"));
+                connection.conn.send_command(&self.post_process_command("pidof apt-get"))?;
     
                 if !connection.conn.had_command_response() {
                     // it's likely no longer running, so we can continue...
@@ -381,7 +415,7 @@ impl AProviderLinuxDebian {
                 }
     
                 // TODO: only print this once eventually, but might be useful like this for the moment...
-                println!("Waiting for existing apt-get to finish before installing packages...");
+                host_println!("Waiting for existing apt-get to finish before installing packages...");
     
                 // sleep a bit to give things a chance...
                 std::thread::sleep(std::time::Duration::from_secs(20));
@@ -399,7 +433,7 @@ impl AProviderLinuxDebian {
         let update_packages = params.update_packages_list;
         if update_packages {
             let apt_get_command = "apt-get -y update".to_string();
-            connection.conn.send_command(&self.post_process_command(&apt_get_command));
+            connection.conn.send_command(&self.post_process_command(&apt_get_command))?;
         }
     
         // Note: first time around, unless we export this DEBIAN_FRONTEND env variable, we get a
@@ -408,7 +442,7 @@ impl AProviderLinuxDebian {
         //       trying again after the first failure works, and it's not time-dependent...
     
         let apt_get_command = format!("export DEBIAN_FRONTEND=noninteractive; apt-get -y install {}", packages_string);
-        connection.conn.send_command(&self.post_process_command(&apt_get_command));
+        connection.conn.send_command(&self.post_process_command(&apt_get_command))?;
     
         if connection.conn.did_exit_with_error_code() {
             return Err(ActionError::FailedCommand(
@@ -416,7 +450,7 @@ impl AProviderLinuxDebian {
                 apt_get_command)));
         }
     
-        Ok(())
+        Ok(ActionResult::Changed)
     }
 }
 
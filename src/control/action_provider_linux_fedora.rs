@@ -13,11 +13,14 @@
  ---------
 */
 
+use std::collections::BTreeSet;
+
 use super::common_actions_linux;
 use super::common_actions_unix;
 
-use super::control_actions::{ActionProvider, ActionError, ControlAction, GenericError, SystemDetailsResult};
+use super::control_actions::{ActionProvider, ActionError, ActionResult, ControlAction, ControlActionType, GenericError, SystemDetailsResult};
 use super::control_common::{ControlSession, ControlSessionParams};
+use super::control_facts::HostFacts;
 
 pub struct AProviderLinuxFedora {
     // params which give us some hints as to context of session, i.e. username - sudo vs root, etc.
@@ -43,7 +46,16 @@ impl ActionProvider for AProviderLinuxFedora {
         Some(&self.session_params)
     }
 
-    fn generic_command(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn supported_actions(&self) -> BTreeSet<ControlActionType> {
+        use ControlActionType::*;
+        // Note: AddPackageRepo is not implemented yet...
+        BTreeSet::from([GenericCommand, AddUser, CreateDirectory, RemoveDirectory, InstallPackages, RemovePackages,
+                        SystemCtl, Firewall, EditFile, CopyPath, RemoveFile, DownloadFile, TransmitFile, ReceiveFile,
+                        CreateSymlink, SetTimeZone, DisableSwap, CreateFile, AddGroup, SetHostname,
+                        CreateSystemdService, ConfigureSSH])
+    }
+
+    fn generic_command(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
         common_actions_unix::generic_command(self, connection, action)
     }
 
@@ -52,47 +64,66 @@ impl ActionProvider for AProviderLinuxFedora {
         common_actions_linux::get_system_details(self, connection)
     }
 
-    fn add_user(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn get_host_facts(&self, connection: &mut ControlSession) -> Result<HostFacts, GenericError> {
+        common_actions_linux::get_host_facts(self, connection)
+    }
+
+    fn add_user(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
         common_actions_linux::add_user(self, connection, action)
     }
 
-    fn create_directory(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn create_directory(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
         common_actions_unix::create_directory(self, connection, action)
     }
 
-    fn remove_directory(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn remove_directory(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
         common_actions_unix::remove_directory(self, connection, action)
     }
 
-    fn install_packages(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
-        let packages_string;
+    fn install_packages(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
+        let packages;
         if let Some(package) = action.params.get_string_value("package") {
             // single package for convenience...
-            packages_string = package;
+            packages = vec![package];
         }
         else if action.params.has_value("packages") {
-            let packages = action.params.get_values_as_vec_of_strings("packages");
-            packages_string = packages.join(" ");
+            packages = action.params.get_values_as_vec_of_strings("packages");
         }
         else {
             return Err(ActionError::InvalidParams(
                 "No 'package' string parameter or 'packages' string array parameter were specified.".to_string()));
         }
 
-        if packages_string.is_empty() {
+        if packages.is_empty() {
             return Err(ActionError::InvalidParams("The resulting 'packages' string list was empty.".to_string()));
         }
 
+        // see which of the packages are already installed, so we only install the ones which aren't,
+        // and can skip everything (including updating) if they're all installed already.
+        // Note: rpm prints "package <name> is not installed" for missing ones, so only exact matches are installed.
+        let rpm_query_command = format!("rpm -q --qf '%{{NAME}}\\n' {}", packages.join(" "));
+        let rpm_query_output = common_actions_unix::probe_command_any_stdout(self, connection, &rpm_query_command)?;
+        let installed_packages: Vec<&str> = rpm_query_output.lines().collect();
+
+        let packages_to_install: Vec<&str> = packages.iter()
+            .map(|package| package.as_str())
+            .filter(|package| !installed_packages.contains(package))
+            .collect();
+        if packages_to_install.is_empty() {
+            return Ok(ActionResult::Unchanged);
+        }
+        let packages_string = packages_to_install.join(" ");
+
         // by default, update the list of packages, as with some providers,
         // this needs to be done first, otherwise packages can't be found...
         let update_packages = action.params.get_value_as_bool("update").unwrap_or(true);
         if update_packages {
             let dnf_command = "dnf -y update".to_string();
-            connection.conn.send_command(&self.post_process_command(&dnf_command));
+            connection.conn.send_command(&self.post_process_command(&dnf_command))?;
         }
 
         let dnf_command = format!("dnf -y install {}", packages_string);
-        connection.conn.send_command(&self.post_process_command(&dnf_command));
+        connection.conn.send_command(&self.post_process_command(&dnf_command))?;
 
         if connection.conn.did_exit_with_error_code() {
             return Err(ActionError::FailedCommand(
@@ -100,10 +131,10 @@ impl ActionProvider for AProviderLinuxFedora {
                 action)));
         }
 
-        Ok(())
+        Ok(ActionResult::Changed)
     }
 
-    fn remove_packages(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn remove_packages(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
         let packages_string;
         if let Some(package) = action.params.get_string_value("package") {
             // single package for convenience...
@@ -123,7 +154,7 @@ impl ActionProvider for AProviderLinuxFedora {
         }
 
         let dnf_command = format!("dnf -y remove {}", packages_string);
-        connection.conn.send_command(&self.post_process_command(&dnf_command));
+        connection.conn.send_command(&self.post_process_command(&dnf_command))?;
 
         let ignore_failure = action.params.get_value_as_bool("ignoreFailure").unwrap_or(false);
 
@@ -134,76 +165,76 @@ impl ActionProvider for AProviderLinuxFedora {
             }
         }
 
-        Ok(())
+        Ok(ActionResult::Changed)
     }
 
-    fn systemctrl(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn systemctrl(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
         common_actions_linux::systemctrl(self, connection, action)
     }
 
-    fn firewall(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn firewall(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
         // fedora apparently needs ufw firewall started first before adding rules, despite the
         // man page saying it's supported, and it working that way on debian/ubuntu
         common_actions_linux::firewall(self, connection, action, true)
     }
 
-    fn edit_file(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn edit_file(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
         common_actions_unix::edit_file(self, connection, action)
     }
 
-    fn copy_path(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn copy_path(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
         common_actions_unix::copy_path(self, connection, action)
     }
 
-    fn remove_file(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn remove_file(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
         common_actions_unix::remove_file(self, connection, action)
     }
 
-    fn download_file(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn download_file(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
         common_actions_unix::download_file(self, connection, action)
     }
 
-    fn transmit_file(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn transmit_file(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
         common_actions_unix::transmit_file(self, connection, action)
     }
 
-    fn receive_file(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn receive_file(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
         common_actions_unix::receive_file(self, connection, action)
     }
 
-    fn create_symlink(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn create_symlink(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
         common_actions_unix::create_symlink(self, connection, action)
     }
 
-    fn set_time_zone(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn set_time_zone(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
         common_actions_linux::set_time_zone(self, connection, action)
     }
 
-    fn disable_swap(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn disable_swap(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
         common_actions_linux::disable_swap(self, connection, action)
     }
 
-    fn create_file(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn create_file(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
         common_actions_unix::create_file(self, connection, action)
     }
 
-    fn add_group(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn add_group(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
         common_actions_linux::add_group(self, connection, action)
     }
 
-    fn set_hostname(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn set_hostname(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
         common_actions_linux::set_hostname(self, connection, action)
     }
 
-    fn create_systemd_service(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn create_systemd_service(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
         common_actions_linux::create_systemd_service(self, connection, action)
     }
 
-    fn configure_ssh(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<(), ActionError> {
+    fn configure_ssh(&self, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
         common_actions_unix::configure_ssh(self, connection, action)
     }
 
-    fn add_package_repo(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<(), ActionError> {
+    fn add_package_repo(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<ActionResult, ActionError> {
         Err(ActionError::NotImplemented)
     }
 }
@@ -15,8 +15,12 @@
 
 use crate::control::terminal_helpers_linux;
 
-use super::control_actions::{ActionProvider, ActionError, ControlAction, GenericError, SystemDetailsResult};
+use super::control_output::host_eprintln;
+use super::common_actions_unix;
+
+use super::control_actions::{ActionProvider, ActionError, ActionResult, ControlAction, GenericError, SystemDetailsResult};
 use super::control_common::ControlSession;
+use super::control_facts::{HostFacts, GATHER_FACTS_COMMAND};
 
 use rpassword::read_password;
 
@@ -29,11 +33,11 @@ pub fn get_system_details(action_provider: &dyn ActionProvider, connection: &mut
 
     let full_command = "lsb_release --id --release";
 
-    connection.conn.send_command(&action_provider.post_process_command(full_command));
+    connection.conn.send_command(&action_provider.post_process_command(full_command))?;
 
     if connection.conn.get_previous_stdout_response().is_empty() {
         // stdout output was empty, which isn't expected...
-        eprintln!("Invalid response from get_system_details() lsb_release command.");
+        host_eprintln!("Invalid response from get_system_details() lsb_release command.");
         return Err(GenericError::CommandFailed("".to_string()));
     }
 
@@ -52,23 +56,48 @@ pub fn get_system_details(action_provider: &dyn ActionProvider, connection: &mut
     // check we got something...
     // TODO: better than this?
     if dist_id.is_empty() || release.is_empty() {
-        eprintln!("Unexpected response from get_system_details() lsb_release command. Expected values were not provided.");
+        host_eprintln!("Unexpected response from get_system_details() lsb_release command. Expected values were not provided.");
         return Err(GenericError::CommandFailed("".to_string()));
     }
 
     Ok(SystemDetailsResult { distr_id: dist_id, release })
 }
 
+// gathers the facts about the host with a single command, as well as the above details.
+pub fn get_host_facts(action_provider: &dyn ActionProvider, connection: &mut ControlSession
+) -> Result<HostFacts, GenericError> {
+    let system_details = get_system_details(action_provider, connection)?;
+
+    let full_command = format!("sh -c '{}'", GATHER_FACTS_COMMAND);
+    connection.conn.send_command(&action_provider.post_process_command(&full_command))?;
+
+    if connection.conn.get_previous_stdout_response().is_empty() {
+        host_eprintln!("Invalid response from get_host_facts() command.");
+        return Err(GenericError::CommandFailed("".to_string()));
+    }
+
+    Ok(HostFacts::from_gather_output(&system_details, connection.conn.get_previous_stdout_response()))
+}
+
 pub fn add_user(action_provider: &dyn ActionProvider, connection: &mut ControlSession, action: &ControlAction
-) -> Result<(), ActionError> {
+) -> Result<ActionResult, ActionError> {
     // use useradd command which should be common across Linux distros...
 
     let mut useradd_command_options = String::new();
 
     let user = action.get_required_string_param("username")?;
     let mut password = action.get_required_string_param("password")?;
-    if password == "$PROMPT" {
-        eprintln!("Please enter password to set for new user '{}':", user);
+
+    // if the user already exists, there's nothing to do, and we don't want to prompt for the password either...
+    // TODO: maybe check the groups and shell are as requested as well?
+    let id_command = format!("id -u {}", user);
+    if common_actions_unix::probe_command_succeeds(action_provider, connection, &id_command)? {
+        return Ok(ActionResult::Unchanged);
+    }
+
+    // don't prompt for dry-runs, as the password will never actually be used...
+    if password == "$PROMPT" && !connection.conn.is_dry_run() {
+        host_eprintln!("Please enter password to set for new user '{}':", user);
         password = read_password().unwrap();
     }
 
@@ -111,7 +140,7 @@ pub fn add_user(action_provider: &dyn ActionProvider, connection: &mut ControlSe
     
     let useradd_full_command = format!("useradd {} {}", useradd_command_options, user);
 
-    connection.conn.send_command(&action_provider.post_process_command(&useradd_full_command));
+    connection.conn.send_command(&action_provider.post_process_command(&useradd_full_command))?;
 
     // check response is nothing...
     if connection.conn.had_command_response() {
@@ -124,30 +153,30 @@ pub fn add_user(action_provider: &dyn ActionProvider, connection: &mut ControlSe
     //       which obviously isn't great...
 //    let change_password_command = format!(" echo -e \"{0}\n{0}\" | passwd {1}", password, user);
     let change_password_command = format!(" echo -e '{}:{}' | chpasswd", user, password);
-    connection.conn.send_command(&action_provider.post_process_command(&change_password_command));
+    connection.conn.send_command(&action_provider.post_process_command(&change_password_command))?;
 
-    Ok(())
+    Ok(ActionResult::Changed)
 }
 
 pub fn systemctrl(action_provider: &dyn ActionProvider, connection: &mut ControlSession, action: &ControlAction
-) -> Result<(), ActionError> {
+) -> Result<ActionResult, ActionError> {
     let service = action.get_required_string_param("service")?;
     let service_action = action.get_required_string_param("action")?;
 
     let systemctrl_command = format!("systemctl {} {}", service_action, service);
     
-    connection.conn.send_command(&action_provider.post_process_command(&systemctrl_command));
+    connection.conn.send_command(&action_provider.post_process_command(&systemctrl_command))?;
 
     if connection.conn.did_exit_with_error_code() {
         return Err(ActionError::FailedCommand(connection.conn.return_failed_command_error_response_str(&systemctrl_command,
             action)));
     }
 
-    Ok(())
+    Ok(ActionResult::Changed)
 }
 
 pub fn firewall(action_provider: &dyn ActionProvider, connection: &mut ControlSession, action: &ControlAction, start_first: bool
-) -> Result<(), ActionError> {
+) -> Result<ActionResult, ActionError> {
     let firewall_type = action.params.get_string_value_with_default("type", "ufw");
     if firewall_type == "ufw" {
         // incredibly basic for the moment...
@@ -161,13 +190,13 @@ pub fn firewall(action_provider: &dyn ActionProvider, connection: &mut ControlSe
         if start_first && action.params.has_value("enabled") {
             let is_enabled = action.params.get_value_as_bool("enabled").unwrap_or(true);
             let ufw_command = format!("ufw --force {}", if is_enabled { "enable" } else { "disable"});
-            connection.conn.send_command(&action_provider.post_process_command(&ufw_command));
+            connection.conn.send_command(&action_provider.post_process_command(&ufw_command))?;
 
             // we can't just rely on stderr being useful here, i.e. if ufw wasn't installed or something...
             if connection.conn.did_exit_with_error_code() {
                 // stdout can sometimes be useful though, so look for obvious things to be a bit more helpful
                 if connection.conn.get_previous_stdout_response().contains("ufw: command not found") {
-                    eprintln!("Error in 'firewall' action: 'ufw' does not seem to be installed.");
+                    host_eprintln!("Error in 'firewall' action: 'ufw' does not seem to be installed.");
                 }
                 return Err(ActionError::FailedCommand(connection.conn.return_failed_command_error_response_str(&ufw_command,
                     action)));
@@ -177,14 +206,14 @@ pub fn firewall(action_provider: &dyn ActionProvider, connection: &mut ControlSe
         let rules = action.params.get_values_as_vec_of_strings("rules");
         for rule in rules {
             let ufw_command = format!("ufw {}", rule);
-            connection.conn.send_command(&action_provider.post_process_command(&ufw_command));
+            connection.conn.send_command(&action_provider.post_process_command(&ufw_command))?;
 
             // we can't just rely on stderr being useful here when things fail, i.e. if ufw wasn't installed or something,
             // but the exit code should always be indicative...
             if connection.conn.did_exit_with_error_code() {
                 // stdout can sometimes be useful though, so look for obvious things to be a bit more helpful
                 if connection.conn.get_previous_stdout_response().contains("ufw: command not found") {
-                    eprintln!("Error in 'firewall' action: 'ufw' does not seem to be installed.");
+                    host_eprintln!("Error in 'firewall' action: 'ufw' does not seem to be installed.");
                 }
                 return Err(ActionError::FailedCommand(connection.conn.return_failed_command_error_response_str(&ufw_command,
                     action)));
@@ -195,7 +224,7 @@ pub fn firewall(action_provider: &dyn ActionProvider, connection: &mut ControlSe
             if action.params.has_value("enabled") {
                 let is_enabled = action.params.get_value_as_bool("enabled").unwrap_or(true);
                 let ufw_command = format!("ufw --force {}", if is_enabled { "enable" } else { "disable"});
-                connection.conn.send_command(&action_provider.post_process_command(&ufw_command));
+                connection.conn.send_command(&action_provider.post_process_command(&ufw_command))?;
 
                 if connection.conn.did_exit_with_error_code() {
                     return Err(ActionError::FailedCommand(connection.conn.return_failed_command_error_response_str(&ufw_command,
@@ -209,17 +238,17 @@ pub fn firewall(action_provider: &dyn ActionProvider, connection: &mut ControlSe
         return Err(ActionError::InvalidParams("Invalid firewall type param".to_string()));
     }
 
-    Ok(())
+    Ok(ActionResult::Changed)
 }
 
 pub fn set_time_zone(action_provider: &dyn ActionProvider, connection: &mut ControlSession, action: &ControlAction
-) -> Result<(), ActionError> {
+) -> Result<ActionResult, ActionError> {
     let time_zone = action.get_required_string_param("timeZone")?;
    
     // "UTC", "Pacific/Auckland", "Europe/London"
 
     let timedatectl_command = format!("timedatectl {}", time_zone);
-    connection.conn.send_command(&action_provider.post_process_command(&timedatectl_command));
+    connection.conn.send_command(&action_provider.post_process_command(&timedatectl_command))?;
 
     if connection.conn.did_exit_with_error_code() {
         return Err(ActionError::FailedCommand(connection.conn.return_failed_command_error_response_str(&timedatectl_command,
@@ -228,11 +257,11 @@ pub fn set_time_zone(action_provider: &dyn ActionProvider, connection: &mut Cont
 
     // TODO: also restart things like crond that might have been affected?
 
-    Ok(())
+    Ok(ActionResult::Changed)
 }
 
 pub fn disable_swap(action_provider: &dyn ActionProvider, connection: &mut ControlSession, action: &ControlAction
-) -> Result<(), ActionError> {
+) -> Result<ActionResult, ActionError> {
     let filename = action.get_required_string_param("filename")?;
 
     // Note: filename can be '*' to delete all active swapfiles, however it needs to be quoted in YAML
@@ -240,7 +269,7 @@ pub fn disable_swap(action_provider: &dyn ActionProvider, connection: &mut Contr
 
     // cat /proc/swaps
     let list_swapfiles_command = "cat /proc/swaps".to_string();
-    connection.conn.send_command(&action_provider.post_process_command(&list_swapfiles_command));
+    connection.conn.send_command(&action_provider.post_process_command(&list_swapfiles_command))?;
 
     if connection.conn.did_exit_with_error_code() {
         return Err(ActionError::FailedCommand(connection.conn.return_failed_command_error_response_str(&list_swapfiles_command,
@@ -260,7 +289,7 @@ pub fn disable_swap(action_provider: &dyn ActionProvider, connection: &mut Contr
         // swapfiles, so just return success...
         // TODO: we might still have a file on disk if something was done manually? (but how to know
         //       about it other than fstab? likely not worth worrying about?)
-        return Ok(());
+        return Ok(ActionResult::Changed);
     }
     else if swap_file_lines.len() > 1 {
         for data_line in swap_file_lines.iter().skip(1) {
@@ -284,7 +313,7 @@ pub fn disable_swap(action_provider: &dyn ActionProvider, connection: &mut Contr
     if filename == "*" {
         // disable them all
         let swapoff_command = "swapoff -a".to_string();
-        connection.conn.send_command(&action_provider.post_process_command(&swapoff_command));
+        connection.conn.send_command(&action_provider.post_process_command(&swapoff_command))?;
 
         if connection.conn.did_exit_with_error_code() {
             return Err(ActionError::FailedCommand(connection.conn.return_failed_command_error_response_str(&swapoff_command,
@@ -297,7 +326,7 @@ pub fn disable_swap(action_provider: &dyn ActionProvider, connection: &mut Contr
         let swap_file = &swapfile_names_to_delete[0];
         let swapoff_command = format!("swapoff {}", swap_file);
 
-        connection.conn.send_command(&action_provider.post_process_command(&swapoff_command));
+        connection.conn.send_command(&action_provider.post_process_command(&swapoff_command))?;
 
         if connection.conn.did_exit_with_error_code() {
             return Err(ActionError::FailedCommand(connection.conn.return_failed_command_error_response_str(&swapoff_command,
@@ -312,7 +341,7 @@ pub fn disable_swap(action_provider: &dyn ActionProvider, connection: &mut Contr
     // now edit /etc/fstab and comment out any line which configures the swapfile we found above...
     let fstab_string_contents = connection.conn.get_text_file_contents(FSTAB_FILE_PATH).unwrap();
     if fstab_string_contents.is_empty() {
-        eprintln!("Error: /etc/fstab remote file has empty contents.");
+        host_eprintln!("Error: /etc/fstab remote file has empty contents.");
         return Err(ActionError::FailedCommand("".to_string()));
     }
     let fstab_contents_lines = fstab_string_contents.lines();
@@ -340,7 +369,7 @@ pub fn disable_swap(action_provider: &dyn ActionProvider, connection: &mut Contr
     let new_file_contents_string = new_file_contents_lines.join("\n") + "\n";
 
     let stat_command = format!("stat {}", FSTAB_FILE_PATH);
-    connection.conn.send_command(&action_provider.post_process_command(&stat_command));
+    connection.conn.send_command(&action_provider.post_process_command(&stat_command))?;
     if let Some(strerr) = connection.conn.get_previous_stderr_response() {
         return Err(ActionError::FailedOther(format!("Error accessing remote fstab path: {}", strerr)));
     }
@@ -355,7 +384,7 @@ pub fn disable_swap(action_provider: &dyn ActionProvider, connection: &mut Contr
     }
     else {
         mode = 0o644;
-        eprintln!("Can't extract stat details from file. Using 644 as default permissions mode.");
+        host_eprintln!("Can't extract stat details from file. Using 644 as default permissions mode.");
     }
 
     let send_res = connection.conn.send_text_file_contents(FSTAB_FILE_PATH, mode, &new_file_contents_string);
@@ -367,63 +396,79 @@ pub fn disable_swap(action_provider: &dyn ActionProvider, connection: &mut Contr
     // TODO: maybe wipe them optionally?
     for swap_file in swapfile_names_to_delete {
         let rm_command = format!("rm {}", swap_file);
-        connection.conn.send_command(&action_provider.post_process_command(&rm_command));
+        connection.conn.send_command(&action_provider.post_process_command(&rm_command))?;
         if let Some(strerr) = connection.conn.get_previous_stderr_response() {
             return Err(ActionError::FailedCommand(format!("Error deleting swapfile file: {}", strerr)));
         }
     }
     
-    Ok(())
+    Ok(ActionResult::Changed)
 }
 
 pub fn add_group(action_provider: &dyn ActionProvider, connection: &mut ControlSession, action: &ControlAction
-) -> Result<(), ActionError> {
+) -> Result<ActionResult, ActionError> {
     // use groupadd and usermod commands which should be common across Linux distros...
     let group_name = action.get_required_string_param("name")?;
 
-    let groupadd_full_command = format!("groupadd {}", group_name);
+    let mut result = ActionResult::Unchanged;
 
-    connection.conn.send_command(&action_provider.post_process_command(&groupadd_full_command));
+    let getent_command = format!("getent group {}", group_name);
+    let group_existed = common_actions_unix::probe_command_succeeds(action_provider, connection, &getent_command)?;
+    if !group_existed {
+        let groupadd_full_command = format!("groupadd {}", group_name);
 
-    if connection.conn.did_exit_with_error_code() {
-        return Err(ActionError::FailedCommand(connection.conn.return_failed_command_error_response_str(&groupadd_full_command,
-            action)));
+        connection.conn.send_command(&action_provider.post_process_command(&groupadd_full_command))?;
+
+        if connection.conn.did_exit_with_error_code() {
+            return Err(ActionError::FailedCommand(connection.conn.return_failed_command_error_response_str(&groupadd_full_command,
+                action)));
+        }
+
+        result = ActionResult::Changed;
     }
 
     // now add users specified to the group
     // see if there's just a single group...
-    if action.params.has_value("user") {
-        let usermod_command = format!("usermod -aG {} {}", group_name, action.params.get_string_value_with_default("user", ""));
-        connection.conn.send_command(&action_provider.post_process_command(&usermod_command));
+    let users = if action.params.has_value("user") {
+        vec![action.params.get_string_value_with_default("user", "")]
+    }
+    else {
+        // there might be multiple
+        action.params.get_values_as_vec_of_strings("users")
+    };
+
+    for user in users {
+        // if the group already existed, the user might already be in it...
+        if group_existed {
+            let id_command = format!("id -nG {}", user);
+            if let Some(user_groups) = common_actions_unix::probe_command_stdout(action_provider, connection, &id_command)? {
+                if user_groups.split_whitespace().any(|group| group == group_name) {
+                    continue;
+                }
+            }
+        }
+
+        let usermod_command = format!("usermod -aG {} {}", group_name, user);
+        connection.conn.send_command(&action_provider.post_process_command(&usermod_command))?;
         if connection.conn.did_exit_with_error_code() {
             return Err(ActionError::FailedCommand(connection.conn.return_failed_command_error_response_str(&usermod_command,
                 action)));
         }
-    }
-    else if action.params.has_value("users") {
-        // there's multiple
-        let users = action.params.get_values_as_vec_of_strings("users");
-        for user in users {
-            let usermod_command = format!("usermod -aG {} {}", group_name, user);
-            connection.conn.send_command(&action_provider.post_process_command(&usermod_command));
-            if connection.conn.did_exit_with_error_code() {
-                return Err(ActionError::FailedCommand(connection.conn.return_failed_command_error_response_str(&usermod_command,
-                    action)));
-            }
-        }
+
+        result = ActionResult::Changed;
     }
 
-    Ok(())
+    Ok(result)
 }
 
 pub fn set_hostname(action_provider: &dyn ActionProvider, connection: &mut ControlSession, action: &ControlAction
-) -> Result<(), ActionError> {
+) -> Result<ActionResult, ActionError> {
     // assume for the moment that systemd is installed, so hostnamectl can be used.
     let host_name = action.get_required_string_param("hostname")?;
 
     let hostnamectrl_full_command = format!("hostnamectl set-hostname {}", host_name);
 
-    connection.conn.send_command(&action_provider.post_process_command(&hostnamectrl_full_command));
+    connection.conn.send_command(&action_provider.post_process_command(&hostnamectrl_full_command))?;
 
     if connection.conn.did_exit_with_error_code() {
         return Err(ActionError::FailedCommand(connection.conn.return_failed_command_error_response_str(&hostnamectrl_full_command,
@@ -432,7 +477,7 @@ pub fn set_hostname(action_provider: &dyn ActionProvider, connection: &mut Contr
 
     // validate that it was set
     let hostnamectrl = "hostnamectl";
-    connection.conn.send_command(&action_provider.post_process_command(hostnamectrl));
+    connection.conn.send_command(&action_provider.post_process_command(hostnamectrl))?;
 
     if connection.conn.did_exit_with_error_code() {
         return Err(ActionError::FailedCommand(connection.conn.return_failed_command_error_response_str(hostnamectrl,
@@ -452,7 +497,7 @@ pub fn set_hostname(action_provider: &dyn ActionProvider, connection: &mut Contr
         if let Some(result) = stripped_line.strip_prefix("Static hostname:") {
             if result.trim() == host_name {
                 // it was set successfully...
-                return Ok(())
+                return Ok(ActionResult::Changed)
             }
         }
     }
@@ -462,7 +507,7 @@ pub fn set_hostname(action_provider: &dyn ActionProvider, connection: &mut Contr
 }
 
 pub fn create_systemd_service(action_provider: &dyn ActionProvider, connection: &mut ControlSession, action: &ControlAction
-) -> Result<(), ActionError> {
+) -> Result<ActionResult, ActionError> {
     let service_name = action.get_required_string_param("name")?;
     let description = action.get_required_string_param("description")?;
     let user = action.get_required_string_param("user")?;
@@ -472,8 +517,6 @@ pub fn create_systemd_service(action_provider: &dyn ActionProvider, connection:
 
     let unit_service_file_path = format!("/etc/systemd/system/{}.service", service_name);
 
-    // TODO: see if it exists already?
-
     let mut file_content = format!("[Unit]\nDescription={}\n", description);
 
     if let Some(after) = action.params.get_string_value("after") {
@@ -506,43 +549,75 @@ pub fn create_systemd_service(action_provider: &dyn ActionProvider, connection:
     // and this...
     file_content.push_str("[Install]\nWantedBy=multi-user.target\n");
 
-    // Note: currently with ssh-rs being used (which doesn't support setting target file mode perms), this
-    //       will only be useable with the 'root' user being enabled.
-    let res = connection.conn.send_text_file_contents(&unit_service_file_path, 0o644, &file_content);
-    if let Err(err) = res {
-        return Err(ActionError::FailedCommand(format!("Error creating remote file for new service: '{}', error: {}",
-        unit_service_file_path, err)));
-    }
+    let mut result = ActionResult::Unchanged;
+
+    // see if the service unit file already exists with the same content, in which case we don't
+    // need to write it again or reload systemd...
+    let test_command = format!("test -f {}", unit_service_file_path);
+    let unit_file_matches = common_actions_unix::probe_command_succeeds(action_provider, connection, &test_command)? &&
+        connection.conn.get_text_file_contents(&unit_service_file_path).is_ok_and(|existing| existing == file_content);
+
+    if !unit_file_matches {
+        // Note: currently with ssh-rs being used (which doesn't support setting target file mode perms), this
+        //       will only be useable with the 'root' user being enabled.
+        let res = connection.conn.send_text_file_contents(&unit_service_file_path, 0o644, &file_content);
+        if let Err(err) = res {
+            return Err(ActionError::FailedCommand(format!("Error creating remote file for new service: '{}', error: {}",
+            unit_service_file_path, err)));
+        }
 
-    // reload it
+        // reload it
 
-    let reload_command = "sudo systemctl daemon-reload";
-    connection.conn.send_command(&action_provider.post_process_command(reload_command));
-    if connection.conn.did_exit_with_error_code() {
-        return Err(ActionError::FailedCommand(connection.conn.return_failed_command_error_response_str(reload_command,
-            action)));
+        let reload_command = "sudo systemctl daemon-reload";
+        connection.conn.send_command(&action_provider.post_process_command(reload_command))?;
+        if connection.conn.did_exit_with_error_code() {
+            return Err(ActionError::FailedCommand(connection.conn.return_failed_command_error_response_str(reload_command,
+                action)));
+        }
+
+        result = ActionResult::Changed;
     }
 
     // check to see if we've been told not to start it now
     let should_start = action.params.get_value_as_bool("startNow").unwrap_or(true);
     if should_start {
-        // now start it
-        let systemctrl_start_command = format!("systemctl start {}", service_name);
-        connection.conn.send_command(&action_provider.post_process_command(&systemctrl_start_command));
+        let is_active_command = format!("systemctl is-active {}", service_name);
+        let is_active = common_actions_unix::probe_command_succeeds(action_provider, connection, &is_active_command)?;
 
-        if connection.conn.did_exit_with_error_code() {
-            return Err(ActionError::FailedCommand(connection.conn.return_failed_command_error_response_str(&systemctrl_start_command,
-                action)));
+        // if it's already running but the unit file has changed, restart it so the changes take effect,
+        // otherwise just start it if it isn't running
+        let systemctrl_start_command = if !is_active {
+            Some(format!("systemctl start {}", service_name))
+        }
+        else if !unit_file_matches {
+            Some(format!("systemctl restart {}", service_name))
+        }
+        else {
+            None
+        };
+
+        if let Some(systemctrl_start_command) = systemctrl_start_command {
+            connection.conn.send_command(&action_provider.post_process_command(&systemctrl_start_command))?;
+
+            if connection.conn.did_exit_with_error_code() {
+                return Err(ActionError::FailedCommand(connection.conn.return_failed_command_error_response_str(&systemctrl_start_command,
+                    action)));
+            }
+            result = ActionResult::Changed;
         }
     }
 
     // now enable it (think this starts it on boot... maybe that should be conditional, i.e. connected with the 'WantedBy' bit?)
-    let systemctrl_enable_command = format!("systemctl enable {}", service_name);
-    connection.conn.send_command(&action_provider.post_process_command(&systemctrl_enable_command));
+    let is_enabled_command = format!("systemctl is-enabled {}", service_name);
+    if !common_actions_unix::probe_command_succeeds(action_provider, connection, &is_enabled_command)? {
+        let systemctrl_enable_command = format!("systemctl enable {}", service_name);
+        connection.conn.send_command(&action_provider.post_process_command(&systemctrl_enable_command))?;
 
-    if connection.conn.did_exit_with_error_code() {
-        return Err(ActionError::FailedCommand(connection.conn.return_failed_command_error_response_str(&systemctrl_enable_command,
-            action)));
+        if connection.conn.did_exit_with_error_code() {
+            return Err(ActionError::FailedCommand(connection.conn.return_failed_command_error_response_str(&systemctrl_enable_command,
+                action)));
+        }
+        result = ActionResult::Changed;
     }
 
     if should_start {
@@ -557,6 +632,84 @@ pub fn create_systemd_service(action_provider: &dyn ActionProvider, connection:
         //       although maybe that's not needed?
     }
 
-    Ok(())
+    Ok(result)
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::control::common_actions_unix::{create_scripted_session, make_test_action, run_scripted_action};
+    use crate::control::control_connection::ControlConnectionScripted;
+
+    #[test]
+    fn test_add_user() {
+        let action = make_test_action(&[("username", "deploy"), ("password", "hunter2")]);
+
+        let mut session = create_scripted_session(ControlConnectionScripted::new().respond("id -u deploy", "1001\n", 0));
+        let (result, operations) = run_scripted_action(add_user, &mut session, &action);
+        assert_eq!(result.ok(), Some(ActionResult::Unchanged));
+        assert_eq!(operations, vec!["id -u deploy"]);
+
+        let mut session = create_scripted_session(ControlConnectionScripted::new().respond("id -u deploy", "", 1));
+        let (result, operations) = run_scripted_action(add_user, &mut session, &action);
+        assert_eq!(result.ok(), Some(ActionResult::Changed));
+        assert_eq!(operations, vec!["id -u deploy", "useradd -m -s /bin/bash deploy", " echo -e 'deploy:hunter2' | chpasswd"]);
+    }
+
+    #[test]
+    fn test_add_group() {
+        let action = make_test_action(&[("name", "docker"), ("user", "deploy")]);
+
+        // the group exists, and the user's already in it
+        let mut session = create_scripted_session(ControlConnectionScripted::new().respond("getent group docker", "docker:x:999:deploy\n", 0)
+                                                                                     .respond("id -nG deploy", "deploy sudo docker\n", 0));
+        let (result, operations) = run_scripted_action(add_group, &mut session, &action);
+        assert_eq!(result.ok(), Some(ActionResult::Unchanged));
+        assert_eq!(operations, vec!["getent group docker", "id -nG deploy"]);
+
+        let mut session = create_scripted_session(ControlConnectionScripted::new().respond("getent group docker", "docker:x:999:\n", 0)
+                                                                                     .respond("id -nG deploy", "deploy sudo\n", 0));
+        let (result, operations) = run_scripted_action(add_group, &mut session, &action);
+        assert_eq!(result.ok(), Some(ActionResult::Changed));
+        assert_eq!(operations, vec!["getent group docker", "id -nG deploy", "usermod -aG docker deploy"]);
+
+        // the group doesn't exist, so the user can't be in it yet
+        let mut session = create_scripted_session(ControlConnectionScripted::new().respond("getent group docker", "", 2));
+        let (result, operations) = run_scripted_action(add_group, &mut session, &action);
+        assert_eq!(result.ok(), Some(ActionResult::Changed));
+        assert_eq!(operations, vec!["getent group docker", "groupadd docker", "usermod -aG docker deploy"]);
+    }
+
+    #[test]
+    fn test_create_systemd_service() {
+        let action = make_test_action(&[("name", "app"), ("description", "App server"), ("user", "deploy"), ("execStart", "/srv/app/bin/server")]);
+        let unit_path = "/etc/systemd/system/app.service";
+
+        let mut session = create_scripted_session(ControlConnectionScripted::new().respond("test -f", "", 1)
+                                                                                     .respond("is-active", "inactive\n", 3)
+                                                                                     .respond("is-enabled", "disabled\n", 1));
+        let (result, operations) = run_scripted_action(create_systemd_service, &mut session, &action);
+        assert_eq!(result.ok(), Some(ActionResult::Changed));
+        assert_eq!(operations.len(), 7);
+        assert_eq!(&operations[2..], ["sudo systemctl daemon-reload", "systemctl is-active app", "systemctl start app", "systemctl is-enabled app",
+                                      "systemctl enable app"]);
+        let unit_file_contents = session.conn.get_text_file_contents(unit_path).unwrap();
+        assert!(unit_file_contents.contains("ExecStart=/srv/app/bin/server\n"));
+
+        // already written, running and enabled
+        let mut session = create_scripted_session(ControlConnectionScripted::new().with_file(unit_path, &unit_file_contents));
+        let (result, operations) = run_scripted_action(create_systemd_service, &mut session, &action);
+        assert_eq!(result.ok(), Some(ActionResult::Unchanged));
+        assert_eq!(operations, vec![format!("test -f {}", unit_path), format!("read file:     {}", unit_path), "systemctl is-active app".to_string(),
+                                    "systemctl is-enabled app".to_string()]);
+
+        // running, but with an out-of-date unit file, so it needs restarting
+        let mut session = create_scripted_session(ControlConnectionScripted::new().with_file(unit_path, "[Unit]\nDescription=Old\n"));
+        let (result, operations) = run_scripted_action(create_systemd_service, &mut session, &action);
+        assert_eq!(result.ok(), Some(ActionResult::Changed));
+        assert!(operations.contains(&"systemctl restart app".to_string()));
+        assert!(!operations.contains(&"systemctl enable app".to_string()));
+    }
+}
@@ -13,20 +13,24 @@
  ---------
 */
 
+use sha2::{Digest, Sha256};
+
 use crate::control::terminal_helpers_linux;
 use crate::params::ParamValue;
 
+use super::control_output::host_eprintln;
 use super::common_actions_unix_edit_file;
 use super::file_modifier_helpers::{modify_sshd_config_file_contents, ModifySshDConfigParams, SshDPermitRootLoginType};
 
-use super::control_actions::{ActionProvider, ActionError, ControlAction};
+use super::control_actions::{ActionProvider, ActionError, ActionResult, ControlAction};
 use super::control_common::ControlSession;
+use super::control_connection::TransportError;
 
 pub fn generic_command(action_provider: &dyn ActionProvider, connection: &mut ControlSession, action: &ControlAction
-) -> Result<(), ActionError> {
+) -> Result<ActionResult, ActionError> {
     let command = action.get_required_string_param("command")?;
     if !command.is_empty() {
-        connection.conn.send_command(&action_provider.post_process_command(&command));
+        connection.conn.send_command(&action_provider.post_process_command(&command))?;
     }
 
     if action.params.get_value_as_bool("errorIfStdErrOutputExists").unwrap_or(false) {
@@ -46,66 +50,49 @@ pub fn generic_command(action_provider: &dyn ActionProvider, connection: &mut Co
 
     // TODO: support for specifying expected number of lines of output as well...
 
-    Ok(())
+    Ok(ActionResult::Changed)
 }
 
 pub fn create_directory(action_provider: &dyn ActionProvider, connection: &mut ControlSession, action: &ControlAction
-) -> Result<(), ActionError> {
+) -> Result<ActionResult, ActionError> {
     let path_to_create = action.get_required_string_param("path")?;
 
-    // TODO: not sure about this... Maybe it should be called something else, maybe it should
-    //       be the default?
-    let multi_level = action.params.get_value_as_bool("multiLevel").unwrap_or(false);
-    let mkdir_command = if !multi_level {
-        format!("mkdir {}", path_to_create)
-    }
-    else {
-        format!("mkdir -p {}", path_to_create)
-    };
-    connection.conn.send_command(&action_provider.post_process_command(&mkdir_command));
-
-    if connection.conn.did_exit_with_error_code() {
-        return Err(ActionError::FailedCommand(connection.conn.return_failed_command_error_response_str(&mkdir_command,
-            action)));
-    }
+    let mut result = ActionResult::Unchanged;
 
-    if let Some(permissions) = action.params.get_string_or_int_value_as_string("permissions") {
-        let chmod_command = format!("chmod {} {}", permissions, path_to_create);
-        connection.conn.send_command(&action_provider.post_process_command(&chmod_command));
+    // see if it exists already, in which case we just need to check the permissions and ownership below...
+    let existing_details = get_remote_path_mode_and_ownership(action_provider, connection, &path_to_create, true)?;
 
-        if connection.conn.did_exit_with_error_code() {
-            return Err(ActionError::FailedCommand(connection.conn.return_failed_command_error_response_str(&chmod_command,
-                action)));
+    if existing_details.is_none() {
+        // TODO: not sure about this... Maybe it should be called something else, maybe it should
+        //       be the default?
+        let multi_level = action.params.get_value_as_bool("multiLevel").unwrap_or(false);
+        let mkdir_command = if !multi_level {
+            format!("mkdir {}", path_to_create)
         }
-    }
-
-    if let Some(owner) = action.params.get_string_value("owner") {
-        let chown_command = format!("chown {} {}", owner, path_to_create);
-        connection.conn.send_command(&action_provider.post_process_command(&chown_command));
+        else {
+            format!("mkdir -p {}", path_to_create)
+        };
+        connection.conn.send_command(&action_provider.post_process_command(&mkdir_command))?;
 
         if connection.conn.did_exit_with_error_code() {
-            return Err(ActionError::FailedCommand(connection.conn.return_failed_command_error_response_str(&chown_command,
+            return Err(ActionError::FailedCommand(connection.conn.return_failed_command_error_response_str(&mkdir_command,
                 action)));
         }
-    }
 
-    if let Some(group) = action.params.get_string_value("group") {
-        let chgrp_command = format!("chgrp {} {}", group, path_to_create);
-        connection.conn.send_command(&action_provider.post_process_command(&chgrp_command));
+        result = ActionResult::Changed;
+    }
 
-        if connection.conn.did_exit_with_error_code() {
-            return Err(ActionError::FailedCommand(connection.conn.return_failed_command_error_response_str(&chgrp_command,
-                action)));
-        }
+    if apply_path_permissions_and_ownership(action_provider, connection, action, &path_to_create, existing_details.as_ref())? {
+        result = ActionResult::Changed;
     }
 
     // TODO: check for 'groups' as well to handle setting multiple...
 
-    Ok(())
+    Ok(result)
 }
 
 pub fn remove_directory(action_provider: &dyn ActionProvider, connection: &mut ControlSession, action: &ControlAction
-) -> Result<(), ActionError> {
+) -> Result<ActionResult, ActionError> {
     let path_to_remove = action.get_required_string_param("path")?;
 
     let recursive = action.params.get_value_as_bool("recursive").unwrap_or(true);
@@ -119,22 +106,22 @@ pub fn remove_directory(action_provider: &dyn ActionProvider, connection: &mut C
 
     let ignore_failure = action.params.get_value_as_bool("ignoreFailure").unwrap_or(false);
 
-    connection.conn.send_command(&action_provider.post_process_command(&rmdir_command));
+    connection.conn.send_command(&action_provider.post_process_command(&rmdir_command))?;
     if !ignore_failure && connection.conn.did_exit_with_error_code() {
         return Err(ActionError::FailedCommand(connection.conn.return_failed_command_error_response_str(&rmdir_command,
             action)));
     }
 
-    Ok(())
+    Ok(ActionResult::Changed)
 }
 
 pub fn edit_file(action_provider: &dyn ActionProvider, connection: &mut ControlSession, action: &ControlAction
-) -> Result<(), ActionError> {
+) -> Result<ActionResult, ActionError> {
     common_actions_unix_edit_file::edit_file(action_provider, connection, action)
 }
 
 pub fn copy_path(action_provider: &dyn ActionProvider, connection: &mut ControlSession, action: &ControlAction
-) -> Result<(), ActionError> {
+) -> Result<ActionResult, ActionError> {
     let source_path = action.get_required_string_param("sourcePath")?;
     let dest_path = action.get_required_string_param("destPath")?;
    
@@ -151,55 +138,55 @@ pub fn copy_path(action_provider: &dyn ActionProvider, connection: &mut ControlS
     option_flags = option_flags.trim().to_string();
 
     let cp_command = format!("cp {} {} {}", option_flags, source_path, dest_path);
-    connection.conn.send_command(&action_provider.post_process_command(&cp_command));
+    connection.conn.send_command(&action_provider.post_process_command(&cp_command))?;
 
     if connection.conn.did_exit_with_error_code() {
         return Err(ActionError::FailedCommand(connection.conn.return_failed_command_error_response_str(&cp_command,
             action)));
     }
 
-    Ok(())
+    Ok(ActionResult::Changed)
 }
 
 pub fn remove_file(action_provider: &dyn ActionProvider, connection: &mut ControlSession, action: &ControlAction
-) -> Result<(), ActionError> {
+) -> Result<ActionResult, ActionError> {
     let path = action.get_required_string_param("path")?;
 
     let rm_command = format!("rm {}", path);
 
     let ignore_failure = action.params.get_value_as_bool("ignoreFailure").unwrap_or(false);
 
-    connection.conn.send_command(&action_provider.post_process_command(&rm_command));
+    connection.conn.send_command(&action_provider.post_process_command(&rm_command))?;
     if !ignore_failure && connection.conn.did_exit_with_error_code() {
         return Err(ActionError::FailedCommand(connection.conn.return_failed_command_error_response_str(&rm_command,
             action)));
     }
 
-    Ok(())
+    Ok(ActionResult::Changed)
 }
 
 pub fn download_file(action_provider: &dyn ActionProvider, connection: &mut ControlSession, action: &ControlAction
-) -> Result<(), ActionError> {
+) -> Result<ActionResult, ActionError> {
     let source_url = action.get_required_string_param("sourceURL")?;
     let dest_path = action.get_required_string_param("destPath")?;
 
     // use wget (maybe curl backup?) for the moment
     let wget_command = format!("wget {} -O {}", source_url, dest_path);
-    connection.conn.send_command(&action_provider.post_process_command(&wget_command));
+    connection.conn.send_command(&action_provider.post_process_command(&wget_command))?;
 
     if let Some(permissions) = action.params.get_string_or_int_value_as_string("permissions") {
         let chmod_command = format!("chmod {} {}", permissions, dest_path);
-        connection.conn.send_command(&action_provider.post_process_command(&chmod_command));
+        connection.conn.send_command(&action_provider.post_process_command(&chmod_command))?;
     }
 
     if let Some(owner) = action.params.get_string_value("owner") {
         let chown_command = format!("chown {} {}", owner, dest_path);
-        connection.conn.send_command(&action_provider.post_process_command(&chown_command));
+        connection.conn.send_command(&action_provider.post_process_command(&chown_command))?;
     }
 
     if let Some(group) = action.params.get_string_value("group") {
         let chgrp_command = format!("chgrp {} {}", group, dest_path);
-        connection.conn.send_command(&action_provider.post_process_command(&chgrp_command));
+        connection.conn.send_command(&action_provider.post_process_command(&chgrp_command))?;
     }
 
     // see if we should also extract it
@@ -207,7 +194,7 @@ pub fn download_file(action_provider: &dyn ActionProvider, connection: &mut Cont
         // check this directory actually exists...
         if !extract_dir.is_empty() {
             let test_cmd = format!("test -d {} && echo \"yep\"", extract_dir);
-            connection.conn.send_command(&action_provider.post_process_command(&test_cmd));
+            connection.conn.send_command(&action_provider.post_process_command(&test_cmd))?;
 
             // check the output is "yep"
             if connection.conn.get_previous_stdout_response().is_empty() {
@@ -221,21 +208,21 @@ pub fn download_file(action_provider: &dyn ActionProvider, connection: &mut Cont
             if dest_path.ends_with(".zip") {
                 // assume it's a .zip file...
                 let zip_cmd = format!("unzip {} -d {}", dest_path, extract_dir);
-                connection.conn.send_command(&action_provider.post_process_command(&zip_cmd));
+                connection.conn.send_command(&action_provider.post_process_command(&zip_cmd))?;
             }
             else {
                 // otherwise, assume it's some form of tar file...
                 let tar_cmd = format!("tar -xf {} -C {}", dest_path, extract_dir);
-                connection.conn.send_command(&action_provider.post_process_command(&tar_cmd));
+                connection.conn.send_command(&action_provider.post_process_command(&tar_cmd))?;
             }
         }
     }
 
-    Ok(())
+    Ok(ActionResult::Changed)
 }
 
 pub fn transmit_file(action_provider: &dyn ActionProvider, connection: &mut ControlSession, action: &ControlAction
-) -> Result<(), ActionError> {
+) -> Result<ActionResult, ActionError> {
     // local source path
     // TODO: not sure about this naming...
     let source_path = action.get_required_string_param("localSourcePath")?;
@@ -248,27 +235,42 @@ pub fn transmit_file(action_provider: &dyn ActionProvider, connection: &mut Cont
         mode = i32::from_str_radix(&permissions, 8).unwrap();
     }
 
-    let send_res = connection.conn.send_file(&source_path, &dest_path, mode);
-    if send_res.is_err() {
-        return Err(ActionError::FailedOther("Failed to send file to host".to_string()));
+    // see if the file on the host is already the same as the local one, in which case we don't need to send it
+    let local_checksum = get_local_file_sha256(&source_path).map_err(|err| {
+        ActionError::FailedOther(format!("Couldn't read local file: '{}', error: {}", source_path, err))
+    })?;
+
+    let existing_details = get_remote_path_mode_and_ownership(action_provider, connection, &dest_path, false)?;
+    let remote_checksum = if existing_details.is_some() {
+        probe_command_stdout(action_provider, connection, &format!("sha256sum {}", dest_path))?
+            .and_then(|output| output.split_whitespace().next().map(|checksum| checksum.to_string()))
     }
+    else {
+        None
+    };
 
-    if let Some(owner) = action.params.get_string_value("owner") {
-        let chown_command = format!("chown {} {}", owner, dest_path);
-        connection.conn.send_command(&action_provider.post_process_command(&chown_command));
+    if remote_checksum.as_deref() == Some(local_checksum.as_str()) {
+        // it's the same, so we just need to check the permissions and ownership
+        if apply_path_permissions_and_ownership(action_provider, connection, action, &dest_path, existing_details.as_ref())? {
+            return Ok(ActionResult::Changed);
+        }
+        return Ok(ActionResult::Unchanged);
     }
 
-    if let Some(group) = action.params.get_string_value("group") {
-        let chgrp_command = format!("chgrp {} {}", group, dest_path);
-        connection.conn.send_command(&action_provider.post_process_command(&chgrp_command));
+    let send_res = connection.conn.send_file(&source_path, &dest_path, mode);
+    if send_res.is_err() {
+        return Err(ActionError::FailedOther("Failed to send file to host".to_string()));
     }
 
+    // overwriting an existing file might not change its mode, so set it and the ownership if specified
+    apply_path_permissions_and_ownership(action_provider, connection, action, &dest_path, None)?;
+
     // see if we should also extract it
     if let Some(extract_dir) = action.params.get_string_value("extractDir") {
         // check this directory actually exists...
         if !extract_dir.is_empty() {
             let test_cmd = format!("test -d {} && echo \"yep\"", extract_dir);
-            connection.conn.send_command(&action_provider.post_process_command(&test_cmd));
+            connection.conn.send_command(&action_provider.post_process_command(&test_cmd))?;
 
             // check the output is "yep"
             if connection.conn.get_previous_stdout_response().is_empty() {
@@ -282,12 +284,12 @@ pub fn transmit_file(action_provider: &dyn ActionProvider, connection: &mut Cont
             if dest_path.ends_with(".zip") {
                 // assume it's a .zip file...
                 let zip_cmd = format!("unzip {} -d {}", dest_path, extract_dir);
-                connection.conn.send_command(&action_provider.post_process_command(&zip_cmd));
+                connection.conn.send_command(&action_provider.post_process_command(&zip_cmd))?;
             }
             else {
                 // otherwise, assume it's some form of tar file...
                 let tar_cmd = format!("tar -xf {} -C {}", dest_path, extract_dir);
-                connection.conn.send_command(&action_provider.post_process_command(&tar_cmd));
+                connection.conn.send_command(&action_provider.post_process_command(&tar_cmd))?;
             }
 
             // validate that extraction worked.
@@ -307,11 +309,11 @@ pub fn transmit_file(action_provider: &dyn ActionProvider, connection: &mut Cont
         }
     }
 
-    Ok(())
+    Ok(ActionResult::Changed)
 }
 
 pub fn receive_file(_action_provider: &dyn ActionProvider, connection: &mut ControlSession, action: &ControlAction
-) -> Result<(), ActionError> {
+) -> Result<ActionResult, ActionError> {
     // remote source path
     // TODO: not sure about this naming...
     let source_path = action.get_required_string_param("remoteSourcePath")?;
@@ -324,75 +326,96 @@ pub fn receive_file(_action_provider: &dyn ActionProvider, connection: &mut Cont
         return Err(ActionError::FailedOther("Failed to receive file from host".to_string()));
     }
 
-    Ok(())
+    Ok(ActionResult::Changed)
 }
 
 pub fn create_symlink(action_provider: &dyn ActionProvider, connection: &mut ControlSession, action: &ControlAction
-) -> Result<(), ActionError> {
+) -> Result<ActionResult, ActionError> {
     let target_path = action.get_required_string_param("targetPath")?;
   
     // link path
     let link_path = action.get_required_string_param("linkPath")?;
-    let ln_command = format!("ln -s {} {}", target_path, link_path);
-    connection.conn.send_command(&action_provider.post_process_command(&ln_command));
+
+    // see if the link already exists and points to the right target...
+    let readlink_command = format!("readlink {}", link_path);
+    let existing_target = probe_command_stdout(action_provider, connection, &readlink_command)?;
+    if existing_target.as_deref() == Some(target_path.as_str()) {
+        return Ok(ActionResult::Unchanged);
+    }
+
+    // if it's a link to somewhere else, replace it (-n so that a link to a directory is replaced itself,
+    // rather than a new link being created within the directory).
+    let ln_command = if existing_target.is_some() {
+        format!("ln -sfn {} {}", target_path, link_path)
+    }
+    else {
+        format!("ln -s {} {}", target_path, link_path)
+    };
+    connection.conn.send_command(&action_provider.post_process_command(&ln_command))?;
 
     if connection.conn.did_exit_with_error_code() {
         return Err(ActionError::FailedCommand(connection.conn.return_failed_command_error_response_str(&ln_command,
             action)));
     }
 
-    Ok(())
+    Ok(ActionResult::Changed)
 }
 
 pub fn create_file(action_provider: &dyn ActionProvider, connection: &mut ControlSession, action: &ControlAction
-) -> Result<(), ActionError> {
+) -> Result<ActionResult, ActionError> {
     let path_to_create = action.get_required_string_param("path")?;
 
     // TODO: maybe add support for creating any subdirs if required?
-    
+
+    let mut result = ActionResult::Unchanged;
+
+    let mut existing_details = get_remote_path_mode_and_ownership(action_provider, connection, &path_to_create, false)?;
+
     // see if there's any content we need
     if let Some(content) = action.params.get_string_value("content") {
-        // send the content as a file to write
-        let send_res = connection.conn.send_text_file_contents(&path_to_create, 0o644, &content);
-        if send_res.is_err() {
-            return Err(ActionError::FailedOther("Failed to send text file contents to create file.".to_string()));
+        // only write it if the file doesn't already have the same content
+        let existing_content = if existing_details.is_some() {
+            connection.conn.get_text_file_contents(&path_to_create).ok()
+        }
+        else {
+            None
+        };
+
+        if existing_content.as_deref() != Some(content.as_str()) {
+            // send the content as a file to write
+            let send_res = connection.conn.send_text_file_contents(&path_to_create, 0o644, &content);
+            if send_res.is_err() {
+                return Err(ActionError::FailedOther("Failed to send text file contents to create file.".to_string()));
+            }
+            // we don't know what the mode and ownership will be now, so set them if specified
+            existing_details = None;
+            result = ActionResult::Changed;
         }
     }
-    else {
+    else if existing_details.is_none() {
         // create an empty file, as there was no content param specified.
         let touch_command = format!("touch {}", path_to_create);
-        connection.conn.send_command(&action_provider.post_process_command(&touch_command));
+        connection.conn.send_command(&action_provider.post_process_command(&touch_command))?;
         if let Some(strerr) = connection.conn.get_previous_stderr_response() {
             return Err(ActionError::FailedOther(format!("Failed to create file: Err: {}", strerr)));
         }
+        result = ActionResult::Changed;
     }
 
-    // TODO: maybe move this somewhere more common, so it can be shared more?
-    if let Some(permissions) = action.params.get_string_or_int_value_as_string("permissions") {
-        let chmod_command = format!("chmod {} {}", permissions, path_to_create);
-        connection.conn.send_command(&action_provider.post_process_command(&chmod_command));
-    }
-
-    if let Some(owner) = action.params.get_string_value("owner") {
-        let chown_command = format!("chown {} {}", owner, path_to_create);
-        connection.conn.send_command(&action_provider.post_process_command(&chown_command));
-    }
-
-    if let Some(group) = action.params.get_string_value("group") {
-        let chgrp_command = format!("chgrp {} {}", group, path_to_create);
-        connection.conn.send_command(&action_provider.post_process_command(&chgrp_command));
+    if apply_path_permissions_and_ownership(action_provider, connection, action, &path_to_create, existing_details.as_ref())? {
+        result = ActionResult::Changed;
     }
 
     // TODO: check for 'groups' as well to handle setting multiple...
 
-    Ok(())
+    Ok(result)
 }
 
 // Note: rather than using the exiting EditFile functionality (which needs improvement), for the moment this is using
 //       bespoke other code to make the config file changes, so as to hopefully be a bit more robust to variations
 //       in things like whitespace...
 pub fn configure_ssh(action_provider: &dyn ActionProvider, connection: &mut ControlSession, action: &ControlAction
-) -> Result<(), ActionError> {
+) -> Result<ActionResult, ActionError> {
 
     let mut modify_sshd_config = ModifySshDConfigParams::new();
 
@@ -454,7 +477,7 @@ pub fn configure_ssh(action_provider: &dyn ActionProvider, connection: &mut Cont
     // Note: the Stat returned by scp_recv() is currently a private field, so we can only access bits of it,
     //       so we need to do a full stat call remotely to get the actual info
     let stat_command = format!("stat {}", REMOTE_CONF_FILEPATH);
-    connection.conn.send_command(&action_provider.post_process_command(&stat_command));
+    connection.conn.send_command(&action_provider.post_process_command(&stat_command))?;
     if let Some(strerr) = connection.conn.get_previous_stderr_response() {
         return Err(ActionError::FailedOther(format!("Error accessing remote file path: {}", strerr)));
     }
@@ -463,7 +486,7 @@ pub fn configure_ssh(action_provider: &dyn ActionProvider, connection: &mut Cont
     if action.params.get_value_as_bool("backup").unwrap_or(false) {
         // TODO: something more robust than this...
         let mv_command = format!("cp {0} {0}.bak", REMOTE_CONF_FILEPATH);
-        connection.conn.send_command(&action_provider.post_process_command(&mv_command));
+        connection.conn.send_command(&action_provider.post_process_command(&mv_command))?;
         if let Some(strerr) = connection.conn.get_previous_stderr_response() {
             return Err(ActionError::FailedOther(format!("Error making backup copy of remote file path: {}", strerr)));
         }
@@ -476,7 +499,7 @@ pub fn configure_ssh(action_provider: &dyn ActionProvider, connection: &mut Cont
     // download the file
     let string_contents = connection.conn.get_text_file_contents(REMOTE_CONF_FILEPATH).unwrap();
     if string_contents.is_empty() {
-        eprintln!("Error: remote file: {} has empty contents.", REMOTE_CONF_FILEPATH);
+        host_eprintln!("Error: remote file: {} has empty contents.", REMOTE_CONF_FILEPATH);
         return Err(ActionError::FailedOther("".to_string()));
     }
 
@@ -490,7 +513,7 @@ pub fn configure_ssh(action_provider: &dyn ActionProvider, connection: &mut Cont
     }
     else {
         mode = 0o644;
-        eprintln!("Can't extract stat details from file. Using 644 as default permissions mode.");
+        host_eprintln!("Can't extract stat details from file. Using 644 as default permissions mode.");
     }
     
     let send_res = connection.conn.send_text_file_contents(REMOTE_CONF_FILEPATH, mode, &modified_file_contents);
@@ -508,7 +531,7 @@ pub fn configure_ssh(action_provider: &dyn ActionProvider, connection: &mut Cont
     let restart_sshd_service = action.params.get_value_as_bool("restartService").unwrap_or(true);
     if restart_sshd_service {
         let systemctrl_restart_command = "systemctl restart sshd";
-        connection.conn.send_command(&action_provider.post_process_command(systemctrl_restart_command));
+        connection.conn.send_command(&action_provider.post_process_command(systemctrl_restart_command))?;
 
         if connection.conn.did_exit_with_error_code() {
             return Err(ActionError::FailedCommand(connection.conn.return_failed_command_error_response_str(systemctrl_restart_command,
@@ -516,5 +539,225 @@ pub fn configure_ssh(action_provider: &dyn ActionProvider, connection: &mut Cont
         }
     }
 
-    Ok(())
-}
\ No newline at end of file
+    Ok(ActionResult::Changed)
+}
+
+// State probe helpers, used by actions to check the current state of the host before making any changes,
+// so that they can skip things which are already as required.
+// With dry-runs there's no actual host to query, so these always report that things aren't as required,
+// so that the full list of commands which would be needed on a fresh host is shown.
+
+// quotes the arg for use in a shell command if it needs it, i.e. if it has spaces or quotes in it.
+pub fn quote_shell_arg(arg: &str) -> String {
+    if !arg.is_empty() && arg.chars().all(|c| c.is_ascii_alphanumeric() || "-_./=:,@+%".contains(c)) {
+        arg.to_string()
+    }
+    else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+// runs the command, returning whether it succeeded with a 0 exit code.
+pub fn probe_command_succeeds(action_provider: &dyn ActionProvider, connection: &mut ControlSession, command: &str) -> Result<bool, TransportError> {
+    let outcome = connection.conn.send_command(&action_provider.post_process_command(command))?;
+
+    Ok(!connection.conn.is_dry_run() && !outcome.exited_with_error_code())
+}
+
+// runs the command, returning the trimmed stdout output if it succeeded with a 0 exit code.
+pub fn probe_command_stdout(action_provider: &dyn ActionProvider, connection: &mut ControlSession, command: &str) -> Result<Option<String>, TransportError> {
+    if !probe_command_succeeds(action_provider, connection, command)? {
+        return Ok(None);
+    }
+
+    Ok(Some(connection.conn.get_previous_stdout_response().trim().to_string()))
+}
+
+// runs the command, returning any stdout output regardless of the exit code, for commands like package
+// queries which return non-0 exit codes if only some items match.
+pub fn probe_command_any_stdout(action_provider: &dyn ActionProvider, connection: &mut ControlSession, command: &str) -> Result<String, TransportError> {
+    let outcome = connection.conn.send_command(&action_provider.post_process_command(command))?;
+
+    if connection.conn.is_dry_run() {
+        return Ok(String::new());
+    }
+
+    Ok(outcome.stdout)
+}
+
+// returns the octal permissions, owner and group of the path if it exists.
+pub fn get_remote_path_mode_and_ownership(action_provider: &dyn ActionProvider, connection: &mut ControlSession, path: &str,
+    is_directory: bool) -> Result<Option<(String, String, String)>, TransportError> {
+    let type_test = if is_directory { "-d" } else { "-f" };
+    let stat_command = format!("test {} {1} && stat -c '%a %U %G' {1}", type_test, path);
+    let Some(stat_response) = probe_command_stdout(action_provider, connection, &stat_command)? else {
+        return Ok(None);
+    };
+
+    let items: Vec<&str> = stat_response.split_whitespace().collect();
+    if items.len() != 3 {
+        return Ok(None);
+    }
+
+    Ok(Some((items[0].to_string(), items[1].to_string(), items[2].to_string())))
+}
+
+// sets the permissions, owner and group of the path from the action's 'permissions', 'owner' and 'group' params if they're
+// specified and they're different to the existing details (if known), returning whether anything was changed.
+fn apply_path_permissions_and_ownership(action_provider: &dyn ActionProvider, connection: &mut ControlSession, action: &ControlAction,
+    path: &str, existing_details: Option<&(String, String, String)>) -> Result<bool, ActionError> {
+    let mut changed = false;
+
+    if let Some(permissions) = action.params.get_string_or_int_value_as_string("permissions") {
+        if existing_details.is_none_or(|details| !are_permissions_equal(&details.0, &permissions)) {
+            let chmod_command = format!("chmod {} {}", permissions, path);
+            connection.conn.send_command(&action_provider.post_process_command(&chmod_command))?;
+
+            if connection.conn.did_exit_with_error_code() {
+                return Err(ActionError::FailedCommand(connection.conn.return_failed_command_error_response_str(&chmod_command,
+                    action)));
+            }
+            changed = true;
+        }
+    }
+
+    if let Some(owner) = action.params.get_string_value("owner") {
+        if existing_details.is_none_or(|details| details.1 != owner) {
+            let chown_command = format!("chown {} {}", owner, path);
+            connection.conn.send_command(&action_provider.post_process_command(&chown_command))?;
+
+            if connection.conn.did_exit_with_error_code() {
+                return Err(ActionError::FailedCommand(connection.conn.return_failed_command_error_response_str(&chown_command,
+                    action)));
+            }
+            changed = true;
+        }
+    }
+
+    if let Some(group) = action.params.get_string_value("group") {
+        if existing_details.is_none_or(|details| details.2 != group) {
+            let chgrp_command = format!("chgrp {} {}", group, path);
+            connection.conn.send_command(&action_provider.post_process_command(&chgrp_command))?;
+
+            if connection.conn.did_exit_with_error_code() {
+                return Err(ActionError::FailedCommand(connection.conn.return_failed_command_error_response_str(&chgrp_command,
+                    action)));
+            }
+            changed = true;
+        }
+    }
+
+    Ok(changed)
+}
+
+fn get_local_file_sha256(path: &str) -> Result<String, std::io::Error> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// compares octal permissions strings, ignoring any leading zeros, i.e. "0755" and "755" are equal.
+fn are_permissions_equal(permissions1: &str, permissions2: &str) -> bool {
+    permissions1.trim_start_matches('0') == permissions2.trim_start_matches('0')
+}
+
+#[cfg(test)]
+pub fn create_test_session_params() -> super::control_common::ControlSessionParams {
+    use super::control_common::{ControlSessionParams, ControlSessionUserAuth, UserAuthUserPass};
+
+    ControlSessionParams::new("", 22, ControlSessionUserAuth::UserPass(UserAuthUserPass::new("root", "")), false)
+}
+
+#[cfg(test)]
+pub fn make_test_action(params: &[(&str, &str)]) -> ControlAction {
+    let mut action = ControlAction::new();
+    for (name, value) in params {
+        action.params.values.insert(name.to_string(), ParamValue::Str(value.to_string()));
+    }
+    action
+}
+
+#[cfg(test)]
+pub fn create_scripted_session(connection: super::control_connection::ControlConnectionScripted) -> ControlSession {
+    ControlSession { conn: Box::new(connection), params: create_test_session_params() }
+}
+
+// runs the action function with the (scripted) session, returning its result along with the commands (and other
+// operations) it performed, for testing actions' checks of the host's current state.
+#[cfg(test)]
+pub fn run_scripted_action(action_fn: fn(&dyn ActionProvider, &mut ControlSession, &ControlAction) -> Result<ActionResult, ActionError>,
+                           session: &mut ControlSession, action: &ControlAction) -> (Result<ActionResult, ActionError>, Vec<String>) {
+    use super::control_connection::RecordedOperation;
+
+    let provider = super::action_provider_linux_debian::AProviderLinuxDebian::new(create_test_session_params());
+    let result = action_fn(&provider, session, action);
+    let operations = session.conn.take_recorded_operations().into_iter().map(|operation| match operation {
+        RecordedOperation::Command(command) => command,
+        operation => operation.to_string(),
+    }).collect();
+
+    (result, operations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::control::control_connection::ControlConnectionScripted;
+
+    #[test]
+    fn test_are_permissions_equal() {
+        assert!(are_permissions_equal("0755", "755"));
+        assert!(are_permissions_equal("755", "0755"));
+        assert!(are_permissions_equal("644", "644"));
+        assert!(!are_permissions_equal("644", "0755"));
+        assert!(!are_permissions_equal("0700", "070"));
+    }
+
+    #[test]
+    fn test_create_directory() {
+        let action = make_test_action(&[("path", "/srv/app"), ("permissions", "0755"), ("owner", "www-data"), ("group", "www-data")]);
+        let stat_command = "test -d /srv/app && stat -c '%a %U %G' /srv/app";
+
+        // already as required
+        let mut session = create_scripted_session(ControlConnectionScripted::new().respond("stat -c", "755 www-data www-data\n", 0));
+        let (result, operations) = run_scripted_action(create_directory, &mut session, &action);
+        assert_eq!(result.ok(), Some(ActionResult::Unchanged));
+        assert_eq!(operations, vec![stat_command]);
+
+        // exists, but with the wrong permissions and owner
+        let mut session = create_scripted_session(ControlConnectionScripted::new().respond("stat -c", "700 root www-data\n", 0));
+        let (result, operations) = run_scripted_action(create_directory, &mut session, &action);
+        assert_eq!(result.ok(), Some(ActionResult::Changed));
+        assert_eq!(operations, vec![stat_command, "chmod 0755 /srv/app", "chown www-data /srv/app"]);
+
+        // doesn't exist
+        let mut session = create_scripted_session(ControlConnectionScripted::new().respond("stat -c", "", 1));
+        let (result, operations) = run_scripted_action(create_directory, &mut session, &action);
+        assert_eq!(result.ok(), Some(ActionResult::Changed));
+        assert_eq!(operations, vec![stat_command, "mkdir /srv/app", "chmod 0755 /srv/app", "chown www-data /srv/app", "chgrp www-data /srv/app"]);
+    }
+
+    #[test]
+    fn test_create_symlink() {
+        let action = make_test_action(&[("targetPath", "/srv/app/releases/2"), ("linkPath", "/srv/app/current")]);
+
+        let mut session = create_scripted_session(ControlConnectionScripted::new().respond("readlink", "/srv/app/releases/2\n", 0));
+        let (result, operations) = run_scripted_action(create_symlink, &mut session, &action);
+        assert_eq!(result.ok(), Some(ActionResult::Unchanged));
+        assert_eq!(operations, vec!["readlink /srv/app/current"]);
+
+        // the link needs replacing, as it points somewhere else
+        let mut session = create_scripted_session(ControlConnectionScripted::new().respond("readlink", "/srv/app/releases/1\n", 0));
+        let (result, operations) = run_scripted_action(create_symlink, &mut session, &action);
+        assert_eq!(result.ok(), Some(ActionResult::Changed));
+        assert_eq!(operations, vec!["readlink /srv/app/current", "ln -sfn /srv/app/releases/2 /srv/app/current"]);
+
+        let mut session = create_scripted_session(ControlConnectionScripted::new().respond("readlink", "", 1));
+        let (result, operations) = run_scripted_action(create_symlink, &mut session, &action);
+        assert_eq!(result.ok(), Some(ActionResult::Changed));
+        assert_eq!(operations, vec!["readlink /srv/app/current", "ln -s /srv/app/releases/2 /srv/app/current"]);
+    }
+}
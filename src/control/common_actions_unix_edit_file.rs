@@ -15,7 +15,8 @@
 
 use crate::params::{ParamValue, Params};
 
-use super::control_actions::{ActionProvider, ActionError, ControlAction};
+use super::control_output::host_eprintln;
+use super::control_actions::{ActionProvider, ActionError, ActionResult, ControlAction};
 use super::control_common::ControlSession;
 
 use super::terminal_helpers_linux;
@@ -202,13 +203,13 @@ fn process_insert_line_entry(entry: &BTreeMap<String, ParamValue>) -> Option<Ins
                 "above" => InsertLinePositionType::Above,
                 "below" => InsertLinePositionType::Below,
                 _ => {
-                    eprintln!("Warning: unrecognised 'position' value for insertLine entry item. Setting to 'below'.");
+                    host_eprintln!("Warning: unrecognised 'position' value for insertLine entry item. Setting to 'below'.");
                     InsertLinePositionType::Below
                 }
             }
         },
         _ => {
-            eprintln!("Warning: undefined 'position' value for insertLine entry item. Setting to 'below'.");
+            host_eprintln!("Warning: undefined 'position' value for insertLine entry item. Setting to 'below'.");
             InsertLinePositionType::Below
         }
     };
@@ -266,21 +267,12 @@ fn process_comment_line_entry(entry: &BTreeMap<String, ParamValue>) -> Option<Co
 // TODO: this is pretty nasty and hacky, but works for all cases I want so far...
 pub fn perform_edit_file_operation(action_provider: &dyn ActionProvider, connection: &mut ControlSession,
     edit_file_params: EditFileParams
-) -> Result<(), ActionError> {
-
-    if edit_file_params.backup {
-        // TODO: something more robust than this...
-        let mv_command = format!("cp {0} {0}.bak", edit_file_params.filepath);
-        connection.conn.send_command(&action_provider.post_process_command(&mv_command));
-        if let Some(strerr) = connection.conn.get_previous_stderr_response() {
-            return Err(ActionError::FailedOther(format!("Error making backup copy of remote file path: {}", strerr)));
-        }
-    }
+) -> Result<ActionResult, ActionError> {
 
     // Note: the Stat returned by scp_recv() is currently a private field, so we can only access bits of it,
     //       so we need to do a full stat call remotely to get the actual info
     let stat_command = format!("stat {}", edit_file_params.filepath);
-    connection.conn.send_command(&action_provider.post_process_command(&stat_command));
+    connection.conn.send_command(&action_provider.post_process_command(&stat_command))?;
     if let Some(strerr) = connection.conn.get_previous_stderr_response() {
         return Err(ActionError::FailedOther(format!("Error accessing remote file path: {}", strerr)));
     }
@@ -292,10 +284,10 @@ pub fn perform_edit_file_operation(action_provider: &dyn ActionProvider, connect
     // download the file
     let string_contents = connection.conn.get_text_file_contents(&edit_file_params.filepath).unwrap();
     if string_contents.is_empty() {
-        eprintln!("Error: remote file: {} has empty contents.", edit_file_params.filepath);
+        host_eprintln!("Error: remote file: {} has empty contents.", edit_file_params.filepath);
         return Err(ActionError::FailedOther("".to_string()));
     }
-    let file_contents_lines = string_contents.lines();
+    let file_contents_lines: Vec<&str> = string_contents.lines().collect();
 
     // brute force replacement - can optimise this or condense it, maybe both,
     // but just get it working for the moment...
@@ -333,7 +325,8 @@ pub fn perform_edit_file_operation(action_provider: &dyn ActionProvider, connect
     let mut insert_string = String::new();
 
     let mut new_file_contents_lines = Vec::new();
-    for line in file_contents_lines {
+    for (line_index, line) in file_contents_lines.iter().enumerate() {
+        let line = *line;
         let mut have_processed_line = false;
         
         insert_type.clear();
@@ -362,6 +355,10 @@ pub fn perform_edit_file_operation(action_provider: &dyn ActionProvider, connect
         }
 
         for comment_item in &edit_file_params.comment_line_items {
+            // don't comment out lines which are already commented out, so re-running doesn't keep adding comment chars
+            if line.starts_with(&comment_item.comment_char) {
+                continue;
+            }
             if item_matches_closure(&comment_item.match_type, &comment_item.match_string, line) {
                 new_file_contents_lines.push(format!("{}{}", comment_item.comment_char, line));
                 have_processed_line = true;
@@ -370,16 +367,32 @@ pub fn perform_edit_file_operation(action_provider: &dyn ActionProvider, connect
 
         if !have_processed_line {
             // as mentioned above, on the assumption there won't currently be replace AND insert for a single line...
-            if insert_type == "A" {
+            // and don't insert lines which were already inserted previously...
+            if insert_type == "A" && new_file_contents_lines.last() != Some(&insert_string) {
                 new_file_contents_lines.push(insert_string.clone());
             }
             new_file_contents_lines.push(line.to_string());
-            if insert_type == "B" {
+            if insert_type == "B" && file_contents_lines.get(line_index + 1) != Some(&insert_string.as_str()) {
                 new_file_contents_lines.push(insert_string.clone());
             }
         }
     }
 
+    // if nothing was actually changed (i.e. the edits have already been made previously), we don't need to
+    // write the file back...
+    if new_file_contents_lines == file_contents_lines {
+        return Ok(ActionResult::Unchanged);
+    }
+
+    if edit_file_params.backup {
+        // TODO: something more robust than this...
+        let mv_command = format!("cp {0} {0}.bak", edit_file_params.filepath);
+        connection.conn.send_command(&action_provider.post_process_command(&mv_command))?;
+        if let Some(strerr) = connection.conn.get_previous_stderr_response() {
+            return Err(ActionError::FailedOther(format!("Error making backup copy of remote file path: {}", strerr)));
+        }
+    }
+
     // convert back to single string for entire file, and make sure we append a newline on the end...
     let new_file_contents_string = new_file_contents_lines.join("\n") + "\n";
 
@@ -389,7 +402,7 @@ pub fn perform_edit_file_operation(action_provider: &dyn ActionProvider, connect
     }
     else {
         mode = 0o644;
-        eprintln!("Can't extract stat details from file. Using 644 as default permissions mode.");
+        host_eprintln!("Can't extract stat details from file. Using 644 as default permissions mode.");
     }
     
     let send_res = connection.conn.send_text_file_contents(&edit_file_params.filepath, mode, &new_file_contents_string);
@@ -399,18 +412,18 @@ pub fn perform_edit_file_operation(action_provider: &dyn ActionProvider, connect
 
     // TODO: change user and group of file to cached value from beforehand...
 
-    Ok(())
+    Ok(ActionResult::Changed)
 }
 
 pub fn edit_file(action_provider: &dyn ActionProvider, connection: &mut ControlSession, action: &ControlAction
-) -> Result<(), ActionError> {
+) -> Result<ActionResult, ActionError> {
     let filepath = action.get_required_string_param("filepath")?;
   
     let replace_line_items = extract_edit_line_entry_items(&action.params, "replaceLine", &process_replace_line_entry);
     let insert_line_items = extract_edit_line_entry_items(&action.params, "insertLine", &process_insert_line_entry);
     let comment_line_items = extract_edit_line_entry_items(&action.params, "commentLine", &process_comment_line_entry);
     if replace_line_items.is_empty() && insert_line_items.is_empty() && comment_line_items.is_empty() {
-        eprintln!("Error: editFile Control Action had no items to perform...");
+        host_eprintln!("Error: editFile Control Action had no items to perform...");
         return Err(ActionError::InvalidParams("".to_string()));
     }
     
@@ -418,4 +431,30 @@ pub fn edit_file(action_provider: &dyn ActionProvider, connection: &mut ControlS
         .set_all_items(replace_line_items, insert_line_items, comment_line_items);
 
     perform_edit_file_operation(action_provider, connection, edit_file_params)
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::control::common_actions_unix::{create_scripted_session, make_test_action, run_scripted_action};
+    use crate::control::control_connection::ControlConnectionScripted;
+
+    #[test]
+    fn test_edit_file() {
+        let mut action = make_test_action(&[("filepath", "/etc/ssh/sshd_config")]);
+        let replace_line = BTreeMap::from([("matchString".to_string(), ParamValue::Str("PermitRootLogin".to_string())),
+                                           ("replaceString".to_string(), ParamValue::Str("PermitRootLogin no".to_string()))]);
+        action.params.values.insert("replaceLine".to_string(), ParamValue::Map(replace_line));
+
+        let mut session = create_scripted_session(ControlConnectionScripted::new().with_file("/etc/ssh/sshd_config", "Port 22\nPermitRootLogin yes\n"));
+        let (result, operations) = run_scripted_action(edit_file, &mut session, &action);
+        assert_eq!(result.ok(), Some(ActionResult::Changed));
+        assert_eq!(operations.last().map(|operation| operation.as_str()), Some("write file:    /etc/ssh/sshd_config (mode: 644, 27 bytes)"));
+        assert_eq!(session.conn.get_text_file_contents("/etc/ssh/sshd_config").unwrap(), "Port 22\nPermitRootLogin no\n");
+
+        // re-running it doesn't change anything
+        let (result, operations) = run_scripted_action(edit_file, &mut session, &action);
+        assert_eq!(result.ok(), Some(ActionResult::Unchanged));
+        assert!(!operations.iter().any(|operation| operation.starts_with("write file:")));
+    }
+}
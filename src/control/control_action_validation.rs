@@ -0,0 +1,438 @@
+/*
+ Prod
+ Copyright 2021-2025 Peter Pearson.
+ Licensed under the Apache License, Version 2.0 (the "License");
+ You may not use this file except in compliance with the License.
+ You may obtain a copy of the License at
+ http://www.apache.org/licenses/LICENSE-2.0
+ Unless required by applicable law or agreed to in writing, software
+ distributed under the License is distributed on an "AS IS" BASIS,
+ WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ See the License for the specific language governing permissions and
+ limitations under the License.
+ ---------
+*/
+
+// Pre-flight validation of control action parameters, so that as many problems as possible
+// can be reported up-front before connecting to a host and starting to run actions, rather than
+// discovering them part way through and leaving the host half-configured.
+
+use std::fmt;
+
+use crate::params::ParamValue;
+
+use super::control_actions::{ActionProvider, ControlAction, ControlActionType, ControlActions};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ParamType {
+    Str,
+    Bool,
+    StrArray,
+    // either a single map item, or an array of map items
+    MapOrMapArray,
+    // octal file permissions, i.e. 644 or "0755"
+    OctalPermissions,
+    // TCP port number
+    Port,
+    // either a bool, or one of the schema item's allowed string values
+    BoolOrEnum,
+    // path to a local file which must exist
+    LocalFilePath,
+    Any,
+}
+
+struct ParamSchemaItem {
+    name:           &'static str,
+    param_type:     ParamType,
+    required:       bool,
+    // if not empty, a string value must be one of these
+    allowed_values: &'static [&'static str],
+}
+
+const fn req(name: &'static str, param_type: ParamType) -> ParamSchemaItem {
+    ParamSchemaItem { name, param_type, required: true, allowed_values: &[] }
+}
+
+const fn opt(name: &'static str, param_type: ParamType) -> ParamSchemaItem {
+    ParamSchemaItem { name, param_type, required: false, allowed_values: &[] }
+}
+
+const fn req_enum(name: &'static str, param_type: ParamType, allowed_values: &'static [&'static str]) -> ParamSchemaItem {
+    ParamSchemaItem { name, param_type, required: true, allowed_values }
+}
+
+const fn opt_enum(name: &'static str, param_type: ParamType, allowed_values: &'static [&'static str]) -> ParamSchemaItem {
+    ParamSchemaItem { name, param_type, required: false, allowed_values }
+}
+
+struct ActionParamSchema {
+    params:             Vec<ParamSchemaItem>,
+    // groups of params, of which at least one of each group must be specified
+    required_one_of:    &'static [&'static [&'static str]],
+}
+
+use ParamType::*;
+
+const SYSTEMCTL_ACTIONS: &[&str] = &["start", "stop", "restart", "reload", "try-restart", "reload-or-restart",
+                                     "enable", "disable", "mask", "unmask", "status"];
+
+fn get_action_param_schema(action_type: ControlActionType) -> Option<ActionParamSchema> {
+    let schema = match action_type {
+        ControlActionType::GenericCommand => ActionParamSchema {
+            params: vec![req("command", Str), opt("errorIfStdErrOutputExists", Bool), opt("errorIfNone0ExitCode", Bool)],
+            required_one_of: &[] },
+        ControlActionType::AddUser => ActionParamSchema {
+            params: vec![req("username", Str), req("password", Str), opt("createHome", Bool), opt("defaultGroup", Str),
+                      opt("extraGroups", StrArray), opt("shell", Str)],
+            required_one_of: &[] },
+        ControlActionType::CreateDirectory => ActionParamSchema {
+            params: vec![req("path", Str), opt("multiLevel", Bool), opt("permissions", OctalPermissions), opt("owner", Str),
+                      opt("group", Str)],
+            required_one_of: &[] },
+        ControlActionType::RemoveDirectory => ActionParamSchema {
+            params: vec![req("path", Str), opt("recursive", Bool), opt("ignoreFailure", Bool)],
+            required_one_of: &[] },
+        ControlActionType::InstallPackages => ActionParamSchema {
+            params: vec![opt("package", Str), opt("packages", StrArray), opt("waitForPMToFinish", Bool), opt("update", Bool)],
+            required_one_of: &[&["package", "packages"]] },
+        ControlActionType::RemovePackages => ActionParamSchema {
+            params: vec![opt("package", Str), opt("packages", StrArray), opt("waitForPMToFinish", Bool), opt("ignoreFailure", Bool)],
+            required_one_of: &[&["package", "packages"]] },
+        ControlActionType::SystemCtl => ActionParamSchema {
+            params: vec![req("service", Str), req_enum("action", Str, SYSTEMCTL_ACTIONS)],
+            required_one_of: &[] },
+        ControlActionType::Firewall => ActionParamSchema {
+            params: vec![opt_enum("type", Str, &["ufw"]), opt("enabled", Bool), opt("rules", StrArray)],
+            required_one_of: &[] },
+        ControlActionType::EditFile => ActionParamSchema {
+            params: vec![req("filepath", Str), opt("backup", Bool), opt("replaceLine", MapOrMapArray), opt("insertLine", MapOrMapArray),
+                      opt("commentLine", MapOrMapArray), opt("items", MapOrMapArray)],
+            required_one_of: &[&["replaceLine", "insertLine", "commentLine", "items"]] },
+        ControlActionType::CopyPath => ActionParamSchema {
+            params: vec![req("sourcePath", Str), req("destPath", Str), opt("recursive", Bool), opt("update", Bool)],
+            required_one_of: &[] },
+        ControlActionType::RemoveFile => ActionParamSchema {
+            params: vec![req("path", Str), opt("ignoreFailure", Bool)],
+            required_one_of: &[] },
+        ControlActionType::DownloadFile => ActionParamSchema {
+            params: vec![req("sourceURL", Str), req("destPath", Str), opt("permissions", OctalPermissions), opt("owner", Str),
+                      opt("group", Str), opt("extractDir", Str)],
+            required_one_of: &[] },
+        ControlActionType::TransmitFile => ActionParamSchema {
+            params: vec![req("localSourcePath", LocalFilePath), req("remoteDestPath", Str), opt("permissions", OctalPermissions),
+                      opt("owner", Str), opt("group", Str), opt("extractDir", Str)],
+            required_one_of: &[] },
+        ControlActionType::ReceiveFile => ActionParamSchema {
+            params: vec![req("remoteSourcePath", Str), req("localDestPath", Str)],
+            required_one_of: &[] },
+        ControlActionType::CreateSymlink => ActionParamSchema {
+            params: vec![req("targetPath", Str), req("linkPath", Str)],
+            required_one_of: &[] },
+        ControlActionType::SetTimeZone => ActionParamSchema {
+            params: vec![req("timeZone", Str)],
+            required_one_of: &[] },
+        ControlActionType::DisableSwap => ActionParamSchema {
+            params: vec![req("filename", Str)],
+            required_one_of: &[] },
+        ControlActionType::CreateFile => ActionParamSchema {
+            params: vec![req("path", Str), opt("content", Str), opt("permissions", OctalPermissions), opt("owner", Str),
+                      opt("group", Str)],
+            required_one_of: &[] },
+        ControlActionType::AddGroup => ActionParamSchema {
+            params: vec![req("name", Str), opt("user", Str), opt("users", StrArray)],
+            required_one_of: &[] },
+        ControlActionType::SetHostname => ActionParamSchema {
+            params: vec![req("hostname", Str)],
+            required_one_of: &[] },
+        ControlActionType::CreateSystemdService => ActionParamSchema {
+            params: vec![req("name", Str), req("description", Str), req("user", Str), req("execStart", Str),
+                      opt("after", Str), opt("before", Str), opt("execRestart", Str), opt("execStop", Any),
+                      opt("startNow", Bool)],
+            required_one_of: &[] },
+        ControlActionType::ConfigureSSH => ActionParamSchema {
+            params: vec![opt("passwordAuthentication", Bool), opt("permitEmptyPasswords", Bool),
+                      opt_enum("permitRootLogin", BoolOrEnum, &["prohibit-password"]), opt("port", Port),
+                      opt("pubKeyAuthentication", Bool), opt("backup", Bool), opt("restartService", Bool)],
+            required_one_of: &[&["passwordAuthentication", "permitEmptyPasswords", "permitRootLogin", "port", "pubKeyAuthentication"]] },
+        ControlActionType::AddPackageRepo => ActionParamSchema {
+            params: vec![req_enum("type", Str, &["manualURL"]), req("keyURL", Str), req("sourceListDefURL", Str),
+                      req("localFilePrefix", Str), opt("failOnExistingKeyFile", Bool), opt("updatePackages", Bool)],
+            required_one_of: &[] },
+        ControlActionType::NotSet | ControlActionType::Unrecognised => {
+            return None;
+        }
+    };
+
+    Some(schema)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ValidationSeverity {
+    // the action will not be able to run correctly
+    Error,
+    // something looks suspicious (i.e. unknown params which will be ignored), but the action can still run
+    Warning,
+}
+
+#[derive(Clone, Debug)]
+pub struct ActionValidationIssue {
+    pub action_index:   usize,
+    pub action_type:    ControlActionType,
+    pub severity:       ValidationSeverity,
+    pub message:        String,
+}
+
+impl fmt::Display for ActionValidationIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            ValidationSeverity::Error   => "Error",
+            ValidationSeverity::Warning => "Warning",
+        };
+        // Note: action indices are 1-based in output, to match the 'Running Action' output
+        write!(f, "{}: Action {} ({}): {}", severity, self.action_index + 1, self.action_type, self.message)
+    }
+}
+
+// validates all the actions against their parameter schemas, and against the actions that the provider
+// supports, returning all the problems found.
+pub fn validate_actions(actions: &ControlActions, provider: &dyn ActionProvider) -> Vec<ActionValidationIssue> {
+    let supported_actions = provider.supported_actions();
+
+    let mut issues = Vec::new();
+
+    for (index, action) in actions.actions.iter().enumerate() {
+        if !supported_actions.contains(&action.action) {
+            issues.push(ActionValidationIssue { action_index: index, action_type: action.action,
+                        severity: ValidationSeverity::Error,
+                        message: format!("the '{}' control provider does not implement this action.", provider.name()) });
+        }
+
+        for (severity, message) in validate_action_params(action) {
+            issues.push(ActionValidationIssue { action_index: index, action_type: action.action, severity, message });
+        }
+    }
+
+    issues
+}
+
+fn validate_action_params(action: &ControlAction) -> Vec<(ValidationSeverity, String)> {
+    let mut issues = Vec::new();
+
+    let schema = get_action_param_schema(action.action);
+    if schema.is_none() {
+        issues.push((ValidationSeverity::Error, "invalid action type.".to_string()));
+        return issues;
+    }
+    let schema = schema.unwrap();
+
+    for schema_item in &schema.params {
+        match action.params.get_raw_value(schema_item.name) {
+            Some(value) => {
+                if let Err(message) = check_param_value(schema_item, value) {
+                    issues.push((ValidationSeverity::Error, message));
+                }
+            },
+            None => {
+                if schema_item.required {
+                    issues.push((ValidationSeverity::Error,
+                                 format!("the required '{}' parameter was not specified.", schema_item.name)));
+                }
+            }
+        }
+    }
+
+    for one_of_group in schema.required_one_of {
+        if !one_of_group.iter().any(|name| action.params.has_value(name)) {
+            let names: Vec<String> = one_of_group.iter().map(|name| format!("'{}'", name)).collect();
+            issues.push((ValidationSeverity::Error,
+                         format!("at least one of the following parameters must be specified: {}.", names.join(", "))));
+        }
+    }
+
+    // unknown params are just ignored by actions, so it's likely they're typos...
+    for key in action.params.values.keys() {
+        if !schema.params.iter().any(|schema_item| schema_item.name == key) {
+            issues.push((ValidationSeverity::Warning, format!("unrecognised parameter '{}' will be ignored.", key)));
+        }
+    }
+
+    issues
+}
+
+fn check_param_value(schema_item: &ParamSchemaItem, value: &ParamValue) -> Result<(), String> {
+    let name = schema_item.name;
+
+    let type_error = |expected: &str| -> Result<(), String> {
+        Err(format!("the '{}' parameter should be {}, but was: {}", name, expected, value))
+    };
+
+    // values using host facts can't be known until we connect to the host, so can't be checked now
+    if let ParamValue::Str(str_val) = value {
+        if str_val.contains("{{") {
+            return Ok(());
+        }
+    }
+
+    match schema_item.param_type {
+        Str => {
+            match value {
+                ParamValue::Str(str_val) => check_allowed_value(schema_item, str_val),
+                _ => type_error("a string")
+            }
+        },
+        Bool => {
+            match value {
+                ParamValue::Bool(_) => Ok(()),
+                _ => type_error("a boolean")
+            }
+        },
+        StrArray => {
+            match value {
+                ParamValue::Array(array) if array.iter().all(|item| matches!(item, ParamValue::Str(_))) => Ok(()),
+                _ => type_error("an array of strings")
+            }
+        },
+        MapOrMapArray => {
+            match value {
+                ParamValue::Map(_) => Ok(()),
+                ParamValue::Array(array) if array.iter().all(|item| matches!(item, ParamValue::Map(_))) => Ok(()),
+                _ => type_error("a map or array of maps")
+            }
+        },
+        OctalPermissions => {
+            let permissions_string = match value {
+                ParamValue::Str(str_val) => str_val.clone(),
+                ParamValue::Int(int_val) => format!("{}", int_val),
+                _ => return type_error("an octal permissions value"),
+            };
+            if is_valid_octal_permissions(&permissions_string) {
+                Ok(())
+            }
+            else {
+                Err(format!("the '{}' parameter value '{}' is not a valid octal permissions value (i.e. 644 or 0755).",
+                            name, permissions_string))
+            }
+        },
+        Port => {
+            match value {
+                ParamValue::Int(int_val) if *int_val > 0 && *int_val <= u16::MAX.into() => Ok(()),
+                _ => type_error("a port number between 1 and 65535")
+            }
+        },
+        BoolOrEnum => {
+            match value {
+                ParamValue::Bool(_) => Ok(()),
+                ParamValue::Str(str_val) => check_allowed_value(schema_item, str_val),
+                _ => type_error("a boolean or string")
+            }
+        },
+        LocalFilePath => {
+            match value {
+                ParamValue::Str(str_val) => {
+                    if std::path::Path::new(str_val).exists() {
+                        Ok(())
+                    }
+                    else {
+                        Err(format!("the local file path '{}' specified by the '{}' parameter does not exist.", str_val, name))
+                    }
+                },
+                _ => type_error("a string")
+            }
+        },
+        Any => Ok(()),
+    }
+}
+
+fn check_allowed_value(schema_item: &ParamSchemaItem, value: &str) -> Result<(), String> {
+    if schema_item.allowed_values.is_empty() || schema_item.allowed_values.contains(&value) {
+        return Ok(());
+    }
+
+    let allowed: Vec<String> = schema_item.allowed_values.iter().map(|val| format!("'{}'", val)).collect();
+    Err(format!("the '{}' parameter value '{}' is not one of the supported values: {}.", schema_item.name, value,
+                allowed.join(", ")))
+}
+
+fn is_valid_octal_permissions(value: &str) -> bool {
+    // allow 3 or 4 digits (with the optional leading special bits digit)
+    (value.len() == 3 || value.len() == 4) && value.chars().all(|c| ('0'..='7').contains(&c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_action(action_type: ControlActionType, params: &[(&str, ParamValue)]) -> ControlAction {
+        let mut action = ControlAction::new();
+        action.action = action_type;
+        for (key, value) in params {
+            action.params.values.insert(key.to_string(), value.clone());
+        }
+        action
+    }
+
+    fn error_count(issues: &[(ValidationSeverity, String)]) -> usize {
+        issues.iter().filter(|(severity, _)| *severity == ValidationSeverity::Error).count()
+    }
+
+    #[test]
+    fn test_valid_params() {
+        let action = make_action(ControlActionType::CreateDirectory,
+                                 &[("path", ParamValue::Str("/opt/test".to_string())),
+                                   ("permissions", ParamValue::Int(755)),
+                                   ("multiLevel", ParamValue::Bool(true))]);
+        assert!(validate_action_params(&action).is_empty());
+    }
+
+    #[test]
+    fn test_missing_required_params() {
+        let action = make_action(ControlActionType::AddUser, &[("username", ParamValue::Str("user1".to_string()))]);
+        let issues = validate_action_params(&action);
+        assert_eq!(error_count(&issues), 1);
+
+        // neither of 'package' or 'packages'
+        let action = make_action(ControlActionType::InstallPackages, &[("update", ParamValue::Bool(false))]);
+        assert_eq!(error_count(&validate_action_params(&action)), 1);
+    }
+
+    #[test]
+    fn test_invalid_param_values() {
+        let action = make_action(ControlActionType::CreateFile,
+                                 &[("path", ParamValue::Str("/tmp/file1".to_string())),
+                                   ("permissions", ParamValue::Str("0789".to_string()))]);
+        assert_eq!(error_count(&validate_action_params(&action)), 1);
+
+        let action = make_action(ControlActionType::ConfigureSSH,
+                                 &[("permitRootLogin", ParamValue::Str("sometimes".to_string()))]);
+        assert_eq!(error_count(&validate_action_params(&action)), 1);
+
+        let action = make_action(ControlActionType::ConfigureSSH,
+                                 &[("permitRootLogin", ParamValue::Str("prohibit-password".to_string())),
+                                   ("port", ParamValue::Int(70000))]);
+        assert_eq!(error_count(&validate_action_params(&action)), 1);
+
+        let action = make_action(ControlActionType::SystemCtl,
+                                 &[("service", ParamValue::Str("nginx".to_string())),
+                                   ("action", ParamValue::Bool(true))]);
+        assert_eq!(error_count(&validate_action_params(&action)), 1);
+    }
+
+    #[test]
+    fn test_unrecognised_params_are_warnings() {
+        let action = make_action(ControlActionType::CreateDirectory,
+                                 &[("path", ParamValue::Str("/opt/test".to_string())),
+                                   ("multilevel", ParamValue::Bool(true))]);
+        let issues = validate_action_params(&action);
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].0, ValidationSeverity::Warning);
+    }
+
+    #[test]
+    fn test_octal_permissions() {
+        assert!(is_valid_octal_permissions("644"));
+        assert!(is_valid_octal_permissions("0755"));
+        assert!(!is_valid_octal_permissions("rwx"));
+        assert!(!is_valid_octal_permissions("64"));
+        assert!(!is_valid_octal_permissions("888"));
+    }
+}
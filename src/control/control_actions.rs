@@ -15,18 +15,26 @@
 
 #![allow(dead_code)]
 
+use std::collections::BTreeSet;
 use std::fmt;
+use std::ops::RangeInclusive;
 use std::io::{BufReader, Read};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use yaml_rust::{Yaml, YamlLoader};
 
 use crate::common::FileLoadError;
 use crate::control::control_common::UserAuthPublicKey;
 use crate::control::control_system_validation::SystemValidation;
+use crate::control::control_templating::{get_value_vault_reference_names, is_reserved_name, is_valid_identifier, load_vars_file,
+                                         vars_from_yaml, TemplateVars};
 use crate::params::{ParamValue, Params};
-use super::control_common::{ControlSession, ControlSessionUserAuth, UserAuthUserPass};
+use crate::vault::{get_vault_reference_names, Vault};
+use super::control_common::{ControlSession, ControlSessionUserAuth, UserAuthAgent, UserAuthUserPass};
 use super::control_common::{ControlSessionParams, UserType};
+use super::control_connection::TransportError;
+use super::control_facts::HostFacts;
+use super::control_known_hosts::HostKeyPolicy;
 
 // Note: try and keep the convention of <action><item>
 #[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
@@ -100,6 +108,25 @@ impl Ord for ControlActionType {
 }
 */
 
+// the result of successfully running an action, so that actions can be re-applied to hosts
+// which are already in the required state without failing or modifying anything.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ActionResult {
+    // the host was already in the required state, so nothing was done
+    Unchanged,
+    // the action modified the host
+    Changed,
+}
+
+impl fmt::Display for ActionResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ActionResult::Unchanged => write!(f, "ok"),
+            ActionResult::Changed   => write!(f, "changed"),
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum ActionError {
     NotImplemented,
@@ -108,6 +135,28 @@ pub enum ActionError {
     AuthenticationIssue,
     FailedCommand(String),
     FailedOther(String),
+    // the connection to the host failed while running a command, so it's unknown what state the host was left in
+    Transport(TransportError),
+}
+
+impl From<TransportError> for ActionError {
+    fn from(err: TransportError) -> Self {
+        ActionError::Transport(err)
+    }
+}
+
+impl fmt::Display for ActionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ActionError::NotImplemented => write!(f, "the action provider does not implement this action"),
+            ActionError::InvalidParams(str) => write!(f, "invalid parameters were provided for this action: {}", str),
+            ActionError::CantConnect => write!(f, "can't connect to host"),
+            ActionError::AuthenticationIssue => write!(f, "authentication issue"),
+            ActionError::FailedCommand(str) => write!(f, "{}", str),
+            ActionError::FailedOther(str) => write!(f, "{}", str),
+            ActionError::Transport(err) => write!(f, "connection failure: {}", err),
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -122,17 +171,314 @@ pub struct ControlActions {
     // authentication
     pub auth:       ControlSessionUserAuth,
 
+    // how the host's key is checked against the known hosts
+    pub host_key_policy:    HostKeyPolicy,
+
+    // the hosts to connect via, in order. If not specified, any ProxyJump setting in ~/.ssh/config is used
+    pub jump_hosts:         Option<Vec<JumpHost>>,
+
+    // privilege escalation: whether actions are run via sudo by default (actions can override this), as which
+    // user, and the sudo password for the login user (if needed), which can be '$PROMPT'.
+    pub become_enabled:     bool,
+    pub become_user:        String,
+    pub become_password:    String,
+
     // optional validation
     pub system_validation: SystemValidation,
 
+    // when running on multiple hosts, optionally run them in rolling batches of this size
+    pub serial:     Option<SerialBatchSize>,
+    // the percentage of hosts in a batch which can fail before later batches are aborted
+    pub max_fail_percentage: u32,
+
+    // variables which can be used in '{{ name }}' expressions in action param values
+    pub vars:       TemplateVars,
+
     // full actions to run
     pub actions:    Vec<ControlAction>,
+
+    // actions which are run once after all the other actions, if they've been notified by any actions which changed something
+    pub handlers:   Vec<ControlHandler>,
 }
 
+#[derive(Clone, Debug)]
+pub struct ControlHandler {
+    pub name:       String,
+    pub actions:    Vec<ControlAction>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SerialBatchSize {
+    Count(usize),
+    Percentage(u32),
+}
+
+impl SerialBatchSize {
+    pub fn parse(value: &str) -> Result<SerialBatchSize, String> {
+        let batch_size = if let Some(percentage) = value.trim().strip_suffix('%') {
+            match percentage.trim().parse::<u32>() {
+                Ok(val) if val > 0 && val <= 100 => SerialBatchSize::Percentage(val),
+                _ => return Err(format!("invalid percentage value: '{}'", value)),
+            }
+        }
+        else {
+            match value.trim().parse::<usize>() {
+                Ok(val) if val > 0 => SerialBatchSize::Count(val),
+                _ => return Err(format!("invalid value: '{}'", value)),
+            }
+        };
+
+        Ok(batch_size)
+    }
+
+    // the number of hosts in each batch, given the total number of hosts
+    pub fn get_batch_size(&self, num_hosts: usize) -> usize {
+        let batch_size = match self {
+            SerialBatchSize::Count(count) => *count,
+            SerialBatchSize::Percentage(percentage) => (num_hosts * *percentage as usize).div_ceil(100),
+        };
+
+        batch_size.clamp(1, num_hosts.max(1))
+    }
+}
+
+// a host to connect to the host via (i.e. a bastion host), when it's not directly reachable.
+#[derive(Clone, Debug)]
+pub struct JumpHost {
+    pub hostname:   String,
+    pub port:       Option<u32>,
+    pub user:       Option<String>,
+    // its own auth, otherwise the same auth as for the host is used
+    pub auth:       Option<ControlSessionUserAuth>,
+}
+
+impl JumpHost {
+    // parses a '[user@]hostname[:port]' string (or an 'ssh://' URI of the same), as OpenSSH's ProxyJump does
+    pub fn parse(value: &str) -> Result<JumpHost, String> {
+        let host = value.trim();
+        let host = host.strip_prefix("ssh://").unwrap_or(host);
+        let (user, host) = match host.rsplit_once('@') {
+            Some((user, host)) => (Some(user.to_string()), host),
+            None => (None, host),
+        };
+        let (hostname, port) = match host.split_once(':') {
+            Some((hostname, port)) => (hostname, Some(port.parse::<u32>().map_err(|_| format!("invalid jump host port number: '{}'", port))?)),
+            None => (host, None),
+        };
+        if hostname.is_empty() {
+            return Err(format!("no hostname was specified for jump host: '{}'", value));
+        }
+
+        Ok(JumpHost { hostname: hostname.to_string(), port, user, auth: None })
+    }
+
+    // parses a comma-separated chain of jump hosts, which are connected via in order. 'none' means there aren't any.
+    pub fn parse_chain(value: &str) -> Result<Vec<JumpHost>, String> {
+        if value.trim().eq_ignore_ascii_case("none") {
+            return Ok(Vec::new());
+        }
+        value.split(',').map(JumpHost::parse).collect()
+    }
+
+    // parses the 'jumpHost' param, which can be a string chain of jump hosts, a map with a 'hostname' (and optionally
+    // 'port' and auth items, as with the control script's), or a list of either of those.
+    pub fn from_yaml(value: &Yaml) -> Result<Vec<JumpHost>, String> {
+        match value {
+            Yaml::String(chain) => JumpHost::parse_chain(chain),
+            Yaml::Hash(map) => {
+                let hostname = get_yaml_map_item_as_string(map, "hostname").ok_or("jump hosts need a 'hostname' item")?;
+                let mut jump_host = JumpHost::parse(&hostname)?;
+                match map.get(&Yaml::String("port".to_string())) {
+                    Some(Yaml::Integer(port)) if *port > 0 && *port <= u16::MAX.into() => jump_host.port = Some(*port as u32),
+                    Some(_) => return Err(format!("invalid 'port' value for jump host: '{}'", hostname)),
+                    None => {}
+                }
+                if let Some(user) = get_yaml_map_item_as_string(map, "user") {
+                    jump_host.user = Some(user);
+                }
+                // only auth items other than the user mean it has its own auth
+                let has_auth = ["authType", "password", "publicKeyPath", "privateKeyPath", "passphrase"].iter()
+                                    .any(|item| map.contains_key(&Yaml::String(item.to_string())));
+                if has_auth {
+                    let auth = process_auth_yaml_items(map).ok_or(format!("invalid auth items for jump host: '{}'", hostname))?;
+                    jump_host.auth = Some(auth);
+                }
+                Ok(vec![jump_host])
+            },
+            Yaml::Array(items) => {
+                let mut jump_hosts = Vec::new();
+                for item in items {
+                    if matches!(item, Yaml::Array(_)) {
+                        return Err("jump host list items must be strings or maps".to_string());
+                    }
+                    jump_hosts.extend(JumpHost::from_yaml(item)?);
+                }
+                Ok(jump_hosts)
+            },
+            _ => Err("it should be a string, a map or a list of jump hosts".to_string())
+        }
+    }
+
+    // jump hosts need tunnelling, which only the openssh backend supports, so fail early (when they're specified)
+    // rather than when connecting to each of the hosts
+    pub fn check_backend_supported(jump_hosts: &[JumpHost]) -> Result<(), String> {
+        if !jump_hosts.is_empty() && !cfg!(feature = "openssh") {
+            return Err("connecting via jump hosts isn't supported by the ssh-rs connection backend, \
+                        Prod needs to be built with the 'openssh' feature for it".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn get_vault_reference_names(&self, names: &mut BTreeSet<String>) {
+        let auth_values = self.auth.iter().flat_map(get_auth_string_values);
+        for value in auth_values.chain([&self.hostname]).chain(self.user.iter()) {
+            get_vault_reference_names(value, names);
+        }
+    }
+
+    pub fn substitute_vault_references(&mut self, vault: &Vault) {
+        self.hostname = vault.substitute_references(&self.hostname);
+        if let Some(user) = &mut self.user {
+            *user = vault.substitute_references(user);
+        }
+        if let Some(auth) = &mut self.auth {
+            substitute_auth_vault_references(auth, vault);
+        }
+    }
+}
+
+const DEFAULT_RETRY_DELAY: u32 = 5;
+const DEFAULT_UNTIL_RETRIES: u32 = 3;
+
 #[derive(Clone, Debug)]
 pub struct ControlAction {
     pub action:     ControlActionType,
-    pub params:     Params
+    pub params:     Params,
+    // optional name for the action, i.e. so that runs can be resumed from it with '--start-at'
+    pub name:       Option<String>,
+    // tags for selecting which actions to run with '--tags' and '--skip-tags'
+    pub tags:       Vec<String>,
+    // optional condition for whether the action should be run
+    pub when:       Option<String>,
+    // if the action was expanded from a 'loop' item, the item for this action
+    pub loop_item:  Option<LoopItem>,
+    // names of handlers to run at the end if this action changes anything
+    pub notify:     Vec<String>,
+    // variable name to store the output of the action's last command in, for later actions to use
+    pub register:   Option<String>,
+    // whether a failure of the action should be ignored, and the remaining actions run anyway
+    pub continue_on_error:  bool,
+    // the number of times to retry the action if it fails (or its 'until' condition isn't met), and the
+    // number of seconds to wait before each retry
+    pub retries:            u32,
+    pub retry_delay:        u32,
+    // optional condition which must be met for the action to have succeeded, i.e. on registered output
+    pub until:              Option<String>,
+    // optional overrides of the script's privilege escalation settings for this action
+    pub become_enabled:     Option<bool>,
+    pub become_user:        Option<String>,
+}
+
+// which of the actions to run, from the '--start-at' and '--only' command line args
+#[derive(Clone, Debug, Default)]
+pub struct ActionSelection {
+    // the (1-based) index or name of the action to start at
+    pub start_at:   Option<String>,
+    // (1-based) ranges of the indices of the actions to run
+    pub only:       Option<Vec<RangeInclusive<usize>>>,
+    // only run actions with any of these tags
+    pub tags:       Option<Vec<String>>,
+    // don't run actions with any of these tags
+    pub skip_tags:  Vec<String>,
+}
+
+impl ActionSelection {
+    pub fn is_set(&self) -> bool {
+        self.start_at.is_some() || self.only.is_some() || self.tags.is_some() || !self.skip_tags.is_empty()
+    }
+
+    // i.e. "'--start-at' / '--tags'", for the options which are set
+    pub fn get_options_description(&self) -> String {
+        let options = [("--start-at", self.start_at.is_some()), ("--only", self.only.is_some()),
+                       ("--tags", self.tags.is_some()), ("--skip-tags", !self.skip_tags.is_empty())];
+        options.iter().filter(|(_, is_set)| *is_set).map(|(option, _)| format!("'{}'", option)).collect::<Vec<String>>().join(" / ")
+    }
+
+    // parses a comma-separated list of tags, i.e. "packages,config"
+    pub fn parse_tags(value: &str) -> Result<Vec<String>, String> {
+        let tags: Vec<String> = value.split(',').map(|tag| tag.trim().to_string()).collect();
+        if let Some(tag) = tags.iter().find(|tag| !is_valid_tag(tag)) {
+            return Err(format!("invalid tag: '{}'", tag));
+        }
+        Ok(tags)
+    }
+
+    // the '--tags' and '--skip-tags' tags which none of the actions have, which are probably typos
+    pub fn get_unknown_tags(&self, actions: &[ControlAction]) -> Vec<String> {
+        self.tags.iter().flatten().chain(self.skip_tags.iter())
+            .filter(|tag| !actions.iter().any(|action| action.tags.contains(tag)))
+            .cloned().collect()
+    }
+
+    // whether the action should be run based on its tags
+    pub fn tags_match(&self, action: &ControlAction) -> bool {
+        self.tags.as_ref().is_none_or(|tags| action.tags.iter().any(|tag| tags.contains(tag))) &&
+            !action.tags.iter().any(|tag| self.skip_tags.contains(tag))
+    }
+
+    // parses a comma-separated list of indices and ranges, i.e. "3", "3-7", "3-" or "1,4,6-8"
+    pub fn parse_ranges(value: &str) -> Result<Vec<RangeInclusive<usize>>, String> {
+        let parse_index = |index_str: &str| match index_str.trim().parse::<usize>() {
+            Ok(index) if index > 0 => Ok(index),
+            _ => Err(format!("invalid action index: '{}'", index_str.trim())),
+        };
+
+        let mut ranges = Vec::new();
+        for item in value.split(',') {
+            let range = match item.split_once('-') {
+                Some((start, end)) if end.trim().is_empty() => parse_index(start)?..=usize::MAX,
+                Some((start, end)) => parse_index(start)?..=parse_index(end)?,
+                None => {
+                    let index = parse_index(item)?;
+                    index..=index
+                }
+            };
+            if range.is_empty() {
+                return Err(format!("invalid action range: '{}'", item.trim()));
+            }
+            ranges.push(range);
+        }
+
+        Ok(ranges)
+    }
+
+    // whether each of the actions should be run
+    pub fn get_selected(&self, actions: &[ControlAction]) -> Result<Vec<bool>, String> {
+        let start_index = match &self.start_at {
+            // indices are the same as those printed when running, i.e. "Running Action 17: ..."
+            Some(start_at) => match start_at.parse::<usize>() {
+                Ok(index) if index > 0 && index <= actions.len() => index - 1,
+                Ok(_) => return Err(format!("the '--start-at' action index must be between 1 and {}.", actions.len())),
+                Err(_) => {
+                    actions.iter().position(|action| action.name.as_deref() == Some(start_at.as_str()))
+                        .ok_or_else(|| format!("there's no action named '{}' to start at.", start_at))?
+                }
+            },
+            None => 0,
+        };
+
+        Ok(actions.iter().enumerate().map(|(index, action)| {
+            index >= start_index && self.only.as_ref().is_none_or(|only| only.iter().any(|range| range.contains(&(index + 1)))) &&
+                self.tags_match(action)
+        }).collect())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct LoopItem {
+    pub index:      usize,
+    pub count:      usize,
+    pub value:      ParamValue,
 }
 
 impl fmt::Display for ControlActions {
@@ -152,8 +498,70 @@ impl ControlActions {
                          hostname: String::new(),
                          port: None,
                          auth: ControlSessionUserAuth::UserPass(UserAuthUserPass::new("", "")),
+                         host_key_policy: HostKeyPolicy::AcceptNew,
+                         jump_hosts: None,
+                         become_enabled: false,
+                         become_user: "root".to_string(),
+                         become_password: String::new(),
                          system_validation: SystemValidation::new(),
-                         actions: Vec::with_capacity(0)}
+                         serial: None,
+                         max_fail_percentage: 0,
+                         vars: TemplateVars::new(),
+                         actions: Vec::with_capacity(0),
+                         handlers: Vec::new()}
+    }
+
+    // sets the host to connect to from a '[user@]hostname[:port]' string, i.e. from the command line
+    pub fn set_target_host(&mut self, host: &str) -> Result<(), String> {
+        let (username, host) = match host.split_once('@') {
+            Some((username, host)) => (Some(username), host),
+            None => (None, host),
+        };
+        let (hostname, port) = match host.split_once(':') {
+            Some((hostname, port)) => (hostname, Some(port.parse::<u32>().map_err(|_| format!("invalid port number: '{}'", port))?)),
+            None => (host, None),
+        };
+        if hostname.is_empty() {
+            return Err("no hostname was specified".to_string());
+        }
+
+        self.hostname = hostname.to_string();
+        if port.is_some() {
+            self.port = port;
+        }
+        if let Some(username) = username {
+            self.auth.set_username(username);
+        }
+        Ok(())
+    }
+
+    // the names of the secrets referenced with '{{ vault.name }}' expressions in the connection settings, vars and
+    // action params, so we know whether the vault needs loading.
+    pub fn get_vault_reference_names(&self) -> BTreeSet<String> {
+        let mut names = BTreeSet::new();
+        for value in get_auth_string_values(&self.auth).into_iter().chain([&self.hostname, &self.become_password]) {
+            get_vault_reference_names(value, &mut names);
+        }
+        for jump_host in self.jump_hosts.iter().flatten() {
+            jump_host.get_vault_reference_names(&mut names);
+        }
+
+        let actions = self.actions.iter().chain(self.handlers.iter().flat_map(|handler| handler.actions.iter()));
+        for value in self.vars.values().chain(actions.flat_map(|action| action.params.values.values())) {
+            get_value_vault_reference_names(value, &mut names);
+        }
+        names
+    }
+
+    // substitutes any '{{ vault.name }}' expressions in the connection settings. Vars and action params are
+    // rendered with the vault's secrets when they're used instead.
+    pub fn substitute_vault_references(&mut self, vault: &Vault) {
+        self.hostname = vault.substitute_references(&self.hostname);
+        self.become_password = vault.substitute_references(&self.become_password);
+        substitute_auth_vault_references(&mut self.auth, vault);
+        for jump_host in self.jump_hosts.iter_mut().flatten() {
+            jump_host.substitute_vault_references(vault);
+        }
     }
 
     // TODO: something a bit better than this? Not really sure what though? Use a Result to indicate
@@ -173,6 +581,31 @@ impl ControlActions {
         Err(FileLoadError::CustomError("Unknown file type.".to_string()))
     }
 
+    // the user to run the action as via sudo, if any, or if no action is given, the user to run things like
+    // fact gathering as.
+    pub fn get_become_user(&self, action: Option<&ControlAction>) -> Option<String> {
+        let become_enabled = action.and_then(|action| action.become_enabled).unwrap_or(self.become_enabled);
+        if !become_enabled {
+            return None;
+        }
+        Some(action.and_then(|action| action.become_user.clone()).unwrap_or_else(|| self.become_user.clone()))
+    }
+
+    // whether anything is run via sudo, so whether the sudo password might be needed
+    pub fn uses_become(&self) -> bool {
+        self.get_become_user(None).is_some() ||
+            self.actions.iter().chain(self.handlers.iter().flat_map(|handler| handler.actions.iter()))
+                .any(|action| self.get_become_user(Some(action)).is_some())
+    }
+
+    // the names of all variables set by actions (or handlers) with 'register'
+    pub fn get_registered_var_names(&self) -> BTreeSet<String> {
+        self.actions.iter()
+            .chain(self.handlers.iter().flat_map(|handler| handler.actions.iter()))
+            .filter_map(|action| action.register.clone())
+            .collect()
+    }
+
     fn from_file_txt(path: &str) -> Result<ControlActions, FileLoadError> {
         let file = std::fs::File::open(path).unwrap();
         let _reader = BufReader::new(file);
@@ -194,6 +627,12 @@ impl ControlActions {
             let read_from_string_res = file.read_to_string(&mut yaml_content);
     
             if read_from_string_res.is_ok() {
+                // any included files are relative to the script, as is the 'roles' directory
+                let script_path = Path::new(path);
+                let roles_dir = script_path.parent().unwrap_or(Path::new("")).join("roles");
+                let file_stack = script_path.canonicalize().into_iter().collect();
+                let mut includes = IncludeResolver::new(script_path, &yaml_content, file_stack, roles_dir);
+
                 let yaml_load_res = YamlLoader::load_from_str(&yaml_content);
                 if yaml_load_res.is_ok() {
                     if let Ok(document) = yaml_load_res {
@@ -211,6 +650,9 @@ impl ControlActions {
                             // otherwise, assume it's okay
                             control_actions.auth = auth_params.unwrap();
 
+                            // 'becomeUser' on its own implies 'become', unless that's explicitly disabled
+                            let mut become_enabled = None;
+
                             for (key, value) in hash {
                                 match key.as_str().unwrap() {
                                     "provider" => {
@@ -234,15 +676,26 @@ impl ControlActions {
                                     "systemValidation" => {
                                         // For "convenience", we allow different things, so parse it into a string,
                                         // but note that in Yaml its type could be a string or an integer...
-                                        // TODO: Supporting things like "20.04" without being quoted in YAML might get annoying...
-                                        //       I'd assume it'd likely be interpreted by YAML as a Real/float, and loose the leading '0' ?
+                                        // Unquoted values like 20.04 are Reals, but yaml_rust keeps the original string for
+                                        // those, so the leading '0' isn't lost.
                                         let value_as_string = match value.clone() {
-                                            Yaml::String(val) => {
+                                            Yaml::String(val) | Yaml::Real(val) => {
                                                 val.clone()
                                             },
                                             Yaml::Integer(val) => {
                                                 format!("{}", val)
                                             },
+                                            // or a map of the constraints
+                                            Yaml::Hash(map) => {
+                                                match SystemValidation::parse_yaml_map(&map) {
+                                                    Ok(validation) => control_actions.system_validation = validation,
+                                                    Err(err) => {
+                                                        eprintln!("Error parsing 'systemValidation' param: {}", err);
+                                                        return Err(FileLoadError::CustomError("Error loading file.".to_string()));
+                                                    }
+                                                }
+                                                continue;
+                                            },
                                             _ => {
                                                 eprintln!("Error parsing 'systemValidation' param as a string: input YAML value was of an unexpected type.");
                                                 return Err(FileLoadError::CustomError("Error loading file.".to_string()));
@@ -258,13 +711,123 @@ impl ControlActions {
                                             return Err(FileLoadError::CustomError("Error loading file.".to_string()));
                                         }
                                     }
+                                    "serial" => {
+                                        // either a number of hosts, or a percentage of them
+                                        let value_as_string = match value {
+                                            Yaml::String(val) => val.clone(),
+                                            Yaml::Integer(val) => format!("{}", val),
+                                            _ => String::new()
+                                        };
+                                        match SerialBatchSize::parse(&value_as_string) {
+                                            Ok(batch_size) => control_actions.serial = Some(batch_size),
+                                            Err(err) => {
+                                                eprintln!("Error parsing 'serial' param: {}", err);
+                                                return Err(FileLoadError::CustomError("Error loading file.".to_string()));
+                                            }
+                                        }
+                                    },
+                                    "maxFailPercentage" => {
+                                        match value {
+                                            Yaml::Integer(val) if (0..=100).contains(val) => {
+                                                control_actions.max_fail_percentage = *val as u32;
+                                            },
+                                            _ => {
+                                                eprintln!("Error parsing 'maxFailPercentage' param: it should be an integer between 0 and 100.");
+                                                return Err(FileLoadError::CustomError("Error loading file.".to_string()));
+                                            }
+                                        }
+                                    },
+                                    "hostKeyPolicy" => {
+                                        match value.as_str().map(HostKeyPolicy::parse) {
+                                            Some(Ok(policy)) => control_actions.host_key_policy = policy,
+                                            Some(Err(err)) => {
+                                                eprintln!("Error parsing 'hostKeyPolicy' param: {}", err);
+                                                return Err(FileLoadError::CustomError("Error loading file.".to_string()));
+                                            },
+                                            None => {
+                                                eprintln!("Error parsing 'hostKeyPolicy' param: it should be a string.");
+                                                return Err(FileLoadError::CustomError("Error loading file.".to_string()));
+                                            }
+                                        }
+                                    },
+                                    "jumpHost" | "jumpHosts" => {
+                                        match JumpHost::from_yaml(value).and_then(|jump_hosts| JumpHost::check_backend_supported(&jump_hosts).map(|_| jump_hosts)) {
+                                            Ok(jump_hosts) => control_actions.jump_hosts = Some(jump_hosts),
+                                            Err(err) => {
+                                                eprintln!("Error parsing '{}' param: {}", key.as_str().unwrap(), err);
+                                                return Err(FileLoadError::CustomError("Error loading file.".to_string()));
+                                            }
+                                        }
+                                    },
+                                    "become" => {
+                                        match value {
+                                            Yaml::Boolean(val) => become_enabled = Some(*val),
+                                            _ => {
+                                                eprintln!("Error parsing 'become' param: it should be a boolean.");
+                                                return Err(FileLoadError::CustomError("Error loading file.".to_string()));
+                                            }
+                                        }
+                                    },
+                                    "becomeUser" | "becomePassword" => {
+                                        let val = match value.as_str() {
+                                            Some(val) if !val.trim().is_empty() => val.trim().to_string(),
+                                            _ => {
+                                                eprintln!("Error parsing '{}' param: it should be a non-empty string.", key.as_str().unwrap());
+                                                return Err(FileLoadError::CustomError("Error loading file.".to_string()));
+                                            }
+                                        };
+                                        if key.as_str() == Some("becomeUser") {
+                                            control_actions.become_user = val;
+                                            become_enabled = become_enabled.or(Some(true));
+                                        }
+                                        else {
+                                            control_actions.become_password = val;
+                                        }
+                                    },
+                                    "vars" => {
+                                        match vars_from_yaml(value) {
+                                            Ok(vars) => control_actions.vars = vars,
+                                            Err(err) => {
+                                                eprintln!("Error parsing 'vars' param: {}", err);
+                                                return Err(FileLoadError::CustomError("Error loading file.".to_string()));
+                                            }
+                                        }
+                                    },
                                     "actions" => {
-                                        control_actions.ingest_control_actions_yaml_items(value);
+                                        if let Err(err) = control_actions.ingest_control_actions_yaml_items(value, Some(&mut includes)) {
+                                            eprintln!("Error: {}", err);
+                                            return Err(FileLoadError::CustomError("Error loading file.".to_string()));
+                                        }
+                                    },
+                                    "handlers" => {
+                                        if let Err(err) = control_actions.ingest_control_handlers_yaml_items(value) {
+                                            eprintln!("Error parsing 'handlers' param: {}", err);
+                                            return Err(FileLoadError::CustomError("Error loading file.".to_string()));
+                                        }
                                     },
                                     _ => {}
                                 }
                             }
 
+                            control_actions.become_enabled = become_enabled.unwrap_or(false);
+
+                            let merge_result = control_actions.merge_included_items(includes)
+                                                    .and_then(|_| control_actions.resolve_local_file_paths(None));
+                            if let Err(err) = merge_result {
+                                eprintln!("Error: {}", err);
+                                return Err(FileLoadError::CustomError("Error loading file.".to_string()));
+                            }
+
+                            // make sure any handlers actions notify exist
+                            for action in &control_actions.actions {
+                                for handler_name in &action.notify {
+                                    if !control_actions.handlers.iter().any(|handler| &handler.name == handler_name) {
+                                        eprintln!("Error: the '{}' action notifies a handler which doesn't exist: '{}'", action.action, handler_name);
+                                        return Err(FileLoadError::CustomError("Error loading file.".to_string()));
+                                    }
+                                }
+                            }
+
                             return Ok(control_actions);
                         }
                     }
@@ -283,10 +846,12 @@ impl ControlActions {
         Err(FileLoadError::CustomError("Error loading file.".to_string()))
     }
 
-    fn ingest_control_actions_yaml_items(&mut self, actions_item: &yaml_rust::yaml::Yaml) {
+    // Note: 'include' items are only supported if includes is provided
+    fn ingest_control_actions_yaml_items(&mut self, actions_item: &yaml_rust::yaml::Yaml,
+                                         mut includes: Option<&mut IncludeResolver>) -> Result<(), String> {
         if actions_item.is_array() {
             for item in actions_item.as_vec().unwrap() {
-                self.ingest_control_actions_yaml_items(item);
+                self.ingest_control_actions_yaml_items(item, includes.as_deref_mut())?;
             }
         }
         else {
@@ -294,7 +859,13 @@ impl ControlActions {
             if let yaml_rust::Yaml::Hash(ref hash) = actions_item {
                 for (key, value) in hash {
                     if let Some(key_str) = key.as_str() {
-                        if let yaml_rust::Yaml::Hash(ref val_hash) = value {
+                        if key_str == "include" {
+                            match includes.as_deref_mut() {
+                                Some(includes) => self.ingest_include_item(value, includes)?,
+                                None => return Err("'include' items can only be used in 'actions' lists.".to_string()),
+                            }
+                        }
+                        else if let yaml_rust::Yaml::Hash(ref val_hash) = value {
                             // it's hopefully an action item
                             self.ingest_control_yaml_action_item(key_str, val_hash);
                         }
@@ -302,6 +873,186 @@ impl ControlActions {
                 }
             }
         }
+
+        Ok(())
+    }
+
+    // loads the actions (and any vars and handlers) from the file or role of an 'include' item
+    fn ingest_include_item(&mut self, value: &Yaml, includes: &mut IncludeResolver) -> Result<(), String> {
+        let location = includes.next_include_location();
+        let (target, when, tags) = parse_include_item(value).map_err(|err| format!("{}: invalid 'include' item: {}", location, err))?;
+
+        // files are relative to the file including them, and roles are in the 'roles' directory next to the script
+        let (included_path, role_dir) = match &target {
+            IncludeTarget::File(file) => (includes.path.parent().unwrap_or(Path::new("")).join(file), None),
+            IncludeTarget::Role(name) => {
+                let role_dir = includes.roles_dir.join(name);
+                (role_dir.join("actions.yaml"), role_dir.canonicalize().ok())
+            },
+        };
+
+        let canonical_path = included_path.canonicalize().map_err(|err| {
+            format!("{}: couldn't include {} ({}): {}", location, target, included_path.display(), err)
+        })?;
+        if includes.file_stack.contains(&canonical_path) {
+            let cycle: Vec<String> = includes.file_stack.iter().chain(std::iter::once(&canonical_path))
+                                                          .map(|path| path.display().to_string()).collect();
+            return Err(format!("{}: couldn't include {}, as it would cause an include cycle: {}", location, target, cycle.join(" -> ")));
+        }
+
+        let mut file_stack = includes.file_stack.clone();
+        file_stack.push(canonical_path);
+        let mut included = ControlActions::from_included_file(&included_path, role_dir.as_deref(), file_stack, &includes.roles_dir)
+                                    .map_err(|err| format!("{}: couldn't include {}: {}", location, target, err))?;
+
+        // the include's condition applies to all the actions included
+        if let Some(when) = when {
+            for action in &mut included.actions {
+                action.when = Some(match &action.when {
+                    Some(action_when) => format!("({}) and ({})", when, action_when),
+                    None => when.clone(),
+                });
+            }
+        }
+
+        // and so do its tags
+        for action in &mut included.actions {
+            for tag in &tags {
+                if !action.tags.contains(tag) {
+                    action.tags.push(tag.clone());
+                }
+            }
+        }
+
+        self.actions.append(&mut included.actions);
+        for (name, value) in included.vars {
+            includes.default_vars.entry(name).or_insert(value);
+        }
+        includes.handlers.append(&mut included.handlers);
+
+        Ok(())
+    }
+
+    // loads a file included by a script, which can only have 'vars', 'actions' and 'handlers' items. For roles, the
+    // role's default vars are also loaded, and its actions' local file paths are relative to the role's directories.
+    fn from_included_file(path: &Path, role_dir: Option<&Path>, file_stack: Vec<PathBuf>, roles_dir: &Path) -> Result<ControlActions, String> {
+        let content = std::fs::read_to_string(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+        let documents = YamlLoader::load_from_str(&content).map_err(|err| format!("{}: {}", path.display(), err))?;
+
+        let mut included = ControlActions::new();
+        if let Some(role_dir) = role_dir {
+            let vars_path = role_dir.join("vars.yaml");
+            if vars_path.exists() {
+                included.vars = load_vars_file(&vars_path.to_string_lossy()).map_err(|err| err.to_string())?;
+            }
+        }
+
+        let hash = match documents.first() {
+            Some(Yaml::Hash(hash)) => hash,
+            _ => return Err(format!("{}: expected a map with an 'actions' item.", path.display())),
+        };
+
+        let mut includes = IncludeResolver::new(path, &content, file_stack, roles_dir.to_path_buf());
+        for (key, value) in hash {
+            match key.as_str() {
+                Some("vars") => {
+                    let vars = vars_from_yaml(value).map_err(|err| format!("{}: invalid 'vars' item: {}", path.display(), err))?;
+                    included.vars.extend(vars);
+                },
+                Some("actions") => included.ingest_control_actions_yaml_items(value, Some(&mut includes))?,
+                Some("handlers") => {
+                    included.ingest_control_handlers_yaml_items(value)
+                            .map_err(|err| format!("{}: invalid 'handlers' item: {}", path.display(), err))?;
+                },
+                _ => {
+                    return Err(format!("{}: included files can only have 'vars', 'actions' and 'handlers' items, not '{}'.",
+                                       path.display(), key.as_str().unwrap_or("")));
+                }
+            }
+        }
+
+        included.merge_included_items(includes).map_err(|err| format!("{}: {}", path.display(), err))?;
+        included.resolve_local_file_paths(role_dir).map_err(|err| format!("{}: {}", path.display(), err))?;
+
+        Ok(included)
+    }
+
+    // merges in the vars and handlers from any files included, with the including file's own vars taking precedence
+    fn merge_included_items(&mut self, includes: IncludeResolver) -> Result<(), String> {
+        for (name, value) in includes.default_vars {
+            self.vars.entry(name).or_insert(value);
+        }
+
+        for handler in includes.handlers {
+            if self.handlers.iter().any(|existing| existing.name == handler.name) {
+                return Err(format!("the '{}' handler is defined more than once.", handler.name));
+            }
+            self.handlers.push(handler);
+        }
+
+        Ok(())
+    }
+
+    // loads the contents of createFile 'template' files, and for roles, resolves relative local file paths to the
+    // role's 'files' and 'templates' directories.
+    fn resolve_local_file_paths(&mut self, role_dir: Option<&Path>) -> Result<(), String> {
+        let handler_actions = self.handlers.iter_mut().flat_map(|handler| handler.actions.iter_mut());
+        for action in self.actions.iter_mut().chain(handler_actions) {
+            match action.action {
+                ControlActionType::TransmitFile => {
+                    if let (Some(role_dir), Some(ParamValue::Str(local_path))) = (role_dir, action.params.values.get_mut("localSourcePath")) {
+                        if Path::new(local_path).is_relative() && !local_path.contains("{{") {
+                            *local_path = role_dir.join("files").join(&local_path).to_string_lossy().to_string();
+                        }
+                    }
+                },
+                ControlActionType::CreateFile => {
+                    let template_path = match action.params.values.remove("template") {
+                        Some(ParamValue::Str(template_path)) => template_path,
+                        Some(_) => return Err("the 'template' param of createFile actions must be a file path.".to_string()),
+                        None => continue,
+                    };
+                    if action.params.has_value("content") {
+                        return Err("createFile actions can't have both 'content' and 'template' params.".to_string());
+                    }
+
+                    let template_path = match role_dir {
+                        Some(role_dir) if Path::new(&template_path).is_relative() => role_dir.join("templates").join(template_path),
+                        _ => PathBuf::from(template_path),
+                    };
+                    let content = std::fs::read_to_string(&template_path).map_err(|err| {
+                        format!("couldn't read createFile template file: {}, error: {}", template_path.display(), err)
+                    })?;
+                    action.params.values.insert("content".to_string(), ParamValue::Str(content));
+                },
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    // handlers are a map of the handler name to the action (or list of actions) to run for it
+    fn ingest_control_handlers_yaml_items(&mut self, handlers_item: &yaml_rust::yaml::Yaml) -> Result<(), String> {
+        let handlers_hash = handlers_item.as_hash().ok_or("expected a map of handler names to actions.")?;
+
+        for (key, value) in handlers_hash {
+            let name = key.as_str().ok_or("handler names must be strings.")?;
+
+            // re-use the actions parsing
+            let mut handler_actions = ControlActions::new();
+            handler_actions.ingest_control_actions_yaml_items(value, None)?;
+            if handler_actions.actions.is_empty() {
+                return Err(format!("the '{}' handler has no valid actions.", name));
+            }
+            if handler_actions.actions.iter().any(|action| !action.notify.is_empty()) {
+                return Err(format!("the actions of the '{}' handler can't notify other handlers.", name));
+            }
+
+            self.handlers.push(ControlHandler { name: name.to_string(), actions: handler_actions.actions });
+        }
+
+        Ok(())
     }
 
     fn ingest_control_yaml_action_item(&mut self, name: &str, values: &yaml_rust::yaml::Hash) {
@@ -340,16 +1091,278 @@ impl ControlActions {
             return;
         }
 
+        let mut loop_items = None;
+        let mut retries = None;
+
         for (key, value) in values {
             if let Some(key_str) = key.as_str() {
+                // items which control how the action is run, rather than being params for the action itself
+                if key_str == "loop" || key_str == "withItems" {
+                    if loop_items.is_some() {
+                        eprintln!("Error: the '{}' action can only have one 'loop' or 'withItems' item, ignoring action.", name);
+                        return;
+                    }
+                    match ParamValue::from(value.clone()) {
+                        ParamValue::Array(items) => loop_items = Some(items),
+                        _ => {
+                            eprintln!("Error: the '{}' item of the '{}' action must be a list of items, ignoring action.", key_str, name);
+                            return;
+                        }
+                    }
+                    continue;
+                }
+                // Note: not 'name', as some actions (i.e. addGroup) have a 'name' param
+                else if key_str == "actionName" {
+                    match value {
+                        Yaml::String(val) if !val.trim().is_empty() => new_action.name = Some(val.trim().to_string()),
+                        _ => {
+                            eprintln!("Error: the 'actionName' item of the '{}' action must be a non-empty string, ignoring action.", name);
+                            return;
+                        }
+                    }
+                    continue;
+                }
+                else if key_str == "when" {
+                    new_action.when = match value {
+                        Yaml::String(val) => Some(val.clone()),
+                        Yaml::Boolean(val) => Some(val.to_string()),
+                        _ => {
+                            eprintln!("Error: the 'when' item of the '{}' action must be a string condition, ignoring action.", name);
+                            return;
+                        }
+                    };
+                    continue;
+                }
+                else if key_str == "notify" {
+                    let handler_names: Option<Vec<String>> = match ParamValue::from(value.clone()) {
+                        ParamValue::Str(handler_name) => Some(vec![handler_name]),
+                        ParamValue::Array(items) => {
+                            items.into_iter().map(|item| match item {
+                                ParamValue::Str(handler_name) => Some(handler_name),
+                                _ => None
+                            }).collect()
+                        },
+                        _ => None
+                    };
+                    if let Some(handler_names) = handler_names {
+                        new_action.notify = handler_names;
+                    }
+                    else {
+                        eprintln!("Error: the 'notify' item of the '{}' action must be a handler name or list of handler names, ignoring action.", name);
+                        return;
+                    }
+                    continue;
+                }
+                else if key_str == "tags" {
+                    match parse_tags_item(value) {
+                        Some(tags) => new_action.tags = tags,
+                        None => {
+                            eprintln!("Error: the 'tags' item of the '{}' action must be a tag or list of tags, ignoring action.", name);
+                            return;
+                        }
+                    }
+                    continue;
+                }
+                else if key_str == "continueOnError" {
+                    match value {
+                        Yaml::Boolean(val) => new_action.continue_on_error = *val,
+                        _ => {
+                            eprintln!("Error: the 'continueOnError' item of the '{}' action must be a boolean, ignoring action.", name);
+                            return;
+                        }
+                    }
+                    continue;
+                }
+                else if key_str == "retries" || key_str == "retryDelay" {
+                    let count = match value {
+                        Yaml::Integer(val) if *val >= 0 => *val as u32,
+                        _ => {
+                            eprintln!("Error: the '{}' item of the '{}' action must be a positive integer, ignoring action.", key_str, name);
+                            return;
+                        }
+                    };
+                    if key_str == "retries" {
+                        retries = Some(count);
+                    }
+                    else {
+                        new_action.retry_delay = count;
+                    }
+                    continue;
+                }
+                else if key_str == "until" {
+                    match value {
+                        Yaml::String(val) => new_action.until = Some(val.clone()),
+                        _ => {
+                            eprintln!("Error: the 'until' item of the '{}' action must be a string condition, ignoring action.", name);
+                            return;
+                        }
+                    }
+                    continue;
+                }
+                else if key_str == "become" {
+                    match value {
+                        Yaml::Boolean(val) => new_action.become_enabled = Some(*val),
+                        _ => {
+                            eprintln!("Error: the 'become' item of the '{}' action must be a boolean, ignoring action.", name);
+                            return;
+                        }
+                    }
+                    continue;
+                }
+                else if key_str == "becomeUser" {
+                    match value.as_str() {
+                        Some(user) if !user.trim().is_empty() => {
+                            new_action.become_user = Some(user.trim().to_string());
+                            // it implies 'become', unless that's explicitly disabled
+                            new_action.become_enabled = new_action.become_enabled.or(Some(true));
+                        },
+                        _ => {
+                            eprintln!("Error: the 'becomeUser' item of the '{}' action must be a non-empty string, ignoring action.", name);
+                            return;
+                        }
+                    }
+                    continue;
+                }
+                else if key_str == "register" {
+                    match value.as_str() {
+                        Some(var_name) if is_valid_identifier(var_name) && !is_reserved_name(var_name) => {
+                            new_action.register = Some(var_name.to_string());
+                        },
+                        _ => {
+                            eprintln!("Error: the 'register' item of the '{}' action must be a valid variable name, ignoring action.", name);
+                            return;
+                        }
+                    }
+                    continue;
+                }
+
                 new_action.params.values.insert(key_str.to_string(), ParamValue::from(value.clone()));
             }
         }
 
+        // if there's an 'until' condition, it should be retried a few times by default
+        new_action.retries = retries.unwrap_or(if new_action.until.is_some() { DEFAULT_UNTIL_RETRIES } else { 0 });
+
+        if let Some(loop_items) = loop_items {
+            // expand it into a separate action for each item, with the '{{ item }}' values being substituted
+            // when the actions are run.
+            let count = loop_items.len();
+            for (index, value) in loop_items.into_iter().enumerate() {
+                let mut item_action = new_action.clone();
+                item_action.loop_item = Some(LoopItem { index, count, value });
+                self.actions.push(item_action);
+            }
+            return;
+        }
+
         self.actions.push(new_action);
     }
 }
 
+// the state needed to load files included by 'include' items in a script (or an included file)
+struct IncludeResolver {
+    // the file being loaded
+    path:           PathBuf,
+    // line numbers of the 'include' items in the file, in order, so errors can point to them
+    include_lines:  Vec<usize>,
+    next_include:   usize,
+    // canonical paths of the files currently being loaded (this one last), to detect include cycles
+    file_stack:     Vec<PathBuf>,
+    // the 'roles' directory next to the top-level script
+    roles_dir:      PathBuf,
+    // vars and handlers from the included files, which are merged in after the file has been loaded
+    default_vars:   TemplateVars,
+    handlers:       Vec<ControlHandler>,
+}
+
+impl IncludeResolver {
+    fn new(path: &Path, content: &str, file_stack: Vec<PathBuf>, roles_dir: PathBuf) -> IncludeResolver {
+        IncludeResolver { path: path.to_path_buf(), include_lines: find_include_lines(content), next_include: 0,
+                          file_stack, roles_dir, default_vars: TemplateVars::new(), handlers: Vec::new() }
+    }
+
+    // i.e. "scripts/web.yaml:12" for the next 'include' item in the file
+    fn next_include_location(&mut self) -> String {
+        let line = self.include_lines.get(self.next_include);
+        self.next_include += 1;
+
+        match line {
+            Some(line) => format!("{}:{}", self.path.display(), line),
+            None => self.path.display().to_string(),
+        }
+    }
+}
+
+enum IncludeTarget {
+    File(String),
+    Role(String),
+}
+
+impl fmt::Display for IncludeTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IncludeTarget::File(file) => write!(f, "file '{}'", file),
+            IncludeTarget::Role(name) => write!(f, "role '{}'", name),
+        }
+    }
+}
+
+// 'include' items are either just a file path, or a map with a 'file' or 'role' item, and an optional 'when' condition
+fn parse_include_item(value: &Yaml) -> Result<(IncludeTarget, Option<String>, Vec<String>), String> {
+    let hash = match value {
+        Yaml::String(file) => return Ok((IncludeTarget::File(file.clone()), None, Vec::new())),
+        Yaml::Hash(hash) => hash,
+        _ => return Err("expected a file path, or a map with a 'file' or 'role' item.".to_string()),
+    };
+
+    let mut target = None;
+    let mut when = None;
+    let mut tags = Vec::new();
+    for (key, value) in hash {
+        match (key.as_str().unwrap_or(""), value) {
+            ("file", Yaml::String(file)) if target.is_none() => target = Some(IncludeTarget::File(file.clone())),
+            ("role", Yaml::String(name)) if target.is_none() => {
+                if !is_valid_identifier(name) {
+                    return Err(format!("invalid role name: '{}'", name));
+                }
+                target = Some(IncludeTarget::Role(name.clone()));
+            },
+            ("when", Yaml::String(condition)) => when = Some(condition.clone()),
+            ("when", Yaml::Boolean(condition)) => when = Some(condition.to_string()),
+            ("tags", value) => tags = parse_tags_item(value).ok_or_else(|| "'tags' must be a tag or list of tags.".to_string())?,
+            (key_str, _) => return Err(format!("unexpected item: '{}'", key_str)),
+        }
+    }
+
+    match target {
+        Some(target) => Ok((target, when, tags)),
+        None => Err("expected a 'file' or 'role' item.".to_string()),
+    }
+}
+
+// a tag, or list of tags
+fn parse_tags_item(value: &Yaml) -> Option<Vec<String>> {
+    let tags = match value {
+        Yaml::String(tag) => vec![tag.clone()],
+        Yaml::Array(items) => items.iter().map(|item| item.as_str().map(|tag| tag.to_string())).collect::<Option<Vec<String>>>()?,
+        _ => return None,
+    };
+    tags.iter().all(|tag| is_valid_tag(tag)).then_some(tags)
+}
+
+// tags can't be empty or contain commas or whitespace, so they can be specified with '--tags a,b'
+fn is_valid_tag(tag: &str) -> bool {
+    !tag.is_empty() && !tag.chars().any(|c| c == ',' || c.is_whitespace())
+}
+
+// the (1-based) line numbers of lines starting 'include' items, i.e. '- include: base.yaml'
+fn find_include_lines(content: &str) -> Vec<usize> {
+    content.lines().enumerate().filter(|(_, line)| {
+        let line = line.trim_start();
+        line.strip_prefix('-').unwrap_or(line).trim_start().starts_with("include:")
+    }).map(|(index, _)| index + 1).collect()
+}
+
 fn get_yaml_map_item_as_string(map: &yaml_rust::yaml::Hash, str_val: &str) -> Option<String> {
     if let Some(item) = map.get(&Yaml::String(str_val.to_string())) {
         if let Some(item_str) = item.as_str() {
@@ -371,7 +1384,8 @@ fn process_auth_yaml_items(map: &yaml_rust::yaml::Hash) -> Option<ControlSession
     enum AuthType {
         Unknown,
         UserPass,
-        PublicKey
+        PublicKey,
+        Agent
     }
 
     let mut auth_type = AuthType::Unknown;
@@ -385,6 +1399,9 @@ fn process_auth_yaml_items(map: &yaml_rust::yaml::Hash) -> Option<ControlSession
         else if "publickey".eq_ignore_ascii_case(&auth_type_param_str) {
             auth_type = AuthType::PublicKey;
         }
+        else if "agent".eq_ignore_ascii_case(&auth_type_param_str) {
+            auth_type = AuthType::Agent;
+        }
         else {
             eprintln!("Error: unrecognised control command 'authType' param: '{}'", auth_type_param_str);
             return None;
@@ -409,6 +1426,11 @@ fn process_auth_yaml_items(map: &yaml_rust::yaml::Hash) -> Option<ControlSession
 
     let username = get_yaml_map_item_as_string(map, "user").unwrap_or("$PROMPT".to_string());
 
+    if auth_type == AuthType::Agent {
+        // the keys come from ssh-agent, so there's nothing else needed
+        return Some(ControlSessionUserAuth::Agent(UserAuthAgent::new(&username)));
+    }
+
     if auth_type == AuthType::UserPass {
         
         let password = get_yaml_map_item_as_string(map, "password").unwrap_or_default();
@@ -457,7 +1479,9 @@ impl fmt::Display for ControlAction {
 
 impl ControlAction {
     pub fn new() -> ControlAction {
-        ControlAction { action: ControlActionType::NotSet, params: Params::new() }
+        ControlAction { action: ControlActionType::NotSet, params: Params::new(), name: None, tags: Vec::new(), when: None, loop_item: None, notify: Vec::new(),
+                        register: None, continue_on_error: false, retries: 0, retry_delay: DEFAULT_RETRY_DELAY, until: None,
+                        become_enabled: None, become_user: None }
     }
 
     // convenience method to get an action parameter which is required as a string, and if it doesn't
@@ -469,7 +1493,60 @@ impl ControlAction {
         else {
             Err(ActionError::InvalidParams(format!("The '{}' parameter was not specified.", param_name)))
         }
-    } 
+    }
+
+    // returns the values of any string params which look like they contain secrets (i.e. passwords),
+    // so they can be redacted from any output.
+    pub fn get_secret_param_values(&self) -> Vec<String> {
+        self.params.values.iter()
+            .filter(|(key, _value)| is_secret_param_name(key))
+            .filter_map(|(_key, value)| match value {
+                ParamValue::Str(str_val) if !str_val.is_empty() && str_val != "$PROMPT" => Some(str_val.clone()),
+                _ => None
+            })
+            .collect()
+    }
+}
+
+fn substitute_auth_vault_references(auth: &mut ControlSessionUserAuth, vault: &Vault) {
+    match auth {
+        ControlSessionUserAuth::UserPass(userpass) => {
+            userpass.username = vault.substitute_references(&userpass.username);
+            userpass.password = vault.substitute_references(&userpass.password);
+        },
+        ControlSessionUserAuth::PublicKey(publickey) => {
+            publickey.username = vault.substitute_references(&publickey.username);
+            publickey.publickey_path = vault.substitute_references(&publickey.publickey_path);
+            publickey.privatekey_path = vault.substitute_references(&publickey.privatekey_path);
+            publickey.passphrase = vault.substitute_references(&publickey.passphrase);
+        },
+        ControlSessionUserAuth::Agent(agent) => {
+            agent.username = vault.substitute_references(&agent.username);
+        },
+    }
+}
+
+fn get_auth_string_values(auth: &ControlSessionUserAuth) -> Vec<&String> {
+    match auth {
+        ControlSessionUserAuth::UserPass(userpass) => vec![&userpass.username, &userpass.password],
+        ControlSessionUserAuth::PublicKey(publickey) => vec![&publickey.username, &publickey.publickey_path, &publickey.privatekey_path,
+                                                             &publickey.passphrase],
+        ControlSessionUserAuth::Agent(agent) => vec![&agent.username],
+    }
+}
+
+pub fn is_secret_param_name(name: &str) -> bool {
+    let name_lower = name.to_ascii_lowercase();
+    name_lower.contains("password") || name_lower.contains("passphrase")
+}
+
+// replaces any of the secret values within the string with a placeholder.
+pub fn redact_secret_values(string: &str, secrets: &[String]) -> String {
+    let mut redacted = string.to_string();
+    for secret in secrets {
+        redacted = redacted.replace(secret.as_str(), "********");
+    }
+    redacted
 }
 
 // for retrieving info about host systems.
@@ -493,9 +1570,16 @@ impl SystemDetailsResult {
 pub enum GenericError {
     NotImplemented,
     CommandFailed(String),
+    Transport(TransportError),
     Other(String)
 }
 
+impl From<TransportError> for GenericError {
+    fn from(err: TransportError) -> Self {
+        GenericError::Transport(err)
+    }
+}
+
 pub trait ActionProvider {
 
     // not sure about this one - ideally it'd be static, but...
@@ -507,6 +1591,12 @@ pub trait ActionProvider {
         None
     }
 
+    // the set of actions the provider actually implements, so that scripts can be validated
+    // before connecting to a host and running any of them.
+    fn supported_actions(&self) -> BTreeSet<ControlActionType> {
+        BTreeSet::new()
+    }
+
     // TODO: we might have to make this a derived trait item at some point, but for the moment, we can just
     //       do this...
     fn post_process_command(&self, command: &str) -> String {
@@ -531,7 +1621,7 @@ pub trait ActionProvider {
         final_command
     }
 
-    fn generic_command(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<(), ActionError> {
+    fn generic_command(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<ActionResult, ActionError> {
         Err(ActionError::NotImplemented)
     }
 
@@ -540,91 +1630,411 @@ pub trait ActionProvider {
         Err(GenericError::NotImplemented)
     }
 
-    fn add_user(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<(), ActionError> {
+    // more details of the host than the above (CPUs, memory, disks, network interfaces, etc), for 'facts' variables
+    fn get_host_facts(&self, _connection: &mut ControlSession) -> Result<HostFacts, GenericError> {
+        Err(GenericError::NotImplemented)
+    }
+
+    fn add_user(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<ActionResult, ActionError> {
         Err(ActionError::NotImplemented)
     }
 
-    fn create_directory(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<(), ActionError> {
+    fn create_directory(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<ActionResult, ActionError> {
         Err(ActionError::NotImplemented)
     }
 
-    fn remove_directory(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<(), ActionError> {
+    fn remove_directory(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<ActionResult, ActionError> {
         Err(ActionError::NotImplemented)
     }
 
-    fn install_packages(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<(), ActionError> {
+    fn install_packages(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<ActionResult, ActionError> {
         Err(ActionError::NotImplemented)
     }
 
-    fn remove_packages(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<(), ActionError> {
+    fn remove_packages(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<ActionResult, ActionError> {
         Err(ActionError::NotImplemented)
     }
 
-    fn systemctrl(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<(), ActionError> {
+    fn systemctrl(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<ActionResult, ActionError> {
         Err(ActionError::NotImplemented)
     }
 
-    fn firewall(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<(), ActionError> {
+    fn firewall(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<ActionResult, ActionError> {
         Err(ActionError::NotImplemented)
     }
 
-    fn edit_file(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<(), ActionError> {
+    fn edit_file(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<ActionResult, ActionError> {
         Err(ActionError::NotImplemented)
     }
 
-    fn copy_path(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<(), ActionError> {
+    fn copy_path(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<ActionResult, ActionError> {
         Err(ActionError::NotImplemented)
     }
 
-    fn remove_file(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<(), ActionError> {
+    fn remove_file(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<ActionResult, ActionError> {
         Err(ActionError::NotImplemented)
     }
 
-    fn download_file(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<(), ActionError> {
+    fn download_file(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<ActionResult, ActionError> {
         Err(ActionError::NotImplemented)
     }
 
-    fn transmit_file(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<(), ActionError> {
+    fn transmit_file(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<ActionResult, ActionError> {
         Err(ActionError::NotImplemented)
     }
 
-    fn receive_file(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<(), ActionError> {
+    fn receive_file(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<ActionResult, ActionError> {
         Err(ActionError::NotImplemented)
     }
 
-    fn create_symlink(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<(), ActionError> {
+    fn create_symlink(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<ActionResult, ActionError> {
         Err(ActionError::NotImplemented)
     }
 
-    fn set_time_zone(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<(), ActionError> {
+    fn set_time_zone(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<ActionResult, ActionError> {
         Err(ActionError::NotImplemented)
     }
 
-    fn disable_swap(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<(), ActionError> {
+    fn disable_swap(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<ActionResult, ActionError> {
         Err(ActionError::NotImplemented)
     }
 
-    fn create_file(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<(), ActionError> {
+    fn create_file(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<ActionResult, ActionError> {
         Err(ActionError::NotImplemented)
     }
 
-    fn add_group(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<(), ActionError> {
+    fn add_group(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<ActionResult, ActionError> {
         Err(ActionError::NotImplemented)
     }
 
-    fn set_hostname(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<(), ActionError> {
+    fn set_hostname(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<ActionResult, ActionError> {
         Err(ActionError::NotImplemented)
     }
 
-    fn create_systemd_service(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<(), ActionError> {
+    fn create_systemd_service(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<ActionResult, ActionError> {
         Err(ActionError::NotImplemented)
     }
 
-    fn configure_ssh(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<(), ActionError> {
+    fn configure_ssh(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<ActionResult, ActionError> {
         Err(ActionError::NotImplemented)
     }
 
-    fn add_package_repo(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<(), ActionError> {
+    fn add_package_repo(&self, _connection: &mut ControlSession, _action: &ControlAction) -> Result<ActionResult, ActionError> {
         Err(ActionError::NotImplemented)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serial_batch_size() {
+        assert_eq!(SerialBatchSize::parse("2"), Ok(SerialBatchSize::Count(2)));
+        assert_eq!(SerialBatchSize::parse("25%"), Ok(SerialBatchSize::Percentage(25)));
+        assert!(SerialBatchSize::parse("0").is_err());
+        assert!(SerialBatchSize::parse("150%").is_err());
+
+        assert_eq!(SerialBatchSize::Count(2).get_batch_size(5), 2);
+        assert_eq!(SerialBatchSize::Count(10).get_batch_size(5), 5);
+        assert_eq!(SerialBatchSize::Percentage(25).get_batch_size(10), 3);
+        assert_eq!(SerialBatchSize::Percentage(10).get_batch_size(3), 1);
+    }
+
+    #[test]
+    fn test_auth_items() {
+        let yaml = YamlLoader::load_from_str("user: deploy\nauthType: agent\n").unwrap();
+        let auth = process_auth_yaml_items(yaml[0].as_hash().unwrap()).unwrap();
+        assert!(matches!(&auth, ControlSessionUserAuth::Agent(agent) if agent.username == "deploy"));
+
+        let yaml = YamlLoader::load_from_str("user: deploy\nprivateKeyPath: /keys/id\npublicKeyPath: /keys/id.pub\n").unwrap();
+        let auth = process_auth_yaml_items(yaml[0].as_hash().unwrap()).unwrap();
+        assert!(matches!(&auth, ControlSessionUserAuth::PublicKey(publickey) if publickey.privatekey_path == "/keys/id"));
+
+        let yaml = YamlLoader::load_from_str("authType: kerberos\n").unwrap();
+        assert!(process_auth_yaml_items(yaml[0].as_hash().unwrap()).is_none());
+    }
+
+    #[test]
+    fn test_jump_hosts() {
+        let jump_hosts = JumpHost::parse_chain("admin@bastion1:2222, ssh://bastion2").unwrap();
+        assert_eq!(jump_hosts.len(), 2);
+        assert_eq!((jump_hosts[0].hostname.as_str(), jump_hosts[0].port, jump_hosts[0].user.as_deref()), ("bastion1", Some(2222), Some("admin")));
+        assert_eq!((jump_hosts[1].hostname.as_str(), jump_hosts[1].port, jump_hosts[1].user.as_deref()), ("bastion2", None, None));
+        assert!(JumpHost::parse_chain("none").unwrap().is_empty());
+        assert!(JumpHost::parse_chain("bastion:port").is_err());
+        assert!(JumpHost::parse_chain("bastion1,,bastion2").is_err());
+
+        let yaml = YamlLoader::load_from_str("
+- bastion1
+- hostname: bastion2
+  port: 2200
+  user: jump
+  privateKeyPath: /keys/id
+  publicKeyPath: /keys/id.pub
+").unwrap();
+        let jump_hosts = JumpHost::from_yaml(&yaml[0]).unwrap();
+        assert_eq!(jump_hosts.len(), 2);
+        assert!(jump_hosts[0].auth.is_none());
+        assert_eq!((jump_hosts[1].hostname.as_str(), jump_hosts[1].port), ("bastion2", Some(2200)));
+        assert!(matches!(&jump_hosts[1].auth, Some(ControlSessionUserAuth::PublicKey(publickey)) if publickey.username == "jump"));
+
+        let yaml = YamlLoader::load_from_str("port: 22\n").unwrap();
+        assert!(JumpHost::from_yaml(&yaml[0]).is_err());
+
+        // only the openssh backend can tunnel through them
+        assert!(JumpHost::check_backend_supported(&[]).is_ok());
+        assert_eq!(JumpHost::check_backend_supported(&jump_hosts).is_ok(), cfg!(feature = "openssh"));
+    }
+
+    #[test]
+    fn test_loop_expansion() {
+        let yaml = YamlLoader::load_from_str(r#"
+- addUser:
+    username: "{{ item.name }}"
+    loop:
+      - name: alice
+      - name: bob
+- createDirectory:
+    path: "/opt/{{ item }}"
+    withItems: ["app1", "app2", "app3"]
+    when: "item != 'app2'"
+- createDirectory:
+    path: "/opt/none"
+    loop: []
+"#).unwrap();
+
+        let mut control_actions = ControlActions::new();
+        control_actions.ingest_control_actions_yaml_items(&yaml[0], None).unwrap();
+
+        assert_eq!(control_actions.actions.len(), 5);
+        assert_eq!(control_actions.actions[0].action, ControlActionType::AddUser);
+        assert!(!control_actions.actions[0].params.values.contains_key("loop"));
+
+        let loop_item = control_actions.actions[4].loop_item.as_ref().unwrap();
+        assert_eq!((loop_item.index, loop_item.count), (2, 3));
+        assert!(matches!(&loop_item.value, ParamValue::Str(str_val) if str_val == "app3"));
+        assert_eq!(control_actions.actions[4].when.as_deref(), Some("item != 'app2'"));
+    }
+
+    #[test]
+    fn test_error_policy_items() {
+        let yaml = YamlLoader::load_from_str(r#"
+actions:
+  - genericCommand:
+      command: "systemctl is-active app"
+      register: appStatus
+      until: "appStatus.stdout == 'active'"
+      retryDelay: 2
+  - removeFile:
+      path: "/tmp/old"
+      continueOnError: true
+      retries: 1
+  - removeFile:
+      path: "/tmp/old"
+      retries: -1
+"#).unwrap();
+
+        let mut control_actions = ControlActions::new();
+        control_actions.ingest_control_actions_yaml_items(&yaml[0]["actions"], None).unwrap();
+        assert_eq!(control_actions.actions.len(), 2);
+
+        let action = &control_actions.actions[0];
+        assert_eq!(action.until.as_deref(), Some("appStatus.stdout == 'active'"));
+        assert_eq!((action.retries, action.retry_delay, action.continue_on_error), (DEFAULT_UNTIL_RETRIES, 2, false));
+        assert!(!action.params.values.contains_key("until"));
+
+        let action = &control_actions.actions[1];
+        assert_eq!((action.retries, action.retry_delay, action.continue_on_error), (1, DEFAULT_RETRY_DELAY, true));
+    }
+
+    #[test]
+    fn test_action_selection() {
+        assert_eq!(ActionSelection::parse_ranges("3").unwrap(), vec![3..=3]);
+        assert_eq!(ActionSelection::parse_ranges("1, 4,6-8").unwrap(), vec![1..=1, 4..=4, 6..=8]);
+        assert_eq!(ActionSelection::parse_ranges("5-").unwrap(), vec![5..=usize::MAX]);
+        assert!(ActionSelection::parse_ranges("0").is_err());
+        assert!(ActionSelection::parse_ranges("7-3").is_err());
+        assert!(ActionSelection::parse_ranges("a-3").is_err());
+
+        let yaml = YamlLoader::load_from_str(r#"
+actions:
+  - genericCommand:
+      command: "apt-get update"
+  - genericCommand:
+      actionName: "restart app"
+      command: "systemctl restart app"
+  - removeFile:
+      path: "/tmp/old"
+  - removeFile:
+      actionName: ""
+      path: "/tmp/old"
+"#).unwrap();
+
+        let mut control_actions = ControlActions::new();
+        control_actions.ingest_control_actions_yaml_items(&yaml[0]["actions"], None).unwrap();
+        assert_eq!(control_actions.actions.len(), 3);
+        assert_eq!(control_actions.actions[1].name.as_deref(), Some("restart app"));
+        assert!(!control_actions.actions[1].params.values.contains_key("actionName"));
+
+        let actions = &control_actions.actions;
+        let selection = ActionSelection { start_at: Some("restart app".to_string()), only: None, ..Default::default() };
+        assert_eq!(selection.get_selected(actions).unwrap(), vec![false, true, true]);
+        let selection = ActionSelection { start_at: Some("2".to_string()), only: Some(vec![1..=1, 3..=3]), ..Default::default() };
+        assert_eq!(selection.get_selected(actions).unwrap(), vec![false, false, true]);
+        assert!(ActionSelection { start_at: Some("4".to_string()), only: None, ..Default::default() }.get_selected(actions).is_err());
+        assert!(ActionSelection { start_at: Some("deploy".to_string()), only: None, ..Default::default() }.get_selected(actions).is_err());
+    }
+
+    #[test]
+    fn test_action_become() {
+        let yaml = YamlLoader::load_from_str(r#"
+actions:
+  - genericCommand:
+      command: "whoami"
+  - genericCommand:
+      command: "whoami"
+      becomeUser: postgres
+  - genericCommand:
+      command: "whoami"
+      become: false
+  - genericCommand:
+      command: "whoami"
+      become: "yes"
+"#).unwrap();
+
+        let mut control_actions = ControlActions::new();
+        control_actions.ingest_control_actions_yaml_items(&yaml[0]["actions"], None).unwrap();
+        // the one with an invalid 'become' value is ignored
+        assert_eq!(control_actions.actions.len(), 3);
+        assert!(!control_actions.actions[1].params.values.contains_key("becomeUser"));
+
+        let become_users = |control_actions: &ControlActions| -> Vec<Option<String>> {
+            control_actions.actions.iter().map(|action| control_actions.get_become_user(Some(action))).collect()
+        };
+        assert_eq!(become_users(&control_actions), vec![None, Some("postgres".to_string()), None]);
+        assert!(control_actions.uses_become());
+
+        control_actions.become_enabled = true;
+        assert_eq!(control_actions.get_become_user(None).as_deref(), Some("root"));
+        assert_eq!(become_users(&control_actions), vec![Some("root".to_string()), Some("postgres".to_string()), None]);
+    }
+
+    #[test]
+    fn test_action_tags() {
+        assert_eq!(ActionSelection::parse_tags("packages, config").unwrap(), vec!["packages", "config"]);
+        assert!(ActionSelection::parse_tags("packages,").is_err());
+
+        let yaml = YamlLoader::load_from_str(r#"
+actions:
+  - installPackages:
+      packages: ["nginx"]
+      tags: [packages, web]
+  - genericCommand:
+      command: "systemctl restart nginx"
+      tags: services
+  - removeFile:
+      path: "/tmp/old"
+  - removeFile:
+      path: "/tmp/old"
+      tags: [1]
+"#).unwrap();
+
+        let mut control_actions = ControlActions::new();
+        control_actions.ingest_control_actions_yaml_items(&yaml[0]["actions"], None).unwrap();
+        let actions = &control_actions.actions;
+        assert_eq!(actions.len(), 3);
+        assert_eq!(actions[0].tags, vec!["packages", "web"]);
+        assert_eq!(actions[1].tags, vec!["services"]);
+
+        let selection = ActionSelection { tags: Some(vec!["web".to_string(), "services".to_string()]), ..Default::default() };
+        assert_eq!(selection.get_selected(actions).unwrap(), vec![true, true, false]);
+        let selection = ActionSelection { skip_tags: vec!["packages".to_string(), "typo".to_string()], ..Default::default() };
+        assert_eq!(selection.get_selected(actions).unwrap(), vec![false, true, true]);
+        assert_eq!(selection.get_unknown_tags(actions), vec!["typo"]);
+
+        let yaml = YamlLoader::load_from_str("{role: web, tags: [nginx, web]}").unwrap();
+        assert!(matches!(parse_include_item(&yaml[0]).unwrap(), (_, None, tags) if tags == vec!["nginx", "web"]));
+    }
+
+    #[test]
+    fn test_action_name_param() {
+        // the 'name' param of actions mustn't be taken as the name of the action
+        let yaml = YamlLoader::load_from_str(r#"
+actions:
+  - addGroup:
+      name: "deploy"
+  - addGroup:
+      actionName: "add admins"
+      name: "admins"
+"#).unwrap();
+
+        let mut control_actions = ControlActions::new();
+        control_actions.ingest_control_actions_yaml_items(&yaml[0]["actions"], None).unwrap();
+        assert_eq!(control_actions.actions.len(), 2);
+        assert_eq!(control_actions.actions[0].name, None);
+        assert_eq!(control_actions.actions[0].params.get_string_value("name").as_deref(), Some("deploy"));
+        assert_eq!(control_actions.actions[1].name.as_deref(), Some("add admins"));
+        assert_eq!(control_actions.actions[1].params.get_string_value("name").as_deref(), Some("admins"));
+    }
+
+    #[test]
+    fn test_include_items() {
+        let content = "actions:\n  - include: base.yaml\n  - genericCommand:\n      command: ls\n  -   include:\n        role: web\n";
+        assert_eq!(find_include_lines(content), vec![2, 5]);
+
+        let yaml = YamlLoader::load_from_str(content).unwrap();
+        let items = yaml[0]["actions"].as_vec().unwrap();
+        assert!(matches!(parse_include_item(&items[0]["include"]).unwrap(), (IncludeTarget::File(file), None, _) if file == "base.yaml"));
+        assert!(matches!(parse_include_item(&items[2]["include"]).unwrap(), (IncludeTarget::Role(name), None, _) if name == "web"));
+
+        let yaml = YamlLoader::load_from_str("{role: web, when: 'installWeb'}").unwrap();
+        assert!(matches!(parse_include_item(&yaml[0]).unwrap(), (_, Some(when), _) if when == "installWeb"));
+
+        assert!(parse_include_item(&YamlLoader::load_from_str("{role: '../web'}").unwrap()[0]).is_err());
+        assert!(parse_include_item(&YamlLoader::load_from_str("{role: web, file: base.yaml}").unwrap()[0]).is_err());
+        assert!(parse_include_item(&YamlLoader::load_from_str("{when: 'true'}").unwrap()[0]).is_err());
+
+        // includes aren't supported in handlers
+        let yaml = YamlLoader::load_from_str("restartApp:\n  include: base.yaml").unwrap();
+        assert!(ControlActions::new().ingest_control_handlers_yaml_items(&yaml[0]).is_err());
+    }
+
+    #[test]
+    fn test_handlers() {
+        let yaml = YamlLoader::load_from_str(r#"
+actions:
+  - createFile:
+      path: "/etc/app.conf"
+      notify: restartApp
+  - editFile:
+      filepath: "/etc/other.conf"
+      notify: [restartApp, reloadOther]
+handlers:
+  restartApp:
+    systemCtl:
+      service: app
+      action: restart
+  reloadOther:
+    - systemCtl:
+        service: other
+        action: reload
+"#).unwrap();
+
+        let mut control_actions = ControlActions::new();
+        control_actions.ingest_control_actions_yaml_items(&yaml[0]["actions"], None).unwrap();
+        assert!(control_actions.ingest_control_handlers_yaml_items(&yaml[0]["handlers"]).is_ok());
+
+        assert_eq!(control_actions.actions[0].notify, vec!["restartApp".to_string()]);
+        assert_eq!(control_actions.actions[1].notify, vec!["restartApp".to_string(), "reloadOther".to_string()]);
+        assert!(!control_actions.actions[0].params.values.contains_key("notify"));
+
+        assert_eq!(control_actions.handlers.len(), 2);
+        assert_eq!(control_actions.handlers[0].name, "restartApp");
+        assert_eq!(control_actions.handlers[1].actions[0].action, ControlActionType::SystemCtl);
+
+        // handlers can't have no actions
+        let yaml = YamlLoader::load_from_str("restartApp: []").unwrap();
+        assert!(ControlActions::new().ingest_control_handlers_yaml_items(&yaml[0]).is_err());
+    }
+}
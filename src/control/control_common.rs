@@ -14,19 +14,26 @@
 */
 #![allow(dead_code)]
 
-use super::control_connection::{ControlConnection, ControlConnectionDummyDebug};
+use super::control_connection::{ControlConnection, ControlConnectionBecome, ControlConnectionDummyDebug, ControlConnectionLogging,
+                                ControlConnectionRecording};
+
+use super::control_known_hosts::HostKeyPolicy;
+#[cfg(any(feature = "openssh", feature = "sshrs"))]
+use super::control_known_hosts::verify_host_key;
 
 #[cfg(feature = "openssh")]
-use super::control_connection_openssh::ControlConnectionOpenSSH;
+use super::control_connection_openssh::{open_tunnel, ControlConnectionOpenSSH};
 
 #[cfg(feature = "openssh")]
 use ssh2::Session;
-use std::fmt;
 #[cfg(feature = "openssh")]
+use std::os::unix::io::AsRawFd;
+use std::fmt;
+#[cfg(any(feature = "openssh", feature = "sshrs"))]
 use std::net::TcpStream;
 
 #[cfg(feature = "sshrs")]
-use super::control_connection_sshrs::ControlConnectionSshRs;
+use super::control_connection_sshrs::{ControlConnectionSshRs, HostKeyCheckingStream};
 #[cfg(feature = "sshrs")]
 use ssh;
 
@@ -43,6 +50,15 @@ pub enum UserType {
     Sudo
 }
 
+// privilege escalation settings, for running commands (and installing transferred files) as another user via sudo,
+// i.e. from 'become: true' in control scripts.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BecomeParams {
+    pub user:           String,
+    // the sudo password, if one was specified. It's only ever written to sudo's stdin, never put in commands.
+    pub password:       Option<String>,
+}
+
 #[derive(Clone, Debug)]
 pub struct UserAuthUserPass {
     pub username:           String,
@@ -71,10 +87,42 @@ impl UserAuthPublicKey {
     }
 }
 
+// authentication with the keys in the running ssh-agent (from $SSH_AUTH_SOCK), so passphrase-protected and
+// hardware-backed keys can be used without passphrases in scripts
+#[derive(Clone, Debug)]
+pub struct UserAuthAgent {
+    pub username:           String,
+}
+
+impl UserAuthAgent {
+    pub fn new(username: &str) -> UserAuthAgent {
+        UserAuthAgent { username: username.to_string() }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum ControlSessionUserAuth {
     UserPass(UserAuthUserPass),
-    PublicKey(UserAuthPublicKey)
+    PublicKey(UserAuthPublicKey),
+    Agent(UserAuthAgent),
+}
+
+impl ControlSessionUserAuth {
+    pub fn get_username(&self) -> &str {
+        match self {
+            ControlSessionUserAuth::UserPass(userpass) => &userpass.username,
+            ControlSessionUserAuth::PublicKey(publickey) => &publickey.username,
+            ControlSessionUserAuth::Agent(agent) => &agent.username,
+        }
+    }
+
+    pub fn set_username(&mut self, username: &str) {
+        match self {
+            ControlSessionUserAuth::UserPass(userpass) => userpass.username = username.to_string(),
+            ControlSessionUserAuth::PublicKey(publickey) => publickey.username = username.to_string(),
+            ControlSessionUserAuth::Agent(agent) => agent.username = username.to_string(),
+        }
+    }
 }
 
 pub struct ControlSessionParams {
@@ -84,7 +132,18 @@ pub struct ControlSessionParams {
     user_auth:                      ControlSessionUserAuth,
 
 pub user_type:                      UserType,
-pub hide_commands_from_history:     bool  
+pub hide_commands_from_history:     bool,
+pub host_key_policy:                HostKeyPolicy,
+// the hosts to connect to the target host via, in order
+pub jump_hosts:                     Vec<JumpHostParams>,
+}
+
+// a host which the target host (or the next jump host) is connected to via, i.e. a bastion host
+#[derive(Clone, Debug)]
+pub struct JumpHostParams {
+    pub hostname:   String,
+    pub port:       u32,
+    pub auth:       ControlSessionUserAuth,
 }
 
 impl ControlSessionParams {
@@ -98,7 +157,9 @@ impl ControlSessionParams {
              target_port,
              user_auth,
              user_type,
-             hide_commands_from_history }
+             hide_commands_from_history,
+             host_key_policy: HostKeyPolicy::AcceptNew,
+             jump_hosts: Vec::new() }
     }
 }
 
@@ -107,6 +168,8 @@ pub enum ControlSessionCreationError {
     ConfigFailure(String),
     ConnectionError(String),
     AuthenticationError(String),
+    // the host's key doesn't match the known one (or has been revoked), so it might not be the host we think it is
+    HostKeyMismatch(String),
     Other(String),
 }
 
@@ -122,6 +185,9 @@ impl fmt::Display for ControlSessionCreationError {
             Self::AuthenticationError(err) => {
                 write!(f, "Authentication Error: {}", err)
             },
+            Self::HostKeyMismatch(err) => {
+                write!(f, "Host key verification failed: {}", err)
+            },
             Self::Other(err) => {
                 write!(f, "Other error: {}", err)
             }
@@ -138,6 +204,8 @@ impl ControlSessionCreationError {
         match self {
             Self::ConfigFailure(_) => false,
             Self::AuthenticationError(_) => false,
+            // this is never going to fix itself, and retrying would just keep connecting to a possibly malicious host
+            Self::HostKeyMismatch(_) => false,
             Self::ConnectionError(_) => true,
             Self::Other(_) => true
         }
@@ -153,38 +221,32 @@ impl ControlSession {
 
     #[cfg(feature = "openssh")]
     pub fn new_openssh(control_session_params: ControlSessionParams) -> Result<ControlSession, ControlSessionCreationError> {
-        let ssh_host_target = format!("{}:{}", control_session_params.target_host,
-                                               control_session_params.target_port );
-        let tcp_connection = TcpStream::connect(&ssh_host_target);
-        if let Err(err) = tcp_connection {
-            return Err(ControlSessionCreationError::ConnectionError(format!("Error connecting to host: {}", err.to_string())));
-        }
-        let tcp_connection = tcp_connection.unwrap();
-        let mut sess = Session::new().unwrap();
-
-        sess.set_tcp_stream(tcp_connection);
-        sess.handshake().unwrap();
-        let auth_res;
-        if let ControlSessionUserAuth::UserPass(user_pass) = &control_session_params.user_auth {
-            auth_res = sess.userauth_password(&user_pass.username, &user_pass.password);
-            if let Err(err) = auth_res {
-                return Err(ControlSessionCreationError::AuthenticationError(
-                    format!("Authentication failure with user/pass: {}, err: {}...", &user_pass.username, err)));
+        let host_key_policy = control_session_params.host_key_policy;
+
+        // we connect directly to the first host, and then to each of the others (the last being the target host) via
+        // a tunnel through the previous one
+        let mut hosts = control_session_params.jump_hosts.iter().map(|jump_host| (jump_host.hostname.as_str(), jump_host.port, &jump_host.auth))
+                            .chain([(control_session_params.target_host.as_str(), control_session_params.target_port, &control_session_params.user_auth)]);
+        let (first_host, first_port, first_auth) = hosts.next().unwrap();
+
+        let ssh_host_target = format!("{}:{}", first_host, first_port);
+        let tcp_connection = TcpStream::connect(&ssh_host_target).map_err(|err| {
+            if control_session_params.jump_hosts.is_empty() {
+                ControlSessionCreationError::ConnectionError(format!("Error connecting to host: {}", err))
             }
-        }
-        else if let ControlSessionUserAuth::PublicKey(pub_key) = &control_session_params.user_auth {
-            let pub_key_path = Some(std::path::Path::new(&pub_key.publickey_path));
-            let priv_key_path = std::path::Path::new(&pub_key.privatekey_path);
-            auth_res = sess.userauth_pubkey_file(&pub_key.username, pub_key_path,
-                                                 priv_key_path, Some(&pub_key.passphrase));
-            if let Err(err) = auth_res {
-                return Err(ControlSessionCreationError::AuthenticationError(
-                    format!("Authentication failure with phrase/key for user: {}, err: {}...", &pub_key.username, err)));
+            else {
+                ControlSessionCreationError::ConnectionError(format!("Error connecting to jump host {}: {}", ssh_host_target, err))
             }
-        }
-        else {
-            // this shouldn't be reach-able, but...
-            return Err(ControlSessionCreationError::ConfigFailure("Unhandled auth type".to_string()));
+        })?;
+        let mut sess = start_openssh_session(tcp_connection, first_host, first_port, first_auth, host_key_policy)?;
+
+        let mut prev_host = first_host;
+        for (hostname, port, auth) in hosts {
+            let tunnel_stream = open_tunnel(sess, hostname, port)
+                .map_err(|err| ControlSessionCreationError::ConnectionError(format!("Error connecting to {}:{} via jump host {}: {}",
+                                                                                    hostname, port, prev_host, err)))?;
+            sess = start_openssh_session(tunnel_stream, hostname, port, auth, host_key_policy)?;
+            prev_host = hostname;
         }
 
         let ssh_connection = ControlConnectionOpenSSH::new(sess);
@@ -193,6 +255,11 @@ impl ControlSession {
 
     #[cfg(feature = "sshrs")]
     pub fn new_sshrs(control_session_params: ControlSessionParams) -> Result<ControlSession, ControlSessionCreationError> {
+        if !control_session_params.jump_hosts.is_empty() {
+            return Err(ControlSessionCreationError::ConfigFailure("Connecting via jump hosts isn't supported by the ssh-rs backend, \
+                                                                   Prod needs to be built with the 'openssh' feature for it.".to_string()));
+        }
+
         let sess_builder;
         
         if let ControlSessionUserAuth::UserPass(user_pass) = &control_session_params.user_auth {
@@ -204,15 +271,36 @@ impl ControlSession {
                 .password(&pub_key.passphrase)
                 .private_key_path(&pub_key.privatekey_path);
         }
+        else if let ControlSessionUserAuth::Agent(_) = &control_session_params.user_auth {
+            return Err(ControlSessionCreationError::ConfigFailure("ssh-agent authentication isn't supported by the ssh-rs backend, \
+                                                                   Prod needs to be built with the 'openssh' feature for it.".to_string()));
+        }
         else {
             // this shouldn't be reach-able, but...
             return Err(ControlSessionCreationError::ConfigFailure("Unhandled auth type".to_string()));
         }
 
         let ssh_host_target = format!("{}:{}", control_session_params.target_host, control_session_params.target_port);
-
-        let session = sess_builder.connect(ssh_host_target);
+        let tcp_connection = TcpStream::connect(&ssh_host_target)
+            .map_err(|err| ControlSessionCreationError::ConnectionError(format!("Error connecting to host: {}", err)))?;
+        // ssh-rs expects the stream to be non-blocking
+        tcp_connection.set_nonblocking(true)
+            .map_err(|err| ControlSessionCreationError::ConnectionError(format!("Error connecting to host: {}", err)))?;
+
+        // ssh-rs doesn't provide the host key, so it's checked by the stream as it's received, before any credentials are sent
+        let hostname = control_session_params.target_host.clone();
+        let port = control_session_params.target_port;
+        let host_key_policy = control_session_params.host_key_policy;
+        let stream = HostKeyCheckingStream::new(tcp_connection, Box::new(move |host_key: &[u8]| {
+            verify_host_key(&hostname, port, host_key, host_key_policy)
+        }));
+        let host_key_error = stream.get_error_slot();
+
+        let session = sess_builder.connect_bio(stream);
         if let Err(err) = &session {
+            if let Some(host_key_err) = host_key_error.lock().unwrap_or_else(|err| err.into_inner()).take() {
+                return Err(host_key_err);
+            }
             match err {
                 ssh::SshError::IoError(int_err) => {
                     return Err(ControlSessionCreationError::ConnectionError(format!("Error connecting to host: {}", int_err)));
@@ -240,5 +328,102 @@ impl ControlSession {
 
         Ok(ControlSession { conn: Box::new(dummy_connection), params: control_session_params })
     }
+
+    pub fn new_recording(control_session_params: ControlSessionParams) -> Result<ControlSession, ControlSessionCreationError> {
+        let recording_connection = ControlConnectionRecording::new();
+
+        Ok(ControlSession { conn: Box::new(recording_connection), params: control_session_params })
+    }
+
+    // wraps the connection so that every command run is logged, for retrieval with take_executed_commands()
+    pub fn enable_command_logging(&mut self) {
+        let inner = std::mem::replace(&mut self.conn, Box::new(ControlConnectionDummyDebug::new()));
+        self.conn = Box::new(ControlConnectionLogging::new(inner));
+    }
+
+    // wraps the connection so that commands can be run via sudo, once enabled with conn.set_become()
+    pub fn enable_become(&mut self) {
+        let inner = std::mem::replace(&mut self.conn, Box::new(ControlConnectionDummyDebug::new()));
+        self.conn = Box::new(ControlConnectionBecome::new(inner));
+    }
+}
+
+// does the SSH handshake over the stream, checks the host key and authenticates
+#[cfg(feature = "openssh")]
+fn start_openssh_session<S: 'static + AsRawFd>(stream: S, hostname: &str, port: u32, user_auth: &ControlSessionUserAuth,
+                                               host_key_policy: HostKeyPolicy) -> Result<Session, ControlSessionCreationError> {
+    let mut sess = Session::new()
+        .map_err(|err| ControlSessionCreationError::Other(format!("Error creating SSH session: {}", err)))?;
+
+    sess.set_tcp_stream(stream);
+    sess.handshake()
+        .map_err(|err| ControlSessionCreationError::ConnectionError(format!("SSH handshake with {}:{} failed: {}", hostname, port, err)))?;
+
+    // check the host key before sending any credentials to it (no key is rejected by verify_host_key())
+    let host_key = sess.host_key().map(|(key, _key_type)| key.to_vec()).unwrap_or_default();
+    verify_host_key(hostname, port, &host_key, host_key_policy)?;
+    let auth_res;
+    if let ControlSessionUserAuth::UserPass(user_pass) = user_auth {
+        auth_res = sess.userauth_password(&user_pass.username, &user_pass.password);
+        if let Err(err) = auth_res {
+            return Err(ControlSessionCreationError::AuthenticationError(
+                format!("Authentication failure with user/pass: {}, err: {}...", &user_pass.username, err)));
+        }
+    }
+    else if let ControlSessionUserAuth::PublicKey(pub_key) = user_auth {
+        // the public key can be worked out from the private key if it's not given
+        let pub_key_path = Some(std::path::Path::new(&pub_key.publickey_path)).filter(|path| !path.as_os_str().is_empty());
+        let priv_key_path = std::path::Path::new(&pub_key.privatekey_path);
+        auth_res = sess.userauth_pubkey_file(&pub_key.username, pub_key_path,
+                                             priv_key_path, Some(&pub_key.passphrase));
+        if let Err(err) = auth_res {
+            return Err(ControlSessionCreationError::AuthenticationError(
+                format!("Authentication failure with phrase/key for user: {}, err: {}...", &pub_key.username, err)));
+        }
+    }
+    else if let ControlSessionUserAuth::Agent(agent) = user_auth {
+        authenticate_with_agent(&sess, &agent.username)?;
+    }
+
+    Ok(sess)
+}
+
+// tries each of the identities in the ssh-agent in turn, until one of them is accepted
+#[cfg(feature = "openssh")]
+fn authenticate_with_agent(sess: &Session, username: &str) -> Result<(), ControlSessionCreationError> {
+    let mut agent = sess.agent()
+        .map_err(|err| ControlSessionCreationError::ConfigFailure(format!("Couldn't use ssh-agent: {}", err)))?;
+    agent.connect()
+        .map_err(|err| ControlSessionCreationError::ConfigFailure(format!("Couldn't connect to ssh-agent (is SSH_AUTH_SOCK set?): {}", err)))?;
+    let identities = agent.list_identities().and_then(|_| agent.identities())
+        .map_err(|err| ControlSessionCreationError::ConfigFailure(format!("Couldn't get the identities from ssh-agent: {}", err)))?;
+
+    let result = if identities.is_empty() {
+        Err(ControlSessionCreationError::AuthenticationError("ssh-agent doesn't have any identities, add them with 'ssh-add'.".to_string()))
+    }
+    else if identities.iter().any(|identity| agent.userauth(username, identity).is_ok()) {
+        Ok(())
+    }
+    else {
+        Err(ControlSessionCreationError::AuthenticationError(
+            format!("Authentication failure with ssh-agent for user: {}, none of its {} identities were accepted...", username, identities.len())))
+    };
+
+    let _ = agent.disconnect();
+    result
 }
 
+// whether the value matches the pattern, which can have '*' (any number of chars) and '?' (any single char) wildcards,
+// as used by OpenSSH's host patterns.
+pub fn wildcard_matches(pattern: &str, value: &str) -> bool {
+    match pattern.chars().next() {
+        None => value.is_empty(),
+        Some('*') => (0..=value.len()).filter(|index| value.is_char_boundary(*index))
+                                      .any(|index| wildcard_matches(&pattern[1..], &value[index..])),
+        Some('?') => {
+            let mut chars = value.chars();
+            chars.next().is_some() && wildcard_matches(&pattern[1..], chars.as_str())
+        },
+        Some(char) => value.starts_with(char) && wildcard_matches(&pattern[char.len_utf8()..], &value[char.len_utf8()..]),
+    }
+}
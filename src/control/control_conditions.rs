@@ -0,0 +1,471 @@
+/*
+ Prod
+ Copyright 2021-2025 Peter Pearson.
+ Licensed under the Apache License, Version 2.0 (the "License");
+ You may not use this file except in compliance with the License.
+ You may obtain a copy of the License at
+ http://www.apache.org/licenses/LICENSE-2.0
+ Unless required by applicable law or agreed to in writing, software
+ distributed under the License is distributed on an "AS IS" BASIS,
+ WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ See the License for the specific language governing permissions and
+ limitations under the License.
+ ---------
+*/
+
+// Conditions for the 'when' item of actions, so that actions can only be run on some hosts, i.e.:
+//   when: "facts.distro_id == 'Debian' and facts.release >= 12"
+//   when: "appUser is defined and not exists('/home/' + appUser)"
+// Supported are: comparisons (==, !=, <, <=, >, >=) between variables, facts, environment variables
+// and literal values, 'and', 'or', 'not', parenthesis, 'is defined' / 'is not defined' and 'exists(path)'
+// which checks whether a path exists on the host.
+
+use std::fmt;
+
+use crate::params::ParamValue;
+
+use super::control_system_validation::SystemValidationReleaseVersion;
+use super::control_templating::{TemplateContext, TemplateError};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConditionError {
+    ParseError(String),
+    TemplateError(TemplateError),
+    HostValueUnavailable,
+    InvalidComparison(String),
+}
+
+impl fmt::Display for ConditionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConditionError::ParseError(err) => write!(f, "invalid condition: {}", err),
+            ConditionError::TemplateError(err) => write!(f, "{}", err),
+            ConditionError::HostValueUnavailable => write!(f, "the value is only available when run on the host"),
+            ConditionError::InvalidComparison(err) => write!(f, "invalid comparison: {}", err),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompareOp {
+    Equal,
+    NotEqual,
+    LessThan,
+    LessThanOrEqual,
+    GreaterThan,
+    GreaterThanOrEqual,
+}
+
+#[derive(Clone, Debug)]
+pub enum Operand {
+    Literal(ParamValue),
+    Variable(String),
+}
+
+#[derive(Clone, Debug)]
+pub enum Condition {
+    Or(Box<Condition>, Box<Condition>),
+    And(Box<Condition>, Box<Condition>),
+    Not(Box<Condition>),
+    Compare(Operand, CompareOp, Operand),
+    IsDefined(String),
+    Exists(Vec<Operand>),
+    Value(Operand),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Int(i32),
+    Op(CompareOp),
+    Plus,
+    Comma,
+    LParen,
+    RParen,
+}
+
+impl Condition {
+    pub fn parse(condition: &str) -> Result<Condition, ConditionError> {
+        let tokens = tokenise(condition)?;
+        if tokens.is_empty() {
+            return Err(ConditionError::ParseError("the condition is empty.".to_string()));
+        }
+
+        let mut parser = Parser { tokens, pos: 0 };
+        let result = parser.parse_or()?;
+        if parser.pos < parser.tokens.len() {
+            return Err(ConditionError::ParseError(format!("unexpected item: {:?}", parser.tokens[parser.pos])));
+        }
+
+        Ok(result)
+    }
+
+    // whether the condition needs facts about the host to be evaluated
+    pub fn uses_facts(&self) -> bool {
+        match self {
+            Condition::Or(lhs, rhs) | Condition::And(lhs, rhs) => lhs.uses_facts() || rhs.uses_facts(),
+            Condition::Not(inner) => inner.uses_facts(),
+            Condition::Compare(lhs, _, rhs) => lhs.uses_facts() || rhs.uses_facts(),
+            Condition::IsDefined(name) => is_fact_name(name),
+            Condition::Exists(parts) => parts.iter().any(|part| part.uses_facts()),
+            Condition::Value(operand) => operand.uses_facts(),
+        }
+    }
+
+    // whether the condition needs to check things on the host (other than facts)
+    pub fn uses_host(&self) -> bool {
+        match self {
+            Condition::Or(lhs, rhs) | Condition::And(lhs, rhs) => lhs.uses_host() || rhs.uses_host(),
+            Condition::Not(inner) => inner.uses_host(),
+            Condition::Exists(_) => true,
+            _ => false,
+        }
+    }
+
+    // path_exists is called to check whether paths exist on the host
+    pub fn evaluate(&self, context: &TemplateContext, path_exists: &mut dyn FnMut(&str) -> bool) -> Result<bool, ConditionError> {
+        match self {
+            Condition::Or(lhs, rhs) => Ok(lhs.evaluate(context, path_exists)? || rhs.evaluate(context, path_exists)?),
+            Condition::And(lhs, rhs) => Ok(lhs.evaluate(context, path_exists)? && rhs.evaluate(context, path_exists)?),
+            Condition::Not(inner) => Ok(!inner.evaluate(context, path_exists)?),
+            Condition::Compare(lhs, op, rhs) => compare(&lhs.evaluate(context)?, *op, &rhs.evaluate(context)?),
+            Condition::IsDefined(name) => {
+                match context.evaluate_expression(name) {
+                    Ok(Some(_)) => Ok(true),
+                    Ok(None) => Err(ConditionError::HostValueUnavailable),
+                    Err(_) => Ok(false),
+                }
+            },
+            Condition::Exists(parts) => {
+                let mut path = String::new();
+                for part in parts {
+                    path.push_str(&value_as_string(&part.evaluate(context)?)?);
+                }
+                Ok(path_exists(&path))
+            },
+            Condition::Value(operand) => {
+                match operand.evaluate(context)? {
+                    ParamValue::Bool(bool_val) => Ok(bool_val),
+                    ParamValue::Int(int_val) => Ok(int_val != 0),
+                    ParamValue::Str(str_val) => Ok(!str_val.is_empty() && str_val != "false"),
+                    ParamValue::Array(array) => Ok(!array.is_empty()),
+                    ParamValue::Map(map) => Ok(!map.is_empty()),
+                    _ => Ok(false),
+                }
+            },
+        }
+    }
+}
+
+impl Operand {
+    fn uses_facts(&self) -> bool {
+        matches!(self, Operand::Variable(name) if is_fact_name(name))
+    }
+
+    fn evaluate(&self, context: &TemplateContext) -> Result<ParamValue, ConditionError> {
+        match self {
+            Operand::Literal(value) => Ok(value.clone()),
+            Operand::Variable(name) => {
+                match context.evaluate_expression(name) {
+                    Ok(Some(value)) => Ok(value),
+                    Ok(None) => Err(ConditionError::HostValueUnavailable),
+                    Err(err) => Err(ConditionError::TemplateError(err)),
+                }
+            }
+        }
+    }
+}
+
+fn is_fact_name(name: &str) -> bool {
+    name == "facts" || name.starts_with("facts.")
+}
+
+fn value_as_string(value: &ParamValue) -> Result<String, ConditionError> {
+    match value {
+        ParamValue::Str(str_val) => Ok(str_val.clone()),
+        ParamValue::Int(int_val) => Ok(int_val.to_string()),
+        ParamValue::Bool(bool_val) => Ok(if *bool_val {"true"} else {"false"}.to_string()),
+        _ => Err(ConditionError::InvalidComparison(format!("can't compare array or map value: {}", value))),
+    }
+}
+
+fn compare(lhs: &ParamValue, op: CompareOp, rhs: &ParamValue) -> Result<bool, ConditionError> {
+    // integers are compared as integers...
+    if let (ParamValue::Int(lhs_int), ParamValue::Int(rhs_int)) = (lhs, rhs) {
+        return Ok(match op {
+            CompareOp::Equal => lhs_int == rhs_int,
+            CompareOp::NotEqual => lhs_int != rhs_int,
+            CompareOp::LessThan => lhs_int < rhs_int,
+            CompareOp::LessThanOrEqual => lhs_int <= rhs_int,
+            CompareOp::GreaterThan => lhs_int > rhs_int,
+            CompareOp::GreaterThanOrEqual => lhs_int >= rhs_int,
+        });
+    }
+
+    let lhs_string = value_as_string(lhs)?;
+    let rhs_string = value_as_string(rhs)?;
+
+    // equality of anything else is done as strings, so that i.e. facts.release == 12 works
+    // (the release is a string)
+    let version_check = match op {
+        CompareOp::Equal => return Ok(lhs_string == rhs_string),
+        CompareOp::NotEqual => return Ok(lhs_string != rhs_string),
+        // otherwise, the values are assumed to be release versions
+        CompareOp::LessThan => SystemValidationReleaseVersion::LessThan(rhs_string),
+        CompareOp::LessThanOrEqual => SystemValidationReleaseVersion::LessThanOrEqual(rhs_string),
+        CompareOp::GreaterThan => SystemValidationReleaseVersion::GreaterThan(rhs_string),
+        CompareOp::GreaterThanOrEqual => SystemValidationReleaseVersion::GreaterThanOrEqual(rhs_string),
+    };
+
+    Ok(version_check.is_version_okay(&lhs_string))
+}
+
+fn tokenise(condition: &str) -> Result<Vec<Token>, ConditionError> {
+    let mut tokens = Vec::new();
+    let chars: Vec<char> = condition.chars().collect();
+    let mut pos = 0;
+
+    while pos < chars.len() {
+        let chr = chars[pos];
+        if chr.is_whitespace() {
+            pos += 1;
+            continue;
+        }
+
+        let two_chars: String = chars[pos..(pos + 2).min(chars.len())].iter().collect();
+        let op = match two_chars.as_str() {
+            "==" => Some(CompareOp::Equal),
+            "!=" => Some(CompareOp::NotEqual),
+            "<=" => Some(CompareOp::LessThanOrEqual),
+            ">=" => Some(CompareOp::GreaterThanOrEqual),
+            _ => None,
+        };
+        if let Some(op) = op {
+            tokens.push(Token::Op(op));
+            pos += 2;
+            continue;
+        }
+
+        match chr {
+            '<' => tokens.push(Token::Op(CompareOp::LessThan)),
+            '>' => tokens.push(Token::Op(CompareOp::GreaterThan)),
+            '(' => tokens.push(Token::LParen),
+            ')' => tokens.push(Token::RParen),
+            '+' => tokens.push(Token::Plus),
+            ',' => tokens.push(Token::Comma),
+            '\'' | '"' => {
+                let end = chars[pos + 1..].iter().position(|c| *c == chr)
+                                .ok_or_else(|| ConditionError::ParseError("unterminated string.".to_string()))?;
+                tokens.push(Token::Str(chars[pos + 1..pos + 1 + end].iter().collect()));
+                pos += end + 2;
+                continue;
+            },
+            _ if chr.is_ascii_digit() => {
+                // numbers with dots in, i.e. 20.04, are treated as (version) strings
+                let length = chars[pos..].iter().take_while(|c| c.is_ascii_digit() || **c == '.').count();
+                let number_string: String = chars[pos..pos + length].iter().collect();
+                match number_string.parse::<i32>() {
+                    Ok(int_val) => tokens.push(Token::Int(int_val)),
+                    Err(_) => tokens.push(Token::Str(number_string)),
+                }
+                pos += length;
+                continue;
+            },
+            _ if chr.is_ascii_alphabetic() || chr == '_' => {
+                let length = chars[pos..].iter().take_while(|c| c.is_ascii_alphanumeric() || **c == '_' || **c == '-' || **c == '.').count();
+                tokens.push(Token::Ident(chars[pos..pos + length].iter().collect()));
+                pos += length;
+                continue;
+            },
+            _ => return Err(ConditionError::ParseError(format!("unexpected character: '{}'", chr))),
+        }
+
+        pos += 1;
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens:     Vec<Token>,
+    pos:        usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Ident(ident)) if ident == keyword)
+    }
+
+    fn expect(&mut self, token: Token) -> Result<(), ConditionError> {
+        if self.peek() == Some(&token) {
+            self.pos += 1;
+            return Ok(());
+        }
+
+        Err(ConditionError::ParseError(format!("expected {:?}", token)))
+    }
+
+    fn parse_or(&mut self) -> Result<Condition, ConditionError> {
+        let mut result = self.parse_and()?;
+        while self.peek_keyword("or") {
+            self.pos += 1;
+            result = Condition::Or(Box::new(result), Box::new(self.parse_and()?));
+        }
+
+        Ok(result)
+    }
+
+    fn parse_and(&mut self) -> Result<Condition, ConditionError> {
+        let mut result = self.parse_not()?;
+        while self.peek_keyword("and") {
+            self.pos += 1;
+            result = Condition::And(Box::new(result), Box::new(self.parse_not()?));
+        }
+
+        Ok(result)
+    }
+
+    fn parse_not(&mut self) -> Result<Condition, ConditionError> {
+        if self.peek_keyword("not") {
+            self.pos += 1;
+            return Ok(Condition::Not(Box::new(self.parse_not()?)));
+        }
+
+        self.parse_comparison()
+    }
+
+    fn parse_comparison(&mut self) -> Result<Condition, ConditionError> {
+        if self.peek() == Some(&Token::LParen) {
+            self.pos += 1;
+            let result = self.parse_or()?;
+            self.expect(Token::RParen)?;
+            return Ok(result);
+        }
+
+        if self.peek_keyword("exists") {
+            self.pos += 1;
+            self.expect(Token::LParen)?;
+            let mut parts = vec![self.parse_operand()?];
+            while self.peek() == Some(&Token::Plus) {
+                self.pos += 1;
+                parts.push(self.parse_operand()?);
+            }
+            self.expect(Token::RParen)?;
+            return Ok(Condition::Exists(parts));
+        }
+
+        let lhs = self.parse_operand()?;
+
+        if self.peek_keyword("is") {
+            self.pos += 1;
+            let negated = self.peek_keyword("not");
+            if negated {
+                self.pos += 1;
+            }
+            if !self.peek_keyword("defined") {
+                return Err(ConditionError::ParseError("expected 'defined' after 'is'.".to_string()));
+            }
+            self.pos += 1;
+
+            let name = match lhs {
+                Operand::Variable(name) => name,
+                _ => return Err(ConditionError::ParseError("'is defined' can only be used with variables.".to_string())),
+            };
+            let result = Condition::IsDefined(name);
+            return Ok(if negated { Condition::Not(Box::new(result)) } else { result });
+        }
+
+        if let Some(Token::Op(op)) = self.peek() {
+            let op = *op;
+            self.pos += 1;
+            let rhs = self.parse_operand()?;
+            return Ok(Condition::Compare(lhs, op, rhs));
+        }
+
+        Ok(Condition::Value(lhs))
+    }
+
+    fn parse_operand(&mut self) -> Result<Operand, ConditionError> {
+        let operand = match self.peek() {
+            Some(Token::Str(str_val)) => Operand::Literal(ParamValue::Str(str_val.clone())),
+            Some(Token::Int(int_val)) => Operand::Literal(ParamValue::Int(*int_val)),
+            Some(Token::Ident(ident)) if ident == "true" => Operand::Literal(ParamValue::Bool(true)),
+            Some(Token::Ident(ident)) if ident == "false" => Operand::Literal(ParamValue::Bool(false)),
+            Some(Token::Ident(ident)) if !matches!(ident.as_str(), "and" | "or" | "not" | "is" | "defined" | "exists") => {
+                Operand::Variable(ident.clone())
+            },
+            Some(token) => return Err(ConditionError::ParseError(format!("unexpected item: {:?}", token))),
+            None => return Err(ConditionError::ParseError("unexpected end of condition.".to_string())),
+        };
+        self.pos += 1;
+
+        Ok(operand)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::control_actions::SystemDetailsResult;
+    use super::super::control_templating::{facts_from_system_details, TemplateVars};
+
+    fn make_context() -> TemplateContext {
+        let mut vars = TemplateVars::new();
+        vars.insert("appUser".to_string(), ParamValue::Str("deploy".to_string()));
+        vars.insert("appPort".to_string(), ParamValue::Int(8080));
+        vars.insert("enabled".to_string(), ParamValue::Bool(false));
+
+        let mut context = TemplateContext::new(vars);
+
+        let mut system_details = SystemDetailsResult::new();
+        system_details.distr_id = "Debian".to_string();
+        system_details.release = "12".to_string();
+        context.set_facts(facts_from_system_details(&system_details));
+
+        context
+    }
+
+    fn check(condition: &str) -> bool {
+        Condition::parse(condition).unwrap().evaluate(&make_context(), &mut |path| path == "/home/deploy").unwrap()
+    }
+
+    #[test]
+    fn test_conditions() {
+        assert!(check("facts.distro_id == 'Debian'"));
+        assert!(check("facts.distro_id == 'Debian' and facts.release >= 12"));
+        assert!(!check("facts.release < 12"));
+        assert!(check("facts.release < 12 or appPort == 8080"));
+        assert!(check("not (facts.release > 12 or enabled)"));
+        assert!(check("facts.release == 12"));
+        assert!(check("appPort >= 1024 and appPort != 8000"));
+
+        assert!(check("appUser is defined"));
+        assert!(check("otherUser is not defined"));
+        assert!(!check("enabled"));
+
+        assert!(check("exists('/home/' + appUser)"));
+        assert!(!check("exists('/home/other')"));
+    }
+
+    #[test]
+    fn test_condition_errors() {
+        assert!(Condition::parse("").is_err());
+        assert!(Condition::parse("appPort ==").is_err());
+        assert!(Condition::parse("(appPort == 1").is_err());
+        assert!(Condition::parse("appPort == 'abc").is_err());
+        assert!(Condition::parse("appPort is set").is_err());
+
+        let condition = Condition::parse("otherUser == 'x'").unwrap();
+        assert!(matches!(condition.evaluate(&make_context(), &mut |_| true), Err(ConditionError::TemplateError(_))));
+
+        let condition = Condition::parse("facts.release >= 12").unwrap();
+        assert!(condition.uses_facts());
+        assert_eq!(condition.evaluate(&TemplateContext::new(TemplateVars::new()), &mut |_| true), Err(ConditionError::HostValueUnavailable));
+    }
+}
@@ -14,9 +14,14 @@
 */
 #![allow(dead_code)]
 
+use std::collections::BTreeMap;
 use std::fmt;
+use std::time::Duration;
 
+use super::control_output::host_eprintln;
+use super::common_actions_unix::quote_shell_arg;
 use super::control_actions::ControlAction;
+use super::control_common::BecomeParams;
 
 #[derive(Clone, Debug)]
 pub enum RemoteFileContentsControlError {
@@ -46,9 +51,122 @@ impl fmt::Display for RemoteFileContentsControlError {
     }
 }
 
+// an individual remote operation recorded by ControlConnectionRecording, in the order it was requested
+#[derive(Clone, Debug, PartialEq)]
+pub enum RecordedOperation {
+    Command(String),
+    ReadRemoteFile(String),
+    WriteRemoteFile(String, i32, usize), // remote path, mode, content length
+    SendFile(String, String, i32),       // local path, remote path, mode
+    ReceiveFile(String, String),         // remote path, local path
+}
+
+impl fmt::Display for RecordedOperation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordedOperation::Command(command) => write!(f, "command:       {}", command.trim_start()),
+            RecordedOperation::ReadRemoteFile(path) => write!(f, "read file:     {}", path),
+            RecordedOperation::WriteRemoteFile(path, mode, length) => {
+                write!(f, "write file:    {} (mode: {:o}, {} bytes)", path, mode, length)
+            },
+            RecordedOperation::SendFile(local_path, remote_path, mode) => {
+                write!(f, "upload file:   {} -> {} (mode: {:o})", local_path, remote_path, mode)
+            },
+            RecordedOperation::ReceiveFile(remote_path, local_path) => {
+                write!(f, "download file: {} -> {}", remote_path, local_path)
+            },
+        }
+    }
+}
+
+// a command which was run on the host, recorded by ControlConnectionLogging
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExecutedCommand {
+    pub command:    String,
+    pub exit_code:  Option<i32>,
+    pub stderr:     Option<String>,
+}
+
+// the outcome of a command which was run on the host, whatever its exit code
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CommandOutcome {
+    pub stdout:     String,
+    // None if there wasn't any, or the connection backend can't separate it from stdout (i.e. ssh-rs)
+    pub stderr:     Option<String>,
+    pub exit_code:  Option<i32>,
+    pub duration:   Duration,
+}
+
+impl CommandOutcome {
+    pub fn exited_with_error_code(&self) -> bool {
+        matches!(self.exit_code, Some(exit_code) if exit_code != 0)
+    }
+}
+
+// a failure to run a command on the host at all (i.e. because the connection was dropped), as opposed to
+// the command itself failing
+#[derive(Clone, Debug, PartialEq)]
+pub enum TransportError {
+    ChannelOpen(String),
+    Exec(String),
+    Io(String),
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportError::ChannelOpen(err) => write!(f, "couldn't open channel to host: {}", err),
+            TransportError::Exec(err) => write!(f, "couldn't run command on host: {}", err),
+            TransportError::Io(err) => write!(f, "error communicating with host: {}", err),
+        }
+    }
+}
+
 pub trait ControlConnection {
-    fn send_command(&mut self, _command: &str) {
+    // runs the command, returning its output and exit code, or an error if it couldn't be run at all. The response
+    // is also available from the get_previous_*() methods below until the next command is run.
+    fn send_command(&mut self, command: &str) -> Result<CommandOutcome, TransportError>;
+
+    // runs the command with the input written to its stdin (i.e. a sudo password), so that the input isn't part of the
+    // command itself (where other users could see it in the process list). Connections which can't write to stdin
+    // upload the input to a private temporary file, and redirect stdin from that instead.
+    fn send_command_with_input(&mut self, command: &str, input: &str) -> Result<CommandOutcome, TransportError> {
+        let mktemp_outcome = self.send_command("mktemp")?;
+        let input_path = mktemp_outcome.stdout.trim().to_string();
+        if mktemp_outcome.exited_with_error_code() || input_path.is_empty() {
+            return Ok(mktemp_outcome);
+        }
+        // mktemp creates it only readable by the user
+        let upload_result = self.send_text_file_contents(&input_path, 0o600, &format!("{}\n", input));
+        let input_path = quote_shell_arg(&input_path);
+        if let Err(err) = upload_result {
+            self.send_command(&format!("rm -f {}", input_path))?;
+            return Err(TransportError::Io(format!("couldn't upload the command's input: {}", err.to_string().trim_end())));
+        }
+        self.send_command(&format!("({}) < {}; status=$?; rm -f {}; exit $status", command, input_path, input_path))
+    }
+
+    // sets the user (and sudo password) to run commands as, or None to run them as the login user. Only connections
+    // wrapped with ControlSession::enable_become() do anything with this.
+    fn set_become(&mut self, _become_params: Option<BecomeParams>) {
+
+    }
+
+    // whether this connection only records operations without actually connecting to a host
+    fn is_dry_run(&self) -> bool {
+        false
+    }
 
+    // returns (and clears) any operations recorded since the last call. Only connections which
+    // record operations (i.e. for dry-runs) return anything.
+    fn take_recorded_operations(&mut self) -> Vec<RecordedOperation> {
+        Vec::new()
+    }
+
+    // returns (and clears) any commands run since the last call. Only connections which log commands
+    // return anything.
+    fn take_executed_commands(&mut self) -> Vec<ExecutedCommand> {
+        Vec::new()
     }
 
     // whether there was a response output to stdout...
@@ -117,8 +235,14 @@ impl ControlConnectionDummyDebug {
 
 impl ControlConnection for ControlConnectionDummyDebug {
 
-    fn send_command(&mut self, command: &str) {
-        eprintln!("Running command: '{}'", command);
+    fn send_command(&mut self, command: &str) -> Result<CommandOutcome, TransportError> {
+        host_eprintln!("Running command: '{}'", command);
+        Ok(CommandOutcome::default())
+    }
+
+    fn send_command_with_input(&mut self, command: &str, _input: &str) -> Result<CommandOutcome, TransportError> {
+        host_eprintln!("Running command: '{}'", command);
+        Ok(CommandOutcome::default())
     }
 
     fn had_command_response(&self) -> bool {
@@ -137,3 +261,554 @@ impl ControlConnection for ControlConnectionDummyDebug {
         Err(RemoteFileContentsControlError::NotImplemented)
     }
 }
+
+// Connection which doesn't connect to anything, but records every operation requested of it
+// in order, so that dry-runs can show exactly what actions would do to a host.
+// Note: as there's no host, command responses are always empty and successful, and remote files
+//       always have empty contents, so actions which depend on remote output may not be able to
+//       fully complete.
+pub struct ControlConnectionRecording {
+    operations:     Vec<RecordedOperation>,
+}
+
+impl ControlConnectionRecording {
+    pub fn new() -> ControlConnectionRecording {
+        ControlConnectionRecording { operations: Vec::new() }
+    }
+}
+
+impl ControlConnection for ControlConnectionRecording {
+
+    fn send_command(&mut self, command: &str) -> Result<CommandOutcome, TransportError> {
+        self.operations.push(RecordedOperation::Command(command.to_string()));
+        Ok(CommandOutcome::default())
+    }
+
+    // the input isn't recorded, as it's likely to be a password
+    fn send_command_with_input(&mut self, command: &str, _input: &str) -> Result<CommandOutcome, TransportError> {
+        self.operations.push(RecordedOperation::Command(command.to_string()));
+        Ok(CommandOutcome::default())
+    }
+
+    fn is_dry_run(&self) -> bool {
+        true
+    }
+
+    fn take_recorded_operations(&mut self) -> Vec<RecordedOperation> {
+        std::mem::take(&mut self.operations)
+    }
+
+    fn get_text_file_contents(&mut self, filepath: &str) -> Result<String, RemoteFileContentsControlError> {
+        self.operations.push(RecordedOperation::ReadRemoteFile(filepath.to_string()));
+        Ok(String::new())
+    }
+
+    fn send_text_file_contents(&mut self, filepath: &str, mode: i32, contents: &str) -> Result<(), RemoteFileContentsControlError> {
+        self.operations.push(RecordedOperation::WriteRemoteFile(filepath.to_string(), mode, contents.len()));
+        Ok(())
+    }
+
+    fn send_file(&mut self, local_filepath: &str, dest_filepath: &str, mode: i32) -> Result<(), ()> {
+        self.operations.push(RecordedOperation::SendFile(local_filepath.to_string(), dest_filepath.to_string(), mode));
+        // this is the one thing we can validate locally...
+        if !std::path::Path::new(local_filepath).exists() {
+            return Err(());
+        }
+        Ok(())
+    }
+
+    fn receive_file(&mut self, remote_filepath: &str, local_filepath: &str) -> Result<(), ()> {
+        self.operations.push(RecordedOperation::ReceiveFile(remote_filepath.to_string(), local_filepath.to_string()));
+        Ok(())
+    }
+}
+
+// Connection for tests which records operations like ControlConnectionRecording, but isn't a dry-run, and responds
+// to commands and file reads with canned responses, so that actions' checks of the current state of the host
+// can be tested. Commands without a response succeed with no output.
+#[cfg(test)]
+pub struct ControlConnectionScripted {
+    recording:  ControlConnectionRecording,
+    // the (first) response whose string the command contains is used
+    responses:  Vec<(String, CommandOutcome)>,
+    // commands which contain any of these can't be run, as if the connection had dropped
+    failures:   Vec<String>,
+    files:      BTreeMap<String, String>,
+    previous:   CommandOutcome,
+}
+
+#[cfg(test)]
+impl ControlConnectionScripted {
+    pub fn new() -> ControlConnectionScripted {
+        ControlConnectionScripted { recording: ControlConnectionRecording::new(), responses: Vec::new(), failures: Vec::new(), files: BTreeMap::new(),
+                                    previous: CommandOutcome::default() }
+    }
+
+    pub fn respond(mut self, command_contains: &str, stdout: &str, exit_code: i32) -> ControlConnectionScripted {
+        self.responses.push((command_contains.to_string(), CommandOutcome { stdout: stdout.to_string(), exit_code: Some(exit_code),
+                                                                            ..Default::default() }));
+        self
+    }
+
+    pub fn fail(mut self, command_contains: &str) -> ControlConnectionScripted {
+        self.failures.push(command_contains.to_string());
+        self
+    }
+
+    pub fn with_file(mut self, filepath: &str, contents: &str) -> ControlConnectionScripted {
+        self.files.insert(filepath.to_string(), contents.to_string());
+        self
+    }
+}
+
+#[cfg(test)]
+impl ControlConnection for ControlConnectionScripted {
+
+    fn send_command(&mut self, command: &str) -> Result<CommandOutcome, TransportError> {
+        self.recording.send_command(command)?;
+        if self.failures.iter().any(|command_contains| command.contains(command_contains.as_str())) {
+            return Err(TransportError::Io("connection lost".to_string()));
+        }
+        self.previous = self.responses.iter().find(|(command_contains, _)| command.contains(command_contains.as_str()))
+                                      .map(|(_, outcome)| outcome.clone())
+                                      .unwrap_or(CommandOutcome { exit_code: Some(0), ..Default::default() });
+        Ok(self.previous.clone())
+    }
+
+    fn take_recorded_operations(&mut self) -> Vec<RecordedOperation> {
+        self.recording.take_recorded_operations()
+    }
+
+    fn had_command_response(&self) -> bool {
+        !self.previous.stdout.is_empty()
+    }
+
+    fn get_previous_stdout_response(&self) -> &str {
+        &self.previous.stdout
+    }
+
+    fn get_exit_code(&self) -> Option<i32> {
+        self.previous.exit_code
+    }
+
+    fn did_exit_with_error_code(&self) -> bool {
+        self.previous.exited_with_error_code()
+    }
+
+    fn get_text_file_contents(&mut self, filepath: &str) -> Result<String, RemoteFileContentsControlError> {
+        self.recording.get_text_file_contents(filepath)?;
+        self.files.get(filepath).cloned().ok_or_else(|| RemoteFileContentsControlError::RemoteFileDoesntExist(filepath.to_string()))
+    }
+
+    // the file can be read back afterwards
+    fn send_text_file_contents(&mut self, filepath: &str, mode: i32, contents: &str) -> Result<(), RemoteFileContentsControlError> {
+        self.recording.send_text_file_contents(filepath, mode, contents)?;
+        self.files.insert(filepath.to_string(), contents.to_string());
+        Ok(())
+    }
+}
+
+// Connection which wraps another connection, passing everything through to it, but logging each command
+// which is run (along with its exit code and any stderr output), i.e. for the JSON run report.
+pub struct ControlConnectionLogging {
+    inner:      Box<dyn ControlConnection>,
+    commands:   Vec<ExecutedCommand>,
+}
+
+impl ControlConnectionLogging {
+    pub fn new(inner: Box<dyn ControlConnection>) -> ControlConnectionLogging {
+        ControlConnectionLogging { inner, commands: Vec::new() }
+    }
+
+    // commands which couldn't be run are logged with the error as the stderr output
+    fn log_command(&mut self, command: &str, result: &Result<CommandOutcome, TransportError>) {
+        let (exit_code, stderr) = match result {
+            Ok(outcome) => (outcome.exit_code, outcome.stderr.clone()),
+            Err(err) => (None, Some(err.to_string())),
+        };
+        self.commands.push(ExecutedCommand { command: command.to_string(), exit_code, stderr });
+    }
+}
+
+impl ControlConnection for ControlConnectionLogging {
+
+    fn send_command(&mut self, command: &str) -> Result<CommandOutcome, TransportError> {
+        let result = self.inner.send_command(command);
+        self.log_command(command, &result);
+        result
+    }
+
+    // the input isn't logged, as it's likely to be a password
+    fn send_command_with_input(&mut self, command: &str, input: &str) -> Result<CommandOutcome, TransportError> {
+        let result = self.inner.send_command_with_input(command, input);
+        self.log_command(command, &result);
+        result
+    }
+
+    fn set_become(&mut self, become_params: Option<BecomeParams>) {
+        self.inner.set_become(become_params);
+    }
+
+    fn is_dry_run(&self) -> bool {
+        self.inner.is_dry_run()
+    }
+
+    fn take_recorded_operations(&mut self) -> Vec<RecordedOperation> {
+        self.inner.take_recorded_operations()
+    }
+
+    fn take_executed_commands(&mut self) -> Vec<ExecutedCommand> {
+        std::mem::take(&mut self.commands)
+    }
+
+    fn had_command_response(&self) -> bool {
+        self.inner.had_command_response()
+    }
+
+    fn get_previous_stdout_response(&self) -> &str {
+        self.inner.get_previous_stdout_response()
+    }
+
+    fn get_previous_stderr_response(&self) -> Option<&str> {
+        self.inner.get_previous_stderr_response()
+    }
+
+    fn get_exit_code(&self) -> Option<i32> {
+        self.inner.get_exit_code()
+    }
+
+    fn did_exit_with_error_code(&self) -> bool {
+        self.inner.did_exit_with_error_code()
+    }
+
+    fn get_text_file_contents(&mut self, filepath: &str) -> Result<String, RemoteFileContentsControlError> {
+        self.inner.get_text_file_contents(filepath)
+    }
+
+    fn send_text_file_contents(&mut self, filepath: &str, mode: i32, contents: &str) -> Result<(), RemoteFileContentsControlError> {
+        self.inner.send_text_file_contents(filepath, mode, contents)
+    }
+
+    fn send_file(&mut self, local_filepath: &str, dest_filepath: &str, mode: i32) -> Result<(), ()> {
+        self.inner.send_file(local_filepath, dest_filepath, mode)
+    }
+
+    fn receive_file(&mut self, remote_filepath: &str, local_filepath: &str) -> Result<(), ()> {
+        self.inner.receive_file(remote_filepath, local_filepath)
+    }
+}
+
+// temporary directory template for transferring files via when running as another user
+const BECOME_TRANSFER_DIR_TEMPLATE: &str = "/tmp/prod_become.XXXXXXXX";
+
+// Connection which wraps another connection, and when set to (i.e. for actions with 'become'), runs the commands via
+// sudo as another user. The login user generally can't write to (or maybe read) the paths files are transferred to
+// and from, so files are transferred via a temporary directory as the login user, and installed into place (or
+// read) via sudo, with the requested mode.
+pub struct ControlConnectionBecome {
+    inner:              Box<dyn ControlConnection>,
+    become_params:      Option<BecomeParams>,
+    // whether sudo needs the password for each of the users which have been run as so far
+    password_needed:    BTreeMap<String, bool>,
+}
+
+impl ControlConnectionBecome {
+    pub fn new(inner: Box<dyn ControlConnection>) -> ControlConnectionBecome {
+        ControlConnectionBecome { inner, become_params: None, password_needed: BTreeMap::new() }
+    }
+
+    // runs the command as the become user if set, otherwise as the login user
+    fn run_command(&mut self, command: &str) -> Result<CommandOutcome, TransportError> {
+        self.run_command_with_output_file(command, None)
+    }
+
+    // as above, but with stdout optionally redirected to a file by the login user's shell
+    fn run_command_with_output_file(&mut self, command: &str, output_filepath: Option<&str>) -> Result<CommandOutcome, TransportError> {
+        let redirect = output_filepath.map(|path| format!(" > {}", quote_shell_arg(path))).unwrap_or_default();
+        let become_params = match self.become_params.clone() {
+            Some(become_params) => become_params,
+            None => return self.inner.send_command(&format!("{}{}", command, redirect)),
+        };
+
+        let user = quote_shell_arg(&become_params.user);
+        let command = quote_shell_arg(command.trim_start());
+        match become_params.password {
+            Some(password) if self.is_password_needed(&become_params.user)? => {
+                // '-k' so that sudo always reads the password from stdin, even if it's cached, as otherwise it'd be
+                // left there for the command to read
+                let sudo_command = format!("sudo -k -S -p '' -u {} -- sh -c {}{}", user, command, redirect);
+                self.inner.send_command_with_input(&sudo_command, &password)
+            },
+            // '-n' so that sudo fails rather than waiting for a password
+            _ => self.inner.send_command(&format!("sudo -n -u {} -- sh -c {}{}", user, command, redirect)),
+        }
+    }
+
+    // sudo might be configured to not need a password (i.e. 'NOPASSWD'), in which case it can't be written to its stdin,
+    // so check that first.
+    fn is_password_needed(&mut self, user: &str) -> Result<bool, TransportError> {
+        if self.inner.is_dry_run() {
+            return Ok(true);
+        }
+        if let Some(password_needed) = self.password_needed.get(user) {
+            return Ok(*password_needed);
+        }
+
+        let outcome = self.inner.send_command(&format!("sudo -n -u {} -- true", quote_shell_arg(user)))?;
+        let password_needed = outcome.exit_code != Some(0);
+        self.password_needed.insert(user.to_string(), password_needed);
+        Ok(password_needed)
+    }
+
+    // creates a temporary directory (as the login user) to transfer files via, which the become user can get to
+    // the files within if 'accessible' is set, but not list.
+    fn create_transfer_dir(&mut self, accessible: bool) -> Result<String, String> {
+        let outcome = self.inner.send_command(&format!("mktemp -d {}", BECOME_TRANSFER_DIR_TEMPLATE))
+                            .map_err(|err| format!("couldn't create temporary directory: {}", err))?;
+        let mut transfer_dir = outcome.stdout.trim().to_string();
+        if self.inner.is_dry_run() && transfer_dir.is_empty() {
+            transfer_dir = BECOME_TRANSFER_DIR_TEMPLATE.to_string();
+        }
+        if outcome.exited_with_error_code() || transfer_dir.is_empty() {
+            return Err(format!("couldn't create temporary directory: {}", outcome.stderr.unwrap_or_default()));
+        }
+
+        if accessible {
+            self.inner.send_command(&format!("chmod 711 {}", quote_shell_arg(&transfer_dir)))
+                .map_err(|err| format!("couldn't set permissions of temporary directory: {}", err))?;
+        }
+
+        Ok(transfer_dir)
+    }
+
+    // this is only a clean-up, so a failure to remove it doesn't fail the transfer
+    fn remove_transfer_dir(&mut self, transfer_dir: &str) {
+        if let Err(err) = self.inner.send_command(&format!("rm -rf {}", quote_shell_arg(transfer_dir))) {
+            host_eprintln!("Warning: couldn't remove temporary directory: {}: {}", transfer_dir, err);
+        }
+    }
+
+    // uploads the file (or contents) to a temporary directory, and installs it to the destination path as the become user.
+    fn install_file<F>(&mut self, become_params: &BecomeParams, dest_filepath: &str, mode: i32, upload: F) -> Result<(), String>
+            where F: FnOnce(&mut dyn ControlConnection, &str, i32) -> Result<(), String> {
+        // root can read the file regardless, but other users need to be able to read it
+        let is_root = become_params.user == "root";
+        let transfer_dir = self.create_transfer_dir(!is_root)?;
+        let transfer_path = format!("{}/file", transfer_dir);
+
+        let mut result = upload(self.inner.as_mut(), &transfer_path, if is_root {0o600} else {0o644});
+        if result.is_ok() {
+            let install_command = format!("install -m {:o} {} {}", mode, quote_shell_arg(&transfer_path), quote_shell_arg(dest_filepath));
+            result = match self.run_command(&install_command) {
+                Ok(outcome) if !outcome.exited_with_error_code() => Ok(()),
+                Ok(outcome) => Err(format!("couldn't install file to: {} as user: {}: {}", dest_filepath, become_params.user,
+                                           outcome.stderr.unwrap_or_default())),
+                Err(err) => Err(format!("couldn't install file to: {} as user: {}: {}", dest_filepath, become_params.user, err)),
+            };
+        }
+
+        self.remove_transfer_dir(&transfer_dir);
+        result
+    }
+}
+
+impl ControlConnection for ControlConnectionBecome {
+
+    fn send_command(&mut self, command: &str) -> Result<CommandOutcome, TransportError> {
+        self.run_command(command)
+    }
+
+    // this is only used for running commands with sudo, so it's never run as the become user
+    fn send_command_with_input(&mut self, command: &str, input: &str) -> Result<CommandOutcome, TransportError> {
+        self.inner.send_command_with_input(command, input)
+    }
+
+    fn set_become(&mut self, become_params: Option<BecomeParams>) {
+        self.become_params = become_params;
+    }
+
+    fn is_dry_run(&self) -> bool {
+        self.inner.is_dry_run()
+    }
+
+    fn take_recorded_operations(&mut self) -> Vec<RecordedOperation> {
+        self.inner.take_recorded_operations()
+    }
+
+    fn take_executed_commands(&mut self) -> Vec<ExecutedCommand> {
+        self.inner.take_executed_commands()
+    }
+
+    fn had_command_response(&self) -> bool {
+        self.inner.had_command_response()
+    }
+
+    fn get_previous_stdout_response(&self) -> &str {
+        self.inner.get_previous_stdout_response()
+    }
+
+    fn get_previous_stderr_response(&self) -> Option<&str> {
+        self.inner.get_previous_stderr_response()
+    }
+
+    fn get_exit_code(&self) -> Option<i32> {
+        self.inner.get_exit_code()
+    }
+
+    fn did_exit_with_error_code(&self) -> bool {
+        self.inner.did_exit_with_error_code()
+    }
+
+    fn get_text_file_contents(&mut self, filepath: &str) -> Result<String, RemoteFileContentsControlError> {
+        if self.become_params.is_none() {
+            return self.inner.get_text_file_contents(filepath);
+        }
+
+        let outcome = self.run_command(&format!("cat -- {}", quote_shell_arg(filepath)))
+                          .map_err(|err| RemoteFileContentsControlError::TransferError(format!("couldn't read file: {}: {}", filepath, err)))?;
+        if outcome.exited_with_error_code() {
+            return Err(RemoteFileContentsControlError::TransferError(format!("couldn't read file: {}: {}", filepath,
+                                                                     outcome.stderr.unwrap_or_default())));
+        }
+        Ok(outcome.stdout)
+    }
+
+    fn send_text_file_contents(&mut self, filepath: &str, mode: i32, contents: &str) -> Result<(), RemoteFileContentsControlError> {
+        let become_params = match self.become_params.clone() {
+            Some(become_params) => become_params,
+            None => return self.inner.send_text_file_contents(filepath, mode, contents),
+        };
+
+        self.install_file(&become_params, filepath, mode, |inner, transfer_path, transfer_mode| {
+            inner.send_text_file_contents(transfer_path, transfer_mode, contents).map_err(|err| err.to_string())
+        }).map_err(RemoteFileContentsControlError::TransferError)
+    }
+
+    fn send_file(&mut self, local_filepath: &str, dest_filepath: &str, mode: i32) -> Result<(), ()> {
+        let become_params = match self.become_params.clone() {
+            Some(become_params) => become_params,
+            None => return self.inner.send_file(local_filepath, dest_filepath, mode),
+        };
+
+        self.install_file(&become_params, dest_filepath, mode, |inner, transfer_path, transfer_mode| {
+            inner.send_file(local_filepath, transfer_path, transfer_mode).map_err(|_| format!("couldn't upload file: {}", local_filepath))
+        }).map_err(|err| host_eprintln!("Error: {}", err))
+    }
+
+    fn receive_file(&mut self, remote_filepath: &str, local_filepath: &str) -> Result<(), ()> {
+        if self.become_params.is_none() {
+            return self.inner.receive_file(remote_filepath, local_filepath);
+        }
+
+        // the file is copied as the become user, but written to by the login user's shell, so only it can read it
+        let transfer_dir = self.create_transfer_dir(false).map_err(|err| host_eprintln!("Error: {}", err))?;
+        let transfer_path = format!("{}/file", transfer_dir);
+
+        let copy_result = self.run_command_with_output_file(&format!("cat -- {}", quote_shell_arg(remote_filepath)), Some(&transfer_path));
+        let result = match copy_result {
+            Ok(outcome) if !outcome.exited_with_error_code() => self.inner.receive_file(&transfer_path, local_filepath),
+            Ok(outcome) => {
+                host_eprintln!("Error: couldn't read file: {}: {}", remote_filepath, outcome.stderr.unwrap_or_default());
+                Err(())
+            },
+            Err(err) => {
+                host_eprintln!("Error: couldn't read file: {}: {}", remote_filepath, err);
+                Err(())
+            }
+        };
+
+        self.remove_transfer_dir(&transfer_dir);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::control::control_actions::{redact_secret_values, ControlActionType};
+    use crate::params::ParamValue;
+
+    #[test]
+    fn test_recording_order() {
+        let mut connection = ControlConnectionRecording::new();
+        assert!(connection.send_command("apt-get update").is_ok());
+        assert_eq!(connection.get_text_file_contents("/etc/hosts").ok().as_deref(), Some(""));
+        assert!(connection.send_text_file_contents("/etc/motd", 0o644, "hello").is_ok());
+        // the local file doesn't exist, but the operation should still be recorded
+        assert!(connection.send_file("/nonexistent/local_file", "/tmp/remote_file", 0o600).is_err());
+        assert!(connection.receive_file("/var/log/syslog", "syslog").is_ok());
+
+        assert_eq!(connection.take_recorded_operations(),
+                   vec![RecordedOperation::Command("apt-get update".to_string()),
+                        RecordedOperation::ReadRemoteFile("/etc/hosts".to_string()),
+                        RecordedOperation::WriteRemoteFile("/etc/motd".to_string(), 0o644, 5),
+                        RecordedOperation::SendFile("/nonexistent/local_file".to_string(), "/tmp/remote_file".to_string(), 0o600),
+                        RecordedOperation::ReceiveFile("/var/log/syslog".to_string(), "syslog".to_string())]);
+        assert!(connection.take_recorded_operations().is_empty());
+    }
+
+    #[test]
+    fn test_become() {
+        let mut connection = ControlConnectionBecome::new(Box::new(ControlConnectionRecording::new()));
+        assert!(connection.send_command("id").is_ok());
+        connection.set_become(Some(BecomeParams { user: "root".to_string(), password: None }));
+        assert!(connection.send_command(" apt-get update").is_ok());
+        assert!(connection.send_text_file_contents("/etc/motd", 0o644, "hello").is_ok());
+        connection.set_become(Some(BecomeParams { user: "postgres".to_string(), password: Some("hunter2".to_string()) }));
+        assert_eq!(connection.get_text_file_contents("/etc/postgresql/pg_hba.conf").ok().as_deref(), Some(""));
+
+        let operations = connection.take_recorded_operations();
+        assert_eq!(operations,
+                   vec![RecordedOperation::Command("id".to_string()),
+                        RecordedOperation::Command("sudo -n -u root -- sh -c 'apt-get update'".to_string()),
+                        RecordedOperation::Command("mktemp -d /tmp/prod_become.XXXXXXXX".to_string()),
+                        RecordedOperation::WriteRemoteFile("/tmp/prod_become.XXXXXXXX/file".to_string(), 0o600, 5),
+                        RecordedOperation::Command("sudo -n -u root -- sh -c 'install -m 644 /tmp/prod_become.XXXXXXXX/file /etc/motd'".to_string()),
+                        RecordedOperation::Command("rm -rf /tmp/prod_become.XXXXXXXX".to_string()),
+                        RecordedOperation::Command("sudo -k -S -p '' -u postgres -- sh -c 'cat -- /etc/postgresql/pg_hba.conf'".to_string())]);
+        // the password is never part of the commands
+        assert!(!operations.iter().any(|operation| operation.to_string().contains("hunter2")));
+    }
+
+    #[test]
+    fn test_recording_redaction() {
+        let mut action = ControlAction::new();
+        action.action = ControlActionType::AddUser;
+        action.params.values.insert("username".to_string(), ParamValue::Str("deploy".to_string()));
+        action.params.values.insert("password".to_string(), ParamValue::Str("hunter2".to_string()));
+
+        let mut connection = ControlConnectionRecording::new();
+        assert!(connection.send_command("  echo 'deploy:hunter2' | chpasswd").is_ok());
+
+        // as printed with dry-runs
+        let secrets = action.get_secret_param_values();
+        let printed: Vec<String> = connection.take_recorded_operations().iter()
+                                             .map(|operation| redact_secret_values(&operation.to_string(), &secrets)).collect();
+        assert_eq!(printed, vec!["command:       echo 'deploy:********' | chpasswd".to_string()]);
+    }
+
+    // a connection which has been dropped
+    struct ControlConnectionDropped {
+
+    }
+
+    impl ControlConnection for ControlConnectionDropped {
+        fn send_command(&mut self, _command: &str) -> Result<CommandOutcome, TransportError> {
+            Err(TransportError::ChannelOpen("connection reset".to_string()))
+        }
+    }
+
+    #[test]
+    fn test_transport_errors() {
+        let mut connection = ControlConnectionLogging::new(Box::new(ControlConnectionBecome::new(Box::new(ControlConnectionDropped {}))));
+        connection.set_become(Some(BecomeParams { user: "root".to_string(), password: None }));
+        assert_eq!(connection.send_command("apt-get update"), Err(TransportError::ChannelOpen("connection reset".to_string())));
+        // the transfer dir can't be created, so the file can't be installed
+        assert!(connection.send_text_file_contents("/etc/motd", 0o644, "hello").is_err());
+
+        assert_eq!(connection.take_executed_commands(),
+                   vec![ExecutedCommand { command: "apt-get update".to_string(), exit_code: None,
+                                          stderr: Some("couldn't open channel to host: connection reset".to_string()) }]);
+    }
+}
@@ -14,14 +14,19 @@
 */
 #![allow(dead_code)]
 
-use ssh2::{Session, Channel};
+use ssh2::{BlockDirections, Session, Channel};
 
 use std::path::Path;
 
 use std::io::BufReader;
+use std::io::ErrorKind;
 use std::io::prelude::*;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::time::Instant;
 
-use super::control_connection::{ControlConnection, RemoteFileContentsControlError};
+use super::control_output::host_eprintln;
+use super::control_connection::{CommandOutcome, ControlConnection, RemoteFileContentsControlError, TransportError};
 
 const BUFFER_SIZE: usize = 16 * 1024;
 
@@ -45,53 +50,73 @@ impl ControlConnectionOpenSSH {
     }
 
     fn debug(&mut self, command: &str) {
-        eprintln!("Command: '{}'", command);
+        host_eprintln!("Command: '{}'", command);
     }
 
-    fn send_command_exec(&mut self, command: &str) {
+    fn send_command_exec(&mut self, command: &str, input: Option<&str>) -> Result<CommandOutcome, TransportError> {
+        let start_time = Instant::now();
+
+        // clear the previous command's response first, so it's not left around if this one fails
+        self.prev_std_out.clear();
+        self.prev_std_err.clear();
+        self.exit_code = None;
+
         // Currently we spawn a new channel for each request, which isn't great...
-        let mut channel = self.session.channel_session().unwrap();
+        let mut channel = self.session.channel_session().map_err(|err| TransportError::ChannelOpen(err.to_string()))?;
+
+        channel.exec(command).map_err(|err| TransportError::Exec(err.to_string()))?;
 
-        channel.exec(command).unwrap();
+        if let Some(input) = input {
+            channel.write_all(input.as_bytes())
+                .and_then(|_| channel.write_all(b"\n"))
+                .map_err(|err| TransportError::Io(err.to_string()))?;
+            channel.send_eof().map_err(|err| TransportError::Io(err.to_string()))?;
+        }
 
-        self.prev_std_out = String::new();
-        channel.read_to_string(&mut self.prev_std_out).unwrap();
+        // output which isn't valid UTF-8 is converted lossily rather than failing
+        let mut std_out = Vec::new();
+        channel.read_to_end(&mut std_out).map_err(|err| TransportError::Io(err.to_string()))?;
 
-        self.prev_std_err = String::new();
-        channel.stderr().read_to_string(&mut self.prev_std_err).unwrap();
+        let mut std_err = Vec::new();
+        channel.stderr().read_to_end(&mut std_err).map_err(|err| TransportError::Io(err.to_string()))?;
 
-        channel.wait_close().unwrap();
+        channel.wait_close().map_err(|err| TransportError::Io(err.to_string()))?;
 
-        if let Ok(code) = channel.exit_status() {
-            self.exit_code = Some(code);
-        }
-        else {
-            self.exit_code = None;
-        }
+        self.prev_std_out = String::from_utf8_lossy(&std_out).to_string();
+        self.prev_std_err = String::from_utf8_lossy(&std_err).to_string();
+        self.exit_code = channel.exit_status().ok();
+
+        Ok(CommandOutcome { stdout: self.prev_std_out.clone(), stderr: self.get_previous_stderr_response().map(|std_err| std_err.to_string()),
+                            exit_code: self.exit_code, duration: start_time.elapsed() })
     }
 
-    fn send_command_shell(&mut self, command: &str) {
+    fn send_command_shell(&mut self, command: &str) -> Result<(), TransportError> {
         if !self.have_shell_session {
             self.session.set_timeout(2000);
-            let mut channel = self.session.channel_session().unwrap();
+            let mut channel = self.session.channel_session().map_err(|err| TransportError::ChannelOpen(err.to_string()))?;
 
-            channel.request_pty("xterm", None, None).unwrap();
+            channel.request_pty("xterm", None, None).map_err(|err| TransportError::ChannelOpen(err.to_string()))?;
 
-            channel.shell().unwrap();
+            channel.shell().map_err(|err| TransportError::Exec(err.to_string()))?;
 
             self.shell_channel = Some(channel);
             self.have_shell_session = true;
         }
 
-        let channel = self.shell_channel.as_mut().unwrap();
-        channel.write(command.as_bytes()).unwrap();
+        let channel = match self.shell_channel.as_mut() {
+            Some(channel) => channel,
+            None => return Err(TransportError::ChannelOpen("no shell channel".to_string())),
+        };
+        channel.write_all(command.as_bytes()).map_err(|err| TransportError::Io(err.to_string()))?;
 
         let response = BufReader::new(channel.stream(0));
         let mut response_lines = response.lines();
 
         while let Some(Ok(line)) = response_lines.next() {
-            eprintln!("Resp: {}", line);
+            host_eprintln!("Resp: {}", line);
         }
+
+        Ok(())
     }
 
     
@@ -99,20 +124,16 @@ impl ControlConnectionOpenSSH {
         let scp_res = self.session.scp_recv(Path::new(&filepath));
 
         // TODO: handle this properly (need to work out what that is though - just checking for common error codes from libssh2?)...
-        if let Err(err) = scp_res {
-            return Err(RemoteFileContentsControlError::Other(err.to_string()));
-        }
-
-        let (mut remote_file, _stat) = scp_res.unwrap();
+        let (mut remote_file, _stat) = match scp_res {
+            Ok(scp) => scp,
+            Err(err) => return Err(RemoteFileContentsControlError::Other(err.to_string())),
+        };
 
         let mut byte_contents = Vec::new();
-        remote_file.read_to_end(&mut byte_contents).unwrap();
+        remote_file.read_to_end(&mut byte_contents).map_err(|err| RemoteFileContentsControlError::TransferError(err.to_string()))?;
 
         // Close the channel and wait for the whole content to be transferred
-        remote_file.send_eof().unwrap();
-        remote_file.wait_eof().unwrap();
-        remote_file.close().unwrap();
-        remote_file.wait_close().unwrap();
+        close_scp_channel(&mut remote_file).map_err(|err| RemoteFileContentsControlError::TransferError(err.to_string()))?;
 
         let string_contents = String::from_utf8_lossy(&byte_contents);
 
@@ -125,130 +146,105 @@ impl ControlConnectionOpenSSH {
         let scp_res = self.session.scp_send(Path::new(&filepath), mode, byte_contents.len() as u64, None);
         
         // TODO: handle this properly (need to work out what that is though - just checking for common error codes from libssh2?)...
-        if let Err(err) = scp_res {
-            return Err(RemoteFileContentsControlError::Other(err.to_string()));
-        }
-
-        let mut remote_file = scp_res.unwrap();
+        let mut remote_file = match scp_res {
+            Ok(remote_file) => remote_file,
+            Err(err) => return Err(RemoteFileContentsControlError::Other(err.to_string())),
+        };
         
-        // TODO: there seems to be a 32kb limit here in practice based on send_file_via_scp() testing, so this might
-        //       need changing to support longer files as well...
-        remote_file.write(byte_contents).unwrap();
+        remote_file.write_all(byte_contents).map_err(|err| RemoteFileContentsControlError::TransferError(err.to_string()))?;
         // Close the channel and wait for the whole content to be transferred
-        remote_file.send_eof().unwrap();
-        remote_file.wait_eof().unwrap();
-        remote_file.close().unwrap();
-        remote_file.wait_close().unwrap();
+        close_scp_channel(&mut remote_file).map_err(|err| RemoteFileContentsControlError::TransferError(err.to_string()))?;
         
         Ok(())
     }
 
     pub fn send_file_via_scp(&self, local_filepath: &str, dest_filepath: &str, mode: i32) -> Result<(), ()> {
         // TODO: better error handling here and below...
-        if !std::path::Path::new(local_filepath).exists() {
-            return Err(());
-        }
+        let file_size = match std::fs::metadata(local_filepath) {
+            Ok(metadata) => metadata.len(),
+            Err(_) => return Err(()),
+        };
 
-        let file_size = std::fs::metadata(&local_filepath).unwrap().len();
+        let mut remote_file = self.session.scp_send(Path::new(dest_filepath), mode, file_size, None)
+            .map_err(|err| host_eprintln!("Error opening remote file: {}", err))?;
 
-        let mut remote_file = self.session.scp_send(Path::new(dest_filepath), mode, file_size as u64, None).unwrap();
-
-        let mut file = std::fs::File::open(local_filepath).unwrap();
-        let mut buffer = Vec::with_capacity(BUFFER_SIZE);
+        let mut file = std::fs::File::open(local_filepath).map_err(|err| host_eprintln!("Error opening local file: {}", err))?;
+        let mut buffer = vec![0u8; BUFFER_SIZE];
         loop {
-            let bytes_read = std::io::Read::by_ref(&mut file).take(BUFFER_SIZE as u64).read_to_end(&mut buffer).unwrap();
+            let bytes_read = file.read(&mut buffer).map_err(|err| host_eprintln!("Error reading local file: {}", err))?;
             if bytes_read == 0 {
                 break;
             }
 
-            let bytes_written = remote_file.write(&buffer);
-            if bytes_written.is_ok() {
-                let bytes_written = bytes_written.unwrap();
-                assert!(bytes_written == bytes_read);
-
-                if bytes_read < BUFFER_SIZE {
-                    break;
-                }
-                
-                // buffer is extended each time read_to_end() is called, so we need this.
-                // In theory, it should be very cheap, as it doesn't de-allocate the memory...
-                buffer.clear();
-            }
-            else {
-                eprintln!("Error writing file to SSH session...");
+            if remote_file.write_all(&buffer[..bytes_read]).is_err() {
+                host_eprintln!("Error writing file to SSH session...");
                 return Err(());
             }
         }
 
         // Close the channel and wait for the whole content to be transferred
-        remote_file.send_eof().unwrap();
-        remote_file.wait_eof().unwrap();
-        remote_file.close().unwrap();
-        remote_file.wait_close().unwrap();
+        close_scp_channel(&mut remote_file).map_err(|err| host_eprintln!("Error writing file to SSH session: {}", err))?;
 
         Ok(())
     }
 
     fn receive_file_via_scp(&self, remote_filepath: &str, local_filepath: &str) -> Result<(), ()> {
         let recv_res = self.session.scp_recv(Path::new(&remote_filepath));
-        if let Err(err) = recv_res {
-            eprintln!("Error opening remote file: code: {}", err.code());
-            return Err(());
-        }
-        
-        let (mut remote_file, _stat) = recv_res.unwrap();
+        let (mut remote_file, _stat) = match recv_res {
+            Ok(scp) => scp,
+            Err(err) => {
+                host_eprintln!("Error opening remote file: code: {}", err.code());
+                return Err(());
+            }
+        };
 
-        let local_file = std::fs::File::create(local_filepath);
-        if let Err(err) = local_file {
-            eprintln!("Error creating local file: {}", err.to_string());
-            return Err(());
-        }
-        let mut local_file = local_file.unwrap();
+        let mut local_file = match std::fs::File::create(local_filepath) {
+            Ok(local_file) => local_file,
+            Err(err) => {
+                host_eprintln!("Error creating local file: {}", err.to_string());
+                return Err(());
+            }
+        };
 
-        let mut buffer = Vec::with_capacity(BUFFER_SIZE);
+        let mut buffer = vec![0u8; BUFFER_SIZE];
         loop {
-            let bytes_read = std::io::Read::by_ref(&mut remote_file).take(BUFFER_SIZE as u64).read_to_end(&mut buffer).unwrap();
+            let bytes_read = remote_file.read(&mut buffer).map_err(|err| host_eprintln!("Error reading file from SSH session: {}", err))?;
             if bytes_read == 0 {
                 break;
             }
 
-            let bytes_written = local_file.write(&buffer);
-            if bytes_written.is_ok() {
-                let bytes_written = bytes_written.unwrap();
-                assert!(bytes_written == bytes_read);
-
-                if bytes_read < BUFFER_SIZE {
-                    break;
-                }
-                
-                // buffer is extended each time read_to_end() is called, so we need this.
-                // In theory, it should be very cheap, as it doesn't de-allocate the memory...
-                buffer.clear();
-            }
-            else {
-                eprintln!("Error reading file from SSH session...");
+            if local_file.write_all(&buffer[..bytes_read]).is_err() {
+                host_eprintln!("Error writing local file...");
                 return Err(());
             }
         }
 
         // Close the channel and wait for the whole content to be transferred
-        remote_file.send_eof().unwrap();
-        remote_file.wait_eof().unwrap();
-        remote_file.close().unwrap();
-        remote_file.wait_close().unwrap();
+        close_scp_channel(&mut remote_file).map_err(|err| host_eprintln!("Error reading file from SSH session: {}", err))?;
 
         Ok(())
     }
 }
 
+fn close_scp_channel(channel: &mut Channel) -> Result<(), ssh2::Error> {
+    channel.send_eof()?;
+    channel.wait_eof()?;
+    channel.close()?;
+    channel.wait_close()
+}
+
 impl ControlConnection for ControlConnectionOpenSSH {
 
-    fn send_command(&mut self, command: &str) {
+    fn send_command(&mut self, command: &str) -> Result<CommandOutcome, TransportError> {
 //        self.debug(command);
-        self.send_command_exec(command);
+        self.send_command_exec(command, None)
 //        self.send_command_shell(command);
     }
 
+    fn send_command_with_input(&mut self, command: &str, input: &str) -> Result<CommandOutcome, TransportError> {
+        self.send_command_exec(command, Some(input))
+    }
+
     fn had_command_response(&self) -> bool {
         !self.prev_std_out.is_empty()
     }
@@ -296,3 +292,197 @@ impl ControlConnection for ControlConnectionOpenSSH {
     }
 
 }
+
+// opens a 'direct-tcpip' channel to the host via the (jump host's) session, and returns a local stream which is
+// forwarded through it, so another session can be started over it. The session is owned by the forwarding
+// thread, which exits (closing the session) when either end of the tunnel is closed.
+pub fn open_tunnel(session: Session, hostname: &str, port: u32) -> Result<UnixStream, String> {
+    let channel = session.channel_direct_tcpip(hostname, port as u16, None)
+        .map_err(|err| format!("couldn't open tunnel: {}", err))?;
+    let (local_stream, tunnel_stream) = UnixStream::pair()
+        .map_err(|err| format!("couldn't create local stream for tunnel: {}", err))?;
+    tunnel_stream.set_nonblocking(true)
+        .map_err(|err| format!("couldn't create local stream for tunnel: {}", err))?;
+
+    // both ends are non-blocking, so we can forward in both directions from the one thread, as libssh2 sessions
+    // can't be used from multiple threads at once
+    session.set_blocking(false);
+    let tunnel_channel = TunnelChannel { session, channel };
+
+    std::thread::spawn(move || forward_tunnel(tunnel_channel, tunnel_stream));
+
+    Ok(local_stream)
+}
+
+// how long to wait for either end of a tunnel to be ready before checking both again anyway, in case
+// libssh2 has something buffered which it didn't tell us it was waiting on
+const TUNNEL_POLL_TIMEOUT_MS: i32 = 100;
+
+// one (non-blocking) end of a tunnel which is being forwarded
+trait TunnelEnd: Read + Write {
+    // the fd and events to wait on for this end to be ready to be read from and/or written to
+    fn get_poll_fd(&self, want_read: bool, want_write: bool) -> libc::pollfd;
+
+    // whether the other side has closed it, when a read returns nothing
+    fn is_eof(&self) -> bool {
+        true
+    }
+
+    fn close(&mut self);
+}
+
+impl TunnelEnd for UnixStream {
+    fn get_poll_fd(&self, want_read: bool, want_write: bool) -> libc::pollfd {
+        let mut events = 0;
+        if want_read {
+            events |= libc::POLLIN;
+        }
+        if want_write {
+            events |= libc::POLLOUT;
+        }
+        libc::pollfd { fd: self.as_raw_fd(), events, revents: 0 }
+    }
+
+    fn close(&mut self) {
+        // so the session using the tunnel finds out it's gone
+        let _ = self.shutdown(std::net::Shutdown::Both);
+    }
+}
+
+// the ssh end of a tunnel, which owns the session so that it's closed along with the tunnel
+struct TunnelChannel {
+    session:    Session,
+    channel:    Channel,
+}
+
+impl Read for TunnelChannel {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.channel.read(buf)
+    }
+}
+
+impl Write for TunnelChannel {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.channel.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.channel.flush()
+    }
+}
+
+impl TunnelEnd for TunnelChannel {
+    fn get_poll_fd(&self, want_read: bool, want_write: bool) -> libc::pollfd {
+        // libssh2 knows what it's actually waiting on the socket for (i.e. it may need to read for a write to go
+        // through), so use that if it's blocked on something
+        let events = match self.session.block_directions() {
+            BlockDirections::Inbound => libc::POLLIN,
+            BlockDirections::Outbound => libc::POLLOUT,
+            BlockDirections::Both => libc::POLLIN | libc::POLLOUT,
+            BlockDirections::None if want_write && !want_read => libc::POLLOUT,
+            BlockDirections::None => libc::POLLIN,
+        };
+        libc::pollfd { fd: self.session.as_raw_fd(), events, revents: 0 }
+    }
+
+    fn is_eof(&self) -> bool {
+        self.channel.eof()
+    }
+
+    fn close(&mut self) {
+        self.session.set_blocking(true);
+        let _ = self.channel.close();
+    }
+}
+
+// forwards data between the two ends until either of them is closed (or fails), and then closes both
+fn forward_tunnel<A: TunnelEnd, B: TunnelEnd>(mut end_a: A, mut end_b: B) {
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    let mut a_to_b: Vec<u8> = Vec::new();
+    let mut b_to_a: Vec<u8> = Vec::new();
+
+    while let (Some(did_a), Some(did_b)) = (pump_tunnel_data(&mut end_a, &mut end_b, &mut a_to_b, &mut buffer),
+                                            pump_tunnel_data(&mut end_b, &mut end_a, &mut b_to_a, &mut buffer)) {
+        if !did_a && !did_b {
+            // wait until either end is ready for whatever's outstanding
+            let mut poll_fds = [end_a.get_poll_fd(a_to_b.is_empty(), !b_to_a.is_empty()),
+                                end_b.get_poll_fd(b_to_a.is_empty(), !a_to_b.is_empty())];
+            let res = unsafe { libc::poll(poll_fds.as_mut_ptr(), poll_fds.len() as libc::nfds_t, TUNNEL_POLL_TIMEOUT_MS) };
+            if res < 0 && std::io::Error::last_os_error().kind() != ErrorKind::Interrupted {
+                break;
+            }
+        }
+    }
+
+    end_a.close();
+    end_b.close();
+}
+
+// reads anything available from the source (if there's nothing still pending), and writes what's pending to the
+// destination. Returns whether anything was read or written, or None if either end was closed or failed.
+fn pump_tunnel_data<S: TunnelEnd, D: TunnelEnd>(source: &mut S, dest: &mut D, pending: &mut Vec<u8>, buffer: &mut [u8]) -> Option<bool> {
+    let mut did_something = false;
+
+    if pending.is_empty() {
+        match source.read(buffer) {
+            Ok(0) => {
+                if source.is_eof() {
+                    return None;
+                }
+            },
+            Ok(length) => {
+                pending.extend_from_slice(&buffer[..length]);
+                did_something = true;
+            },
+            Err(err) if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::Interrupted => {},
+            Err(_) => return None,
+        }
+    }
+    if !pending.is_empty() {
+        match dest.write(pending) {
+            Ok(length) => {
+                pending.drain(..length);
+                did_something = true;
+            },
+            Err(err) if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::Interrupted => {},
+            Err(_) => return None,
+        }
+    }
+
+    Some(did_something)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_forward_tunnel() {
+        // a local stream forwarded to a "remote" one, as a tunnel through a jump host would be
+        let (mut local_stream, tunnel_stream) = UnixStream::pair().unwrap();
+        let (remote_tunnel_stream, mut remote_stream) = UnixStream::pair().unwrap();
+        tunnel_stream.set_nonblocking(true).unwrap();
+        remote_tunnel_stream.set_nonblocking(true).unwrap();
+
+        let forwarding_thread = std::thread::spawn(move || forward_tunnel(tunnel_stream, remote_tunnel_stream));
+
+        // more than a single buffer's worth, so it's forwarded in parts
+        let request: Vec<u8> = (0..BUFFER_SIZE * 3).map(|i| (i % 251) as u8).collect();
+        local_stream.write_all(&request).unwrap();
+        let mut received = vec![0u8; request.len()];
+        remote_stream.read_exact(&mut received).unwrap();
+        assert_eq!(received, request);
+
+        remote_stream.write_all(b"response").unwrap();
+        let mut received = [0u8; 8];
+        local_stream.read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"response");
+
+        // closing one end should close the other
+        drop(local_stream);
+        let mut buffer = [0u8; 8];
+        assert_eq!(remote_stream.read(&mut buffer).unwrap(), 0);
+        forwarding_thread.join().unwrap();
+    }
+}
@@ -22,13 +22,134 @@ use std::path::Path;
 use std::io::prelude::*;
 
 use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
-use super::control_connection::{ControlConnection, RemoteFileContentsControlError};
+use super::control_output::host_eprintln;
+use super::control_common::ControlSessionCreationError;
+use super::control_connection::{CommandOutcome, ControlConnection, RemoteFileContentsControlError, TransportError};
 
 const BUFFER_SIZE: usize = 16 * 1024;
 
+// SSH message numbers of the packets we need to look for in the initial key exchange
+const SSH_MSG_NEWKEYS: u8 = 21;
+const SSH_MSG_KEXDH_REPLY: u8 = 31;
+// the max packet size SSH implementations have to support, anything bigger is invalid
+const MAX_PACKET_SIZE: usize = 35000;
+
+pub type HostKeyVerifier = Box<dyn FnMut(&[u8]) -> Result<(), ControlSessionCreationError> + Send>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum KeyExchangeState {
+    // waiting for the server's 'SSH-2.0-...' version line
+    Version,
+    // reading the (unencrypted) key exchange packets
+    Packets,
+    // the key exchange has finished, and everything after that is encrypted
+    Done,
+}
+
+// Wraps the TCP stream of ssh-rs connections to check the host key the server sends in its key exchange reply, as ssh-rs
+// doesn't provide any way of getting it. If the key isn't accepted, reading from the stream fails, so the connection is
+// aborted before any credentials are sent. ssh-rs itself verifies that the server has the private key for it.
+pub struct HostKeyCheckingStream {
+    stream:             TcpStream,
+    state:              KeyExchangeState,
+    // the data received during the key exchange which hasn't been parsed yet
+    pending:            Vec<u8>,
+    have_host_key:      bool,
+    verifier:           HostKeyVerifier,
+    // the host key error, if it wasn't accepted, as ssh-rs only gives us an IO error
+    error:              Arc<Mutex<Option<ControlSessionCreationError>>>,
+}
+
+impl HostKeyCheckingStream {
+    pub fn new(stream: TcpStream, verifier: HostKeyVerifier) -> HostKeyCheckingStream {
+        HostKeyCheckingStream { stream, state: KeyExchangeState::Version, pending: Vec::new(), have_host_key: false, verifier,
+                                error: Arc::new(Mutex::new(None)) }
+    }
+
+    pub fn get_error_slot(&self) -> Arc<Mutex<Option<ControlSessionCreationError>>> {
+        self.error.clone()
+    }
+
+    fn process_pending(&mut self) -> Result<(), ControlSessionCreationError> {
+        loop {
+            match self.state {
+                KeyExchangeState::Version => {
+                    // servers can send other lines before the version one
+                    let Some(line_end) = self.pending.iter().position(|byte| *byte == b'\n') else {
+                        return Ok(());
+                    };
+                    let line: Vec<u8> = self.pending.drain(..=line_end).collect();
+                    if line.starts_with(b"SSH-") {
+                        self.state = KeyExchangeState::Packets;
+                    }
+                },
+                KeyExchangeState::Packets => {
+                    let Some(length) = self.pending.get(0..4).map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()) as usize) else {
+                        return Ok(());
+                    };
+                    if length > MAX_PACKET_SIZE {
+                        return Err(ControlSessionCreationError::ConnectionError("invalid key exchange packet from host".to_string()));
+                    }
+                    if self.pending.len() < 4 + length {
+                        return Ok(());
+                    }
+                    // the packet length is followed by the padding length, and then the payload
+                    let packet: Vec<u8> = self.pending.drain(..4 + length).collect();
+                    match packet.get(5) {
+                        Some(&SSH_MSG_KEXDH_REPLY) => {
+                            let host_key = packet.get(6..10).map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()) as usize)
+                                                 .and_then(|key_length| packet.get(10..10 + key_length))
+                                                 .ok_or(ControlSessionCreationError::ConnectionError("invalid host key from host".to_string()))?;
+                            (self.verifier)(host_key)?;
+                            self.have_host_key = true;
+                        },
+                        Some(&SSH_MSG_NEWKEYS) => {
+                            // never let the connection carry on without the key being checked
+                            if !self.have_host_key {
+                                return Err(ControlSessionCreationError::HostKeyMismatch("couldn't get the host key from the host".to_string()));
+                            }
+                            self.state = KeyExchangeState::Done;
+                            self.pending = Vec::new();
+                        },
+                        _ => {}
+                    }
+                },
+                KeyExchangeState::Done => return Ok(()),
+            }
+        }
+    }
+}
+
+impl Read for HostKeyCheckingStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let count = self.stream.read(buf)?;
+        if self.state != KeyExchangeState::Done && count > 0 {
+            self.pending.extend_from_slice(&buf[..count]);
+            if let Err(err) = self.process_pending() {
+                let message = err.to_string();
+                *self.error.lock().unwrap_or_else(|err| err.into_inner()) = Some(err);
+                return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, message));
+            }
+        }
+        Ok(count)
+    }
+}
+
+impl Write for HostKeyCheckingStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
 pub struct ControlConnectionSshRs {
-    local_session:      LocalSession<TcpStream>,
+    local_session:      LocalSession<HostKeyCheckingStream>,
 
     pub prev_std_out:   String,
     pub prev_std_err:   String,
@@ -37,36 +158,29 @@ pub struct ControlConnectionSshRs {
 }
 
 impl ControlConnectionSshRs {
-    pub fn new(session: SessionConnector<TcpStream>) -> ControlConnectionSshRs {
+    pub fn new(session: SessionConnector<HostKeyCheckingStream>) -> ControlConnectionSshRs {
         ControlConnectionSshRs { local_session: session.run_local(),
                                  prev_std_out: String::new(), prev_std_err: String::new(),
                                  exit_code: None }
     }
 
     fn debug(&mut self, command: &str) {
-        eprintln!("Command: '{}'", command);
+        host_eprintln!("Command: '{}'", command);
     }
 
-    fn send_command_exec(&mut self, command: &str) {
+    fn send_command_exec(&mut self, command: &str) -> Result<CommandOutcome, TransportError> {
+        let start_time = Instant::now();
+
         self.prev_std_out = String::new();
         self.prev_std_err = String::new();
+        self.exit_code = None;
         
-        let exec = self.local_session.open_exec();
-        if let Err(_err) = exec {
-            // TODO: error...
-            //   But what?
-            return;
-        }
+        let mut exec = self.local_session.open_exec().map_err(|err| TransportError::ChannelOpen(err.to_string()))?;
 
-        let mut exec = exec.unwrap();
-        let result = exec.exec_command(command);
-        if let Err(_err) = result {
-            // TODO: error...
-            return;
-        }
+        exec.exec_command(command).map_err(|err| TransportError::Exec(err.to_string()))?;
 
         // Note: this is needed here for result processing and for exit_status state to be valid...
-        let vec: Vec<u8> = exec.get_output().unwrap();
+        let vec: Vec<u8> = exec.get_output().map_err(|err| TransportError::Io(err.to_string()))?;
 
         if let Ok(exit_code) = exec.exit_status() {
             self.exit_code = Some(exit_code as i32);
@@ -74,10 +188,9 @@ impl ControlConnectionSshRs {
         // Note: the output from an ssh-rs exec.send_command() call is not separated into stdout/stderr,
         //       so we have no way of easily identifying if there was an error or not via the stdout/stderr output...
         
-        self.prev_std_out = String::from_utf8(vec).unwrap();
-        if self.prev_std_out.is_empty() {
+        self.prev_std_out = String::from_utf8_lossy(&vec).to_string();
 
-        }
+        Ok(CommandOutcome { stdout: self.prev_std_out.clone(), stderr: None, exit_code: self.exit_code, duration: start_time.elapsed() })
     }
 
     fn send_command_shell(&mut self, _command: &str) {
@@ -127,11 +240,11 @@ impl ControlConnectionSshRs {
         let local_temp_file_path = tmp_local_file.path();
         let local_file = File::create(local_temp_file_path);
         if local_file.is_err() {
-            eprintln!("Error creating temporary file to scp text contents to remote: {}", local_temp_file_path.display());
+            host_eprintln!("Error creating temporary file to scp text contents to remote: {}", local_temp_file_path.display());
             return Err(RemoteFileContentsControlError::CantCreateLocalTempFile(local_file.err().unwrap().to_string()));
         }
         let mut local_file = local_file.unwrap();
-        local_file.write_all(contents.as_bytes()).unwrap();
+        local_file.write_all(contents.as_bytes()).map_err(|err| RemoteFileContentsControlError::CantCreateLocalTempFile(err.to_string()))?;
 
         // TODO: not sure what to do about the file mode... ssh-rs does not support specifying the mode
         //       via the upload() method, but maybe it copies it from the source file, and we can just
@@ -184,9 +297,9 @@ impl ControlConnectionSshRs {
 
 impl ControlConnection for ControlConnectionSshRs {
 
-    fn send_command(&mut self, command: &str) {
+    fn send_command(&mut self, command: &str) -> Result<CommandOutcome, TransportError> {
 //        self.debug(command);
-        self.send_command_exec(command);
+        self.send_command_exec(command)
 //        self.send_command_shell(command);
     }
 
@@ -235,3 +348,75 @@ impl ControlConnection for ControlConnectionSshRs {
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::TcpListener;
+
+    fn create_packet(payload: &[u8]) -> Vec<u8> {
+        let padding = [0u8; 4];
+        let mut packet = ((payload.len() + padding.len() + 1) as u32).to_be_bytes().to_vec();
+        packet.push(padding.len() as u8);
+        packet.extend_from_slice(payload);
+        packet.extend_from_slice(&padding);
+        packet
+    }
+
+    #[test]
+    fn test_host_key_checking_stream() {
+        let host_key = b"\x00\x00\x00\x0bssh-ed25519key".to_vec();
+        let mut kex_reply = vec![SSH_MSG_KEXDH_REPLY];
+        kex_reply.extend_from_slice(&(host_key.len() as u32).to_be_bytes());
+        kex_reply.extend_from_slice(&host_key);
+        kex_reply.extend_from_slice(b"\x00\x00\x00\x04rest");
+
+        let mut server_data = b"banner\r\nSSH-2.0-OpenSSH_9.2\r\n".to_vec();
+        server_data.extend(create_packet(&[20, 1, 2, 3]));
+        server_data.extend(create_packet(&kex_reply));
+        server_data.extend(create_packet(&[SSH_MSG_NEWKEYS]));
+        // this would be encrypted, so shouldn't be parsed
+        server_data.extend(b"\xff\xff\xff\xff");
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        for accept in [true, false] {
+            let client = TcpStream::connect(address).unwrap();
+            let (mut server, _) = listener.accept().unwrap();
+            server.write_all(&server_data).unwrap();
+            drop(server);
+
+            let seen_keys = Arc::new(Mutex::new(Vec::new()));
+            let verifier_seen_keys = seen_keys.clone();
+            let mut stream = HostKeyCheckingStream::new(client, Box::new(move |key: &[u8]| {
+                verifier_seen_keys.lock().unwrap().push(key.to_vec());
+                if accept { Ok(()) } else { Err(ControlSessionCreationError::HostKeyMismatch("changed".to_string())) }
+            }));
+            let error_slot = stream.get_error_slot();
+
+            // read it in small chunks, to make sure partial packets are handled
+            let mut received = Vec::new();
+            let mut buffer = [0u8; 7];
+            let result = loop {
+                match stream.read(&mut buffer) {
+                    Ok(0) => break Ok(()),
+                    Ok(count) => received.extend_from_slice(&buffer[..count]),
+                    Err(err) => break Err(err),
+                }
+            };
+
+            assert_eq!(*seen_keys.lock().unwrap(), vec![host_key.clone()]);
+            if accept {
+                assert!(result.is_ok());
+                assert_eq!(received, server_data);
+                assert!(error_slot.lock().unwrap().is_none());
+            }
+            else {
+                assert!(result.is_err());
+                assert_eq!(*error_slot.lock().unwrap(), Some(ControlSessionCreationError::HostKeyMismatch("changed".to_string())));
+            }
+        }
+    }
+}
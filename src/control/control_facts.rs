@@ -0,0 +1,285 @@
+/*
+ Prod
+ Copyright 2021-2025 Peter Pearson.
+ Licensed under the Apache License, Version 2.0 (the "License");
+ You may not use this file except in compliance with the License.
+ You may obtain a copy of the License at
+ http://www.apache.org/licenses/LICENSE-2.0
+ Unless required by applicable law or agreed to in writing, software
+ distributed under the License is distributed on an "AS IS" BASIS,
+ WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ See the License for the specific language governing permissions and
+ limitations under the License.
+ ---------
+*/
+
+// Details of a host (CPUs, memory, disks, network interfaces, etc), which are available to control scripts
+// as 'facts.<name>' variables, and can be printed with 'prod control --facts <host>'.
+
+use std::collections::BTreeMap;
+
+use crate::params::ParamValue;
+
+use super::control_actions::SystemDetailsResult;
+use super::control_templating::TemplateVars;
+
+// a single command to gather all the facts (other than the distro details), with each section of output
+// starting with a '### <name>' line, so we don't need lots of round-trips to the host.
+// Note: this can't contain single quotes, as it's run with "sh -c '...'".
+pub const GATHER_FACTS_COMMAND: &str = "echo \"### hostname\"; hostname; \
+echo \"### kernel\"; uname -r; \
+echo \"### architecture\"; uname -m; \
+echo \"### cpu_count\"; nproc 2>/dev/null || getconf _NPROCESSORS_ONLN; \
+echo \"### meminfo\"; cat /proc/meminfo; \
+echo \"### mounts\"; df -P -k -T 2>/dev/null; \
+echo \"### interfaces\"; ip -o addr show 2>/dev/null; \
+echo \"### init_system\"; cat /proc/1/comm 2>/dev/null; \
+echo \"### package_managers\"; command -v apt-get dnf yum zypper apk pacman 2>/dev/null; \
+echo \"### virtualization\"; systemd-detect-virt 2>/dev/null; true";
+
+// filesystem types of mounts which aren't interesting, i.e. in-memory ones
+const IGNORED_FILESYSTEM_TYPES: &[&str] = &["tmpfs", "devtmpfs", "squashfs", "overlay", "efivarfs", "proc", "sysfs"];
+
+#[derive(Clone, Debug, Default)]
+pub struct HostFacts {
+    pub distro_id:              String,
+    pub release:                String,
+    pub hostname:               String,
+    pub kernel:                 String,
+    pub architecture:           String,
+    pub cpu_count:              Option<u32>,
+    pub memory_total_mb:        Option<u64>,
+    pub memory_available_mb:    Option<u64>,
+    pub mounts:                 Vec<MountFacts>,
+    pub interfaces:             Vec<InterfaceFacts>,
+    // i.e. "systemd"
+    pub init_system:            String,
+    // i.e. "apt" or "dnf"
+    pub package_manager:        String,
+    // i.e. "kvm", "docker", or "none" for physical hosts
+    pub virtualization:         String,
+}
+
+#[derive(Clone, Debug)]
+pub struct MountFacts {
+    pub mount:          String,
+    pub device:         String,
+    pub filesystem:     String,
+    pub size_mb:        u64,
+    pub available_mb:   u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct InterfaceFacts {
+    pub name:           String,
+    // addresses without the prefix length, i.e. "192.168.1.5"
+    pub ipv4:           Vec<String>,
+    pub ipv6:           Vec<String>,
+}
+
+impl HostFacts {
+    // just the distro details, without any of the other facts
+    pub fn from_system_details(system_details: &SystemDetailsResult) -> HostFacts {
+        HostFacts { distro_id: system_details.distr_id.clone(), release: system_details.release.clone(), ..Default::default() }
+    }
+
+    // parses the output of GATHER_FACTS_COMMAND. Anything which couldn't be found is left empty / unset.
+    pub fn from_gather_output(system_details: &SystemDetailsResult, output: &str) -> HostFacts {
+        let mut facts = HostFacts::from_system_details(system_details);
+
+        for (section, lines) in split_sections(output) {
+            let first_line = lines.first().map(|line| line.trim().to_string()).unwrap_or_default();
+            match section {
+                "hostname" => facts.hostname = first_line,
+                "kernel" => facts.kernel = first_line,
+                "architecture" => facts.architecture = first_line,
+                "cpu_count" => facts.cpu_count = first_line.parse().ok(),
+                "meminfo" => {
+                    facts.memory_total_mb = get_meminfo_value_mb(&lines, "MemTotal:");
+                    facts.memory_available_mb = get_meminfo_value_mb(&lines, "MemAvailable:");
+                },
+                "mounts" => facts.mounts = parse_df_lines(&lines),
+                "interfaces" => facts.interfaces = parse_ip_addr_lines(&lines),
+                "init_system" => facts.init_system = first_line,
+                "package_managers" => {
+                    facts.package_manager = match first_line.rsplit('/').next().unwrap_or("") {
+                        "apt-get" => "apt".to_string(),
+                        package_manager => package_manager.to_string(),
+                    };
+                },
+                "virtualization" => facts.virtualization = first_line,
+                _ => {}
+            }
+        }
+
+        facts
+    }
+
+    // all the IPv4 addresses of the host, other than loopback ones
+    pub fn get_ipv4_addresses(&self) -> Vec<String> {
+        self.interfaces.iter().flat_map(|interface| interface.ipv4.iter())
+                       .filter(|address| !address.starts_with("127.")).cloned().collect()
+    }
+
+    // the 'facts' variables for control scripts. Facts which weren't found aren't set, so they can be checked
+    // with 'is defined'.
+    pub fn to_template_vars(&self) -> TemplateVars {
+        let mut vars = TemplateVars::new();
+
+        let strings = [("distro_id", &self.distro_id), ("release", &self.release), ("hostname", &self.hostname),
+                       ("kernel", &self.kernel), ("architecture", &self.architecture), ("init_system", &self.init_system),
+                       ("package_manager", &self.package_manager), ("virtualization", &self.virtualization)];
+        for (name, value) in strings {
+            if !value.is_empty() {
+                vars.insert(name.to_string(), ParamValue::Str(value.clone()));
+            }
+        }
+
+        let ints = [("cpu_count", self.cpu_count.map(u64::from)), ("memory_total_mb", self.memory_total_mb),
+                    ("memory_available_mb", self.memory_available_mb)];
+        for (name, value) in ints {
+            if let Some(value) = value {
+                vars.insert(name.to_string(), int_value(value));
+            }
+        }
+
+        let mounts = self.mounts.iter().map(|mount| {
+            let mut map = BTreeMap::new();
+            map.insert("mount".to_string(), ParamValue::Str(mount.mount.clone()));
+            map.insert("device".to_string(), ParamValue::Str(mount.device.clone()));
+            map.insert("filesystem".to_string(), ParamValue::Str(mount.filesystem.clone()));
+            map.insert("size_mb".to_string(), int_value(mount.size_mb));
+            map.insert("available_mb".to_string(), int_value(mount.available_mb));
+            ParamValue::Map(map)
+        });
+        vars.insert("mounts".to_string(), ParamValue::Array(mounts.collect()));
+
+        let str_array = |values: &[String]| ParamValue::Array(values.iter().map(|value| ParamValue::Str(value.clone())).collect());
+        let interfaces = self.interfaces.iter().map(|interface| {
+            let mut map = BTreeMap::new();
+            map.insert("name".to_string(), ParamValue::Str(interface.name.clone()));
+            map.insert("ipv4".to_string(), str_array(&interface.ipv4));
+            map.insert("ipv6".to_string(), str_array(&interface.ipv6));
+            ParamValue::Map(map)
+        });
+        vars.insert("interfaces".to_string(), ParamValue::Array(interfaces.collect()));
+        vars.insert("ipv4_addresses".to_string(), str_array(&self.get_ipv4_addresses()));
+
+        vars
+    }
+}
+
+// ParamValue ints are only 32-bit, so clamp (i.e. for very large filesystems)
+fn int_value(value: u64) -> ParamValue {
+    ParamValue::Int(value.min(i32::MAX as u64) as i32)
+}
+
+fn split_sections(output: &str) -> Vec<(&str, Vec<&str>)> {
+    let mut sections: Vec<(&str, Vec<&str>)> = Vec::new();
+    for line in output.lines() {
+        if let Some(section) = line.strip_prefix("### ") {
+            sections.push((section.trim(), Vec::new()));
+        }
+        else if let Some((_, lines)) = sections.last_mut() {
+            if !line.trim().is_empty() {
+                lines.push(line);
+            }
+        }
+    }
+    sections
+}
+
+// i.e. "MemTotal:        8024316 kB"
+fn get_meminfo_value_mb(lines: &[&str], name: &str) -> Option<u64> {
+    let line = lines.iter().find(|line| line.starts_with(name))?;
+    let value_kb = line[name.len()..].split_whitespace().next()?.parse::<u64>().ok()?;
+    Some(value_kb / 1024)
+}
+
+// parses 'df -P -k -T' output, i.e.:
+// "Filesystem     Type 1024-blocks    Used Available Capacity Mounted on"
+// "/dev/vda1      ext4    40470732 3530472  35159488      10% /"
+fn parse_df_lines(lines: &[&str]) -> Vec<MountFacts> {
+    lines.iter().skip(1).filter_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 7 || IGNORED_FILESYSTEM_TYPES.contains(&fields[1]) {
+            return None;
+        }
+        Some(MountFacts { mount: fields[6..].join(" "),
+                          device: fields[0].to_string(),
+                          filesystem: fields[1].to_string(),
+                          size_mb: fields[2].parse::<u64>().ok()? / 1024,
+                          available_mb: fields[4].parse::<u64>().ok()? / 1024 })
+    }).collect()
+}
+
+// parses 'ip -o addr show' output, i.e.:
+// "2: eth0    inet 192.168.1.5/24 brd 192.168.1.255 scope global eth0\       valid_lft forever preferred_lft forever"
+fn parse_ip_addr_lines(lines: &[&str]) -> Vec<InterfaceFacts> {
+    let mut interfaces: Vec<InterfaceFacts> = Vec::new();
+    for line in lines {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 4 {
+            continue;
+        }
+        // i.e. "eth0@if5" for container interfaces
+        let name = fields[1].split('@').next().unwrap_or(fields[1]);
+        let address = fields[3].split('/').next().unwrap_or(fields[3]).to_string();
+
+        let index = match interfaces.iter().position(|interface| interface.name == name) {
+            Some(index) => index,
+            None => {
+                interfaces.push(InterfaceFacts { name: name.to_string(), ipv4: Vec::new(), ipv6: Vec::new() });
+                interfaces.len() - 1
+            }
+        };
+        match fields[2] {
+            "inet" => interfaces[index].ipv4.push(address),
+            "inet6" => interfaces[index].ipv6.push(address),
+            _ => {}
+        }
+    }
+    interfaces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gather_output() {
+        let output = "### hostname\nweb1\n### kernel\n6.1.0-18-amd64\n### architecture\nx86_64\n### cpu_count\n4\n\
+### meminfo\nMemTotal:        8024316 kB\nMemFree:          123456 kB\nMemAvailable:    4096000 kB\n\
+### mounts\nFilesystem     Type     1024-blocks    Used Available Capacity Mounted on\n\
+/dev/vda1      ext4        40470732 3530472  35159488      10% /\n\
+tmpfs          tmpfs         802432       0    802432       0% /run/user/0\n\
+/dev/vdb1      xfs        104857600 1048576 103809024       1% /mnt/data disk\n\
+### interfaces\n1: lo    inet 127.0.0.1/8 scope host lo\\       valid_lft forever preferred_lft forever\n\
+2: eth0    inet 192.168.1.5/24 brd 192.168.1.255 scope global eth0\\       valid_lft forever preferred_lft forever\n\
+2: eth0    inet6 fe80::1/64 scope link \\       valid_lft forever preferred_lft forever\n\
+### init_system\nsystemd\n### package_managers\n/usr/bin/apt-get\n### virtualization\nkvm\n";
+
+        let mut system_details = SystemDetailsResult::new();
+        system_details.distr_id = "Debian".to_string();
+        system_details.release = "12".to_string();
+
+        let facts = HostFacts::from_gather_output(&system_details, output);
+        assert_eq!((facts.hostname.as_str(), facts.kernel.as_str(), facts.architecture.as_str()), ("web1", "6.1.0-18-amd64", "x86_64"));
+        assert_eq!((facts.cpu_count, facts.memory_total_mb, facts.memory_available_mb), (Some(4), Some(7836), Some(4000)));
+        assert_eq!(facts.mounts.len(), 2);
+        assert_eq!((facts.mounts[1].mount.as_str(), facts.mounts[1].filesystem.as_str(), facts.mounts[1].size_mb), ("/mnt/data disk", "xfs", 102400));
+        assert_eq!(facts.interfaces.len(), 2);
+        assert_eq!(facts.interfaces[1].ipv6, vec!["fe80::1"]);
+        assert_eq!(facts.get_ipv4_addresses(), vec!["192.168.1.5"]);
+        assert_eq!((facts.init_system.as_str(), facts.package_manager.as_str(), facts.virtualization.as_str()), ("systemd", "apt", "kvm"));
+
+        let vars = facts.to_template_vars();
+        assert!(matches!(vars.get("cpu_count"), Some(ParamValue::Int(4))));
+        assert!(matches!(vars.get("distro_id"), Some(ParamValue::Str(val)) if val == "Debian"));
+
+        // missing facts aren't set
+        let facts = HostFacts::from_gather_output(&system_details, "### hostname\nweb1\n### cpu_count\n");
+        let vars = facts.to_template_vars();
+        assert!(!vars.contains_key("cpu_count") && !vars.contains_key("kernel"));
+    }
+}
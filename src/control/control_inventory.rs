@@ -0,0 +1,460 @@
+/*
+ Prod
+ Copyright 2021-2025 Peter Pearson.
+ Licensed under the Apache License, Version 2.0 (the "License");
+ You may not use this file except in compliance with the License.
+ You may obtain a copy of the License at
+ http://www.apache.org/licenses/LICENSE-2.0
+ Unless required by applicable law or agreed to in writing, software
+ distributed under the License is distributed on an "AS IS" BASIS,
+ WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ See the License for the specific language governing permissions and
+ limitations under the License.
+ ---------
+*/
+
+// Inventory files describe a set of hosts (and groups of them) that a single control script can be run against,
+// with optional per-host overrides of the connection settings in the control script, i.e.:
+//
+// defaults:
+//   user: admin
+// hosts:
+//   web1:
+//     hostname: 192.168.0.10
+//   web2:
+//     hostname: 192.168.0.11
+//     port: 2222
+//   db1: 192.168.0.20
+// groups:
+//   web: [web1, web2]
+//   db: [db1]
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Read;
+
+use yaml_rust::{Yaml, YamlLoader};
+
+use crate::common::FileLoadError;
+use crate::vault::{get_vault_reference_names, Vault};
+
+use super::control_actions::{ControlActions, JumpHost};
+use super::control_common::{ControlSessionUserAuth, UserAuthAgent, UserAuthPublicKey, UserAuthUserPass};
+use super::control_known_hosts::HostKeyPolicy;
+
+// the connection/auth params which can be overridden per-host (or for all hosts via 'defaults'), including the
+// sudo password for 'become'
+const AUTH_OVERRIDE_PARAMS: &[&str] = &["user", "authType", "password", "publicKeyPath", "privateKeyPath", "passphrase", "becomePassword",
+                                        "hostKeyPolicy"];
+
+#[derive(Clone, Debug)]
+pub struct InventoryHost {
+    // the name of the host within the inventory
+    pub name:           String,
+    // the actual hostname or IP address to connect to
+    pub hostname:       String,
+    pub port:           Option<u32>,
+    // raw auth param values which override those in the control script
+    pub auth_overrides: BTreeMap<String, String>,
+    // the hosts to connect via, which override those in the control script
+    pub jump_hosts:     Option<Vec<JumpHost>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Inventory {
+    // kept in the order they're specified in the file, so they're run in that order
+    pub hosts:      Vec<InventoryHost>,
+    pub groups:     BTreeMap<String, Vec<String>>,
+}
+
+impl Inventory {
+    pub fn from_file(path: &str) -> Result<Inventory, FileLoadError> {
+        let mut file = std::fs::File::open(path)?;
+        let mut yaml_content = String::new();
+        file.read_to_string(&mut yaml_content)?;
+
+        let inventory = Inventory::from_yaml_string(&yaml_content).map_err(|err| {
+            FileLoadError::CustomError(format!("{}, error: {}", path, err))
+        })?;
+
+        for host in &inventory.hosts {
+            JumpHost::check_backend_supported(host.jump_hosts.as_deref().unwrap_or_default()).map_err(|err| {
+                FileLoadError::CustomError(format!("{}, error: invalid 'jumpHost' value for: '{}': {}.", path, host.name, err))
+            })?;
+        }
+
+        Ok(inventory)
+    }
+
+    pub fn from_yaml_string(yaml_content: &str) -> Result<Inventory, String> {
+        let documents = YamlLoader::load_from_str(yaml_content).map_err(|err| err.to_string())?;
+        if documents.is_empty() {
+            return Err("the inventory file was empty.".to_string());
+        }
+
+        let doc = documents[0].as_hash().ok_or("expected a map of 'hosts' and 'groups' items.")?;
+
+        let mut default_port = None;
+        let mut default_auth_overrides = BTreeMap::new();
+        let mut default_jump_hosts = None;
+        if let Some(defaults) = doc.get(&Yaml::String("defaults".to_string())) {
+            let defaults = defaults.as_hash().ok_or("the 'defaults' item must be a map.")?;
+            default_port = get_port_value(defaults, "defaults")?;
+            default_auth_overrides = get_auth_overrides(defaults);
+            default_jump_hosts = get_jump_hosts_value(defaults, "defaults")?;
+        }
+
+        let mut inventory = Inventory { hosts: Vec::new(), groups: BTreeMap::new() };
+
+        let hosts = doc.get(&Yaml::String("hosts".to_string())).and_then(|hosts| hosts.as_hash())
+                       .ok_or("no 'hosts' map was specified.")?;
+
+        for (name, value) in hosts {
+            let name = yaml_scalar_as_string(name).ok_or("host names must be strings.")?;
+
+            let mut host = InventoryHost { name: name.clone(), hostname: name.clone(), port: default_port,
+                                           auth_overrides: default_auth_overrides.clone(), jump_hosts: default_jump_hosts.clone() };
+
+            match value {
+                // just the name, which is also the hostname
+                Yaml::Null => {},
+                // just the hostname
+                Yaml::String(hostname) => {
+                    host.hostname = hostname.clone();
+                },
+                Yaml::Hash(host_map) => {
+                    if let Some(hostname) = host_map.get(&Yaml::String("hostname".to_string())).and_then(yaml_scalar_as_string) {
+                        host.hostname = hostname;
+                    }
+                    if let Some(port) = get_port_value(host_map, &name)? {
+                        host.port = Some(port);
+                    }
+                    host.auth_overrides.extend(get_auth_overrides(host_map));
+                    if let Some(jump_hosts) = get_jump_hosts_value(host_map, &name)? {
+                        host.jump_hosts = Some(jump_hosts);
+                    }
+                },
+                _ => {
+                    return Err(format!("unexpected value type for host: '{}'.", name));
+                }
+            }
+
+            // allow "hostname:port" in the same way as control scripts do...
+            if let Some((hostname, port)) = host.hostname.clone().split_once(':') {
+                host.hostname = hostname.to_string();
+                host.port = Some(port.parse::<u32>().map_err(|_| format!("invalid port number for host: '{}'.", name))?);
+            }
+
+            inventory.hosts.push(host);
+        }
+
+        if let Some(groups) = doc.get(&Yaml::String("groups".to_string())) {
+            let groups = groups.as_hash().ok_or("the 'groups' item must be a map of group names to lists of hosts.")?;
+            for (group_name, group_hosts) in groups {
+                let group_name = yaml_scalar_as_string(group_name).ok_or("group names must be strings.")?;
+                if inventory.hosts.iter().any(|host| host.name == group_name) {
+                    return Err(format!("group name: '{}' is the same as a host name.", group_name));
+                }
+
+                let mut host_names = Vec::new();
+                for host_name in group_hosts.as_vec().ok_or(format!("group: '{}' must be a list of host names.", group_name))? {
+                    let host_name = yaml_scalar_as_string(host_name).ok_or("group host names must be strings.")?;
+                    if !inventory.hosts.iter().any(|host| host.name == host_name) {
+                        return Err(format!("group: '{}' contains unknown host: '{}'.", group_name, host_name));
+                    }
+                    host_names.push(host_name);
+                }
+
+                inventory.groups.insert(group_name, host_names);
+            }
+        }
+
+        Ok(inventory)
+    }
+
+    // returns the hosts which match the comma-separated list of host and/or group names (or 'all'),
+    // in the order they're specified in the inventory.
+    pub fn select_hosts(&self, limit: Option<&str>) -> Result<Vec<&InventoryHost>, String> {
+        let limit = limit.unwrap_or("all");
+
+        let mut selected_names = Vec::new();
+        for item in limit.split(',').map(|item| item.trim()).filter(|item| !item.is_empty()) {
+            if item == "all" {
+                selected_names.extend(self.hosts.iter().map(|host| host.name.clone()));
+            }
+            else if let Some(group_hosts) = self.groups.get(item) {
+                selected_names.extend(group_hosts.iter().cloned());
+            }
+            else if self.hosts.iter().any(|host| host.name == item) {
+                selected_names.push(item.to_string());
+            }
+            else {
+                return Err(format!("'{}' is not a host or group name in the inventory.", item));
+            }
+        }
+
+        let selected_hosts: Vec<&InventoryHost> = self.hosts.iter().filter(|host| selected_names.contains(&host.name)).collect();
+        if selected_hosts.is_empty() {
+            return Err("no hosts were selected.".to_string());
+        }
+
+        Ok(selected_hosts)
+    }
+
+    // the names of the secrets referenced with '{{ vault.name }}' expressions in the hosts' settings
+    pub fn get_vault_reference_names(&self) -> BTreeSet<String> {
+        let mut names = BTreeSet::new();
+        for host in &self.hosts {
+            for value in host.auth_overrides.values().chain([&host.hostname]) {
+                get_vault_reference_names(value, &mut names);
+            }
+            for jump_host in host.jump_hosts.iter().flatten() {
+                jump_host.get_vault_reference_names(&mut names);
+            }
+        }
+        names
+    }
+
+    pub fn substitute_vault_references(&mut self, vault: &Vault) {
+        for host in &mut self.hosts {
+            host.hostname = vault.substitute_references(&host.hostname);
+            for value in host.auth_overrides.values_mut() {
+                *value = vault.substitute_references(value);
+            }
+            for jump_host in host.jump_hosts.iter_mut().flatten() {
+                jump_host.substitute_vault_references(vault);
+            }
+        }
+    }
+}
+
+impl InventoryHost {
+    // returns a copy of the control actions with the hostname, port and auth settings of this host applied.
+    pub fn apply_to_actions(&self, actions: &ControlActions) -> Result<ControlActions, String> {
+        let mut host_actions = actions.clone();
+
+        host_actions.hostname = self.hostname.clone();
+        if self.port.is_some() {
+            host_actions.port = self.port;
+        }
+
+        if !self.auth_overrides.is_empty() {
+            host_actions.auth = apply_auth_overrides(&actions.auth, &self.auth_overrides)
+                                    .map_err(|err| format!("host: '{}': {}", self.name, err))?;
+        }
+        if let Some(become_password) = self.auth_overrides.get("becomePassword") {
+            host_actions.become_password = become_password.clone();
+        }
+        if let Some(host_key_policy) = self.auth_overrides.get("hostKeyPolicy") {
+            host_actions.host_key_policy = HostKeyPolicy::parse(host_key_policy).map_err(|err| format!("host: '{}': {}", self.name, err))?;
+        }
+        if self.jump_hosts.is_some() {
+            host_actions.jump_hosts = self.jump_hosts.clone();
+        }
+
+        Ok(host_actions)
+    }
+}
+
+fn apply_auth_overrides(auth: &ControlSessionUserAuth, overrides: &BTreeMap<String, String>) -> Result<ControlSessionUserAuth, String> {
+    let get_override = |name: &str| overrides.get(name).cloned();
+
+    #[derive(PartialEq)]
+    enum AuthType {
+        UserPass,
+        PublicKey,
+        Agent,
+    }
+
+    // work out which type of auth to use, in a similar way to control scripts: explicit type first, then from
+    // the params provided, and otherwise keep what the script specifies.
+    let auth_type = match get_override("authType") {
+        Some(auth_type) if "userpass".eq_ignore_ascii_case(&auth_type) => AuthType::UserPass,
+        Some(auth_type) if "publickey".eq_ignore_ascii_case(&auth_type) => AuthType::PublicKey,
+        Some(auth_type) if "agent".eq_ignore_ascii_case(&auth_type) => AuthType::Agent,
+        Some(auth_type) => {
+            return Err(format!("unrecognised 'authType' value: '{}'", auth_type));
+        },
+        None => {
+            if overrides.contains_key("publicKeyPath") || overrides.contains_key("privateKeyPath") || overrides.contains_key("passphrase") {
+                AuthType::PublicKey
+            }
+            else if overrides.contains_key("password") {
+                AuthType::UserPass
+            }
+            else {
+                match auth {
+                    ControlSessionUserAuth::UserPass(_) => AuthType::UserPass,
+                    ControlSessionUserAuth::PublicKey(_) => AuthType::PublicKey,
+                    ControlSessionUserAuth::Agent(_) => AuthType::Agent,
+                }
+            }
+        }
+    };
+
+    let username = get_override("user").unwrap_or(auth.get_username().to_string());
+    match auth_type {
+        AuthType::UserPass => {
+            let script_password = match auth {
+                ControlSessionUserAuth::UserPass(userpass) => userpass.password.clone(),
+                _ => String::new(),
+            };
+            let password = get_override("password").unwrap_or(script_password);
+            Ok(ControlSessionUserAuth::UserPass(UserAuthUserPass::new(&username, &password)))
+        },
+        AuthType::PublicKey => {
+            // the key paths of the script are used if it's also using publickey auth, otherwise they need specifying
+            let (public_key, private_key, passphrase) = match auth {
+                ControlSessionUserAuth::PublicKey(publickey) => {
+                    (get_override("publicKeyPath").unwrap_or(publickey.publickey_path.clone()),
+                     get_override("privateKeyPath").unwrap_or(publickey.privatekey_path.clone()),
+                     get_override("passphrase").unwrap_or(publickey.passphrase.clone()))
+                },
+                _ => {
+                    (get_override("publicKeyPath").ok_or("the 'publicKeyPath' param must be specified for publickey auth.")?,
+                     get_override("privateKeyPath").ok_or("the 'privateKeyPath' param must be specified for publickey auth.")?,
+                     get_override("passphrase").unwrap_or_default())
+                }
+            };
+            Ok(ControlSessionUserAuth::PublicKey(UserAuthPublicKey::new(&username, &public_key, &private_key, &passphrase)))
+        },
+        AuthType::Agent => Ok(ControlSessionUserAuth::Agent(UserAuthAgent::new(&username))),
+    }
+}
+
+fn yaml_scalar_as_string(value: &Yaml) -> Option<String> {
+    match value {
+        Yaml::String(str_val) => Some(str_val.clone()),
+        Yaml::Integer(int_val) => Some(int_val.to_string()),
+        _ => None
+    }
+}
+
+fn get_port_value(map: &yaml_rust::yaml::Hash, item_name: &str) -> Result<Option<u32>, String> {
+    match map.get(&Yaml::String("port".to_string())) {
+        Some(Yaml::Integer(port)) if *port > 0 && *port <= u16::MAX.into() => Ok(Some(*port as u32)),
+        Some(_) => Err(format!("invalid 'port' value for: '{}'.", item_name)),
+        None => Ok(None)
+    }
+}
+
+fn get_jump_hosts_value(map: &yaml_rust::yaml::Hash, item_name: &str) -> Result<Option<Vec<JumpHost>>, String> {
+    match map.get(&Yaml::String("jumpHost".to_string())).or(map.get(&Yaml::String("jumpHosts".to_string()))) {
+        Some(value) => JumpHost::from_yaml(value).map(Some).map_err(|err| format!("invalid 'jumpHost' value for: '{}': {}.", item_name, err)),
+        None => Ok(None)
+    }
+}
+
+fn get_auth_overrides(map: &yaml_rust::yaml::Hash) -> BTreeMap<String, String> {
+    let mut overrides = BTreeMap::new();
+    for param in AUTH_OVERRIDE_PARAMS {
+        if let Some(value) = map.get(&Yaml::String(param.to_string())).and_then(yaml_scalar_as_string) {
+            overrides.insert(param.to_string(), value);
+        }
+    }
+    overrides
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_INVENTORY: &str =
+"defaults:
+  user: admin
+hosts:
+  web1:
+    hostname: 192.168.0.10
+  web2:
+    hostname: 192.168.0.11
+    port: 2222
+  db1:
+    hostname: 192.168.0.20:2200
+    jumpHost: admin@bastion:2222,bastion2
+  backup1:
+    jumpHost:
+      hostname: bastion3
+      user: backup
+      authType: agent
+groups:
+  web: [web1, web2]
+  db:
+    - db1
+";
+
+    fn selected_names(inventory: &Inventory, limit: Option<&str>) -> Vec<String> {
+        inventory.select_hosts(limit).unwrap().iter().map(|host| host.name.clone()).collect()
+    }
+
+    #[test]
+    fn test_load_inventory() {
+        let inventory = Inventory::from_yaml_string(TEST_INVENTORY).unwrap();
+        assert_eq!(inventory.hosts.len(), 4);
+        assert_eq!(inventory.groups.len(), 2);
+
+        assert_eq!(inventory.hosts[1].hostname, "192.168.0.11");
+        assert_eq!(inventory.hosts[1].port, Some(2222));
+        assert_eq!(inventory.hosts[2].hostname, "192.168.0.20");
+        assert_eq!(inventory.hosts[2].port, Some(2200));
+        assert_eq!(inventory.hosts[3].hostname, "backup1");
+        assert_eq!(inventory.hosts[3].auth_overrides.get("user").unwrap(), "admin");
+
+        assert!(inventory.hosts[0].jump_hosts.is_none());
+        let jump_hosts = inventory.hosts[2].jump_hosts.as_ref().unwrap();
+        assert_eq!(jump_hosts.len(), 2);
+        assert_eq!((jump_hosts[0].hostname.as_str(), jump_hosts[0].port, jump_hosts[0].user.as_deref()), ("bastion", Some(2222), Some("admin")));
+        assert_eq!((jump_hosts[1].hostname.as_str(), jump_hosts[1].port, jump_hosts[1].user.as_deref()), ("bastion2", None, None));
+        let jump_hosts = inventory.hosts[3].jump_hosts.as_ref().unwrap();
+        assert!(matches!(&jump_hosts[0].auth, Some(ControlSessionUserAuth::Agent(agent)) if agent.username == "backup"));
+    }
+
+    #[test]
+    fn test_invalid_inventory() {
+        assert!(Inventory::from_yaml_string("hosts:\n  web1:\ngroups:\n  web: [web2]\n").is_err());
+        assert!(Inventory::from_yaml_string("groups:\n  web: [web2]\n").is_err());
+        assert!(Inventory::from_yaml_string("hosts:\n  web1:\n    port: 70000\n").is_err());
+        assert!(Inventory::from_yaml_string("hosts:\n  web1:\n    jumpHost: bastion:abc\n").is_err());
+    }
+
+    #[test]
+    fn test_select_hosts() {
+        let inventory = Inventory::from_yaml_string(TEST_INVENTORY).unwrap();
+
+        assert_eq!(selected_names(&inventory, None), vec!["web1", "web2", "db1", "backup1"]);
+        assert_eq!(selected_names(&inventory, Some("web")), vec!["web1", "web2"]);
+        // order is always inventory order, and duplicates are removed
+        assert_eq!(selected_names(&inventory, Some("backup1,web,web1")), vec!["web1", "web2", "backup1"]);
+
+        assert!(inventory.select_hosts(Some("web3")).is_err());
+    }
+
+    #[test]
+    fn test_apply_auth_overrides() {
+        let script_auth = ControlSessionUserAuth::PublicKey(UserAuthPublicKey::new("user1", "/keys/id.pub", "/keys/id", ""));
+
+        let mut overrides = BTreeMap::new();
+        overrides.insert("user".to_string(), "user2".to_string());
+        match apply_auth_overrides(&script_auth, &overrides).unwrap() {
+            ControlSessionUserAuth::PublicKey(publickey) => {
+                assert_eq!(publickey.username, "user2");
+                assert_eq!(publickey.privatekey_path, "/keys/id");
+            },
+            _ => panic!("expected publickey auth")
+        }
+
+        overrides.insert("password".to_string(), "$PROMPT".to_string());
+        assert!(matches!(apply_auth_overrides(&script_auth, &overrides).unwrap(), ControlSessionUserAuth::UserPass(_)));
+
+        let script_auth = ControlSessionUserAuth::UserPass(UserAuthUserPass::new("user1", ""));
+        overrides.clear();
+        overrides.insert("authType".to_string(), "publickey".to_string());
+        assert!(apply_auth_overrides(&script_auth, &overrides).is_err());
+
+        overrides.insert("authType".to_string(), "agent".to_string());
+        let agent_auth = apply_auth_overrides(&script_auth, &overrides).unwrap();
+        assert!(matches!(&agent_auth, ControlSessionUserAuth::Agent(agent) if agent.username == "user1"));
+        // and it's kept for hosts which don't override it
+        overrides.clear();
+        overrides.insert("user".to_string(), "user2".to_string());
+        assert!(matches!(apply_auth_overrides(&agent_auth, &overrides).unwrap(), ControlSessionUserAuth::Agent(agent) if agent.username == "user2"));
+    }
+}
@@ -0,0 +1,478 @@
+/*
+ Prod
+ Copyright 2021-2025 Peter Pearson.
+ Licensed under the Apache License, Version 2.0 (the "License");
+ You may not use this file except in compliance with the License.
+ You may obtain a copy of the License at
+ http://www.apache.org/licenses/LICENSE-2.0
+ Unless required by applicable law or agreed to in writing, software
+ distributed under the License is distributed on an "AS IS" BASIS,
+ WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ See the License for the specific language governing permissions and
+ limitations under the License.
+ ---------
+*/
+
+// Journal of the files on the host which were modified, created or removed by the actions of a run, with
+// copies of the original files (and their mode and owner), so that the changes can be rolled back with
+// 'prod control --rollback <run-id>'.
+// Each run has its own directory on the host within JOURNAL_DIR, containing a 'journal' file with a line
+// per changed path, and the backup copies of the original files, named by index.
+
+use std::fmt;
+
+use super::common_actions_unix::{probe_command_stdout, probe_command_succeeds, quote_shell_arg};
+use super::control_actions::{ActionProvider, ControlAction, ControlActionType};
+use super::control_common::ControlSession;
+use super::control_connection::TransportError;
+use super::control_output::host_eprintln;
+use super::terminal_helpers_linux;
+
+pub const JOURNAL_DIR: &str = "/var/lib/prod/journal";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum JournalChange {
+    Created,
+    Modified,
+    Removed,
+}
+
+impl fmt::Display for JournalChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JournalChange::Created      => write!(f, "created"),
+            JournalChange::Modified     => write!(f, "modified"),
+            JournalChange::Removed      => write!(f, "removed"),
+        }
+    }
+}
+
+// the state of a path before the action which changed it was run
+#[derive(Clone, Debug, PartialEq)]
+pub enum PreviousState {
+    Absent,
+    // a regular file, with the path of the backup copy of it in the run's journal directory
+    File { backup_path: String, permissions: String, owner: String, group: String },
+    Symlink { target: String },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct JournalEntry {
+    pub change:     JournalChange,
+    pub path:       String,
+    pub previous:   PreviousState,
+}
+
+impl JournalEntry {
+    // the tab-separated line for the entry in the journal file, i.e.:
+    // "modified\t/etc/ssh/sshd_config\tfile\t/var/lib/prod/journal/<run-id>/1\t644\troot\troot"
+    pub fn to_journal_line(&self) -> String {
+        let mut items = vec![self.change.to_string(), self.path.clone()];
+        match &self.previous {
+            PreviousState::Absent => items.push("absent".to_string()),
+            PreviousState::File { backup_path, permissions, owner, group } => {
+                items.extend(["file".to_string(), backup_path.clone(), permissions.clone(), owner.clone(), group.clone()]);
+            },
+            PreviousState::Symlink { target } => items.extend(["symlink".to_string(), target.clone()]),
+        }
+        items.join("\t")
+    }
+
+    pub fn parse_journal_line(line: &str) -> Result<JournalEntry, String> {
+        let items: Vec<&str> = line.split('\t').collect();
+        if items.len() < 3 {
+            return Err(format!("invalid journal line: '{}'", line));
+        }
+
+        let change = match items[0] {
+            "created" => JournalChange::Created,
+            "modified" => JournalChange::Modified,
+            "removed" => JournalChange::Removed,
+            _ => return Err(format!("invalid journal change type: '{}'", items[0])),
+        };
+
+        let previous = match (items[2], items.len()) {
+            ("absent", 3) => PreviousState::Absent,
+            ("file", 7) => PreviousState::File { backup_path: items[3].to_string(), permissions: items[4].to_string(),
+                                                 owner: items[5].to_string(), group: items[6].to_string() },
+            ("symlink", 4) => PreviousState::Symlink { target: items[3].to_string() },
+            _ => return Err(format!("invalid journal line: '{}'", line)),
+        };
+
+        Ok(JournalEntry { change, path: items[1].to_string(), previous })
+    }
+}
+
+// the state of a path before an action is run, so it can be journaled if the action changes it.
+pub struct PathSnapshot {
+    path:       String,
+    previous:   PreviousState,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum JournalState {
+    // the journal directory is only created on the host the first time a path needs to be journaled
+    NotStarted,
+    Started,
+    Disabled,
+}
+
+// the journal of the changes of a run on a single host.
+pub struct ChangeJournal {
+    run_id:             String,
+    state:              JournalState,
+    next_backup_index:  usize,
+    pub num_entries:    usize,
+}
+
+impl ChangeJournal {
+    pub fn new(run_id: &str, enabled: bool) -> ChangeJournal {
+        ChangeJournal { run_id: run_id.to_string(), state: if enabled { JournalState::NotStarted } else { JournalState::Disabled },
+                        next_backup_index: 1, num_entries: 0 }
+    }
+
+    pub fn get_run_dir(&self) -> String {
+        get_run_dir(&self.run_id)
+    }
+
+    // records the current state of the paths the action will change (if any), before it's run.
+    pub fn snapshot_action_paths(&mut self, provider: &dyn ActionProvider, connection: &mut ControlSession,
+                                 action: &ControlAction) -> Result<Vec<PathSnapshot>, TransportError> {
+        let paths = get_journaled_paths(action);
+        if paths.is_empty() || !self.ensure_started(provider, connection)? {
+            return Ok(Vec::new());
+        }
+
+        let mut snapshots = Vec::new();
+        for path in paths {
+            match self.snapshot_path(provider, connection, &path) {
+                Ok(Some(snapshot)) => snapshots.push(snapshot),
+                Ok(None) => {},
+                Err(err) => return Err(self.disable_after_error(err)),
+            }
+        }
+
+        Ok(snapshots)
+    }
+
+    // after the action has run, adds entries to the journal for any of the snapshotted paths it changed (or
+    // might have done if it failed), otherwise removes the backup copies which aren't needed.
+    pub fn record_changes(&mut self, provider: &dyn ActionProvider, connection: &mut ControlSession, snapshots: Vec<PathSnapshot>,
+                          may_have_changed: bool) -> Result<(), TransportError> {
+        self.record_snapshot_changes(provider, connection, snapshots, may_have_changed).map_err(|err| self.disable_after_error(err))
+    }
+
+    // if we can't talk to the host, we can't know what's been changed, so nothing more is journaled, rather than
+    // journaling things incorrectly (i.e. a path we couldn't check being recorded as removed).
+    fn disable_after_error(&mut self, err: TransportError) -> TransportError {
+        if self.state != JournalState::Disabled {
+            host_eprintln!("  Warning: lost the connection to the host while journaling changes, so no more changes will be journaled.");
+            self.state = JournalState::Disabled;
+        }
+        err
+    }
+
+    fn record_snapshot_changes(&mut self, provider: &dyn ActionProvider, connection: &mut ControlSession, snapshots: Vec<PathSnapshot>,
+                               may_have_changed: bool) -> Result<(), TransportError> {
+        for snapshot in snapshots {
+            let exists = probe_command_succeeds(provider, connection, &format!("test -e {0} -o -L {0}", quote_shell_arg(&snapshot.path)))?;
+
+            let change = match (&snapshot.previous, exists) {
+                (PreviousState::Absent, false) => None,
+                (PreviousState::Absent, true) => Some(JournalChange::Created),
+                (_, false) => Some(JournalChange::Removed),
+                (_, true) => Some(JournalChange::Modified),
+            };
+
+            let change = match change {
+                Some(change) if may_have_changed => change,
+                _ => {
+                    if let PreviousState::File { backup_path, .. } = &snapshot.previous {
+                        probe_command_succeeds(provider, connection, &format!("rm -f {}", quote_shell_arg(backup_path)))?;
+                    }
+                    continue;
+                }
+            };
+
+            let entry = JournalEntry { change, path: snapshot.path, previous: snapshot.previous };
+            let append_command = format!("printf '%s\\n' {} >> {}", quote_shell_arg(&entry.to_journal_line()),
+                                         quote_shell_arg(&format!("{}/journal", self.get_run_dir())));
+            // the redirection needs to be done by the same user as the printf
+            if probe_command_succeeds(provider, connection, &format!("sh -c {}", quote_shell_arg(&append_command)))? {
+                self.num_entries += 1;
+            }
+            else {
+                host_eprintln!("  Warning: couldn't add the change to: {} to the journal, so it can't be rolled back.", entry.path);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn ensure_started(&mut self, provider: &dyn ActionProvider, connection: &mut ControlSession) -> Result<bool, TransportError> {
+        if self.state == JournalState::NotStarted {
+            let run_dir = self.get_run_dir();
+            let created = probe_command_succeeds(provider, connection, &format!("mkdir -p -m 700 {}", quote_shell_arg(&run_dir)))
+                .map_err(|err| self.disable_after_error(err))?;
+            if created {
+                self.state = JournalState::Started;
+            }
+            else {
+                host_eprintln!("  Warning: couldn't create the change journal directory: {}, so changes to files won't be journaled.", run_dir);
+                self.state = JournalState::Disabled;
+            }
+        }
+
+        Ok(self.state == JournalState::Started)
+    }
+
+    fn snapshot_path(&mut self, provider: &dyn ActionProvider, connection: &mut ControlSession, path: &str) -> Result<Option<PathSnapshot>, TransportError> {
+        // the journal is line and tab-separated, and relative paths would depend on the user
+        if !path.starts_with('/') || path.contains(['\t', '\n']) {
+            host_eprintln!("  Warning: changes to path: {} can't be journaled.", path);
+            return Ok(None);
+        }
+
+        // with the C locale, so the file types and field names in the output are the English ones which are parsed
+        let stat_output = match probe_command_stdout(provider, connection, &format!("LC_ALL=C stat {}", quote_shell_arg(path)))? {
+            Some(stat_output) => stat_output,
+            None => return Ok(Some(PathSnapshot { path: path.to_string(), previous: PreviousState::Absent })),
+        };
+
+        let previous = match terminal_helpers_linux::extract_file_type_from_stat_output(&stat_output).as_deref() {
+            Some("regular file") | Some("regular empty file") => {
+                let Some(stat_details) = terminal_helpers_linux::extract_details_from_stat_output(&stat_output) else {
+                    return Ok(None);
+                };
+                let backup_path = format!("{}/{}", self.get_run_dir(), self.next_backup_index);
+                self.next_backup_index += 1;
+
+                if !probe_command_succeeds(provider, connection, &format!("cp -p {} {}", quote_shell_arg(path), quote_shell_arg(&backup_path)))? {
+                    host_eprintln!("  Warning: couldn't make a backup copy of: {}, so changes to it won't be journaled.", path);
+                    return Ok(None);
+                }
+
+                let access_details = stat_details.access_details;
+                PreviousState::File { backup_path, permissions: access_details.permissions_num, owner: access_details.owner,
+                                      group: access_details.group }
+            },
+            Some("symbolic link") => {
+                let Some(target) = probe_command_stdout(provider, connection, &format!("readlink {}", quote_shell_arg(path)))? else {
+                    return Ok(None);
+                };
+                PreviousState::Symlink { target }
+            },
+            // directories and other things aren't journaled
+            Some(_) => return Ok(None),
+            None => {
+                host_eprintln!("  Warning: couldn't determine the type of path: {}, so changes to it won't be journaled.", path);
+                return Ok(None);
+            }
+        };
+
+        Ok(Some(PathSnapshot { path: path.to_string(), previous }))
+    }
+}
+
+// the paths of files (or symlinks) on the host the (rendered) action might modify, create or remove.
+pub fn get_journaled_paths(action: &ControlAction) -> Vec<String> {
+    let path_param = match action.action {
+        ControlActionType::EditFile => "filepath",
+        ControlActionType::ConfigureSSH => return vec!["/etc/ssh/sshd_config".to_string()],
+        ControlActionType::CreateFile | ControlActionType::RemoveFile => "path",
+        ControlActionType::TransmitFile if !action.params.has_value("extractDir") => "remoteDestPath",
+        ControlActionType::DownloadFile if !action.params.has_value("extractDir") => "destPath",
+        ControlActionType::CopyPath if !action.params.get_value_as_bool("recursive").unwrap_or(false) => "destPath",
+        ControlActionType::CreateSymlink => "linkPath",
+        _ => return Vec::new(),
+    };
+
+    action.params.get_string_value(path_param).into_iter().collect()
+}
+
+pub fn get_run_dir(run_id: &str) -> String {
+    format!("{}/{}", JOURNAL_DIR, run_id)
+}
+
+// run ids are the UTC date and time the run started, followed by the process id so that runs started
+// at the same time are distinct, i.e. "20250314-093012-4817".
+pub fn generate_run_id() -> String {
+    let secs = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0);
+    format!("{}-{}", format_utc_timestamp(secs), std::process::id())
+}
+
+pub fn is_valid_run_id(run_id: &str) -> bool {
+    !run_id.is_empty() && run_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+// i.e. "20250314-093012"
+fn format_utc_timestamp(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let secs_of_day = secs % 86400;
+
+    // convert the days since the epoch to the civil date
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}{:02}{:02}-{:02}{:02}{:02}", year, month, day, secs_of_day / 3600, (secs_of_day / 60) % 60, secs_of_day % 60)
+}
+
+// the previous file (or symlink) is put together next to the path first, and then moved into place, so that if
+// anything fails part way, the path is left as it was, rather than with nothing there at all.
+fn get_restore_commands(entry: &JournalEntry) -> Vec<String> {
+    let path = quote_shell_arg(&entry.path);
+    let temp_path = quote_shell_arg(&get_restore_temp_path(&entry.path));
+    match &entry.previous {
+        PreviousState::Absent => vec![format!("rm -f {}", path)],
+        PreviousState::File { backup_path, permissions, owner, group } => {
+            vec![format!("cp -p {} {}", quote_shell_arg(backup_path), temp_path), format!("chmod {} {}", permissions, temp_path),
+                 format!("chown {}:{} {}", quote_shell_arg(owner), quote_shell_arg(group), temp_path), format!("mv -fT {} {}", temp_path, path)]
+        },
+        PreviousState::Symlink { target } => {
+            vec![format!("ln -sfn {} {}", quote_shell_arg(target), temp_path), format!("mv -fT {} {}", temp_path, path)]
+        },
+    }
+}
+
+fn get_restore_temp_path(path: &str) -> String {
+    format!("{}.prod-restore", path)
+}
+
+// restores the paths in the run's journal on the host to their previous states, in the reverse order to which
+// they were changed, returning the number restored. Once everything has been restored, the journal is renamed
+// so that it can't be rolled back again.
+pub fn rollback_run(provider: &dyn ActionProvider, connection: &mut ControlSession, run_id: &str) -> Result<usize, String> {
+    let run_dir = get_run_dir(run_id);
+    let journal_path = format!("{}/journal", run_dir);
+
+    let journal_contents = probe_command_stdout(provider, connection, &format!("cat {}", quote_shell_arg(&journal_path)))
+        .map_err(|err| format!("couldn't read the change journal: {}", err))?
+        .ok_or_else(|| format!("no change journal for run ID: {} was found on the host", run_id))?;
+
+    let entries = journal_contents.lines().filter(|line| !line.trim().is_empty()).map(JournalEntry::parse_journal_line)
+                                  .collect::<Result<Vec<JournalEntry>, String>>()?;
+
+    let mut num_failed = 0;
+    for entry in entries.iter().rev() {
+        let mut restored = true;
+        for command in get_restore_commands(entry) {
+            // if the connection's gone, there's no point trying the rest of them
+            restored = probe_command_succeeds(provider, connection, &command)
+                .map_err(|err| format!("couldn't restore: {}: {}", entry.path, err))?;
+            if !restored {
+                break;
+            }
+        }
+
+        if restored {
+            host_eprintln!(" Restored: {} (which was {})", entry.path, entry.change);
+        }
+        else {
+            // don't leave a half-restored copy lying around
+            probe_command_succeeds(provider, connection, &format!("rm -f {}", quote_shell_arg(&get_restore_temp_path(&entry.path))))
+                .map_err(|err| format!("couldn't remove the temporary copy of: {}: {}", entry.path, err))?;
+            host_eprintln!(" Error: couldn't restore: {} (which was {})", entry.path, entry.change);
+            num_failed += 1;
+        }
+    }
+
+    if num_failed > 0 {
+        return Err(format!("{} of {} paths couldn't be restored", num_failed, entries.len()));
+    }
+
+    probe_command_succeeds(provider, connection, &format!("mv {} {}.rolled-back", quote_shell_arg(&journal_path),
+                                                          quote_shell_arg(&journal_path)))
+        .map_err(|err| format!("couldn't mark the change journal as rolled back: {}", err))?;
+
+    Ok(entries.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::control::action_provider_linux_debian::AProviderLinuxDebian;
+    use crate::control::common_actions_unix::{create_scripted_session, create_test_session_params, make_test_action};
+    use crate::control::control_connection::ControlConnectionScripted;
+
+    #[test]
+    fn test_journal_lines() {
+        let entries = [JournalEntry { change: JournalChange::Modified, path: "/etc/ssh/sshd_config".to_string(),
+                                      previous: PreviousState::File { backup_path: "/var/lib/prod/journal/run1/1".to_string(),
+                                                                      permissions: "644".to_string(), owner: "root".to_string(),
+                                                                      group: "root".to_string() } },
+                       JournalEntry { change: JournalChange::Created, path: "/opt/app/config file.txt".to_string(),
+                                      previous: PreviousState::Absent },
+                       JournalEntry { change: JournalChange::Removed, path: "/usr/local/bin/app".to_string(),
+                                      previous: PreviousState::Symlink { target: "/opt/app/bin/app".to_string() } }];
+
+        assert_eq!(entries[0].to_journal_line(), "modified\t/etc/ssh/sshd_config\tfile\t/var/lib/prod/journal/run1/1\t644\troot\troot");
+        assert_eq!(entries[1].to_journal_line(), "created\t/opt/app/config file.txt\tabsent");
+
+        for entry in &entries {
+            assert_eq!(JournalEntry::parse_journal_line(&entry.to_journal_line()).as_ref(), Ok(entry));
+        }
+
+        assert!(JournalEntry::parse_journal_line("changed\t/etc/hosts\tabsent").is_err());
+        assert!(JournalEntry::parse_journal_line("modified\t/etc/hosts\tfile\t/var/lib/prod/journal/run1/1").is_err());
+    }
+
+    #[test]
+    fn test_restore_commands() {
+        let entry = JournalEntry { change: JournalChange::Modified, path: "/etc/app/app.conf".to_string(),
+                                   previous: PreviousState::File { backup_path: "/var/lib/prod/journal/run1/1".to_string(),
+                                                                   permissions: "640".to_string(), owner: "root".to_string(),
+                                                                   group: "app".to_string() } };
+        // nothing's removed until the copy has succeeded
+        assert_eq!(get_restore_commands(&entry), vec!["cp -p /var/lib/prod/journal/run1/1 /etc/app/app.conf.prod-restore",
+                                                      "chmod 640 /etc/app/app.conf.prod-restore",
+                                                      "chown root:app /etc/app/app.conf.prod-restore",
+                                                      "mv -fT /etc/app/app.conf.prod-restore /etc/app/app.conf"]);
+
+        let entry = JournalEntry { change: JournalChange::Modified, path: "/usr/local/bin/app".to_string(),
+                                   previous: PreviousState::Symlink { target: "/opt/app/bin/app".to_string() } };
+        assert_eq!(get_restore_commands(&entry), vec!["ln -sfn /opt/app/bin/app /usr/local/bin/app.prod-restore",
+                                                      "mv -fT /usr/local/bin/app.prod-restore /usr/local/bin/app"]);
+    }
+
+    #[test]
+    fn test_journal_connection_failure() {
+        let mut action = make_test_action(&[("path", "/etc/app.conf")]);
+        action.action = ControlActionType::CreateFile;
+        let provider = AProviderLinuxDebian::new(create_test_session_params());
+        let mut journal = ChangeJournal::new("run1", true);
+
+        // the file doesn't exist before the action
+        let mut session = create_scripted_session(ControlConnectionScripted::new().respond("stat", "", 1));
+        let snapshots = journal.snapshot_action_paths(&provider, &mut session, &action).unwrap();
+        assert_eq!(snapshots.len(), 1);
+
+        // the connection dropping afterwards shouldn't be taken to mean it's still absent (or for existing files,
+        // that they've been removed), and nothing more should be journaled
+        let mut session = create_scripted_session(ControlConnectionScripted::new().fail("test -e"));
+        assert!(matches!(journal.record_changes(&provider, &mut session, snapshots, true), Err(TransportError::Io(_))));
+        assert_eq!(journal.num_entries, 0);
+        assert!(!session.conn.take_recorded_operations().iter().any(|operation| operation.to_string().contains("printf")));
+
+        let mut session = create_scripted_session(ControlConnectionScripted::new());
+        assert!(journal.snapshot_action_paths(&provider, &mut session, &action).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_run_ids() {
+        assert_eq!(format_utc_timestamp(0), "19700101-000000");
+        assert_eq!(format_utc_timestamp(1709251199), "20240229-235959");
+        assert_eq!(format_utc_timestamp(1741944612), "20250314-093012");
+
+        assert!(is_valid_run_id(&generate_run_id()));
+        assert!(!is_valid_run_id("../etc"));
+        assert!(!is_valid_run_id(""));
+    }
+}
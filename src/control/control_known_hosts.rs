@@ -0,0 +1,398 @@
+/*
+ Prod
+ Copyright 2021-2025 Peter Pearson.
+ Licensed under the Apache License, Version 2.0 (the "License");
+ You may not use this file except in compliance with the License.
+ You may obtain a copy of the License at
+ http://www.apache.org/licenses/LICENSE-2.0
+ Unless required by applicable law or agreed to in writing, software
+ distributed under the License is distributed on an "AS IS" BASIS,
+ WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ See the License for the specific language governing permissions and
+ limitations under the License.
+ ---------
+*/
+
+// Checking of the host keys of the hosts we connect to, against the user's OpenSSH known_hosts files (including
+// hashed entries), and Prod's own known hosts file, which new host keys are added to with the 'accept-new' policy.
+// Prod's file is separate from the user's one so that we never modify the user's one, and so that the keys of
+// freshly provisioned instances (which often re-use the IP addresses of old instances) can be forgotten when
+// they're provisioned.
+
+use std::fmt;
+use std::io::Write;
+
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use super::control_common::{wildcard_matches, ControlSessionCreationError};
+use super::control_output::host_eprintln;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HostKeyPolicy {
+    // the host key must already be known
+    Strict,
+    // unknown host keys are added to Prod's known hosts file, but changed ones are rejected
+    AcceptNew,
+    // host keys aren't checked at all
+    Off,
+}
+
+impl HostKeyPolicy {
+    pub fn parse(value: &str) -> Result<HostKeyPolicy, String> {
+        match value.trim() {
+            "strict" => Ok(HostKeyPolicy::Strict),
+            "accept-new" => Ok(HostKeyPolicy::AcceptNew),
+            "off" => Ok(HostKeyPolicy::Off),
+            _ => Err(format!("invalid host key policy: '{}', it should be 'strict', 'accept-new' or 'off'", value)),
+        }
+    }
+}
+
+impl fmt::Display for HostKeyPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostKeyPolicy::Strict => write!(f, "strict"),
+            HostKeyPolicy::AcceptNew => write!(f, "accept-new"),
+            HostKeyPolicy::Off => write!(f, "off"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum HostKeyStatus {
+    // the key matches a known key for the host
+    Known,
+    // there are no keys of the key's type for the host
+    Unknown,
+    // the host has a different key of the same type, at the file location
+    Changed(String),
+    // the key has been marked as revoked, at the file location
+    Revoked(String),
+}
+
+#[derive(Clone, Debug)]
+struct KnownHostEntry {
+    // 'path:line', for error messages
+    location:   String,
+    revoked:    bool,
+    hosts:      String,
+    key_type:   String,
+    key:        Vec<u8>,
+}
+
+#[derive(Clone, Debug)]
+pub struct KnownHosts {
+    entries:    Vec<KnownHostEntry>,
+}
+
+impl KnownHosts {
+    // loads Prod's known hosts file, and the user's OpenSSH ones. Any files which don't exist are ignored.
+    pub fn load() -> KnownHosts {
+        let home_dir = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
+        let paths = [get_known_hosts_file_path(), format!("{}/.ssh/known_hosts", home_dir), format!("{}/.ssh/known_hosts2", home_dir)];
+
+        let mut known_hosts = KnownHosts { entries: Vec::new() };
+        for path in paths {
+            if let Ok(contents) = std::fs::read_to_string(&path) {
+                known_hosts.add_entries(&path, &contents);
+            }
+        }
+        known_hosts
+    }
+
+    fn add_entries(&mut self, path: &str, contents: &str) {
+        for (index, line) in contents.lines().enumerate() {
+            let mut items = line.split_whitespace().peekable();
+            if items.peek().is_none_or(|item| item.starts_with('#')) {
+                continue;
+            }
+
+            // certificate authorities aren't supported, so they're ignored
+            let revoked = match items.peek() {
+                Some(&"@revoked") => true,
+                Some(&"@cert-authority") => continue,
+                _ => false,
+            };
+            if revoked {
+                items.next();
+            }
+
+            // invalid lines are ignored, as OpenSSH does
+            let (Some(hosts), Some(key_type), Some(key)) = (items.next(), items.next(), items.next()) else {
+                continue;
+            };
+            let Ok(key) = STANDARD.decode(key) else {
+                continue;
+            };
+            self.entries.push(KnownHostEntry { location: format!("{}:{}", path, index + 1), revoked, hosts: hosts.to_string(),
+                                               key_type: key_type.to_string(), key });
+        }
+    }
+
+    pub fn check(&self, hostname: &str, port: u32, key: &[u8]) -> HostKeyStatus {
+        let host_name = get_known_hosts_name(hostname, port);
+        let key_type = get_key_type(key).unwrap_or_default();
+
+        let entries: Vec<&KnownHostEntry> = self.entries.iter().filter(|entry| hosts_field_matches(&entry.hosts, &host_name)).collect();
+        if let Some(entry) = entries.iter().find(|entry| entry.revoked && entry.key == key) {
+            return HostKeyStatus::Revoked(entry.location.clone());
+        }
+        if entries.iter().any(|entry| !entry.revoked && entry.key == key) {
+            return HostKeyStatus::Known;
+        }
+        // keys of other types aren't a conflict, as which type is used depends on what the client supports
+        match entries.iter().find(|entry| !entry.revoked && entry.key_type == key_type) {
+            Some(entry) => HostKeyStatus::Changed(entry.location.clone()),
+            None => HostKeyStatus::Unknown,
+        }
+    }
+}
+
+// Prod's own known hosts file, from $PROD_KNOWN_HOSTS_FILE or ~/.prod_known_hosts
+pub fn get_known_hosts_file_path() -> String {
+    if let Ok(path) = std::env::var("PROD_KNOWN_HOSTS_FILE") {
+        return path;
+    }
+
+    let home_dir = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
+    format!("{}/.prod_known_hosts", home_dir)
+}
+
+// checks the host key the host presented against the known hosts with the policy, adding it to Prod's known hosts
+// file if it's new and the policy allows that.
+pub fn verify_host_key(hostname: &str, port: u32, key: &[u8], policy: HostKeyPolicy) -> Result<(), ControlSessionCreationError> {
+    if policy == HostKeyPolicy::Off {
+        return Ok(());
+    }
+
+    // without a valid key there's nothing to check, so it can't be trusted with any credentials
+    let Some(key_type) = get_key_type(key) else {
+        return Err(ControlSessionCreationError::HostKeyMismatch(format!("'{}' didn't present a valid host key, so it can't be verified.",
+                                                                        hostname)));
+    };
+
+    let key_description = format!("{} key {}", key_type, get_key_fingerprint(key));
+    match KnownHosts::load().check(hostname, port, key) {
+        HostKeyStatus::Known => Ok(()),
+        HostKeyStatus::Revoked(location) => {
+            Err(ControlSessionCreationError::HostKeyMismatch(format!("the host key of '{}' ({}) has been revoked ({}).",
+                                                                     hostname, key_description, location)))
+        },
+        HostKeyStatus::Changed(location) => {
+            Err(ControlSessionCreationError::HostKeyMismatch(format!("the host key of '{}' ({}) doesn't match the known one ({}). \
+                Someone could be intercepting the connection, or the host's key could have changed (i.e. if it's been re-installed), \
+                in which case the old key can be removed with: ssh-keygen -R '{}' -f {}", hostname, key_description, location,
+                get_known_hosts_name(hostname, port), location.rsplit_once(':').map(|(path, _)| path).unwrap_or(&location))))
+        },
+        HostKeyStatus::Unknown if policy == HostKeyPolicy::Strict => {
+            Err(ControlSessionCreationError::ConfigFailure(format!("the host key of '{}' ({}) isn't known, and the host key policy is \
+                'strict'. Connect to it with ssh first to add it to ~/.ssh/known_hosts, or use the 'accept-new' policy.",
+                hostname, key_description)))
+        },
+        HostKeyStatus::Unknown => {
+            // only connect if the key has been recorded, otherwise a different key would be accepted next time as well
+            let path = get_known_hosts_file_path();
+            add_host_key(&path, hostname, port, key).map_err(|err| {
+                ControlSessionCreationError::ConfigFailure(format!("couldn't add the new host key of '{}' ({}) to: {}, error: {}",
+                                                                   hostname, key_description, path, err))
+            })?;
+            host_eprintln!("Warning: permanently added the host key of '{}' ({}) to: {}", hostname, key_description, path);
+            Ok(())
+        }
+    }
+}
+
+// appends the key to the known hosts file
+pub fn add_host_key(path: &str, hostname: &str, port: u32, key: &[u8]) -> std::io::Result<()> {
+    let key_type = get_key_type(key).ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid host key"))?;
+    let line = format!("{} {} {}\n", get_known_hosts_name(hostname, port), key_type, STANDARD.encode(key));
+
+    // this is a single write, so concurrent connections to different hosts can't interleave their lines
+    let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(line.as_bytes())
+}
+
+// removes any keys for the host from the known hosts file, i.e. when an instance has been freshly provisioned with the
+// same IP address as an old one. Returns the number of keys removed.
+pub fn remove_host_keys(path: &str, hostname: &str) -> std::io::Result<usize> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err),
+    };
+
+    let host_name = hostname.to_ascii_lowercase();
+    let port_prefix = format!("[{}]:", host_name);
+    let mut num_removed = 0;
+    let mut new_contents = String::with_capacity(contents.len());
+    for line in contents.lines() {
+        let hosts = line.split_whitespace().find(|item| !item.starts_with('@')).unwrap_or("");
+        if !line.trim_start().starts_with('#') &&
+                (hosts_field_matches(hosts, &host_name) || hosts.split(',').any(|host| host.starts_with(&port_prefix))) {
+            num_removed += 1;
+            continue;
+        }
+        new_contents.push_str(line);
+        new_contents.push('\n');
+    }
+
+    if num_removed > 0 {
+        std::fs::write(path, new_contents)?;
+    }
+    Ok(num_removed)
+}
+
+// the name of the host as it's written in known_hosts files
+fn get_known_hosts_name(hostname: &str, port: u32) -> String {
+    let hostname = hostname.to_ascii_lowercase();
+    if port == 22 {
+        hostname
+    }
+    else {
+        format!("[{}]:{}", hostname, port)
+    }
+}
+
+// the type of the key, i.e. 'ssh-ed25519', which is the first string within the key blob
+pub fn get_key_type(key: &[u8]) -> Option<&str> {
+    let length = u32::from_be_bytes(key.get(0..4)?.try_into().ok()?) as usize;
+    let key_type = std::str::from_utf8(key.get(4..4 + length)?).ok()?;
+    // i.e. "ssh-ed25519" or "ecdsa-sha2-nistp256"
+    Some(key_type).filter(|key_type| !key_type.is_empty() && key_type.chars().all(|c| c.is_ascii_alphanumeric() || "-@.".contains(c)))
+}
+
+// the fingerprint of the key, in the same format as OpenSSH prints it
+pub fn get_key_fingerprint(key: &[u8]) -> String {
+    format!("SHA256:{}", STANDARD_NO_PAD.encode(Sha256::digest(key)))
+}
+
+// whether the hosts field of a known_hosts line matches the host name (which should be lower case, and in the
+// '[hostname]:port' form for non-standard ports). The field is either a hashed name, or a comma-separated list of
+// patterns, which can have '*' and '?' wildcards, and be negated with a '!' prefix.
+fn hosts_field_matches(hosts: &str, host_name: &str) -> bool {
+    if let Some(hashed) = hosts.strip_prefix("|1|") {
+        let Some((salt, hash)) = hashed.split_once('|') else {
+            return false;
+        };
+        let (Ok(salt), Ok(hash)) = (STANDARD.decode(salt), STANDARD.decode(hash)) else {
+            return false;
+        };
+        let Ok(mut mac) = Hmac::<Sha1>::new_from_slice(&salt) else {
+            return false;
+        };
+        mac.update(host_name.as_bytes());
+        return mac.verify_slice(&hash).is_ok();
+    }
+
+    let mut matched = false;
+    for pattern in hosts.split(',') {
+        let (negated, pattern) = match pattern.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern),
+        };
+        if wildcard_matches(&pattern.to_ascii_lowercase(), host_name) {
+            if negated {
+                return false;
+            }
+            matched = true;
+        }
+    }
+    matched
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn known_hosts_from_contents(path: &str, contents: &str) -> KnownHosts {
+        let mut known_hosts = KnownHosts { entries: Vec::new() };
+        known_hosts.add_entries(path, contents);
+        known_hosts
+    }
+
+    fn create_key(key_type: &str, data: &[u8]) -> Vec<u8> {
+        let mut key = (key_type.len() as u32).to_be_bytes().to_vec();
+        key.extend_from_slice(key_type.as_bytes());
+        key.extend_from_slice(data);
+        key
+    }
+
+    #[test]
+    fn test_check_host_key() {
+        let key1 = create_key("ssh-ed25519", b"key1");
+        let key2 = create_key("ssh-ed25519", b"key2");
+        let rsa_key = create_key("ssh-rsa", b"key3");
+        assert_eq!(get_key_type(&key1), Some("ssh-ed25519"));
+
+        // the hashed entry is for '[10.0.0.5]:2222'
+        let salt = b"0123456789abcdefghij";
+        let mut mac = Hmac::<Sha1>::new_from_slice(salt).unwrap();
+        mac.update(b"[10.0.0.5]:2222");
+        let hashed_name = format!("|1|{}|{}", STANDARD.encode(salt), STANDARD.encode(mac.finalize().into_bytes()));
+
+        let contents = format!("# comment\n\
+                                web1.example.com,192.168.0.10 ssh-ed25519 {key1} web1\n\
+                                {hashed_name} ssh-ed25519 {key1}\n\
+                                *.internal,!db.internal ssh-ed25519 {key1}\n\
+                                @revoked * ssh-ed25519 {key2}\n\
+                                @cert-authority *.example.com ssh-ed25519 {key2}\n\
+                                invalid line\n",
+                               key1 = STANDARD.encode(&key1), key2 = STANDARD.encode(&key2));
+        let known_hosts = known_hosts_from_contents("known_hosts", &contents);
+
+        assert_eq!(known_hosts.check("web1.example.com", 22, &key1), HostKeyStatus::Known);
+        assert_eq!(known_hosts.check("WEB1.example.com", 22, &key1), HostKeyStatus::Known);
+        assert_eq!(known_hosts.check("192.168.0.10", 22, &key1), HostKeyStatus::Known);
+        // the entries are for port 22 only
+        assert_eq!(known_hosts.check("web1.example.com", 2222, &key1), HostKeyStatus::Unknown);
+        assert_eq!(known_hosts.check("10.0.0.5", 2222, &key1), HostKeyStatus::Known);
+        assert_eq!(known_hosts.check("10.0.0.5", 22, &key1), HostKeyStatus::Unknown);
+        assert_eq!(known_hosts.check("app.internal", 22, &key1), HostKeyStatus::Known);
+        assert_eq!(known_hosts.check("db.internal", 22, &key1), HostKeyStatus::Unknown);
+
+        assert_eq!(known_hosts.check("web1.example.com", 22, &key2), HostKeyStatus::Revoked("known_hosts:5".to_string()));
+        let key3 = create_key("ssh-ed25519", b"key3");
+        assert_eq!(known_hosts.check("10.0.0.5", 2222, &key3), HostKeyStatus::Changed("known_hosts:3".to_string()));
+        // keys of other types aren't known, rather than changed
+        assert_eq!(known_hosts.check("web1.example.com", 22, &rsa_key), HostKeyStatus::Unknown);
+    }
+
+    #[test]
+    fn test_invalid_host_keys() {
+        // a host which doesn't present a (valid) key must never be connected to, whatever the policy, unless checking is off
+        for key in [Vec::new(), b"garbage".to_vec(), create_key("", b"key1"), create_key("ssh\ted25519", b"key1")] {
+            assert_eq!(get_key_type(&key), None);
+            for policy in [HostKeyPolicy::AcceptNew, HostKeyPolicy::Strict] {
+                assert!(matches!(verify_host_key("web1.example.com", 22, &key, policy), Err(ControlSessionCreationError::HostKeyMismatch(_))));
+            }
+        }
+        assert!(verify_host_key("web1.example.com", 22, &[], HostKeyPolicy::Off).is_ok());
+    }
+
+    #[test]
+    fn test_add_and_remove_host_keys() {
+        let path = std::env::temp_dir().join(format!("prod_test_known_hosts_{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let key1 = create_key("ssh-ed25519", b"key1");
+        let key2 = create_key("ssh-ed25519", b"key2");
+
+        add_host_key(path, "192.168.0.10", 22, &key1).unwrap();
+        add_host_key(path, "192.168.0.10", 2222, &key1).unwrap();
+        add_host_key(path, "192.168.0.11", 22, &key2).unwrap();
+        let known_hosts = known_hosts_from_contents(path, &std::fs::read_to_string(path).unwrap());
+        assert_eq!(known_hosts.check("192.168.0.10", 2222, &key1), HostKeyStatus::Known);
+        assert_eq!(known_hosts.check("192.168.0.11", 22, &key2), HostKeyStatus::Known);
+
+        assert_eq!(remove_host_keys(path, "192.168.0.10").unwrap(), 2);
+        let known_hosts = known_hosts_from_contents(path, &std::fs::read_to_string(path).unwrap());
+        assert_eq!(known_hosts.check("192.168.0.10", 22, &key1), HostKeyStatus::Unknown);
+        assert_eq!(known_hosts.check("192.168.0.11", 22, &key2), HostKeyStatus::Known);
+
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(remove_host_keys(path, "192.168.0.10").unwrap(), 0);
+    }
+}
@@ -19,10 +19,31 @@ extern crate rpassword;
 
 use rpassword::read_password;
 
-use crate::control::control_actions::{ActionError, ControlActionType};
-use crate::control::control_common::{ControlSession, ControlSessionParams, ControlSessionUserAuth, UserAuthUserPass};
-
-use super::control_actions::{ControlActions, ActionProvider};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::column_list_printer::{Alignment, ColumnListPrinter};
+
+use crate::control::control_actions::{ActionError, GenericError, JumpHost, ActionResult, ControlAction, ControlActionType, is_secret_param_name, redact_secret_values};
+use crate::params::ParamValue;
+use crate::vault::Vault;
+use crate::control::control_common::{BecomeParams, ControlSession, ControlSessionParams, ControlSessionUserAuth, JumpHostParams, UserAuthUserPass};
+
+use super::common_actions_unix::{probe_command_succeeds, quote_shell_arg};
+use super::control_output::{host_eprintln, host_println, set_output_prefix, clear_output_prefix};
+use super::control_actions::{ControlActions, ActionProvider, ActionSelection};
+use super::control_action_validation::{validate_actions, ValidationSeverity};
+use super::control_conditions::{Condition, ConditionError};
+use super::control_facts::HostFacts;
+use super::control_inventory::InventoryHost;
+use super::control_known_hosts::HostKeyPolicy;
+use super::control_ssh_config::SshHostConfig;
+use super::control_journal::{generate_run_id, rollback_run, ChangeJournal};
+use super::control_report::{write_report_file, ActionReport, HostReport, RunReport, SummaryReport, SystemValidationReport};
+use super::control_templating::{action_uses_facts, create_registered_result, escape_template_string, facts_from_system_details,
+                                substitute_value_vault_references, TemplateContext, TemplateVars};
 
 use super::action_provider_linux_debian;
 use super::action_provider_linux_fedora;
@@ -32,11 +53,102 @@ pub struct ControlManager {
 
 pub struct ControlGeneralParams {
     pub retry:      bool,
+    // don't connect to the host, just print what each action would do
+    pub dry_run:    bool,
+    // the max number of hosts to run on concurrently when running on multiple hosts
+    pub forks:      usize,
+    // variables from the command line, which override any with the same name in the control script
+    pub vars:       TemplateVars,
+    // path to write a JSON report of the results to
+    pub report_json_path:   Option<String>,
+    // which of the actions to run, from '--start-at', '--only', '--tags' and '--skip-tags'
+    pub action_selection:   ActionSelection,
+    // just print the actions each tag selects, without running anything
+    pub list_tags:          bool,
+    // the full command line args, so the command to resume a failed run can be printed
+    pub command_args:       Vec<String>,
+    // whether to journal the changes to files on the hosts, so they can be rolled back with '--rollback'
+    pub journal_changes:    bool,
+    // the id of the run for the journal, the same for all the hosts
+    pub run_id:             String,
+    // the secrets for any '{{ vault.name }}' expressions, if the vault has been loaded
+    pub vault:              Option<Vault>,
 }
 
 impl ControlGeneralParams {
     pub fn new() -> ControlGeneralParams {
-        ControlGeneralParams { retry: false }
+        ControlGeneralParams { retry: false, dry_run: false, forks: 5, vars: TemplateVars::new(), report_json_path: None,
+                               action_selection: ActionSelection::default(), list_tags: false, command_args: Vec::new(),
+                               journal_changes: true, run_id: generate_run_id(), vault: None }
+    }
+}
+
+// cache of values the user has been prompted for, keyed by type and username/key path, so that when running
+// against multiple hosts the same details only need to be entered once.
+type PromptCache = BTreeMap<(String, String), String>;
+
+// the final connection details for a host, after any prompting.
+struct HostTarget {
+    hostname:   String,
+    port:       u32,
+    auth:       ControlSessionUserAuth,
+    host_key_policy: HostKeyPolicy,
+    // the sudo password, if anything's run as another user and one's needed
+    become_password: Option<String>,
+    // the hosts to connect to the host via, or why they're invalid
+    jump_hosts: Result<Vec<JumpHostParams>, String>,
+}
+
+impl HostTarget {
+    fn get_become_params(&self, actions: &ControlActions, action: Option<&ControlAction>) -> Option<BecomeParams> {
+        get_become_params(actions, action, &self.auth, self.become_password.as_deref())
+    }
+}
+
+// the outcome of running the actions on a single host.
+#[derive(Clone, Debug)]
+pub struct HostRunResult {
+    pub num_unchanged:  usize,
+    pub num_changed:    usize,
+    // actions which weren't run because their 'when' condition wasn't met
+    pub num_skipped:    usize,
+    pub num_failed:     usize,
+    // actions which failed, but had 'continueOnError' set
+    pub num_ignored:    usize,
+    pub num_not_run:    usize,
+    // set if the actions couldn't be run at all, i.e. the host couldn't be connected to
+    pub host_error:     Option<String>,
+    // the '--start-at' value to resume from the action which failed
+    pub resume_at:      Option<String>,
+    // the number of changes to files which were journaled, and so can be rolled back
+    pub num_journaled:  usize,
+
+    // details for the JSON run report
+    pub connection_duration:    Option<Duration>,
+    pub system_validation:      Option<SystemValidationReport>,
+    pub action_reports:         Vec<ActionReport>,
+}
+
+impl HostRunResult {
+    fn new(num_actions: usize) -> HostRunResult {
+        HostRunResult { num_unchanged: 0, num_changed: 0, num_skipped: 0, num_failed: 0, num_ignored: 0, num_not_run: num_actions,
+                        host_error: None, resume_at: None, num_journaled: 0, connection_duration: None, system_validation: None, action_reports: Vec::new() }
+    }
+
+    pub fn was_successful(&self) -> bool {
+        self.host_error.is_none() && self.num_failed == 0
+    }
+
+    pub fn get_status(&self) -> &str {
+        if let Some(host_error) = &self.host_error {
+            host_error.as_str()
+        }
+        else if self.num_failed > 0 {
+            "failed"
+        }
+        else {
+            "success"
+        }
     }
 }
 
@@ -67,11 +179,11 @@ impl ControlManager {
     }
 
     pub fn run_command(&self, host: &str, command: &str) -> CommandResult {
-        println!("Connecting to host: {}...", host);
+        host_println!("Connecting to host: {}...", host);
 
         let target_host = host.to_string();
 
-        println!("Enter password:");
+        host_println!("Enter password:");
         let password = read_password().unwrap();
 
         let username = "peter";
@@ -88,44 +200,354 @@ impl ControlManager {
         let connection = ControlSession::new_dummy_debug(session_params);
 
         if let Err(err) = connection {
-            eprintln!("Error connecting to host: {}, error: {}", host, err);
+            host_eprintln!("Error connecting to host: {}, error: {}", host, err);
             return CommandResult::ErrorCantConnect("".to_string());
         }
         let mut connection = connection.unwrap();
 
-        connection.conn.send_command(command);
-
-        CommandResult::CommandRunOkay(connection.conn.get_previous_stdout_response().to_string())
+        match connection.conn.send_command(command) {
+            Ok(outcome) => CommandResult::CommandRunOkay(outcome.stdout),
+            Err(err) => CommandResult::Failed(err.to_string()),
+        }
     }
 
     pub fn perform_actions(&self, actions: &ControlActions, general_params: ControlGeneralParams) {
-        if actions.actions.is_empty() {
-            eprintln!("Error: no valid actions specified.");
+        if general_params.list_tags {
+            self.list_action_tags(actions, &general_params);
+            return;
+        }
+
+        let provider = self.create_and_validate_provider(actions, &general_params);
+        if provider.is_none() {
             return;
         }
+        let provider = provider.unwrap();
+
+        if general_params.dry_run {
+            self.perform_actions_dry_run(actions, provider.as_ref(), &general_params);
+            return;
+        }
+
+        let mut prompt_cache = PromptCache::new();
+        let target = self.prepare_host_target(actions, &mut prompt_cache);
+
+        let result = self.run_actions_on_host(actions, &target, provider.as_ref(), &general_params);
+
+        let resume_command = result.resume_at.as_ref().and_then(|start_at| create_resume_command(&general_params, start_at, None));
+        if let Some(resume_command) = &resume_command {
+            host_eprintln!("To resume from the failed action, run: {}", resume_command);
+        }
+        print_rollback_details(&general_params, result.num_journaled);
+
+        if let Some(report_path) = &general_params.report_json_path {
+            write_run_report(report_path, &general_params, vec![create_host_report(&target.hostname, &target, &result, resume_command)]);
+        }
+    }
+
+    // connects to each of the hosts (with their own connection settings, from the script or inventory), and prints
+    // the facts about them.
+    pub fn print_host_facts(&self, host_actions: &[(String, ControlActions)], general_params: &ControlGeneralParams) {
+        self.run_on_each_host(host_actions, general_params, &mut |name, provider, connection| {
+            let facts = match provider.get_host_facts(connection) {
+                Ok(facts) => facts,
+                Err(GenericError::Transport(err)) => {
+                    host_eprintln!("Error: Couldn't get the facts for host: {}: {}", name, err);
+                    return;
+                },
+                Err(_err) => {
+                    host_eprintln!("Error: Couldn't get the facts for host: {}: error response was received from host request.", name);
+                    return;
+                }
+            };
+
+            host_println!("Facts for host: {}", name);
+            print_facts_tables(&facts);
+            host_println!();
+        });
+    }
+
+    // connects to each of the hosts, and restores the files changed by the run with the specified id (from the
+    // journal on each host) to their previous states.
+    pub fn rollback_run(&self, host_actions: &[(String, ControlActions)], run_id: &str, general_params: &ControlGeneralParams) {
+        self.run_on_each_host(host_actions, general_params, &mut |name, provider, connection| {
+            host_eprintln!("Rolling back run ID: {} on host: {}...", run_id, name);
+            match rollback_run(provider, connection, run_id) {
+                Ok(num_restored) => host_eprintln!("Restored {} {} on host: {}.", num_restored,
+                                                    if num_restored == 1 {"path"} else {"paths"}, name),
+                Err(err) => host_eprintln!("Error: Couldn't roll back run ID: {} on host: {}: {}.", run_id, name, err),
+            }
+        });
+    }
+
+    // connects to each of the hosts in turn (with their own connection settings, from the script or inventory),
+    // and calls the function with the connection to each one which could be connected to.
+    fn run_on_each_host(&self, host_actions: &[(String, ControlActions)], general_params: &ControlGeneralParams,
+                        func: &mut dyn FnMut(&str, &dyn ActionProvider, &mut ControlSession)) {
+        let mut prompt_cache = PromptCache::new();
+        let targets: Vec<HostTarget> = host_actions.iter().map(|(_, actions)| self.prepare_host_target(actions, &mut prompt_cache)).collect();
+
+        for ((name, actions), target) in host_actions.iter().zip(&targets) {
+            let session_params = ControlSessionParams::new("", target.port, target.auth.clone(), true);
+            let provider = match self.create_provider(&actions.provider, session_params) {
+                Some(provider) => provider,
+                None => {
+                    // don't let one host's settings stop the others being run on
+                    host_eprintln!("Error: Can't find control provider: '{}' for host: {}, skipping it.", actions.provider, name);
+                    continue;
+                }
+            };
+
+            let connection = self.connect_to_host(target, general_params.retry);
+            if connection.is_none() {
+                continue;
+            }
+            let mut connection = connection.unwrap();
+            connection.conn.set_become(target.get_become_params(actions, None));
+
+            func(name, provider.as_ref(), &mut connection);
+        }
+    }
+
+    // runs the control script against each of the hosts, with the host's connection settings applied to
+    // the script's, printing a summary of the results for each host at the end.
+    pub fn perform_actions_on_hosts(&self, actions: &ControlActions, hosts: &[&InventoryHost], general_params: ControlGeneralParams) {
+        // the tags are the same for all the hosts
+        if general_params.list_tags {
+            self.list_action_tags(actions, &general_params);
+            return;
+        }
+
+        let provider = self.create_and_validate_provider(actions, &general_params);
+        if provider.is_none() {
+            return;
+        }
+        let provider = provider.unwrap();
+
+        // work out the final settings for each host first, so we can error before doing anything if there
+        // are problems with any of them...
+        let mut host_actions: Vec<ControlActions> = Vec::with_capacity(hosts.len());
+        for host in hosts {
+            match host.apply_to_actions(actions) {
+                Ok(actions) => host_actions.push(actions),
+                Err(err) => {
+                    host_eprintln!("Error: invalid inventory host settings for {}. Aborting.", err);
+                    return;
+                }
+            }
+        }
+
+        if general_params.dry_run {
+            for (host, actions) in hosts.iter().zip(&host_actions) {
+                host_println!("Host: {}", host.name);
+                self.perform_actions_dry_run(actions, provider.as_ref(), &general_params);
+                host_println!();
+            }
+            return;
+        }
+
+        // do any prompting for all the hosts up-front, so it isn't interleaved with the running of the actions,
+        // and so the same username/password/passphrase only needs to be entered once.
+        let mut prompt_cache = PromptCache::new();
+        let targets: Vec<HostTarget> = host_actions.iter().map(|actions| self.prepare_host_target(actions, &mut prompt_cache)).collect();
+        // similarly, any params of the actions which need prompting for (i.e. new user passwords) need to be done now
+        // as well, before running on multiple threads, and use the same value for all hosts.
+        let mut action_prompt_cache = PromptCache::new();
+        for actions in &mut host_actions {
+            resolve_action_param_prompts(actions, &mut action_prompt_cache);
+        }
+
+        let batch_size = actions.serial.map(|serial| serial.get_batch_size(hosts.len())).unwrap_or(hosts.len());
+        let num_batches = hosts.len().div_ceil(batch_size);
+
+        let mut results: Vec<HostRunResult> = Vec::with_capacity(hosts.len());
+        let mut aborted = false;
+
+        for (batch_index, batch_host_indices) in (0..hosts.len()).collect::<Vec<usize>>().chunks(batch_size).enumerate() {
+            if aborted {
+                for _host_index in batch_host_indices {
+                    let mut skipped_result = HostRunResult::new(actions.actions.len());
+                    skipped_result.host_error = Some("skipped".to_string());
+                    results.push(skipped_result);
+                }
+                continue;
+            }
+
+            if num_batches > 1 {
+                host_eprintln!("Running batch {} of {} ({} {})...", batch_index + 1, num_batches, batch_host_indices.len(),
+                                if batch_host_indices.len() == 1 {"host"} else {"hosts"});
+            }
+
+            let batch_results = self.run_actions_on_hosts_concurrently(batch_host_indices, hosts, &host_actions, &targets,
+                                                                       &general_params);
+
+            // see if too many failed, in which case we don't run any more batches
+            let num_failed = batch_results.iter().filter(|result| !result.was_successful()).count();
+            if num_batches > 1 && batch_index + 1 < num_batches &&
+                    num_failed * 100 > actions.max_fail_percentage as usize * batch_host_indices.len() {
+                host_eprintln!("Error: {} of {} hosts in batch {} failed, which exceeds the max fail percentage of {}%. Aborting remaining batches.",
+                                num_failed, batch_host_indices.len(), batch_index + 1, actions.max_fail_percentage);
+                aborted = true;
+            }
+
+            results.extend(batch_results);
+        }
+
+        let mut clp = ColumnListPrinter::new(8)
+            .set_alignment_multiple(&[1usize, 2, 3, 4, 5, 6], Alignment::Right)
+            .add_titles(["Host", "ok", "changed", "skipped", "failed", "ignored", "not run", "Status"]);
+
+        for (host, result) in hosts.iter().zip(&results) {
+            let status = result.get_status();
+            clp.add_row_strings(&[&host.name, &result.num_unchanged.to_string(), &result.num_changed.to_string(),
+                                  &result.num_skipped.to_string(), &result.num_failed.to_string(), &result.num_ignored.to_string(),
+                                  &result.num_not_run.to_string(), status]);
+        }
+
+        let num_successful = results.iter().filter(|result| result.was_successful()).count();
+        host_eprintln!();
+        host_eprintln!("Summary for {} {} ({} successful):", hosts.len(), if hosts.len() == 1 {"host"} else {"hosts"}, num_successful);
+        eprint!("{}", clp);
+
+        let resume_commands: Vec<Option<String>> = hosts.iter().zip(&results).map(|(host, result)| {
+            result.resume_at.as_ref().and_then(|start_at| create_resume_command(&general_params, start_at, Some(&host.name)))
+        }).collect();
+        if resume_commands.iter().any(|command| command.is_some()) {
+            host_eprintln!();
+            host_eprintln!("To resume from the failed actions, run:");
+            for (host, resume_command) in hosts.iter().zip(&resume_commands) {
+                if let Some(resume_command) = resume_command {
+                    host_eprintln!(" {}: {}", host.name, resume_command);
+                }
+            }
+        }
+        print_rollback_details(&general_params, results.iter().map(|result| result.num_journaled).sum());
+
+        if let Some(report_path) = &general_params.report_json_path {
+            let host_reports = hosts.iter().zip(&targets).zip(&results).zip(resume_commands)
+                                    .map(|(((host, target), result), resume_command)| {
+                                        create_host_report(&host.name, target, result, resume_command)
+                                    }).collect();
+            write_run_report(report_path, &general_params, host_reports);
+        }
+    }
+
+    // runs the actions on each of the specified hosts on worker threads, with at most 'forks' running at once,
+    // returning the results in the same order as the host indices.
+    fn run_actions_on_hosts_concurrently(&self, host_indices: &[usize], hosts: &[&InventoryHost], host_actions: &[ControlActions],
+                                         targets: &[HostTarget], general_params: &ControlGeneralParams) -> Vec<HostRunResult> {
+        let num_workers = general_params.forks.clamp(1, host_indices.len().max(1));
+
+        // the index into host_indices of the next host for a worker to run on
+        let next_index = AtomicUsize::new(0);
+        let results: Mutex<Vec<Option<HostRunResult>>> = Mutex::new(vec![None; host_indices.len()]);
+
+        std::thread::scope(|scope| {
+            for _ in 0..num_workers {
+                scope.spawn(|| {
+                    loop {
+                        let index = next_index.fetch_add(1, Ordering::SeqCst);
+                        if index >= host_indices.len() {
+                            break;
+                        }
+                        let host_index = host_indices[index];
+                        let actions = &host_actions[host_index];
+
+                        set_output_prefix(&format!("[{}] ", hosts[host_index].name));
+
+                        // each thread needs its own provider...
+                        let session_params = ControlSessionParams::new("", targets[host_index].port, targets[host_index].auth.clone(), true);
+                        let result = match self.create_provider(&actions.provider, session_params) {
+                            Some(provider) => self.run_actions_on_host(actions, &targets[host_index], provider.as_ref(), general_params),
+                            None => {
+                                // this shouldn't be possible, as it's been checked before...
+                                let mut result = HostRunResult::new(actions.actions.len());
+                                result.host_error = Some("invalid provider".to_string());
+                                result
+                            }
+                        };
+
+                        clear_output_prefix();
+
+                        // another worker panicking shouldn't lose this host's result
+                        results.lock().unwrap_or_else(|err| err.into_inner())[index] = Some(result);
+                    }
+                });
+            }
+        });
+
+        results.into_inner().unwrap_or_else(|err| err.into_inner()).into_iter().enumerate().map(|(index, result)| {
+            result.unwrap_or_else(|| {
+                let mut result = HostRunResult::new(host_actions[host_indices[index]].actions.len());
+                result.host_error = Some("the host's run didn't complete".to_string());
+                result
+            })
+        }).collect()
+    }
+
+    // creates the provider, and validates the actions against it, before prompting for anything or connecting, so that
+    // we don't end up leaving the host half-configured due to a typo in a later action...
+    fn create_and_validate_provider(&self, actions: &ControlActions, general_params: &ControlGeneralParams) -> Option<Box<dyn ActionProvider>> {
+        if actions.actions.is_empty() {
+            host_eprintln!("Error: no valid actions specified.");
+            return None;
+        }
 
         // TODO: come up with a better way of handling this partial initialisation / ordering dilema to work
         //       out if a provider exists before querying for usernames and passwords...
-        let mut session_params = ControlSessionParams::new("",
-                                                           actions.port.unwrap_or(22),
-                                                           actions.auth.clone(), true);
+        let session_params = ControlSessionParams::new("",
+                                                       actions.port.unwrap_or(22),
+                                                       actions.auth.clone(), true);
 
         // check the provider exists as a provider name...
         let provider = self.create_provider(&actions.provider, session_params);
         if provider.is_none() {
-            eprintln!("Error: Can't find control provider: '{}'.", actions.provider);
-            return;
+            host_eprintln!("Error: Can't find control provider: '{}'.", actions.provider);
+            return None;
         }
 
         let provider = provider.unwrap();
 
+        let mut num_errors = render_and_validate_actions(actions, &actions.actions, provider.as_ref(), general_params, "");
+        for handler in &actions.handlers {
+            num_errors += render_and_validate_actions(actions, &handler.actions, provider.as_ref(), general_params,
+                                                      &format!("Handler '{}': ", handler.name));
+        }
+
+        if num_errors > 0 && !general_params.dry_run {
+            host_eprintln!("Error: {} {} found while validating the actions. Aborting.", num_errors,
+                        if num_errors == 1 {"problem was"} else {"problems were"});
+            return None;
+        }
+
+        match general_params.action_selection.get_selected(&actions.actions) {
+            Ok(selected) if !selected.contains(&true) => {
+                host_eprintln!("Error: no actions were selected to run with {}.", general_params.action_selection.get_options_description());
+                return None;
+            },
+            Ok(_) => {},
+            Err(err) => {
+                host_eprintln!("Error: {}", err);
+                return None;
+            }
+        }
+
+        for tag in general_params.action_selection.get_unknown_tags(&actions.actions) {
+            host_eprintln!("Warning: none of the actions have the tag: '{}'.", tag);
+        }
+
+        Some(provider)
+    }
+
+    // works out the final hostname, port and auth details to connect to the host with, prompting the user
+    // for anything which wasn't specified.
+    fn prepare_host_target(&self, actions: &ControlActions, prompt_cache: &mut PromptCache) -> HostTarget {
         let mut asked_for_hostname = false;
 //        let mut asked_for_username = false;
 
         let mut hostname = String::new();
         let mut port: Option<u32> = None;
         if actions.hostname.is_empty() || actions.hostname == "$PROMPT" {
-            eprintln!("Please enter hostname to connect to:");
+            host_eprintln!("Please enter hostname to connect to:");
             std::io::stdin().read_line(&mut hostname).expect("Error reading hostname from std input");
             hostname = hostname.trim().to_string();
 
@@ -136,7 +558,7 @@ impl ControlManager {
                 
                 let parsed_port = split_pair.1.parse::<u32>();
                 if let Err(_err) = parsed_port {
-                    eprintln!("Error parsing suffix port number after hostname: {}", split_pair.1);
+                    host_eprintln!("Error parsing suffix port number after hostname: {}", split_pair.1);
                 }
                 else {
                     port = Some(parsed_port.unwrap());
@@ -156,26 +578,78 @@ impl ControlManager {
             port = actions.port;
         }
 
-        // connect to host
-        let target_host = hostname;
-        let target_port = port;
-
         // we take a local copy, so we can modify it and pass it in to be used in a final state...
         let mut auth = actions.auth.clone();
 
-        // attempt to generalise the 'user' part for both enums, as it's needed for both,
-        // but it makes things a bit verbose...
-        let config_username = 
-            match &auth {
-                ControlSessionUserAuth::UserPass(userpass) => &userpass.username,
-                ControlSessionUserAuth::PublicKey(publickey) => &publickey.username,
-            }.clone();
+        // fill in anything not specified from ~/.ssh/config, if the hostname is an alias there
+        let ssh_config = SshHostConfig::load(&hostname);
+        ssh_config.apply(&mut hostname, &mut port, &mut auth);
+
+        let target_host = hostname;
+
+        let username = Self::prompt_for_auth_details(&mut auth, &target_host, asked_for_hostname, prompt_cache);
+
+        let jump_hosts = Self::get_jump_hosts(actions, &ssh_config).map(|jump_hosts| {
+            jump_hosts.into_iter().map(|jump_host| {
+                // jump hosts without their own auth use the same auth as the host
+                let mut jump_hostname = jump_host.hostname;
+                let mut jump_port = jump_host.port;
+                let mut jump_auth = jump_host.auth.unwrap_or_else(|| actions.auth.clone());
+                if let Some(user) = &jump_host.user {
+                    jump_auth.set_username(user);
+                }
+                SshHostConfig::load(&jump_hostname).apply(&mut jump_hostname, &mut jump_port, &mut jump_auth);
+                Self::prompt_for_auth_details(&mut jump_auth, &jump_hostname, false, prompt_cache);
+                JumpHostParams { hostname: jump_hostname, port: jump_port.unwrap_or(22), auth: jump_auth }
+            }).collect()
+        });
+
+        // the sudo password is only needed if anything's run as another user
+        let mut become_password = None;
+        if actions.uses_become() && !actions.become_password.is_empty() {
+            if actions.become_password == "$PROMPT" {
+                if let Some(password) = prompt_cache.get(&("becomePassword".to_string(), username.clone())) {
+                    become_password = Some(password.clone());
+                }
+                else {
+                    if !asked_for_hostname {
+                        host_eprintln!("Enter sudo password for user '{}' on host '{}':", &username, &target_host);
+                    }
+                    else {
+                        host_eprintln!("Enter sudo password for user '{}':", &username);
+                    }
+                    let password = read_password().unwrap();
+                    prompt_cache.insert(("becomePassword".to_string(), username.clone()), password.clone());
+                    become_password = Some(password);
+                }
+            }
+            else {
+                become_password = Some(actions.become_password.clone());
+            }
+        }
+
+        HostTarget { hostname: target_host, port: port.unwrap_or(22), auth, host_key_policy: actions.host_key_policy, become_password,
+                     jump_hosts }
+    }
+
+    // prompts for any of the username, password or key passphrase which are needed for the auth (and weren't specified),
+    // returning the username.
+    fn prompt_for_auth_details(auth: &mut ControlSessionUserAuth, target_host: &str, asked_for_hostname: bool,
+                               prompt_cache: &mut PromptCache) -> String {
+        // the 'user' part is needed for all the auth types
+        let config_username = auth.get_username().to_string();
 
         let mut username = String::new();
         if config_username.is_empty() || config_username == "$PROMPT" {
-            eprintln!("Please enter username to authenticate with:");
-            std::io::stdin().read_line(&mut username).expect("Error reading username from std input");
-            username = username.trim().to_string();
+            if let Some(cached_username) = prompt_cache.get(&("username".to_string(), String::new())) {
+                username = cached_username.clone();
+            }
+            else {
+                host_eprintln!("Please enter username to authenticate with:");
+                std::io::stdin().read_line(&mut username).expect("Error reading username from std input");
+                username = username.trim().to_string();
+                prompt_cache.insert(("username".to_string(), String::new()), username.clone());
+            }
 
 //            asked_for_username = true;
         }
@@ -183,52 +657,101 @@ impl ControlManager {
             username = config_username;
         }
 
-        // now do the two enum types separately, and apply the above username to the contents of that
+        // now do the enum types separately, and apply the above username to the contents of that
         // enum...
-        if let ControlSessionUserAuth::UserPass(userpass) = &mut auth {
+        if let ControlSessionUserAuth::UserPass(userpass) = auth {
             userpass.username = username.clone();
 
             // TODO: do we want to maybe allow empty passwords?
             if userpass.password.is_empty() || userpass.password == "$PROMPT" {
-                if !asked_for_hostname {
-                    eprintln!("Enter password for user '{}' on host '{}':", &username, &target_host);
+                if let Some(password) = prompt_cache.get(&("password".to_string(), username.clone())) {
+                    userpass.password = password.clone();
                 }
                 else {
-                    eprintln!("Enter password for user '{}':", &username);
+                    if !asked_for_hostname {
+                        host_eprintln!("Enter password for user '{}' on host '{}':", &username, target_host);
+                    }
+                    else {
+                        host_eprintln!("Enter password for user '{}':", &username);
+                    }
+                    userpass.password = read_password().unwrap();
+                    prompt_cache.insert(("password".to_string(), username.clone()), userpass.password.clone());
                 }
-                userpass.password = read_password().unwrap();
             }
             
         }
-        else if let ControlSessionUserAuth::PublicKey(publickey) = &mut auth {
+        else if let ControlSessionUserAuth::PublicKey(publickey) = auth {
             publickey.username = username.clone();
 
             // explicitly allow empty passphrases for now...
             if publickey.passphrase == "$PROMPT" {
-                if !asked_for_hostname {
-                    eprintln!("Enter key passphrase for user '{}' on host '{}':", &username, &target_host);
+                if let Some(passphrase) = prompt_cache.get(&("passphrase".to_string(), publickey.privatekey_path.clone())) {
+                    publickey.passphrase = passphrase.clone();
                 }
                 else {
-                    eprintln!("Enter key passphrase:");
+                    if !asked_for_hostname {
+                        host_eprintln!("Enter key passphrase for user '{}' on host '{}':", &username, target_host);
+                    }
+                    else {
+                        host_eprintln!("Enter key passphrase:");
+                    }
+                    publickey.passphrase = read_password().unwrap();
+                    prompt_cache.insert(("passphrase".to_string(), publickey.privatekey_path.clone()), publickey.passphrase.clone());
                 }
-                publickey.passphrase = read_password().unwrap();
             }
         }
+        else if let ControlSessionUserAuth::Agent(agent) = auth {
+            // the keys come from ssh-agent, so there's nothing else to prompt for
+            agent.username = username.clone();
+        }
+
+        username
+    }
+
+    // the hosts to connect to the host via, from the control script (or inventory), or otherwise ~/.ssh/config's ProxyJump.
+    fn get_jump_hosts(actions: &ControlActions, ssh_config: &SshHostConfig) -> Result<Vec<JumpHost>, String> {
+        match (&actions.jump_hosts, ssh_config.get_proxy_jump()) {
+            (Some(jump_hosts), _) => Ok(jump_hosts.clone()),
+            (None, Some(proxy_jump)) => JumpHost::parse_chain(proxy_jump).map_err(|err| format!("invalid ProxyJump in ~/.ssh/config: {}", err)),
+            (None, None) => Ok(Vec::new()),
+        }
+    }
+
+    // connects to the host, retrying if requested (and it might help), printing any errors.
+    fn connect_to_host(&self, target: &HostTarget, retry: bool) -> Option<ControlSession> {
+        let target_host = &target.hostname;
+        let auth = &target.auth;
+
+        let jump_hosts = match &target.jump_hosts {
+            Ok(jump_hosts) => jump_hosts,
+            Err(err) => {
+                host_eprintln!("Error: can't connect to host '{}': {}", target_host, err);
+                return None;
+            }
+        };
 
         let mut connection;
         // always loop for retry logic, but we break out normally on success...
         const RETRY_LIMIT: usize = 15;
         let mut retry_count = 0;
 
-        let port_number = target_port.unwrap_or(22);
+        let port_number = target.port;
 
         loop {
-            eprintln!("Connecting to {}:{}...", target_host, port_number);
+            if jump_hosts.is_empty() {
+                host_eprintln!("Connecting to {}:{}...", target_host, port_number);
+            }
+            else {
+                let via: Vec<String> = jump_hosts.iter().map(|jump_host| format!("{}:{}", jump_host.hostname, jump_host.port)).collect();
+                host_eprintln!("Connecting to {}:{} via {}...", target_host, port_number, via.join(" -> "));
+            }
 
             // Now configure ControlSessionParams properly here...
             // TODO: as above, not really happy with this, but there's various "not great" ways of solving the issue
             //       I don't like, so I'm happier (only just) with this for the moment...
-            session_params = ControlSessionParams::new(&target_host, port_number, auth.clone(), true);
+            let mut session_params = ControlSessionParams::new(target_host, port_number, auth.clone(), true);
+            session_params.host_key_policy = target.host_key_policy;
+            session_params.jump_hosts = jump_hosts.clone();
 
 #[cfg(feature = "openssh")]
             let inner_connection = ControlSession::new_openssh(session_params);
@@ -249,58 +772,135 @@ impl ControlManager {
             let connection_error = inner_connection.err().unwrap();
 
             // TODO: have a re-think about the impl of should_attempt_connection_retry()...
-            let should_retry = general_params.retry && connection_error.should_attempt_connection_retry();
+            let should_retry = retry && connection_error.should_attempt_connection_retry();
 
             if should_retry {
                 // we want to retry automatically after a pause...
                 if retry_count <= RETRY_LIMIT {
-                    eprintln!("Connection failed... will retry in 30 secs...");
+                    host_eprintln!("Connection failed... will retry in 30 secs...");
                     retry_count += 1;
                 }
                 else {
-                    eprintln!("Connection failed after: {} retry attempts, will abort. Latest error was: {}",
+                    host_eprintln!("Connection failed after: {} retry attempts, will abort. Latest error was: {}",
                              retry_count, connection_error);
-                    return
+                    return None;
                 }
                 std::thread::sleep(std::time::Duration::from_secs(30));
-                eprintln!("Retrying connection...");
+                host_eprintln!("Retrying connection...");
             }
             else {
                 // we don't want to retry, just error...
                 // TODO: sprinkling this 22 default everywhere isn't great... maybe make it non-optional
                 //       in the params struct so it's just default constructed with 22, and overridden
                 //       if necessary?
-                eprintln!("Error connecting to: {}:{}, error: {}...", target_host, target_port.unwrap_or(22),
+                host_eprintln!("Error connecting to: {}:{}, error: {}...", target_host, port_number,
                             connection_error);
-                return;
+                return None;
             }
         }
 
-        eprintln!("Connected successfully.");
+        // so that things can be run as other users, if the actions need to
+        connection.enable_become();
+
+        Some(connection)
+    }
+
+    // connects to the host, and runs all the actions in order, stopping at the first failure.
+    fn run_actions_on_host(&self, actions: &ControlActions, target: &HostTarget, provider: &dyn ActionProvider,
+                           general_params: &ControlGeneralParams) -> HostRunResult {
+        let num_actions = actions.actions.len();
+        let mut run_result = HostRunResult::new(num_actions);
+
+        let connection_start_time = Instant::now();
+        let connection = self.connect_to_host(target, general_params.retry);
+        if connection.is_none() {
+            run_result.host_error = Some("unreachable".to_string());
+            return run_result;
+        }
+        let mut connection = connection.unwrap();
+
+        host_eprintln!("Connected successfully.");
+        run_result.connection_duration = Some(connection_start_time.elapsed());
+
+        // log the commands run, for the report
+        connection.enable_command_logging();
+
+        // fact gathering and validation is run with the script's privilege escalation settings, and actions with their own
+        connection.conn.set_become(target.get_become_params(actions, None));
+
+        let mut template_context = create_template_context(actions, general_params);
+        let needs_facts = actions.actions.iter().chain(actions.handlers.iter().flat_map(|handler| handler.actions.iter()))
+                                .any(|action| action_uses_facts(action) ||
+                                     action.when.iter().chain(action.until.iter())
+                                           .any(|condition| Condition::parse(condition).is_ok_and(|condition| condition.uses_facts())));
 
         // see if we need to validate the system details against constraints
         // (i.e. to check it's say "Debian" >= 12)
+        // whether all the facts have been got, rather than just the distro details
+        let mut have_all_facts = false;
         if actions.system_validation.needs_checking() {
-            eprintln!("Performing required System validation...");
+            host_eprintln!("Performing required System validation...");
 
-            // we need to validate something, so ask the provider for details
-            let system_details = provider.get_system_details(&mut connection);
+            // we need to validate something, so ask the provider for details (all the facts if there are resource
+            // constraints, or the actions need them anyway)
+            have_all_facts = actions.system_validation.needs_host_facts() || needs_facts;
+            let facts = if have_all_facts {
+                provider.get_host_facts(&mut connection).inspect(|facts| template_context.set_facts(facts.to_template_vars()))
+            }
+            else {
+                provider.get_system_details(&mut connection).map(|result| {
+                    template_context.set_facts(facts_from_system_details(&result));
+                    HostFacts::from_system_details(&result)
+                })
+            };
             // TODO: handle error value more correctly (currently inner implementations of get_system_details() eprintln())...
-            if let Err(_err) = system_details {
-                eprintln!("Error: Couldn't validate system host details: error response was received from host request. Aborting.");
-                return;
+            if let Err(GenericError::Transport(err)) = &facts {
+                host_eprintln!("Error: Couldn't validate system host details: {}. Aborting.", err);
+                run_result.host_error = Some("system validation failed".to_string());
+                return run_result;
+            }
+            if let Err(_err) = facts {
+                host_eprintln!("Error: Couldn't validate system host details: error response was received from host request. Aborting.");
+                run_result.host_error = Some("system validation failed".to_string());
+                return run_result;
             }
-            if let Ok(result) = system_details {
+            if let Ok(facts) = facts {
                 // we've got details, so check they're acceptable to the validation constraints described...
-                if !actions.system_validation.check_actual_distro_values(&result.distr_id, &result.release) {
+                let failed_constraints = actions.system_validation.get_failed_constraints(&facts);
+                run_result.system_validation = Some(SystemValidationReport { distro_id: facts.distro_id.clone(),
+                                                                             release: facts.release.clone(),
+                                                                             passed: failed_constraints.is_empty(),
+                                                                             failed_constraints: failed_constraints.clone() });
+                if !failed_constraints.is_empty() {
                     // the check failed...
-                    eprintln!("Error: System validation failed expected constraints. System release: '{}'. Aborting.", result.release);
-                    return;
+                    for failed_constraint in &failed_constraints {
+                        host_eprintln!("Error: System validation failed expected constraint: {}.", failed_constraint);
+                    }
+                    host_eprintln!("Aborting.");
+                    run_result.host_error = Some("system validation failed".to_string());
+                    return run_result;
                 }
                 // otherwise the check passed, so we can just continue...
             }
 
-            eprintln!("System validation was successful.");
+            host_eprintln!("System validation was successful.");
+        }
+
+        if needs_facts && !have_all_facts {
+            // some of the action params use host facts, so we need to get them
+            match provider.get_host_facts(&mut connection) {
+                Ok(facts) => template_context.set_facts(facts.to_template_vars()),
+                Err(GenericError::Transport(err)) => {
+                    host_eprintln!("Error: Couldn't get the host facts needed by the actions: {}. Aborting.", err);
+                    run_result.host_error = Some("facts unavailable".to_string());
+                    return run_result;
+                },
+                Err(_err) => {
+                    host_eprintln!("Error: Couldn't get the host facts needed by the actions: error response was received from host request. Aborting.");
+                    run_result.host_error = Some("facts unavailable".to_string());
+                    return run_result;
+                }
+            }
         }
 
 /*
@@ -309,128 +909,836 @@ impl ControlManager {
         map.insert(ControlActionType::AddUser, &closure as &dyn Fn(_, _) -> _);
 */
 
-        let num_actions = actions.actions.len();
-        eprintln!("Running {} {}...", num_actions, if num_actions == 1 {"action"} else {"actions"});
+        // the commands run to get the host details aren't part of any action, so aren't needed
+        connection.conn.take_executed_commands();
+
+        // this has already been validated, but inventory hosts have their own copy of the actions...
+        let selected = general_params.action_selection.get_selected(&actions.actions).unwrap_or_else(|_| vec![true; num_actions]);
+        let num_selected = selected.iter().filter(|selected| **selected).count();
+        if general_params.action_selection.is_set() {
+            host_eprintln!("Running {} of {} {} (selected with {})...", num_selected, num_actions,
+                            if num_actions == 1 {"action"} else {"actions"}, general_params.action_selection.get_options_description());
+        }
+        else {
+            host_eprintln!("Running {} {}...", num_actions, if num_actions == 1 {"action"} else {"actions"});
+        }
 
-        let mut success = true;
+        // the names of the handlers which have been notified by actions which changed something
+        let mut notified_handlers: BTreeSet<String> = BTreeSet::new();
+
+        let mut journal = ChangeJournal::new(&general_params.run_id, general_params.journal_changes);
 
         for (count, action) in actions.actions.iter().enumerate() {
-            // TODO: Better (automatic - based off lookup) despatch than this...
-            //       Although it's not clear how to easily do that (see above attempt), or if
-            //       there's actually a benefit to doing it that way...
+            if !selected[count] {
+                continue;
+            }
 
             // verbosely print the action we're running...
-            eprintln!(" Running Action {}: {}...", count + 1, action.action);
+            host_eprintln!(" Running Action {}: {}{}...", count + 1, action.action, get_action_details_description(action));
 
-            let result = match action.action {
-                ControlActionType::GenericCommand => {
-                    provider.generic_command(&mut connection, action)
-                },
-                ControlActionType::AddUser => {
-                    provider.add_user(&mut connection, action)
-                },
-                ControlActionType::CreateDirectory => {
-                    provider.create_directory(&mut connection, action)
-                },
-                ControlActionType::RemoveDirectory => {
-                    provider.remove_directory(&mut connection, action)
-                },
-                ControlActionType::InstallPackages => {
-                    provider.install_packages(&mut connection, action)
-                },
-                ControlActionType::RemovePackages => {
-                    provider.remove_packages(&mut connection, action)
-                },
-                ControlActionType::SystemCtl => {
-                    provider.systemctrl(&mut connection, action)
-                },
-                ControlActionType::Firewall => {
-                    provider.firewall(&mut connection, action)
-                },
-                ControlActionType::EditFile => {
-                    provider.edit_file(&mut connection, action)
-                },
-                ControlActionType::CopyPath => {
-                    provider.copy_path(&mut connection, action)
-                },
-                ControlActionType::RemoveFile => {
-                    provider.remove_file(&mut connection, action)
-                },
-                ControlActionType::DownloadFile => {
-                    provider.download_file(&mut connection, action)
-                },
-                ControlActionType::TransmitFile => {
-                    provider.transmit_file(&mut connection, action)
-                },
-                ControlActionType::ReceiveFile => {
-                    provider.receive_file(&mut connection, action)
-                },
-                ControlActionType::CreateSymlink => {
-                    provider.create_symlink(&mut connection, action)
-                },
-                ControlActionType::SetTimeZone => {
-                    provider.set_time_zone(&mut connection, action)
-                },
-                ControlActionType::DisableSwap => {
-                    provider.disable_swap(&mut connection, action)
+            connection.conn.set_become(target.get_become_params(actions, Some(action)));
+            let outcome = run_and_report_action(provider, &mut connection, &mut template_context, &mut journal, action, count, None,
+                                                &mut run_result);
+
+            match outcome {
+                ActionOutcome::Completed(ActionResult::Changed) => notified_handlers.extend(action.notify.iter().cloned()),
+                ActionOutcome::Failed(_) => {
+                    run_result.resume_at = Some(get_resume_start_at(&actions.actions, count));
+                    break;
                 },
-                ControlActionType::CreateFile => {
-                    provider.create_file(&mut connection, action)
+                _ => {}
+            }
+        }
+
+        run_result.num_not_run = num_selected - (run_result.num_unchanged + run_result.num_changed + run_result.num_skipped +
+                                                run_result.num_failed + run_result.num_ignored);
+
+        // now run any handlers which were notified, in the order they were defined, and only once each
+        if !notified_handlers.is_empty() {
+            if run_result.num_failed > 0 {
+                host_eprintln!("Not running {} notified {}, as an action failed.", notified_handlers.len(),
+                            if notified_handlers.len() == 1 {"handler"} else {"handlers"});
+            }
+            else {
+                'handlers: for handler in actions.handlers.iter().filter(|handler| notified_handlers.contains(&handler.name)) {
+                    host_eprintln!("Running handler: '{}'...", handler.name);
+
+                    for (count, action) in handler.actions.iter().enumerate() {
+                        host_eprintln!(" Running Handler Action {}: {}{}...", count + 1, action.action, get_action_details_description(action));
+
+                        connection.conn.set_become(target.get_become_params(actions, Some(action)));
+                        let outcome = run_and_report_action(provider, &mut connection, &mut template_context, &mut journal, action,
+                                                            count, Some(&handler.name), &mut run_result);
+
+                        if let ActionOutcome::Failed(_) = outcome {
+                            break 'handlers;
+                        }
+                    }
+                }
+            }
+        }
+
+        run_result.num_journaled = journal.num_entries;
+
+        if run_result.num_failed == 0 {
+            host_eprintln!("Successfully ran {}.", if num_actions == 1 {"action"} else {"actions"});
+        }
+
+        host_eprintln!("Summary: ok: {}, changed: {}, skipped: {}, failed: {}, ignored: {}, not run: {}", run_result.num_unchanged,
+                    run_result.num_changed, run_result.num_skipped, run_result.num_failed, run_result.num_ignored, run_result.num_not_run);
+
+        run_result
+    }
+
+    // prints the actions each of the tags selects, and which actions would be run with the current '--tags' / '--skip-tags'
+    // (and '--start-at' / '--only') options.
+    fn list_action_tags(&self, actions: &ControlActions, general_params: &ControlGeneralParams) {
+        let mut tags: Vec<&String> = actions.actions.iter().flat_map(|action| action.tags.iter()).collect();
+        tags.sort();
+        tags.dedup();
+
+        let print_actions = |filter: &dyn Fn(&ControlAction) -> bool| {
+            for (count, action) in actions.actions.iter().enumerate().filter(|(_, action)| filter(action)) {
+                host_println!("  Action {}: {}{}", count + 1, action.action, get_action_details_description(action));
+            }
+        };
+
+        if tags.is_empty() {
+            host_println!("None of the {} actions have tags.", actions.actions.len());
+        }
+
+        for tag in tags {
+            let num_tagged = actions.actions.iter().filter(|action| action.tags.contains(tag)).count();
+            host_println!("Tag '{}' selects {} {}:", tag, num_tagged, if num_tagged == 1 {"action"} else {"actions"});
+            print_actions(&|action| action.tags.contains(tag));
+        }
+
+        let num_untagged = actions.actions.iter().filter(|action| action.tags.is_empty()).count();
+        if num_untagged > 0 {
+            host_println!("Untagged actions (not run when using '--tags'):");
+            print_actions(&|action| action.tags.is_empty());
+        }
+
+        let selection = &general_params.action_selection;
+        for tag in selection.get_unknown_tags(&actions.actions) {
+            host_eprintln!("Warning: none of the actions have the tag: '{}'.", tag);
+        }
+        if selection.is_set() {
+            match selection.get_selected(&actions.actions) {
+                Ok(selected) => {
+                    let indices: Vec<String> = selected.iter().enumerate().filter(|(_, selected)| **selected)
+                                                       .map(|(index, _)| (index + 1).to_string()).collect();
+                    host_println!("With {}, {} of {} actions would be run: {}", selection.get_options_description(), indices.len(),
+                                    actions.actions.len(), if indices.is_empty() {"none".to_string()} else {indices.join(", ")});
                 },
-                ControlActionType::AddGroup => {
-                    provider.add_group(&mut connection, action)
+                Err(err) => host_eprintln!("Error: {}", err),
+            }
+        }
+    }
+
+    // runs all the actions against a recording connection which doesn't connect to the host,
+    // and prints the ordered list of remote commands and file transfers each action would perform.
+    fn perform_actions_dry_run(&self, actions: &ControlActions, provider: &dyn ActionProvider, general_params: &ControlGeneralParams) {
+        let mut port = actions.port;
+        let mut jump_hosts = Self::get_jump_hosts(actions, &SshHostConfig::default());
+        let hostname = if actions.hostname.is_empty() || actions.hostname == "$PROMPT" {
+            "<prompted hostname>".to_string()
+        }
+        else {
+            // show what the host alias resolves to from ~/.ssh/config
+            let mut hostname = actions.hostname.clone();
+            let mut auth = actions.auth.clone();
+            let ssh_config = SshHostConfig::load(&hostname);
+            ssh_config.apply(&mut hostname, &mut port, &mut auth);
+            jump_hosts = Self::get_jump_hosts(actions, &ssh_config);
+            hostname
+        };
+        let port_number = port.unwrap_or(22);
+
+        host_println!("Dry-run for host: {}:{} - no connection will be made, and nothing will be modified.", hostname, port_number);
+        match jump_hosts {
+            Ok(jump_hosts) if !jump_hosts.is_empty() => {
+                let via: Vec<String> = jump_hosts.iter().map(|jump_host| format!("{}:{}", jump_host.hostname, jump_host.port.unwrap_or(22))).collect();
+                host_println!("The host would be connected to via: {}", via.join(" -> "));
+            },
+            Ok(_) => {},
+            Err(err) => host_println!("Warning: {}", err),
+        }
+
+        if actions.system_validation.needs_checking() {
+            host_println!("System validation would be performed before running any actions ({}).", actions.system_validation.get_description());
+        }
+
+        let session_params = ControlSessionParams::new(&hostname, port_number, actions.auth.clone(), true);
+        // this can't fail, but...
+        let mut connection = ControlSession::new_recording(session_params).unwrap();
+        connection.enable_become();
+
+        // the sudo password isn't prompted for, but whether there is one changes the commands
+        let become_password = (!actions.become_password.is_empty()).then_some("");
+
+        // host facts and registered values aren't available without a connection, so any expressions using them
+        // are left as they are
+        let mut template_context = create_template_context(actions, general_params);
+        template_context.set_pending_vars(actions.get_registered_var_names());
+
+        let num_actions = actions.actions.len();
+        let selected = general_params.action_selection.get_selected(&actions.actions).unwrap_or_else(|_| vec![true; num_actions]);
+        if general_params.action_selection.is_set() {
+            let num_selected = selected.iter().filter(|selected| **selected).count();
+            host_println!("Would run {} of {} {} (selected with {}):", num_selected, num_actions,
+                            if num_actions == 1 {"action"} else {"actions"}, general_params.action_selection.get_options_description());
+        }
+        else {
+            host_println!("Would run {} {}:", num_actions, if num_actions == 1 {"action"} else {"actions"});
+        }
+
+        for (count, action) in actions.actions.iter().enumerate() {
+            if !selected[count] {
+                continue;
+            }
+
+            host_println!(" Action {}: {}{}", count + 1, action.action, get_action_details_description(action));
+            if !action.notify.is_empty() {
+                host_println!("   Notifies handlers if changed: {}", action.notify.join(", "));
+            }
+
+            connection.conn.set_become(get_become_params(actions, Some(action), &actions.auth, become_password));
+            dry_run_action(provider, &mut connection, &mut template_context, action);
+        }
+
+        if !actions.handlers.is_empty() {
+            host_println!("Handlers (only run at the end if notified by an action which changed something):");
+
+            for handler in &actions.handlers {
+                host_println!(" Handler: '{}'", handler.name);
+
+                for (count, action) in handler.actions.iter().enumerate() {
+                    host_println!("  Action {}: {}{}", count + 1, action.action, get_action_details_description(action));
+
+                    connection.conn.set_become(get_become_params(actions, Some(action), &actions.auth, become_password));
+                    dry_run_action(provider, &mut connection, &mut template_context, action);
+                }
+            }
+        }
+    }
+}
+
+// the privilege escalation settings to run the action with (or things like fact gathering, if there's no action), if any
+fn get_become_params(actions: &ControlActions, action: Option<&ControlAction>, auth: &ControlSessionUserAuth,
+                     password: Option<&str>) -> Option<BecomeParams> {
+    let user = actions.get_become_user(action)?;
+    // there's no need for sudo to run things as the user we've logged in as
+    if user == auth.get_username() {
+        return None;
+    }
+    Some(BecomeParams { user, password: password.map(|password| password.to_string()) })
+}
+
+// the result of running a single action on a host
+enum ActionOutcome {
+    // the action's 'when' condition wasn't met
+    Skipped,
+    Completed(ActionResult),
+    Failed(ActionError),
+    // the action failed, but it had 'continueOnError' set
+    Ignored(ActionError),
+}
+
+impl ActionOutcome {
+    fn get_status(&self) -> &'static str {
+        match self {
+            ActionOutcome::Skipped => "skipped",
+            ActionOutcome::Completed(ActionResult::Unchanged) => "ok",
+            ActionOutcome::Completed(ActionResult::Changed) => "changed",
+            ActionOutcome::Failed(_) => "failed",
+            ActionOutcome::Ignored(_) => "ignored",
+        }
+    }
+}
+
+impl HostRunResult {
+    fn add_outcome(&mut self, outcome: &ActionOutcome) {
+        match outcome {
+            ActionOutcome::Skipped => self.num_skipped += 1,
+            ActionOutcome::Completed(ActionResult::Unchanged) => self.num_unchanged += 1,
+            ActionOutcome::Completed(ActionResult::Changed) => self.num_changed += 1,
+            ActionOutcome::Failed(_) => self.num_failed += 1,
+            ActionOutcome::Ignored(_) => self.num_ignored += 1,
+        }
+    }
+}
+
+// runs the action, adding its outcome to the run result, along with a report of it
+#[allow(clippy::too_many_arguments)]
+fn run_and_report_action(provider: &dyn ActionProvider, connection: &mut ControlSession, template_context: &mut TemplateContext,
+                         journal: &mut ChangeJournal, action: &ControlAction, count: usize, handler_name: Option<&str>,
+                         run_result: &mut HostRunResult) -> ActionOutcome {
+    let start_time = Instant::now();
+    let outcome = execute_action(provider, connection, template_context, journal, action, count);
+    run_result.add_outcome(&outcome);
+
+    // render the action again, so that any secret values in it can be redacted in the report
+    let rendered_action = template_context.render_action(action).unwrap_or_else(|_| action.clone());
+    let mut secrets = rendered_action.get_secret_param_values();
+    secrets.extend(template_context.get_vault_secret_values());
+
+    let mut report = ActionReport::new(count + 1, &rendered_action, handler_name, outcome.get_status());
+    report.redact_params(&secrets);
+    report.duration_secs = start_time.elapsed().as_secs_f64();
+    report.set_commands(connection.conn.take_executed_commands(), &secrets);
+    if let ActionOutcome::Failed(err) | ActionOutcome::Ignored(err) = &outcome {
+        report.set_error(err, &secrets);
+    }
+    run_result.action_reports.push(report);
+
+    outcome
+}
+
+// checks the action's condition (if any), and then runs the action, printing the outcome.
+fn execute_action(provider: &dyn ActionProvider, connection: &mut ControlSession, template_context: &mut TemplateContext,
+                  journal: &mut ChangeJournal, action: &ControlAction, count: usize) -> ActionOutcome {
+    // the 1-based index, as printed when the action is run, and used by '--start-at' and '--only'
+    let index = count + 1;
+
+    template_context.set_loop_item(action.loop_item.as_ref().map(|loop_item| loop_item.value.clone()));
+
+    if let Some(when) = &action.when {
+        match evaluate_condition_on_host(provider, connection, template_context, "when", when) {
+            Ok(true) => {},
+            Ok(false) => {
+                host_eprintln!("  skipped - condition not met: {}", when);
+                return ActionOutcome::Skipped;
+            },
+            Err(err) => {
+                host_eprintln!("Error running action index {} : {} - {}", index, action.action, err);
+                host_eprintln!("  failed");
+                return ActionOutcome::Failed(err);
+            }
+        }
+    }
+
+    let result = match template_context.render_action(action) {
+        // keep copies of any files the action changes first, so the changes can be rolled back
+        Ok(rendered_action) => match journal.snapshot_action_paths(provider, connection, &rendered_action) {
+            Ok(snapshots) => {
+                let max_attempts = action.retries + 1;
+                let mut attempt = 1;
+                let result = loop {
+                    let result = run_action_attempt(provider, connection, template_context, action, &rendered_action);
+                    // there's no point retrying if the connection's failed
+                    let can_retry = !matches!(result, Err(ActionError::NotImplemented | ActionError::InvalidParams(_) | ActionError::Transport(_)));
+                    if result.is_ok() || !can_retry || attempt >= max_attempts {
+                        break result;
+                    }
+
+                    host_eprintln!("  attempt {} of {} failed: {}", attempt, max_attempts, result.unwrap_err());
+                    host_eprintln!("  retrying in {} {}...", action.retry_delay, if action.retry_delay == 1 {"sec"} else {"secs"});
+                    std::thread::sleep(std::time::Duration::from_secs(action.retry_delay as u64));
+                    attempt += 1;
+                };
+
+                // actions which failed part way through might have changed things
+                let may_have_changed = matches!(result, Ok(ActionResult::Changed) |
+                                                        Err(ActionError::FailedCommand(_) | ActionError::FailedOther(_) | ActionError::Transport(_)));
+                match journal.record_changes(provider, connection, snapshots, may_have_changed) {
+                    // the changes can't be rolled back, so the action can't be considered to have succeeded
+                    Err(err) if result.is_ok() => Err(ActionError::Transport(err)),
+                    _ => result,
+                }
+            },
+            // the action can't be run if we've lost the connection
+            Err(err) => Err(ActionError::Transport(err)),
+        },
+        Err(err) => Err(ActionError::InvalidParams(err.to_string())),
+    };
+
+    match result {
+        Ok(ActionResult::Unchanged) => {
+            host_eprintln!("  ok - already in the required state.");
+            ActionOutcome::Completed(ActionResult::Unchanged)
+        },
+        Ok(ActionResult::Changed) => {
+            host_eprintln!("  changed");
+            ActionOutcome::Completed(ActionResult::Changed)
+        },
+        Err(err_result) => {
+            match &err_result {
+                ActionError::NotImplemented => {
+                    host_eprintln!("Error running action index {} : {} - the action provider does not implement this action...",
+                        index, action.action);
                 },
-                ControlActionType::SetHostname => {
-                    provider.set_hostname(&mut connection, action)
+                ActionError::InvalidParams(str) => {
+                    host_eprintln!("Error running action index {} : {} - invalid parameters were provided for this action: {}",
+                        index, action.action, str);
                 },
-                ControlActionType::CreateSystemdService => {
-                    provider.create_systemd_service(&mut connection, action)
+                ActionError::FailedCommand(str) => {
+                    host_eprintln!("Error running action index {} : {} - {}",
+                        index, action.action, str);
                 },
-                ControlActionType::ConfigureSSH => {
-                    provider.configure_ssh(&mut connection, action)
+                ActionError::FailedOther(str) => {
+                    host_eprintln!("Error running action index {} : {} - {}",
+                        index, action.action, str);
                 },
-                ControlActionType::AddPackageRepo => {
-                    provider.add_package_repo(&mut connection, action)
+                ActionError::Transport(err) => {
+                    host_eprintln!("Error running action index {} : {} - connection failure: {}",
+                        index, action.action, err);
                 },
-                ControlActionType::NotSet | ControlActionType::Unrecognised => {
-                   Err(ActionError::FailedOther("Invalid Action Type".to_string()))
+                _ => {
+                    host_eprintln!("Error running action index {} : {} - ...", index, action.action);
                 }
-            };
+            }
+            // there's no point carrying on with the host's other actions if the connection has gone
+            if action.continue_on_error && !matches!(err_result, ActionError::Transport(_)) {
+                host_eprintln!("  failed - ignoring, as 'continueOnError' is set.");
+                ActionOutcome::Ignored(err_result)
+            }
+            else {
+                host_eprintln!("  failed");
+                ActionOutcome::Failed(err_result)
+            }
+        }
+    }
+}
 
-            // TODO: would be nice to be able to pre-perform these NotImplemented and InvalidParams checks on all the actions reliably
-            //       before we start running any of them...
+// evaluates a 'when' or 'until' condition, checking any paths it needs to on the host
+fn evaluate_condition_on_host(provider: &dyn ActionProvider, connection: &mut ControlSession, template_context: &TemplateContext,
+                              condition_type: &str, condition: &str) -> Result<bool, ActionError> {
+    // the condition evaluation can't fail on path checks itself, so keep hold of any connection failure
+    // so it can be reported instead of the (meaningless) result.
+    let mut transport_error = None;
+    let result = Condition::parse(condition).and_then(|condition| {
+        condition.evaluate(template_context, &mut |path| {
+            probe_command_succeeds(provider, connection, &format!("test -e {}", quote_shell_arg(path))).unwrap_or_else(|err| {
+                transport_error.get_or_insert(err);
+                false
+            })
+        })
+    });
+
+    if let Some(err) = transport_error {
+        return Err(ActionError::Transport(err));
+    }
 
-            if let Err(err_result) = result {
-                match err_result {
-                    ActionError::NotImplemented => {
-                        eprintln!("Error running action index {} : {} - the action provider does not implement this action...",
-                            count, action.action);
-                    },
-                    ActionError::InvalidParams(str) => {
-                        eprintln!("Error running action index {} : {} - invalid parameters were provided for this action: {}",
-                            count, action.action, str);
-                    },
-                    ActionError::FailedCommand(str) => {
-                        eprintln!("Error running action index {} : {} - {}",
-                            count, action.action, str);
+    result.map_err(|err| ActionError::FailedOther(format!("couldn't evaluate '{}' condition: {}", condition_type, err)))
+}
+
+// runs the (already rendered) action once, registering its output if requested, and checking its 'until'
+// condition (if any) has been met.
+fn run_action_attempt(provider: &dyn ActionProvider, connection: &mut ControlSession, template_context: &mut TemplateContext,
+                      action: &ControlAction, rendered_action: &ControlAction) -> Result<ActionResult, ActionError> {
+    let result = run_action(provider, connection, rendered_action);
+    if let Some(var_name) = &action.register {
+        let registered = create_registered_result(connection.conn.get_previous_stdout_response(),
+                                                  connection.conn.get_previous_stderr_response(),
+                                                  connection.conn.get_exit_code(),
+                                                  matches!(result, Ok(ActionResult::Changed)), result.is_err());
+        template_context.set_var(var_name, registered);
+    }
+
+    let action_result = result?;
+
+    if let Some(until) = &action.until {
+        if !evaluate_condition_on_host(provider, connection, template_context, "until", until)? {
+            return Err(ActionError::FailedOther(format!("the 'until' condition wasn't met: {}", until)));
+        }
+    }
+
+    Ok(action_result)
+}
+
+// runs the action against a recording connection, printing the remote commands and file transfers it would perform.
+fn dry_run_action(provider: &dyn ActionProvider, connection: &mut ControlSession, template_context: &mut TemplateContext,
+                  action: &ControlAction) {
+    template_context.set_loop_item(action.loop_item.as_ref().map(|loop_item| loop_item.value.clone()));
+
+    if let Some(when) = &action.when {
+        // conditions which depend on the host can't be known, so just note them and carry on
+        match Condition::parse(when) {
+            Ok(condition) if condition.uses_facts() || condition.uses_host() => {
+                host_println!("   Note: only runs if the condition is met on the host: {}", when);
+            },
+            Ok(condition) => {
+                match condition.evaluate(template_context, &mut |_path| false) {
+                    Ok(true) => {},
+                    Ok(false) => {
+                        host_println!("   skipped - condition not met: {}", when);
+                        return;
                     },
-                    ActionError::FailedOther(str) => {
-                        eprintln!("Error running action index {} : {} - {}",
-                            count, action.action, str);
+                    Err(ConditionError::HostValueUnavailable) => {
+                        host_println!("   Note: only runs if the condition is met on the host: {}", when);
                     },
-                    _ => {
-                        eprintln!("Error running action index {} : {} - ...", count, action.action);
+                    Err(err) => {
+                        host_println!("   Error: {}", err);
+                        return;
                     }
                 }
+            },
+            Err(err) => {
+                host_println!("   Error: {}", err);
+                return;
+            }
+        }
+    }
 
-                success = false;
-                break;
+    let action = match template_context.render_action(action) {
+        Ok(rendered_action) => rendered_action,
+        Err(err) => {
+            host_println!("   Error: {}", err);
+            return;
+        }
+    };
+
+    let result = run_action(provider, connection, &action);
+
+    if let Some(var_name) = &action.register {
+        host_println!("   Registers the output as: '{}'", var_name);
+    }
+    if action.retries > 0 {
+        let until = action.until.as_ref().map(|until| format!(", until: {}", until)).unwrap_or_default();
+        host_println!("   Retries up to {} {} if it fails, {} secs apart{}", action.retries, if action.retries == 1 {"time"} else {"times"},
+                        action.retry_delay, until);
+    }
+    if action.continue_on_error {
+        host_println!("   Any failure is ignored, as 'continueOnError' is set.");
+    }
+
+    let mut secrets = action.get_secret_param_values();
+    secrets.extend(template_context.get_vault_secret_values());
+    let operations = connection.conn.take_recorded_operations();
+    if operations.is_empty() {
+        host_println!("   (no remote operations)");
+    }
+    for operation in operations {
+        host_println!("   {}", redact_secret_values(&operation.to_string(), &secrets));
+    }
+
+    // as there's no actual host, any steps after commands whose output is needed to continue can't be known,
+    // so note that, but continue on with the next action regardless...
+    if let Err(err) = result {
+        let err_string = redact_secret_values(&err.to_string(), &secrets);
+        if err_string.is_empty() {
+            host_println!("   Note: the action could not be fully simulated without the host, so later steps may be missing.");
+        }
+        else {
+            host_println!("   Note: the action could not be fully simulated without the host, so later steps may be missing: {}",
+                        err_string);
+        }
+    }
+}
+
+// substitutes any variables in the action params first, so that we can error on any undefined ones, and validates the final
+// values. Host facts aren't available yet, so any of those are validated as-is. Returns the number of errors found.
+fn render_and_validate_actions(actions: &ControlActions, action_list: &[ControlAction], provider: &dyn ActionProvider,
+                               general_params: &ControlGeneralParams, message_prefix: &str) -> usize {
+    let mut template_context = create_template_context(actions, general_params);
+    template_context.set_pending_vars(actions.get_registered_var_names());
+    let mut rendered_actions = actions.clone();
+    rendered_actions.actions = action_list.to_vec();
+
+    let mut num_errors = 0;
+    for (index, action) in rendered_actions.actions.iter_mut().enumerate() {
+        template_context.set_loop_item(action.loop_item.as_ref().map(|loop_item| loop_item.value.clone()));
+        match template_context.render_action(action) {
+            Ok(rendered_action) => *action = rendered_action,
+            Err(err) => {
+                host_eprintln!("{}Error: Action {} ({}): {}", message_prefix, index + 1, action.action, err);
+                num_errors += 1;
             }
         }
 
-        if success {
-            eprintln!("Successfully ran {}.", if num_actions == 1 {"action"} else {"actions"});
+        for condition in action.when.iter().chain(action.until.iter()) {
+            if let Err(err) = Condition::parse(condition) {
+                host_eprintln!("{}Error: Action {} ({}): {}", message_prefix, index + 1, action.action, err);
+                num_errors += 1;
+            }
+        }
+    }
+
+    let validation_issues = validate_actions(&rendered_actions, provider);
+    num_errors += validation_issues.iter().filter(|issue| issue.severity == ValidationSeverity::Error).count();
+    for issue in &validation_issues {
+        host_eprintln!("{}{}", message_prefix, issue);
+    }
+
+    num_errors
+}
+
+fn create_host_report(name: &str, target: &HostTarget, result: &HostRunResult, resume_command: Option<String>) -> HostReport {
+    HostReport { host: name.to_string(),
+                 hostname: target.hostname.clone(),
+                 port: target.port,
+                 status: result.get_status().to_string(),
+                 connection_duration_secs: result.connection_duration.map(|duration| duration.as_secs_f64()),
+                 system_validation: result.system_validation.clone(),
+                 summary: SummaryReport { ok: result.num_unchanged, changed: result.num_changed, skipped: result.num_skipped,
+                                          failed: result.num_failed, ignored: result.num_ignored, not_run: result.num_not_run },
+                 actions: result.action_reports.clone(),
+                 resume_command,
+                 journaled_changes: result.num_journaled }
+}
+
+fn write_run_report(path: &str, general_params: &ControlGeneralParams, host_reports: Vec<HostReport>) {
+    match write_report_file(path, &RunReport { run_id: general_params.run_id.clone(), hosts: host_reports }) {
+        Ok(()) => host_eprintln!("Wrote JSON report to: {}", path),
+        Err(err) => host_eprintln!("Error writing JSON report to: {}, error: {}", path, err),
+    }
+}
+
+// prints the facts as tables of the general facts, mounts and network interfaces
+fn print_facts_tables(facts: &HostFacts) {
+    let optional_number = |value: Option<u64>| value.map(|value| value.to_string()).unwrap_or_default();
+
+    let mut clp = ColumnListPrinter::new(2)
+        .add_titles(["Fact", "Value"]);
+    let general_facts = [("hostname", facts.hostname.clone()), ("distro_id", facts.distro_id.clone()), ("release", facts.release.clone()),
+                         ("kernel", facts.kernel.clone()), ("architecture", facts.architecture.clone()),
+                         ("cpu_count", optional_number(facts.cpu_count.map(u64::from))),
+                         ("memory_total_mb", optional_number(facts.memory_total_mb)),
+                         ("memory_available_mb", optional_number(facts.memory_available_mb)),
+                         ("init_system", facts.init_system.clone()), ("package_manager", facts.package_manager.clone()),
+                         ("virtualization", facts.virtualization.clone()), ("ipv4_addresses", facts.get_ipv4_addresses().join(", "))];
+    for (name, value) in &general_facts {
+        clp.add_row_strings(&[name, value]);
+    }
+    print!("{}", clp);
+
+    if !facts.mounts.is_empty() {
+        println!();
+        let mut clp = ColumnListPrinter::new(5)
+            .set_alignment_multiple(&[3usize, 4], Alignment::Right)
+            .add_titles(["Mount", "Device", "Filesystem", "Size (MB)", "Available (MB)"]);
+        for mount in &facts.mounts {
+            clp.add_row_strings(&[&mount.mount, &mount.device, &mount.filesystem, &mount.size_mb.to_string(),
+                                  &mount.available_mb.to_string()]);
+        }
+        print!("{}", clp);
+    }
+
+    if !facts.interfaces.is_empty() {
+        println!();
+        let mut clp = ColumnListPrinter::new(3)
+            .add_titles(["Interface", "IPv4", "IPv6"]);
+        for interface in &facts.interfaces {
+            clp.add_row_strings(&[&interface.name, &interface.ipv4.join(", "), &interface.ipv6.join(", ")]);
+        }
+        print!("{}", clp);
+    }
+}
+
+// i.e. " 'restart app' (item 2 of 5)" for named actions expanded from a loop, so it's clear which action and item
+// is being run
+fn get_action_details_description(action: &ControlAction) -> String {
+    let mut description = String::new();
+    if let Some(name) = &action.name {
+        description.push_str(&format!(" '{}'", name));
+    }
+    if let Some(loop_item) = &action.loop_item {
+        description.push_str(&format!(" (item {} of {})", loop_item.index + 1, loop_item.count));
+    }
+    description
+}
+
+// the value to resume a run from the specified (0-based) action index with, i.e. the action's name if it has
+// one which isn't ambiguous, otherwise its (1-based) index.
+fn get_resume_start_at(actions: &[ControlAction], index: usize) -> String {
+    let action = &actions[index];
+    if let Some(name) = &action.name {
+        // names of actions expanded from loops are shared by all the items, so only the first item can be
+        // resumed from by name, and names which are numbers would be treated as indices
+        let first_with_name = actions.iter().position(|other| other.name.as_ref() == Some(name));
+        if first_with_name == Some(index) && name.parse::<usize>().is_err() {
+            return name.clone();
         }
     }
+    (index + 1).to_string()
+}
+
+// creates the command line to resume a run with from the specified action, based on the command line args
+// the run was started with, replacing any existing '--start-at' (and '--limit' if a host is specified).
+fn create_resume_command(general_params: &ControlGeneralParams, start_at: &str, limit_host: Option<&str>) -> Option<String> {
+    let control_pos = general_params.command_args.iter().position(|arg| arg == "control")?;
+
+    let mut args: Vec<String> = general_params.command_args[..=control_pos].to_vec();
+    args.push("--start-at".to_string());
+    args.push(start_at.to_string());
+    if let Some(host) = limit_host {
+        args.push("--limit".to_string());
+        args.push(host.to_string());
+    }
+
+    let mut remaining = general_params.command_args[control_pos + 1..].iter();
+    while let Some(arg) = remaining.next() {
+        if arg == "--start-at" || (limit_host.is_some() && arg == "--limit") {
+            // skip the value as well
+            remaining.next();
+            continue;
+        }
+        args.push(arg.clone());
+    }
+
+    Some(args.iter().map(|arg| quote_shell_arg(arg)).collect::<Vec<String>>().join(" "))
+}
+
+// prints the run id to roll back any journaled changes to files with, and the command to do it.
+fn print_rollback_details(general_params: &ControlGeneralParams, num_journaled: usize) {
+    if num_journaled == 0 {
+        return;
+    }
+
+    host_eprintln!("Journaled {} {} to files with run ID: {}", num_journaled, if num_journaled == 1 {"change"} else {"changes"},
+                    general_params.run_id);
+    if let Some(rollback_command) = create_rollback_command(general_params) {
+        host_eprintln!("To roll back the changes, run: {}", rollback_command);
+    }
 }
 
+// creates the command line to roll back the run with, based on the command line args the run was started with,
+// without any of the args which only affect which actions are run, or how.
+fn create_rollback_command(general_params: &ControlGeneralParams) -> Option<String> {
+    let control_pos = general_params.command_args.iter().position(|arg| arg == "control")?;
+
+    let mut args: Vec<String> = general_params.command_args[..=control_pos].to_vec();
+    args.push("--rollback".to_string());
+    args.push(general_params.run_id.clone());
+
+    const SKIPPED_ARGS_WITH_VALUES: &[&str] = &["--start-at", "--only", "--tags", "--skip-tags", "--var", "--vars-file", "--report-json"];
+    let mut remaining = general_params.command_args[control_pos + 1..].iter();
+    while let Some(arg) = remaining.next() {
+        if SKIPPED_ARGS_WITH_VALUES.contains(&arg.as_str()) {
+            // skip the value as well
+            remaining.next();
+            continue;
+        }
+        args.push(arg.clone());
+    }
+
+    Some(args.iter().map(|arg| quote_shell_arg(arg)).collect::<Vec<String>>().join(" "))
+}
+
+// the script's variables, with any from the command line taking precedence
+fn create_template_context(actions: &ControlActions, general_params: &ControlGeneralParams) -> TemplateContext {
+    let mut vars = actions.vars.clone();
+    vars.extend(general_params.vars.clone());
+
+    let Some(vault) = &general_params.vault else {
+        return TemplateContext::new(vars);
+    };
+
+    // variables' values aren't rendered when they're used, so any vault secrets in them need substituting now
+    for value in vars.values_mut() {
+        substitute_value_vault_references(value, vault);
+    }
+    let mut template_context = TemplateContext::new(vars);
+    template_context.set_vault(vault);
+    template_context
+}
+
+// prompts for the values of any secret action params (i.e. new user passwords) which are set to '$PROMPT', replacing them
+// with the values entered, and re-using the same values for the same action params of other hosts.
+fn resolve_action_param_prompts(actions: &mut ControlActions, prompt_cache: &mut PromptCache) {
+    for (index, action) in actions.actions.iter_mut().enumerate() {
+        let action_type = action.action;
+        for (name, value) in action.params.values.iter_mut() {
+            if !is_secret_param_name(name) {
+                continue;
+            }
+            if let ParamValue::Str(str_val) = value {
+                if str_val != "$PROMPT" {
+                    continue;
+                }
+
+                let cache_key = (index.to_string(), name.clone());
+                if let Some(cached_value) = prompt_cache.get(&cache_key) {
+                    *str_val = cached_value.clone();
+                }
+                else {
+                    eprintln!("Please enter the '{}' param value for action {}: {}:", name, index + 1, action_type);
+                    // the params get templated before the action is run, so make sure the value is used as-is
+                    *str_val = escape_template_string(&read_password().unwrap());
+                    prompt_cache.insert(cache_key, str_val.clone());
+                }
+            }
+        }
+    }
+}
+
+// TODO: Better (automatic - based off lookup) despatch than this...
+//       Although it's not clear how to easily do that, or if there's actually a benefit to
+//       doing it that way...
+fn run_action(provider: &dyn ActionProvider, connection: &mut ControlSession, action: &ControlAction) -> Result<ActionResult, ActionError> {
+    match action.action {
+        ControlActionType::GenericCommand => {
+            provider.generic_command(connection, action)
+        },
+        ControlActionType::AddUser => {
+            provider.add_user(connection, action)
+        },
+        ControlActionType::CreateDirectory => {
+            provider.create_directory(connection, action)
+        },
+        ControlActionType::RemoveDirectory => {
+            provider.remove_directory(connection, action)
+        },
+        ControlActionType::InstallPackages => {
+            provider.install_packages(connection, action)
+        },
+        ControlActionType::RemovePackages => {
+            provider.remove_packages(connection, action)
+        },
+        ControlActionType::SystemCtl => {
+            provider.systemctrl(connection, action)
+        },
+        ControlActionType::Firewall => {
+            provider.firewall(connection, action)
+        },
+        ControlActionType::EditFile => {
+            provider.edit_file(connection, action)
+        },
+        ControlActionType::CopyPath => {
+            provider.copy_path(connection, action)
+        },
+        ControlActionType::RemoveFile => {
+            provider.remove_file(connection, action)
+        },
+        ControlActionType::DownloadFile => {
+            provider.download_file(connection, action)
+        },
+        ControlActionType::TransmitFile => {
+            provider.transmit_file(connection, action)
+        },
+        ControlActionType::ReceiveFile => {
+            provider.receive_file(connection, action)
+        },
+        ControlActionType::CreateSymlink => {
+            provider.create_symlink(connection, action)
+        },
+        ControlActionType::SetTimeZone => {
+            provider.set_time_zone(connection, action)
+        },
+        ControlActionType::DisableSwap => {
+            provider.disable_swap(connection, action)
+        },
+        ControlActionType::CreateFile => {
+            provider.create_file(connection, action)
+        },
+        ControlActionType::AddGroup => {
+            provider.add_group(connection, action)
+        },
+        ControlActionType::SetHostname => {
+            provider.set_hostname(connection, action)
+        },
+        ControlActionType::CreateSystemdService => {
+            provider.create_systemd_service(connection, action)
+        },
+        ControlActionType::ConfigureSSH => {
+            provider.configure_ssh(connection, action)
+        },
+        ControlActionType::AddPackageRepo => {
+            provider.add_package_repo(connection, action)
+        },
+        ControlActionType::NotSet | ControlActionType::Unrecognised => {
+            Err(ActionError::FailedOther("Invalid Action Type".to_string()))
+        }
+    }
+}
@@ -0,0 +1,69 @@
+/*
+ Prod
+ Copyright 2021-2025 Peter Pearson.
+ Licensed under the Apache License, Version 2.0 (the "License");
+ You may not use this file except in compliance with the License.
+ You may obtain a copy of the License at
+ http://www.apache.org/licenses/LICENSE-2.0
+ Unless required by applicable law or agreed to in writing, software
+ distributed under the License is distributed on an "AS IS" BASIS,
+ WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ See the License for the specific language governing permissions and
+ limitations under the License.
+ ---------
+*/
+
+// When running control scripts on multiple hosts at once on different threads, the output from each host
+// gets interleaved, so this allows setting a per-thread prefix (i.e. the host name) which the host_eprintln!()
+// and host_println!() macros add to the start of each line they print, so it's clear which host each line is for.
+
+use std::cell::RefCell;
+
+thread_local! {
+    static OUTPUT_PREFIX: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
+pub fn set_output_prefix(prefix: &str) {
+    OUTPUT_PREFIX.with(|output_prefix| *output_prefix.borrow_mut() = prefix.to_string());
+}
+
+pub fn clear_output_prefix() {
+    set_output_prefix("");
+}
+
+// adds the current thread's prefix (if any) to each line of the text.
+pub fn prefix_lines(text: &str) -> String {
+    OUTPUT_PREFIX.with(|output_prefix| {
+        let output_prefix = output_prefix.borrow();
+        if output_prefix.is_empty() {
+            return text.to_string();
+        }
+
+        if text.is_empty() {
+            return output_prefix.trim_end().to_string();
+        }
+
+        text.lines().map(|line| format!("{}{}", output_prefix, line)).collect::<Vec<String>>().join("\n")
+    })
+}
+
+macro_rules! host_eprintln {
+    () => {
+        eprintln!("{}", $crate::control::control_output::prefix_lines(""))
+    };
+    ($($arg:tt)*) => {
+        eprintln!("{}", $crate::control::control_output::prefix_lines(&format!($($arg)*)))
+    };
+}
+
+macro_rules! host_println {
+    () => {
+        println!("{}", $crate::control::control_output::prefix_lines(""))
+    };
+    ($($arg:tt)*) => {
+        println!("{}", $crate::control::control_output::prefix_lines(&format!($($arg)*)))
+    };
+}
+
+pub(crate) use host_eprintln;
+pub(crate) use host_println;
@@ -0,0 +1,217 @@
+/*
+ Prod
+ Copyright 2021-2025 Peter Pearson.
+ Licensed under the Apache License, Version 2.0 (the "License");
+ You may not use this file except in compliance with the License.
+ You may obtain a copy of the License at
+ http://www.apache.org/licenses/LICENSE-2.0
+ Unless required by applicable law or agreed to in writing, software
+ distributed under the License is distributed on an "AS IS" BASIS,
+ WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ See the License for the specific language governing permissions and
+ limitations under the License.
+ ---------
+*/
+
+// Structured report of the results of running a control script, which can be written out as JSON
+// with the '--report-json' option, so the results can be used by other tools (i.e. CI).
+
+use serde::Serialize;
+
+use crate::params::ParamValue;
+
+use super::control_actions::{is_secret_param_name, redact_secret_values, ActionError, ControlAction};
+use super::control_connection::ExecutedCommand;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct RunReport {
+    // the id to roll back any journaled changes to files with
+    pub run_id:     String,
+    pub hosts:      Vec<HostReport>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct HostReport {
+    // the inventory name of the host (or the hostname if there's no inventory)
+    pub host:                       String,
+    pub hostname:                   String,
+    pub port:                       u32,
+    // "success", "failed", or the reason the actions couldn't be run, i.e. "unreachable"
+    pub status:                     String,
+    pub connection_duration_secs:   Option<f64>,
+    pub system_validation:          Option<SystemValidationReport>,
+    pub summary:                    SummaryReport,
+    pub actions:                    Vec<ActionReport>,
+    // the command to resume the run from the action which failed, if one did
+    pub resume_command:             Option<String>,
+    // the number of changes to files which were journaled, and can be rolled back
+    pub journaled_changes:          usize,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SystemValidationReport {
+    pub distro_id:  String,
+    pub release:    String,
+    pub passed:     bool,
+    // descriptions of the constraints which failed
+    pub failed_constraints: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SummaryReport {
+    pub ok:         usize,
+    pub changed:    usize,
+    pub skipped:    usize,
+    pub failed:     usize,
+    pub ignored:    usize,
+    pub not_run:    usize,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ActionReport {
+    // the (1-based) index of the action within the actions, or within the handler's actions
+    pub index:          usize,
+    pub action:         String,
+    pub name:           Option<String>,
+    pub tags:           Vec<String>,
+    // set if the action was run as part of a handler
+    pub handler:        Option<String>,
+    pub loop_item:      Option<serde_json::Value>,
+    pub params:         serde_json::Value,
+    // "ok", "changed", "skipped", "failed" or "ignored"
+    pub status:         String,
+    pub duration_secs:  f64,
+    pub commands:       Vec<CommandReport>,
+    pub error:          Option<ActionErrorReport>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CommandReport {
+    pub command:    String,
+    pub exit_code:  Option<i32>,
+    pub stderr:     Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ActionErrorReport {
+    // i.e. "failed_command" or "invalid_params"
+    pub kind:       String,
+    pub message:    String,
+}
+
+impl ActionReport {
+    // Note: the params should be the rendered ones if possible, so that any secret values within the commands
+    //       can be redacted.
+    pub fn new(index: usize, action: &ControlAction, handler: Option<&str>, status: &str) -> ActionReport {
+        ActionReport { index,
+                       action: action.action.to_string(),
+                       name: action.name.clone(),
+                       tags: action.tags.clone(),
+                       handler: handler.map(|handler| handler.to_string()),
+                       loop_item: action.loop_item.as_ref().map(|loop_item| param_value_to_json(&loop_item.value)),
+                       params: redacted_params_to_json(action),
+                       status: status.to_string(),
+                       duration_secs: 0.0,
+                       commands: Vec::new(),
+                       error: None }
+    }
+
+    // redacts any of the secret values (i.e. from the vault) within the params, which don't have secret names
+    pub fn redact_params(&mut self, secrets: &[String]) {
+        redact_json_values(&mut self.params, secrets);
+    }
+
+    pub fn set_commands(&mut self, commands: Vec<ExecutedCommand>, secrets: &[String]) {
+        self.commands = commands.into_iter().map(|command| {
+            CommandReport { command: redact_secret_values(command.command.trim_start(), secrets),
+                            exit_code: command.exit_code,
+                            stderr: command.stderr.map(|stderr| redact_secret_values(&stderr, secrets)) }
+        }).collect();
+    }
+
+    pub fn set_error(&mut self, err: &ActionError, secrets: &[String]) {
+        let kind = match err {
+            ActionError::NotImplemented => "not_implemented",
+            ActionError::InvalidParams(_) => "invalid_params",
+            ActionError::CantConnect => "cant_connect",
+            ActionError::AuthenticationIssue => "authentication_issue",
+            ActionError::FailedCommand(_) => "failed_command",
+            ActionError::FailedOther(_) => "failed_other",
+            ActionError::Transport(_) => "transport",
+        };
+        self.error = Some(ActionErrorReport { kind: kind.to_string(), message: redact_secret_values(&err.to_string(), secrets) });
+    }
+}
+
+pub fn write_report_file(path: &str, report: &RunReport) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(report).map_err(|err| err.to_string())?;
+    std::fs::write(path, json + "\n").map_err(|err| err.to_string())
+}
+
+fn redacted_params_to_json(action: &ControlAction) -> serde_json::Value {
+    let params = action.params.values.iter().map(|(name, value)| {
+        let value = if is_secret_param_name(name) {
+            serde_json::Value::String("********".to_string())
+        }
+        else {
+            param_value_to_json(value)
+        };
+        (name.clone(), value)
+    });
+
+    serde_json::Value::Object(params.collect())
+}
+
+fn redact_json_values(value: &mut serde_json::Value, secrets: &[String]) {
+    match value {
+        serde_json::Value::String(str_val) => *str_val = redact_secret_values(str_val, secrets),
+        serde_json::Value::Array(items) => items.iter_mut().for_each(|item| redact_json_values(item, secrets)),
+        serde_json::Value::Object(map) => map.values_mut().for_each(|item| redact_json_values(item, secrets)),
+        _ => {}
+    }
+}
+
+fn param_value_to_json(value: &ParamValue) -> serde_json::Value {
+    match value {
+        ParamValue::NotSet | ParamValue::Unknown => serde_json::Value::Null,
+        ParamValue::Bool(val) => serde_json::Value::Bool(*val),
+        ParamValue::Int(val) => serde_json::Value::from(*val),
+        ParamValue::Str(val) => serde_json::Value::String(val.clone()),
+        ParamValue::Array(items) => serde_json::Value::Array(items.iter().map(param_value_to_json).collect()),
+        ParamValue::Map(map) => {
+            serde_json::Value::Object(map.iter().map(|(key, item)| (key.clone(), param_value_to_json(item))).collect())
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::control::control_actions::ControlActionType;
+
+    #[test]
+    fn test_action_report() {
+        let mut action = ControlAction::new();
+        action.action = ControlActionType::AddUser;
+        action.params.values.insert("username".to_string(), ParamValue::Str("deploy".to_string()));
+        action.params.values.insert("password".to_string(), ParamValue::Str("hunter2".to_string()));
+        action.params.values.insert("extraGroups".to_string(), ParamValue::Array(vec![ParamValue::Str("sudo".to_string())]));
+
+        let mut report = ActionReport::new(1, &action, None, "failed");
+        assert_eq!(report.params, serde_json::json!({"username": "deploy", "password": "********", "extraGroups": ["sudo"]}));
+        report.redact_params(&["sudo".to_string()]);
+        assert_eq!(report.params["extraGroups"], serde_json::json!(["********"]));
+
+        let secrets = action.get_secret_param_values();
+        report.set_commands(vec![ExecutedCommand { command: "  echo 'deploy:hunter2' | chpasswd".to_string(), exit_code: Some(1),
+                                                   stderr: Some("error".to_string()) }], &secrets);
+        assert!(!report.commands[0].command.contains("hunter2"));
+        assert_eq!(report.commands[0].exit_code, Some(1));
+
+        report.set_error(&ActionError::FailedCommand("chpasswd hunter2 failed".to_string()), &secrets);
+        let error = report.error.unwrap();
+        assert_eq!(error.kind, "failed_command");
+        assert!(!error.message.contains("hunter2"));
+    }
+}
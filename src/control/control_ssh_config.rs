@@ -0,0 +1,350 @@
+/*
+ Prod
+ Copyright 2021-2025 Peter Pearson.
+ Licensed under the Apache License, Version 2.0 (the "License");
+ You may not use this file except in compliance with the License.
+ You may obtain a copy of the License at
+ http://www.apache.org/licenses/LICENSE-2.0
+ Unless required by applicable law or agreed to in writing, software
+ distributed under the License is distributed on an "AS IS" BASIS,
+ WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ See the License for the specific language governing permissions and
+ limitations under the License.
+ ---------
+*/
+
+// Resolving of host aliases (and the connection settings for them) from the user's OpenSSH client config file
+// (~/.ssh/config), so hosts which are already described there can be connected to by their alias, i.e.:
+//
+// Host web1
+//     HostName 192.168.0.10
+//     User deploy
+//     Port 2222
+//     IdentityFile ~/.ssh/id_deploy
+//
+// As with OpenSSH, the first value found for each setting is used, from the 'Host' sections which match the alias
+// in the order they're in the file. 'Match' sections aren't supported, so are ignored.
+
+use std::path::{Path, PathBuf};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+
+use super::control_common::{wildcard_matches, ControlSessionUserAuth, UserAuthPublicKey};
+
+// to stop recursive 'Include' items from looping forever
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SshHostConfig {
+    pub hostname:       Option<String>,
+    pub user:           Option<String>,
+    pub port:           Option<u32>,
+    pub identity_files: Vec<String>,
+    pub proxy_jump:     Option<String>,
+}
+
+impl SshHostConfig {
+    // the settings for the host alias from ~/.ssh/config, which are all empty if it doesn't exist
+    pub fn load(alias: &str) -> SshHostConfig {
+        let mut config = SshHostConfig::default();
+        let ssh_dir = get_home_dir().join(".ssh");
+        if let Ok(contents) = std::fs::read_to_string(ssh_dir.join("config")) {
+            config.add_settings(&contents, &ssh_dir, alias, 0);
+        }
+        config
+    }
+
+    fn add_settings(&mut self, contents: &str, ssh_dir: &Path, alias: &str, depth: usize) {
+        // settings before any 'Host' line apply to all hosts
+        let mut active = true;
+
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            // keywords are case-insensitive, and can be separated from their values with whitespace or '='
+            let keyword_end = line.find(|c: char| c.is_whitespace() || c == '=').unwrap_or(line.len());
+            let keyword = line[..keyword_end].to_ascii_lowercase();
+            let args = split_args(line[keyword_end..].trim_start().trim_start_matches('=').trim_start());
+
+            match keyword.as_str() {
+                "host" => active = host_patterns_match(&args, alias),
+                "match" => active = args.first().is_some_and(|arg| arg.eq_ignore_ascii_case("all")),
+                "include" if active && depth < MAX_INCLUDE_DEPTH => {
+                    for path in args.iter().flat_map(|arg| get_include_paths(arg, ssh_dir)) {
+                        if let Ok(contents) = std::fs::read_to_string(path) {
+                            self.add_settings(&contents, ssh_dir, alias, depth + 1);
+                        }
+                    }
+                },
+                _ if !active || args.is_empty() => {},
+                "hostname" => {
+                    self.hostname.get_or_insert_with(|| args[0].clone());
+                },
+                "user" => {
+                    self.user.get_or_insert_with(|| args[0].clone());
+                },
+                "port" if self.port.is_none() => self.port = args[0].parse::<u32>().ok(),
+                // all of these are tried, in order
+                "identityfile" => self.identity_files.push(args[0].clone()),
+                "proxyjump" => {
+                    self.proxy_jump.get_or_insert_with(|| args[0].clone());
+                },
+                _ => {}
+            }
+        }
+    }
+
+    // applies the settings to the connection details of the host, with any settings which have been specified
+    // (i.e. by the control script) taking precedence. An unset username is empty or '$PROMPT'.
+    pub fn apply(&self, hostname: &mut String, port: &mut Option<u32>, auth: &mut ControlSessionUserAuth) {
+        let alias = hostname.clone();
+        if let Some(config_hostname) = &self.hostname {
+            *hostname = expand_tokens(config_hostname, &alias, &alias, *port, "");
+        }
+        if port.is_none() {
+            *port = self.port;
+        }
+        let username = auth.get_username().to_string();
+        if let Some(user) = &self.user {
+            if username.is_empty() || username == "$PROMPT" {
+                auth.set_username(user);
+            }
+        }
+
+        // identity files are only used if no authentication was specified, which is the same as password auth
+        // without a password
+        let no_auth_specified = matches!(auth, ControlSessionUserAuth::UserPass(userpass) if userpass.password.is_empty());
+        if no_auth_specified {
+            let identity_file = self.identity_files.iter()
+                                    .map(|path| expand_tokens(path, &alias, hostname, *port, auth.get_username()))
+                                    .find(|path| Path::new(path).is_file());
+            if let Some(identity_file) = identity_file {
+                // the public key is optional, as it can be worked out from the private key
+                let public_key = format!("{}.pub", identity_file);
+                let public_key = if Path::new(&public_key).is_file() { public_key } else { String::new() };
+                let passphrase = if is_private_key_encrypted(&identity_file) { "$PROMPT" } else { "" };
+                *auth = ControlSessionUserAuth::PublicKey(UserAuthPublicKey::new(auth.get_username(), &public_key, &identity_file,
+                                                                                 passphrase));
+            }
+        }
+    }
+
+    // the jump hosts to connect via, if any
+    pub fn get_proxy_jump(&self) -> Option<&str> {
+        self.proxy_jump.as_deref().filter(|proxy_jump| !proxy_jump.eq_ignore_ascii_case("none"))
+    }
+}
+
+fn get_home_dir() -> PathBuf {
+    PathBuf::from(std::env::var("HOME").unwrap_or_else(|_| ".".to_string()))
+}
+
+// splits the arguments of a setting by whitespace, allowing them to be double-quoted if they contain spaces
+fn split_args(args: &str) -> Vec<String> {
+    let mut items = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+    let mut have_item = false;
+    for char in args.chars() {
+        match char {
+            '"' => {
+                in_quotes = !in_quotes;
+                have_item = true;
+            },
+            char if char.is_whitespace() && !in_quotes => {
+                if have_item {
+                    items.push(std::mem::take(&mut current));
+                    have_item = false;
+                }
+            },
+            char => {
+                current.push(char);
+                have_item = true;
+            }
+        }
+    }
+    if have_item {
+        items.push(current);
+    }
+    items
+}
+
+// whether any of the (possibly wildcard) patterns match the alias, and none of the negated ('!') ones do
+fn host_patterns_match(patterns: &[String], alias: &str) -> bool {
+    let alias = alias.to_ascii_lowercase();
+    let mut matched = false;
+    for pattern in patterns {
+        let (negated, pattern) = match pattern.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern.as_str()),
+        };
+        if wildcard_matches(&pattern.to_ascii_lowercase(), &alias) {
+            if negated {
+                return false;
+            }
+            matched = true;
+        }
+    }
+    matched
+}
+
+// the files an 'Include' item refers to, which are relative to ~/.ssh, and can have wildcards in the file name
+fn get_include_paths(include: &str, ssh_dir: &Path) -> Vec<PathBuf> {
+    let path = match include.strip_prefix("~/") {
+        Some(path) => get_home_dir().join(path),
+        None => ssh_dir.join(include),
+    };
+
+    let file_name = path.file_name().and_then(|name| name.to_str()).unwrap_or("");
+    if !file_name.contains(['*', '?']) {
+        return vec![path];
+    }
+
+    let Some(dir) = path.parent() else {
+        return Vec::new();
+    };
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(dir).into_iter().flatten().flatten()
+                                    .filter(|entry| entry.file_name().to_str().is_some_and(|name| wildcard_matches(file_name, name)))
+                                    .map(|entry| entry.path())
+                                    .collect();
+    // OpenSSH includes them in name order
+    paths.sort();
+    paths
+}
+
+// expands the '~' prefix and the '%' tokens OpenSSH supports in HostName and IdentityFile values
+fn expand_tokens(value: &str, alias: &str, hostname: &str, port: Option<u32>, remote_user: &str) -> String {
+    let home_dir = get_home_dir().to_string_lossy().to_string();
+    let value = match value.strip_prefix("~/") {
+        Some(path) => format!("{}/{}", home_dir, path),
+        None => value.to_string(),
+    };
+
+    let mut expanded = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(char) = chars.next() {
+        if char != '%' {
+            expanded.push(char);
+            continue;
+        }
+        match chars.next() {
+            Some('%') => expanded.push('%'),
+            Some('d') => expanded.push_str(&home_dir),
+            Some('h') => expanded.push_str(hostname),
+            Some('n') => expanded.push_str(alias),
+            Some('p') => expanded.push_str(&port.unwrap_or(22).to_string()),
+            Some('r') => expanded.push_str(remote_user),
+            Some('u') => expanded.push_str(&std::env::var("USER").unwrap_or_default()),
+            // leave anything else as it is
+            Some(other) => {
+                expanded.push('%');
+                expanded.push(other);
+            },
+            None => expanded.push('%'),
+        }
+    }
+    expanded
+}
+
+// whether the private key file needs a passphrase, so that it can be prompted for
+fn is_private_key_encrypted(path: &str) -> bool {
+    let Ok(contents) = std::fs::read_to_string(path) else {
+        return false;
+    };
+    // PEM keys say so in their header
+    if contents.contains("ENCRYPTED") {
+        return true;
+    }
+
+    // OpenSSH format keys start with a magic string and then the name of the cipher they're encrypted with
+    const MAGIC: &[u8] = b"openssh-key-v1\0";
+    let body: String = contents.lines().filter(|line| !line.starts_with("-----")).collect::<Vec<&str>>().concat();
+    let Ok(key) = STANDARD.decode(body.trim()) else {
+        return false;
+    };
+    let Some(cipher) = key.strip_prefix(MAGIC) else {
+        return false;
+    };
+    let cipher_length = cipher.get(0..4).map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()) as usize).unwrap_or(0);
+    cipher.get(4..4 + cipher_length).is_some_and(|name| name != b"none")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::control::control_common::UserAuthUserPass;
+
+    fn config_from_contents(contents: &str, alias: &str) -> SshHostConfig {
+        let mut config = SshHostConfig::default();
+        config.add_settings(contents, Path::new("/nonexistent"), alias, 0);
+        config
+    }
+
+    const TEST_CONFIG: &str =
+"# comment
+Host web? !web9
+    HostName 192.168.0.1%h
+    Port 2222
+    IdentityFile \"~/keys/web key\"
+
+Host web1 db1
+    User deploy
+    Port=22
+    ProxyJump bastion
+
+Match host db1
+    User ignored
+
+Host *
+    User admin
+    IdentityFile ~/.ssh/id_ed25519
+    ProxyJump none
+";
+
+    #[test]
+    fn test_resolve_host() {
+        let home_dir = get_home_dir().to_string_lossy().to_string();
+
+        let config = config_from_contents(TEST_CONFIG, "web1");
+        assert_eq!(config.hostname.as_deref(), Some("192.168.0.1%h"));
+        // the first value found is used
+        assert_eq!(config.port, Some(2222));
+        assert_eq!(config.user.as_deref(), Some("deploy"));
+        assert_eq!(config.identity_files, vec!["~/keys/web key".to_string(), "~/.ssh/id_ed25519".to_string()]);
+        assert_eq!(config.get_proxy_jump(), Some("bastion"));
+
+        let config = config_from_contents(TEST_CONFIG, "web9");
+        assert_eq!(config, SshHostConfig { hostname: None, user: Some("admin".to_string()), port: None,
+                                           identity_files: vec!["~/.ssh/id_ed25519".to_string()],
+                                           proxy_jump: Some("none".to_string()) });
+        assert_eq!(config.get_proxy_jump(), None);
+
+        let config = config_from_contents(TEST_CONFIG, "DB1");
+        assert_eq!(config.user.as_deref(), Some("deploy"));
+
+        // the script's settings take precedence
+        let config = config_from_contents(TEST_CONFIG, "web1");
+        let mut hostname = "web1".to_string();
+        let mut port = Some(2200);
+        let mut auth = ControlSessionUserAuth::UserPass(UserAuthUserPass::new("$PROMPT", "$PROMPT"));
+        config.apply(&mut hostname, &mut port, &mut auth);
+        assert_eq!((hostname.as_str(), port), ("192.168.0.1web1", Some(2200)));
+        assert!(matches!(&auth, ControlSessionUserAuth::UserPass(userpass) if userpass.username == "deploy" && userpass.password == "$PROMPT"));
+
+        let mut hostname = "web1".to_string();
+        let mut port = None;
+        let mut auth = ControlSessionUserAuth::UserPass(UserAuthUserPass::new("root", ""));
+        config.apply(&mut hostname, &mut port, &mut auth);
+        assert_eq!(port, Some(2222));
+        assert_eq!(auth.get_username(), "root");
+
+        assert_eq!(expand_tokens("~/.ssh/%r@%h:%p", "web1", "192.168.0.10", None, "deploy"),
+                   format!("{}/.ssh/deploy@192.168.0.10:22", home_dir));
+        assert_eq!(expand_tokens("100%%", "web1", "web1", None, ""), "100%");
+    }
+}
@@ -13,6 +13,23 @@
  ---------
 */
 
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use yaml_rust::Yaml;
+
+use super::control_facts::HostFacts;
+use super::control_output::host_eprintln;
+
+// families of distros, which can be used instead of the distro names, i.e. "debian-like".
+// These are matched (case-insensitively) against the distributor ID 'lsb_release' gives.
+const DISTRO_FAMILIES: &[(&str, &[&str])] = &[
+    ("debian-like", &["debian", "ubuntu", "raspbian", "linuxmint", "pop", "kali", "devuan", "elementary"]),
+    ("redhat-like", &["redhatenterprise", "redhatenterpriseserver", "centos", "fedora", "rocky", "almalinux", "oracleserver"]),
+    ("suse-like", &["suse", "opensuse", "sles"]),
+];
+
 // release version - done as string, so we can cope with
 // both single integer versions and number.number versions.
 #[derive(Clone, Debug, PartialEq)]
@@ -26,72 +43,66 @@ pub enum SystemValidationReleaseVersion {
 }
 
 impl SystemValidationReleaseVersion {
-    fn is_version_okay(&self, host_version: &str) -> bool {
-
-        // TODO: some of this validation is probably better done when reading the action script file rather than here?
-
-        // get the actual string
-        if let Some(expected_release_string) = self.get_version_string() {
-            // if it has a dot in it, we don't currently support that yet...
-            // TODO: handle . dot notation for things like Ubuntu...
-            if expected_release_string.contains('.') {
-                eprintln!("Error: SystemValidationReleaseVersion doesn't currently support release verions with '.' chars in.");
-                return false;
+    pub fn is_version_okay(&self, host_version: &str) -> bool {
+        match self.check_version(host_version) {
+            Ok(is_valid) => is_valid,
+            Err(err) => {
+                host_eprintln!("Error: {}", err);
+                false
             }
+        }
+    }
 
-            // otherwise, assume the expected value is an integer...
-            let expected_int_version = expected_release_string.parse::<u32>();
-            if let Ok(expected_int_version_value) = expected_int_version {
-                // now get the actual host version.
-
-                // again, we don't support . dot notation yet
-                if host_version.contains('.') {
-                    eprintln!("Error: SystemValidationReleaseVersion doesn't currently support release verions with '.' chars in.");
-                    return false;
-                }
-
-                let actual_int_version = host_version.parse::<u32>();
-                if let Ok(actual_int_version_value) = actual_int_version {
-                    // we now have both integer values, so check them
-
-                    let is_valid = match *self {
-                        SystemValidationReleaseVersion::LessThan(_) => {
-                            actual_int_version_value < expected_int_version_value
-                        },
-                        SystemValidationReleaseVersion::LessThanOrEqual(_) => {
-                            actual_int_version_value <= expected_int_version_value
-                        },
-                        SystemValidationReleaseVersion::Equal(_) => {
-                            actual_int_version_value == expected_int_version_value
-                        },
-                        SystemValidationReleaseVersion::GreaterThan(_) => {
-                            actual_int_version_value > expected_int_version_value
-                        },
-                        SystemValidationReleaseVersion::GreaterThanOrEqual(_) => {
-                            actual_int_version_value >= expected_int_version_value
-                        },
-                        _  => {
-                            // shouldn't be possible to reach here, but...
-                            false
-                        }
-                    };
+    // compares the version numbers component by component, so that i.e. "22.04" < "22.10", with missing
+    // components being treated as 0, so "12" == "12.0".
+    pub fn check_version(&self, host_version: &str) -> Result<bool, String> {
+        // get the actual string
+        let expected_release_string = match self.get_version_string() {
+            Some(expected_release_string) => expected_release_string,
+            // otherwise, no release constraint version was set, so it's always okay
+            None => return Ok(true),
+        };
+
+        let expected_version = parse_version(&expected_release_string)
+            .ok_or_else(|| format!("couldn't parse expected release version string: '{}'", expected_release_string))?;
+        let actual_version = parse_version(host_version)
+            .ok_or_else(|| format!("couldn't parse actual release version string: '{}'", host_version))?;
+
+        let ordering = compare_versions(&actual_version, &expected_version);
+
+        let is_valid = match *self {
+            SystemValidationReleaseVersion::LessThan(_) => ordering == Ordering::Less,
+            SystemValidationReleaseVersion::LessThanOrEqual(_) => ordering != Ordering::Greater,
+            SystemValidationReleaseVersion::Equal(_) => ordering == Ordering::Equal,
+            SystemValidationReleaseVersion::GreaterThan(_) => ordering == Ordering::Greater,
+            SystemValidationReleaseVersion::GreaterThanOrEqual(_) => ordering != Ordering::Less,
+            SystemValidationReleaseVersion::None => true,
+        };
+
+        Ok(is_valid)
+    }
 
-                    return is_valid;
-                }
-                else {
-                    eprintln!("Error parsing SystemValidationReleaseVersion actual version string: '{}'", host_version);
-                    return false;
-                }
-            }
-            else {
-                // error
-                eprintln!("Error parsing SystemValidationReleaseVersion expected version string: '{}'", expected_release_string);
-                return false;
-            }
+    // parses a release version with an optional comparison operator prefix, i.e. ">=22.04"
+    pub fn parse(value: &str) -> Result<SystemValidationReleaseVersion, String> {
+        let first_number_pos = value.chars().position(|c| c.is_ascii_digit()).unwrap_or(value.len());
+        let prefix = value[0..first_number_pos].trim();
+        let version = value[first_number_pos..].trim().to_string();
+        // check the remainder value after the comparison operator string is not empty
+        if version.is_empty() {
+            return Err("missing SystemValidation release version value string.".to_string());
         }
-        else {
-            // otherwise, no release constraint version was set, so just return true
-            return true;
+        if parse_version(&version).is_none() {
+            return Err(format!("invalid SystemValidation release version: '{}'", version));
+        }
+
+        match prefix {
+            // if there's no prefix, we assume it's just equals comparison
+            "" | "="  => Ok(SystemValidationReleaseVersion::Equal(version)),
+            "<"  => Ok(SystemValidationReleaseVersion::LessThan(version)),
+            "<=" => Ok(SystemValidationReleaseVersion::LessThanOrEqual(version)),
+            ">"  => Ok(SystemValidationReleaseVersion::GreaterThan(version)),
+            ">=" => Ok(SystemValidationReleaseVersion::GreaterThanOrEqual(version)),
+            _  => Err("unsupported SystemValidation release version operator.".to_string()),
         }
     }
 
@@ -107,16 +118,54 @@ impl SystemValidationReleaseVersion {
     }
 }
 
+impl fmt::Display for SystemValidationReleaseVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let operator = match self {
+            SystemValidationReleaseVersion::None                    => return write!(f, "any"),
+            SystemValidationReleaseVersion::LessThan(_)             => "<",
+            SystemValidationReleaseVersion::LessThanOrEqual(_)      => "<=",
+            SystemValidationReleaseVersion::Equal(_)                => "=",
+            SystemValidationReleaseVersion::GreaterThan(_)          => ">",
+            SystemValidationReleaseVersion::GreaterThanOrEqual(_)   => ">=",
+        };
+        write!(f, "{}{}", operator, self.get_version_string().unwrap_or_default())
+    }
+}
+
+// the numeric components of a version, i.e. "22.04" -> [22, 4]. Any non-numeric suffix of a component
+// is ignored, i.e. "7.9-rc1" -> [7, 9].
+fn parse_version(version: &str) -> Option<Vec<u32>> {
+    version.trim().split('.').map(|component| {
+        let digits: String = component.chars().take_while(|c| c.is_ascii_digit()).collect();
+        digits.parse::<u32>().ok()
+    }).collect()
+}
+
+fn compare_versions(lhs: &[u32], rhs: &[u32]) -> Ordering {
+    let num_components = lhs.len().max(rhs.len());
+    for index in 0..num_components {
+        let ordering = lhs.get(index).unwrap_or(&0).cmp(rhs.get(index).unwrap_or(&0));
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    Ordering::Equal
+}
+
 #[derive(Clone, Debug)]
 pub struct SystemValidation {
-    // optional id/name string to validate the system against (i.e. "Debian")
-    pub id_name:       Option<String>,
+    // optional id/name strings to validate the system against (i.e. "Debian"), which can also be families of distros
+    // (i.e. "debian-like"). The system has to match one of them.
+    pub id_names:       Vec<String>,
 
     // release number validation - note: as strings
-    pub release:       SystemValidationReleaseVersion,
+    pub release:        SystemValidationReleaseVersion,
 
-    // TODO: maybe more stuff like validating free disk space and amount
-    //       of memory in the future?
+    // resource constraints, which need the host facts to check
+    pub min_memory_mb:  Option<u64>,
+    pub min_cpu_count:  Option<u32>,
+    // min free disk space for paths on the host, i.e. "/" or "/var"
+    pub min_free_disk_mb:   BTreeMap<String, u64>,
 }
 
 // System validation infrastructure. This might need a bit of a re-think, but it currently
@@ -124,100 +173,123 @@ pub struct SystemValidation {
 // it's Debian 12 and not 11 for example...
 impl SystemValidation {
     pub fn new() -> SystemValidation {
-        SystemValidation { id_name: None, release: SystemValidationReleaseVersion::None }
+        SystemValidation { id_names: Vec::new(), release: SystemValidationReleaseVersion::None, min_memory_mb: None, min_cpu_count: None,
+                           min_free_disk_mb: BTreeMap::new() }
     }
 
     // whether this is enabled/set or not and so needs checking
     pub fn needs_checking(&self) -> bool {
-        self.id_name.is_some() ||
-            self.release != SystemValidationReleaseVersion::None
+        !self.id_names.is_empty() ||
+            self.release != SystemValidationReleaseVersion::None ||
+            self.needs_host_facts()
+    }
+
+    // whether any of the constraints need more than the distro id and release to check
+    pub fn needs_host_facts(&self) -> bool {
+        self.min_memory_mb.is_some() || self.min_cpu_count.is_some() || !self.min_free_disk_mb.is_empty()
     }
 
-    // check the actual values from the host and see if they pass the
-    // configured check
-    pub fn check_actual_distro_values(&self, distro_id: &str, release: &str) -> bool {
+    // checks the host's facts against all the constraints, returning descriptions of any which failed.
+    pub fn get_failed_constraints(&self, facts: &HostFacts) -> Vec<String> {
+        let mut failures = Vec::new();
+
         // check the distributor id name if wanted...
-        if let Some(expected_id_name) = &self.id_name {
-            // do a case-insensitive comparison for the moment...
-            if !expected_id_name.eq_ignore_ascii_case(distro_id) {
-                // distributor name/id didn't match...
-                return false;
+        if !self.id_names.is_empty() && !self.id_names.iter().any(|id_name| distro_matches(id_name, &facts.distro_id)) {
+            failures.push(format!("distro '{}' is not one of the allowed distros: {}", facts.distro_id, self.id_names.join(", ")));
+        }
+
+        // check the release version
+        match self.release.check_version(&facts.release) {
+            Ok(true) => {},
+            Ok(false) => failures.push(format!("release '{}' does not satisfy the release constraint: '{}'", facts.release, self.release)),
+            Err(err) => failures.push(format!("release '{}' could not be checked against the release constraint: '{}': {}",
+                                              facts.release, self.release, err)),
+        }
+
+        if let Some(min_memory_mb) = self.min_memory_mb {
+            match facts.memory_total_mb {
+                Some(memory_mb) if memory_mb < min_memory_mb => {
+                    failures.push(format!("memory of {} MB is less than the minimum of {} MB", memory_mb, min_memory_mb));
+                },
+                Some(_) => {},
+                None => failures.push("memory could not be determined for the minimum memory constraint".to_string()),
             }
         }
 
-        // check the release version   
-        if !self.release.is_version_okay(release) {
-            // version was not acceptable...
-            return false;
+        if let Some(min_cpu_count) = self.min_cpu_count {
+            match facts.cpu_count {
+                Some(cpu_count) if cpu_count < min_cpu_count => {
+                    failures.push(format!("CPU count of {} is less than the minimum of {}", cpu_count, min_cpu_count));
+                },
+                Some(_) => {},
+                None => failures.push("CPU count could not be determined for the minimum CPU count constraint".to_string()),
+            }
         }
-        
-        // otherwise, assume things are okay...
-        true
+
+        for (path, min_free_mb) in &self.min_free_disk_mb {
+            // the mount the path is on is the one with the longest matching mount point
+            let mount = facts.mounts.iter().filter(|mount| path_is_on_mount(path, &mount.mount))
+                                           .max_by_key(|mount| mount.mount.len());
+            match mount {
+                Some(mount) if mount.available_mb < *min_free_mb => {
+                    failures.push(format!("free disk space for '{}' (on '{}') of {} MB is less than the minimum of {} MB", path, mount.mount,
+                                          mount.available_mb, min_free_mb));
+                },
+                Some(_) => {},
+                None => failures.push(format!("free disk space could not be determined for '{}' for the minimum free disk constraint", path)),
+            }
+        }
+
+        failures
+    }
+
+    // i.e. "distro: Debian, release: >=12, min memory: 1024 MB"
+    pub fn get_description(&self) -> String {
+        let mut items = Vec::new();
+        if !self.id_names.is_empty() {
+            items.push(format!("distro: {}", self.id_names.join(" or ")));
+        }
+        if self.release != SystemValidationReleaseVersion::None {
+            items.push(format!("release: {}", self.release));
+        }
+        if let Some(min_memory_mb) = self.min_memory_mb {
+            items.push(format!("min memory: {} MB", min_memory_mb));
+        }
+        if let Some(min_cpu_count) = self.min_cpu_count {
+            items.push(format!("min CPU count: {}", min_cpu_count));
+        }
+        for (path, min_free_mb) in &self.min_free_disk_mb {
+            items.push(format!("min free disk for '{}': {} MB", path, min_free_mb));
+        }
+        items.join(", ")
     }
 
     fn process_value(&mut self, value: &str) -> bool {
         // first of all, see if there are any numbers in the string, by finding the first
         // char position of any number
-        if let Some(first_number_pos) = value.chars().position(|c| c.is_numeric()) {
+        if value.chars().any(|c| c.is_numeric()) {
             // there were numbers, so assume for the moment, this string is the release/version,
-            // (i.e. "12", or "20.04").
-
-            // see if we might have a prefix
-            if first_number_pos != 0 {
-                // if first_number_pos != 0, assume we've got a custom comparison operator prefix first
-                let prefix = &value[0..first_number_pos];
-                let remainder_value = &value[first_number_pos..];
-                // check the remainder value after the comparison operator string is not empty
-                if remainder_value.is_empty() {
-                    eprintln!("Error: missing SystemValidation release version value string.");
+            // (i.e. "12", or "20.04"), with an optional comparison operator prefix.
+            if self.release != SystemValidationReleaseVersion::None {
+                host_eprintln!("Error: only one SystemValidation release version can be specified.");
+                return false;
+            }
+            match SystemValidationReleaseVersion::parse(value) {
+                Ok(release) => self.release = release,
+                Err(err) => {
+                    host_eprintln!("Error: {}", err);
                     return false;
                 }
-                // otherwise, parse
-                match prefix {
-                    "="  => {
-                        self.release = SystemValidationReleaseVersion::Equal(remainder_value.to_string());
-                    },
-                    "<"  => {
-                        self.release = SystemValidationReleaseVersion::LessThan(remainder_value.to_string());
-                    },
-                    "<=" => {
-                        self.release = SystemValidationReleaseVersion::LessThanOrEqual(remainder_value.to_string());
-                    },
-                    ">"  => {
-                        self.release = SystemValidationReleaseVersion::GreaterThan(remainder_value.to_string());
-                    },
-                    ">=" => {
-                        self.release = SystemValidationReleaseVersion::GreaterThanOrEqual(remainder_value.to_string());
-                    },
-                    _  => {
-                        eprintln!("Error: unsupported SystemValidation release version operator.");
-                        return false;
-                    }
-                }
-            }
-            else {
-                // if there's no prefix, we assume it's just equals comparison
-                self.release = SystemValidationReleaseVersion::Equal(value.to_string());
             }
         }
         else {
             // otherwise, if it hasn't got any number chars in, assume it's the distribution name/id
-
-            // check it is a valid non-empty string
-            if value.is_empty() {
-                eprintln!("Error: missing SystemValidation release version value string.");
+            if let Err(err) = validate_distro_name(value) {
+                host_eprintln!("Error: {}", err);
                 return false;
             }
 
-            // also check there's at least some alphabetic chars in there, as it could just be release version
-            // comparison operator chars without any numbers...
-            if !value.chars().any(|c| c.is_alphabetic()) {
-                // we didn't find any alphabetic chars, so it can't be a valid distribution name/id
-                eprintln!("Error: invalid SystemValidation value: '{}'", value);
-                return false;
-            }
-
-            self.id_name = Some(value.to_string());
+            self.id_names.push(value.to_string());
         }
 
         true
@@ -231,9 +303,9 @@ impl SystemValidation {
         // 1. a single value - which could be either the distro name (i.e. "Debian")
         //    to validate against, or the distro release number (i.e. "11" or "12"
         //    for Debian, or "20.04" for Ubuntu) to validate against.
-        // 2. Multiple values separated by a comma, one of which is the distro name/id and
-        //    the other is the release number. This code will attempt to work out which
-        //    is which.
+        // 2. Multiple values separated by commas, which are any number of distro names/ids
+        //    (any of which can match), and optionally one release number. This code will
+        //    attempt to work out which is which.
 
         // Note: this doesn't support checking for things like codenames, i.e. "bookworm",
         //       because that's a whole other dimension of somewhat arbitrary strings to deal
@@ -261,31 +333,127 @@ impl SystemValidation {
 
         let mut parsed_values = SystemValidation::new();
 
-        // if there's a comma, we have two values
-        if let Some((value1, value2)) = working_value.split_once(',') {
+        for item_value in working_value.split(',') {
             // also trim any whitespace, just to be a bit flexible...
-            if !parsed_values.process_value(value1.trim()) {
-                return Err(format!("Invalid SystemValidation string: couldn't interpret string value correctly: '{}'", value1));
-            }
-            if !parsed_values.process_value(value2.trim()) {
-                return Err(format!("Invalid SystemValidation string: couldn't interpret string value correctly: '{}'", value2));
+            if !parsed_values.process_value(item_value.trim()) {
+                return Err(format!("Invalid SystemValidation string: couldn't interpret string value correctly: '{}'", item_value));
             }
         }
-        else {
-            // otherwise, we only have one value, so try and work out what it is...
-            if !parsed_values.process_value(working_value) {
-                return Err(format!("Invalid SystemValidation string: couldn't interpret string value correctly: '{}'", working_value));
+
+        Ok(parsed_values)
+    }
+
+    // parses the map form, i.e.:
+    // systemValidation:
+    //   distro: [debian-like, Fedora]
+    //   release: ">=12"
+    //   minMemoryMB: 1024
+    //   minCpuCount: 2
+    //   minFreeDiskMB: { "/": 2048, "/var": 10240 }
+    pub fn parse_yaml_map(map: &yaml_rust::yaml::Hash) -> Result<SystemValidation, String> {
+        let mut parsed_values = SystemValidation::new();
+
+        for (key, value) in map {
+            let key_str = key.as_str().unwrap_or("");
+            match key_str {
+                "distro" => {
+                    let names: Vec<&str> = match value {
+                        Yaml::String(name) => vec![name.as_str()],
+                        Yaml::Array(items) => items.iter().map(|item| item.as_str()).collect::<Option<Vec<&str>>>()
+                                                    .ok_or("'distro' must be a distro name or list of distro names")?,
+                        _ => return Err("'distro' must be a distro name or list of distro names".to_string()),
+                    };
+                    for name in names {
+                        validate_distro_name(name)?;
+                        parsed_values.id_names.push(name.to_string());
+                    }
+                },
+                "release" => {
+                    // unquoted versions like 22.04 are Reals, but they keep the original string
+                    let release = match value {
+                        Yaml::String(val) | Yaml::Real(val) => val.clone(),
+                        Yaml::Integer(val) => val.to_string(),
+                        _ => return Err("'release' must be a release version string".to_string()),
+                    };
+                    parsed_values.release = SystemValidationReleaseVersion::parse(&release)?;
+                },
+                "minMemoryMB" => parsed_values.min_memory_mb = Some(get_positive_integer(value, key_str)?),
+                "minCpuCount" => parsed_values.min_cpu_count = Some(get_positive_integer(value, key_str)? as u32),
+                "minFreeDiskMB" => {
+                    match value {
+                        // for the root filesystem
+                        Yaml::Integer(_) => {
+                            parsed_values.min_free_disk_mb.insert("/".to_string(), get_positive_integer(value, key_str)?);
+                        },
+                        Yaml::Hash(paths) => {
+                            for (path, path_value) in paths {
+                                let path = path.as_str().filter(|path| path.starts_with('/'))
+                                               .ok_or("'minFreeDiskMB' paths must be absolute paths")?;
+                                parsed_values.min_free_disk_mb.insert(path.to_string(), get_positive_integer(path_value, key_str)?);
+                            }
+                        },
+                        _ => return Err("'minFreeDiskMB' must be a number of MB, or a map of paths to numbers of MB".to_string()),
+                    }
+                },
+                _ => return Err(format!("unknown SystemValidation item: '{}'", key_str)),
             }
         }
 
+        if !parsed_values.needs_checking() {
+            return Err("no SystemValidation constraints were specified".to_string());
+        }
+
         Ok(parsed_values)
     }
 }
 
+fn get_positive_integer(value: &Yaml, name: &str) -> Result<u64, String> {
+    match value {
+        Yaml::Integer(val) if *val > 0 && *val <= u32::MAX as i64 => Ok(*val as u64),
+        _ => Err(format!("'{}' must be a positive integer", name)),
+    }
+}
+
+fn validate_distro_name(value: &str) -> Result<(), String> {
+    // check it is a valid non-empty string
+    if value.is_empty() {
+        return Err("missing SystemValidation distro name string.".to_string());
+    }
+
+    // also check there's at least some alphabetic chars in there, as it could just be release version
+    // comparison operator chars without any numbers...
+    if !value.chars().any(|c| c.is_alphabetic()) {
+        // we didn't find any alphabetic chars, so it can't be a valid distribution name/id
+        return Err(format!("invalid SystemValidation value: '{}'", value));
+    }
+
+    if value.to_ascii_lowercase().ends_with("-like") && !DISTRO_FAMILIES.iter().any(|(family, _)| family.eq_ignore_ascii_case(value)) {
+        let families: Vec<&str> = DISTRO_FAMILIES.iter().map(|(family, _)| *family).collect();
+        return Err(format!("unknown SystemValidation distro family: '{}', the supported families are: {}", value, families.join(", ")));
+    }
+
+    Ok(())
+}
+
+// whether the distributor id matches the expected name (case-insensitively) or family
+fn distro_matches(expected: &str, distro_id: &str) -> bool {
+    if let Some((_, members)) = DISTRO_FAMILIES.iter().find(|(family, _)| family.eq_ignore_ascii_case(expected)) {
+        return members.iter().any(|member| member.eq_ignore_ascii_case(distro_id));
+    }
+
+    expected.eq_ignore_ascii_case(distro_id)
+}
+
+fn path_is_on_mount(path: &str, mount: &str) -> bool {
+    mount == "/" || path == mount || path.strip_prefix(mount).is_some_and(|remainder| remainder.starts_with('/'))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use crate::control::control_facts::MountFacts;
+
     #[test]
     fn test_parse_invalid_strings1() {
 
@@ -304,28 +472,28 @@ mod tests {
         let res1 = SystemValidation::parse_string_value("Debian");
         assert!(res1.is_ok());
         if let Ok(internal) = res1 {
-            assert_eq!(internal.id_name, Some("Debian".to_string()));
+            assert_eq!(internal.id_names, vec!["Debian".to_string()]);
             assert_eq!(internal.release, SystemValidationReleaseVersion::None);
         }
 
         let res2 = SystemValidation::parse_string_value("12");
         assert!(res2.is_ok());
         if let Ok(internal) = res2 {
-            assert!(internal.id_name.is_none());
+            assert!(internal.id_names.is_empty());
             assert_eq!(internal.release, SystemValidationReleaseVersion::Equal("12".to_string()));
         }
 
         let res3 = SystemValidation::parse_string_value("20.04");
         assert!(res3.is_ok());
         if let Ok(internal) = res3 {
-            assert!(internal.id_name.is_none());
+            assert!(internal.id_names.is_empty());
             assert_eq!(internal.release, SystemValidationReleaseVersion::Equal("20.04".to_string()));
         }
 
         let res4 = SystemValidation::parse_string_value("<12");
         assert!(res4.is_ok());
         if let Ok(internal) = res4 {
-            assert!(internal.id_name.is_none());
+            assert!(internal.id_names.is_empty());
             assert_eq!(internal.release, SystemValidationReleaseVersion::LessThan("12".to_string()));
         }
 
@@ -344,21 +512,21 @@ mod tests {
         let res1 = SystemValidation::parse_string_value("(Debian,12)");
         assert!(res1.is_ok());
         if let Ok(internal) = res1 {
-            assert_eq!(internal.id_name, Some("Debian".to_string()));
+            assert_eq!(internal.id_names, vec!["Debian".to_string()]);
             assert_eq!(internal.release, SystemValidationReleaseVersion::Equal("12".to_string()));
         }
 
         let res2 = SystemValidation::parse_string_value("(12,Debian)");
         assert!(res2.is_ok());
         if let Ok(internal) = res2 {
-            assert_eq!(internal.id_name, Some("Debian".to_string()));
+            assert_eq!(internal.id_names, vec!["Debian".to_string()]);
             assert_eq!(internal.release, SystemValidationReleaseVersion::Equal("12".to_string()));
         }
 
         let res3 = SystemValidation::parse_string_value("(>=12,Debian)");
         assert!(res3.is_ok());
         if let Ok(internal) = res3 {
-            assert_eq!(internal.id_name, Some("Debian".to_string()));
+            assert_eq!(internal.id_names, vec!["Debian".to_string()]);
             assert_eq!(internal.release, SystemValidationReleaseVersion::GreaterThanOrEqual("12".to_string()));
         }
 
@@ -386,4 +554,56 @@ mod tests {
         }
         
     }
-}
\ No newline at end of file
+    #[test]
+    fn test_version_comparisons_dotted1() {
+        let internal = SystemValidation::parse_string_value("(Ubuntu,>=22.04)").unwrap();
+        assert!(internal.release.is_version_okay("22.04"));
+        assert!(internal.release.is_version_okay("22.10"));
+        assert!(internal.release.is_version_okay("24.04"));
+        assert!(!internal.release.is_version_okay("20.04"));
+        assert!(!internal.release.is_version_okay("n/a"));
+
+        let internal = SystemValidation::parse_string_value("12").unwrap();
+        assert!(internal.release.is_version_okay("12.0"));
+        assert!(!internal.release.is_version_okay("12.5"));
+
+        assert!(SystemValidation::parse_string_value(">=12..1").is_err());
+    }
+
+    #[test]
+    fn test_distro_families_and_resources1() {
+        let internal = SystemValidation::parse_string_value("(debian-like,Fedora,>=12)").unwrap();
+        assert_eq!(internal.id_names, vec!["debian-like".to_string(), "Fedora".to_string()]);
+        assert!(SystemValidation::parse_string_value("(unknown-like)").is_err());
+        assert!(SystemValidation::parse_string_value("(11,12)").is_err());
+
+        let mut facts = HostFacts { distro_id: "Ubuntu".to_string(), release: "22.04".to_string(), ..Default::default() };
+        assert!(internal.get_failed_constraints(&facts).is_empty());
+        facts.distro_id = "CentOS".to_string();
+        assert_eq!(internal.get_failed_constraints(&facts).len(), 1);
+
+        let yaml = yaml_rust::YamlLoader::load_from_str("distro: [redhat-like]
+release: 9.2
+minMemoryMB: 2048
+minCpuCount: 2
+minFreeDiskMB: {'/': 1024, '/var/lib': 4096}").unwrap();
+        let internal = SystemValidation::parse_yaml_map(yaml[0].as_hash().unwrap()).unwrap();
+        assert_eq!(internal.release, SystemValidationReleaseVersion::Equal("9.2".to_string()));
+        assert!(internal.needs_host_facts());
+
+        facts.release = "9.2".to_string();
+        facts.memory_total_mb = Some(1024);
+        facts.cpu_count = Some(4);
+        facts.mounts = vec![MountFacts { mount: "/".to_string(), device: "/dev/vda1".to_string(), filesystem: "ext4".to_string(),
+                                         size_mb: 40000, available_mb: 30000 },
+                            MountFacts { mount: "/var".to_string(), device: "/dev/vdb1".to_string(), filesystem: "xfs".to_string(),
+                                         size_mb: 10000, available_mb: 2000 }];
+        let failures = internal.get_failed_constraints(&facts);
+        assert_eq!(failures.len(), 2);
+        assert!(failures[0].starts_with("memory of 1024 MB"));
+        assert!(failures[1].starts_with("free disk space for '/var/lib' (on '/var')"));
+
+        let yaml = yaml_rust::YamlLoader::load_from_str("minCpus: 2").unwrap();
+        assert!(SystemValidation::parse_yaml_map(yaml[0].as_hash().unwrap()).is_err());
+    }
+}
@@ -0,0 +1,570 @@
+/*
+ Prod
+ Copyright 2021-2025 Peter Pearson.
+ Licensed under the Apache License, Version 2.0 (the "License");
+ You may not use this file except in compliance with the License.
+ You may obtain a copy of the License at
+ http://www.apache.org/licenses/LICENSE-2.0
+ Unless required by applicable law or agreed to in writing, software
+ distributed under the License is distributed on an "AS IS" BASIS,
+ WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ See the License for the specific language governing permissions and
+ limitations under the License.
+ ---------
+*/
+
+// Very basic templating support for control scripts, so that string action param values can contain
+// '{{ name }}' expressions which get substituted with variable values before the action is run.
+// Expressions can be:
+//   a variable name, optionally with '.' separated map keys / array indices after it, i.e. 'users.0.name'
+//   'env.NAME' for the value of an environment variable on the local machine
+//   'facts.NAME' for details of the remote host (only available once connected)
+//   'vault.NAME' for a secret from the encrypted vault file
+//   'item' for the current item of actions with a 'loop' item
+//   a quoted string literal, i.e. "{{ '{{' }}" to output a literal '{{'
+// If the whole string value is just a single expression, the type of the variable's value is kept (i.e. an
+// integer or boolean or array), otherwise the value is converted to a string and inserted.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io::Read;
+
+use yaml_rust::{Yaml, YamlLoader};
+
+use crate::common::FileLoadError;
+use crate::params::ParamValue;
+use crate::vault::{get_vault_reference_names, Vault};
+
+use super::control_actions::{ControlAction, SystemDetailsResult};
+
+pub type TemplateVars = BTreeMap<String, ParamValue>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TemplateError {
+    UndefinedVariable(String),
+    UndefinedEnvironmentVariable(String),
+    UndefinedFact(String),
+    UndefinedVaultSecret(String),
+    UnterminatedExpression(String),
+    InvalidExpression(String),
+    NonScalarValue(String),
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateError::UndefinedVariable(name) => write!(f, "undefined variable: '{}'", name),
+            TemplateError::UndefinedEnvironmentVariable(name) => write!(f, "undefined environment variable: '{}'", name),
+            TemplateError::UndefinedFact(name) => write!(f, "undefined host fact: '{}'", name),
+            TemplateError::UndefinedVaultSecret(name) => write!(f, "undefined vault secret: '{}'", name),
+            TemplateError::UnterminatedExpression(value) => write!(f, "unterminated '{{{{' expression in value: '{}'", value),
+            TemplateError::InvalidExpression(expr) => write!(f, "invalid expression: '{}'", expr),
+            TemplateError::NonScalarValue(expr) => write!(f, "the value of '{}' is an array or map, so can't be inserted into a string", expr),
+        }
+    }
+}
+
+enum TemplatePart<'a> {
+    Literal(&'a str),
+    // the trimmed expression, and the full original text including the braces
+    Expression(&'a str, &'a str),
+}
+
+pub struct TemplateContext {
+    vars:       TemplateVars,
+    // facts about the remote host. If None (i.e. we haven't connected yet), any 'facts.' expressions are left as-is,
+    // so they can be rendered later.
+    facts:      Option<TemplateVars>,
+    // the loop item for the current action, if it has one
+    item:       Option<ParamValue>,
+    // the secrets from the vault. If None (i.e. it's not been loaded for a dry-run), any 'vault.' expressions are
+    // left as-is.
+    vault:      Option<TemplateVars>,
+    // names of variables which will only be set by actions (with 'register') when they're run on the host,
+    // so any expressions using them are left as-is until then.
+    pending:    BTreeSet<String>,
+}
+
+impl TemplateContext {
+    pub fn new(vars: TemplateVars) -> TemplateContext {
+        TemplateContext { vars, facts: None, item: None, vault: None, pending: BTreeSet::new() }
+    }
+
+    pub fn set_var(&mut self, name: &str, value: ParamValue) {
+        self.pending.remove(name);
+        self.vars.insert(name.to_string(), value);
+    }
+
+    pub fn set_pending_vars(&mut self, names: BTreeSet<String>) {
+        self.pending = names;
+    }
+
+    pub fn set_loop_item(&mut self, item: Option<ParamValue>) {
+        self.item = item;
+    }
+
+    pub fn set_facts(&mut self, facts: TemplateVars) {
+        self.facts = Some(facts);
+    }
+
+    pub fn set_vault(&mut self, vault: &Vault) {
+        self.vault = Some(vault.get_secrets().iter().map(|(name, value)| (name.clone(), ParamValue::Str(value.clone()))).collect());
+    }
+
+    // the values of the vault's secrets, so they can be redacted from output
+    pub fn get_vault_secret_values(&self) -> Vec<String> {
+        self.vault.iter().flat_map(|vault| vault.values()).filter_map(|value| match value {
+            ParamValue::Str(str_val) if !str_val.is_empty() => Some(str_val.clone()),
+            _ => None,
+        }).collect()
+    }
+
+    pub fn render_action(&self, action: &ControlAction) -> Result<ControlAction, TemplateError> {
+        let mut new_action = action.clone();
+        for value in new_action.params.values.values_mut() {
+            *value = self.render_value(value)?;
+        }
+
+        Ok(new_action)
+    }
+
+    pub fn render_value(&self, value: &ParamValue) -> Result<ParamValue, TemplateError> {
+        match value {
+            ParamValue::Str(str_val) => self.render_string(str_val),
+            ParamValue::Array(array) => {
+                let mut new_array = Vec::with_capacity(array.len());
+                for item in array {
+                    new_array.push(self.render_value(item)?);
+                }
+                Ok(ParamValue::Array(new_array))
+            },
+            ParamValue::Map(map) => {
+                let mut new_map = BTreeMap::new();
+                for (key, item) in map {
+                    new_map.insert(key.clone(), self.render_value(item)?);
+                }
+                Ok(ParamValue::Map(new_map))
+            },
+            _ => Ok(value.clone())
+        }
+    }
+
+    pub fn render_string(&self, value: &str) -> Result<ParamValue, TemplateError> {
+        if !value.contains("{{") {
+            return Ok(ParamValue::Str(value.to_string()));
+        }
+
+        let parts = parse_template(value)?;
+
+        // if it's just a single expression, keep the type of the value
+        if let [TemplatePart::Expression(expr, full_text)] = parts.as_slice() {
+            return match self.evaluate_expression(expr)? {
+                Some(result) => Ok(result),
+                None => Ok(ParamValue::Str(full_text.to_string())),
+            };
+        }
+
+        let mut result = String::with_capacity(value.len());
+        for part in parts {
+            match part {
+                TemplatePart::Literal(text) => result.push_str(text),
+                TemplatePart::Expression(expr, full_text) => {
+                    match self.evaluate_expression(expr)? {
+                        Some(ParamValue::Str(str_val)) => result.push_str(&str_val),
+                        Some(ParamValue::Int(int_val)) => result.push_str(&int_val.to_string()),
+                        Some(ParamValue::Bool(bool_val)) => result.push_str(if bool_val {"true"} else {"false"}),
+                        Some(_) => return Err(TemplateError::NonScalarValue(expr.to_string())),
+                        None => result.push_str(full_text),
+                    }
+                }
+            }
+        }
+
+        Ok(ParamValue::Str(result))
+    }
+
+    // returns None if the expression can't be evaluated yet (i.e. it's a fact, and we don't have them yet, or
+    // a variable which will be registered by an action on the host)
+    pub fn evaluate_expression(&self, expr: &str) -> Result<Option<ParamValue>, TemplateError> {
+        if let Some(literal) = get_quoted_literal(expr) {
+            return Ok(Some(ParamValue::Str(literal.to_string())));
+        }
+
+        let mut segments = expr.split('.');
+        let root = segments.next().unwrap_or("");
+        if root.is_empty() || !is_valid_identifier(root) {
+            return Err(TemplateError::InvalidExpression(expr.to_string()));
+        }
+
+        let mut value = match root {
+            "env" => {
+                let name = segments.next().unwrap_or("");
+                if name.is_empty() || segments.next().is_some() {
+                    return Err(TemplateError::InvalidExpression(expr.to_string()));
+                }
+                return match std::env::var(name) {
+                    Ok(env_value) => Ok(Some(ParamValue::Str(env_value))),
+                    Err(_) => Err(TemplateError::UndefinedEnvironmentVariable(name.to_string())),
+                };
+            },
+            "item" => {
+                match &self.item {
+                    Some(item) => item,
+                    None => return Err(TemplateError::UndefinedVariable("item".to_string())),
+                }
+            },
+            "facts" => {
+                let facts = match &self.facts {
+                    Some(facts) => facts,
+                    None => return Ok(None),
+                };
+                let name = segments.next().unwrap_or("");
+                match facts.get(name) {
+                    Some(fact) => fact,
+                    None => return Err(TemplateError::UndefinedFact(name.to_string())),
+                }
+            },
+            "vault" => {
+                let name = segments.next().unwrap_or("");
+                if name.is_empty() || segments.next().is_some() {
+                    return Err(TemplateError::InvalidExpression(expr.to_string()));
+                }
+                let vault = match &self.vault {
+                    Some(vault) => vault,
+                    None => return Ok(None),
+                };
+                match vault.get(name) {
+                    Some(secret) => secret,
+                    None => return Err(TemplateError::UndefinedVaultSecret(name.to_string())),
+                }
+            },
+            _ => {
+                match self.vars.get(root) {
+                    Some(var) => var,
+                    None if self.pending.contains(root) => return Ok(None),
+                    None => return Err(TemplateError::UndefinedVariable(root.to_string())),
+                }
+            }
+        };
+
+        // look up any sub-items
+        for segment in segments {
+            let item = match value {
+                ParamValue::Map(map) => map.get(segment),
+                ParamValue::Array(array) => segment.parse::<usize>().ok().and_then(|index| array.get(index)),
+                _ => None,
+            };
+            match item {
+                Some(item) => value = item,
+                None => return Err(TemplateError::UndefinedVariable(expr.to_string())),
+            }
+        }
+
+        Ok(Some(value.clone()))
+    }
+}
+
+// whether any of the string values of the action's params use host facts, so that we know if we need to
+// get them from the host before running it.
+pub fn action_uses_facts(action: &ControlAction) -> bool {
+    action.params.values.values().any(value_uses_facts)
+}
+
+fn value_uses_facts(value: &ParamValue) -> bool {
+    match value {
+        ParamValue::Str(str_val) => {
+            match parse_template(str_val) {
+                Ok(parts) => parts.iter().any(|part| matches!(part, TemplatePart::Expression(expr, _)
+                                                                    if *expr == "facts" || expr.starts_with("facts."))),
+                Err(_) => false,
+            }
+        },
+        ParamValue::Array(array) => array.iter().any(value_uses_facts),
+        ParamValue::Map(map) => map.values().any(value_uses_facts),
+        _ => false,
+    }
+}
+
+// adds the names of any secrets referenced with '{{ vault.name }}' expressions in the value
+pub fn get_value_vault_reference_names(value: &ParamValue, names: &mut BTreeSet<String>) {
+    match value {
+        ParamValue::Str(str_val) => get_vault_reference_names(str_val, names),
+        ParamValue::Array(array) => array.iter().for_each(|item| get_value_vault_reference_names(item, names)),
+        ParamValue::Map(map) => map.values().for_each(|item| get_value_vault_reference_names(item, names)),
+        _ => {}
+    }
+}
+
+// substitutes any '{{ vault.name }}' expressions in the value, i.e. for variables, whose values aren't rendered
+// when they're used.
+pub fn substitute_value_vault_references(value: &mut ParamValue, vault: &Vault) {
+    match value {
+        ParamValue::Str(str_val) => *str_val = vault.substitute_references(str_val),
+        ParamValue::Array(array) => array.iter_mut().for_each(|item| substitute_value_vault_references(item, vault)),
+        ParamValue::Map(map) => map.values_mut().for_each(|item| substitute_value_vault_references(item, vault)),
+        _ => {}
+    }
+}
+
+// escapes any '{{' in the string so that it's rendered as-is, i.e. for values the user has been prompted for.
+pub fn escape_template_string(value: &str) -> String {
+    value.replace("{{", "{{ '{{' }}")
+}
+
+pub fn facts_from_system_details(system_details: &SystemDetailsResult) -> TemplateVars {
+    let mut facts = TemplateVars::new();
+    facts.insert("distro_id".to_string(), ParamValue::Str(system_details.distr_id.clone()));
+    facts.insert("release".to_string(), ParamValue::Str(system_details.release.clone()));
+    facts
+}
+
+// the value stored for an action's 'register' item, with the output of the last command the action ran.
+// Trailing newlines are removed from the output, so values can be used directly in other strings.
+pub fn create_registered_result(stdout: &str, stderr: Option<&str>, exit_code: Option<i32>, changed: bool, failed: bool) -> ParamValue {
+    let stdout = stdout.trim_end_matches(['\r', '\n']);
+
+    let mut result = BTreeMap::new();
+    result.insert("stdout".to_string(), ParamValue::Str(stdout.to_string()));
+    result.insert("stdoutLines".to_string(), ParamValue::Array(stdout.lines().map(|line| ParamValue::Str(line.to_string())).collect()));
+    result.insert("stderr".to_string(), ParamValue::Str(stderr.unwrap_or("").trim_end_matches(['\r', '\n']).to_string()));
+    // not all connection backends can provide this
+    if let Some(exit_code) = exit_code {
+        result.insert("exitCode".to_string(), ParamValue::Int(exit_code));
+    }
+    result.insert("changed".to_string(), ParamValue::Bool(changed));
+    result.insert("failed".to_string(), ParamValue::Bool(failed));
+    ParamValue::Map(result)
+}
+
+// parses a 'name=value' command line arg. The value is interpreted the same way as a YAML scalar would be,
+// so 'port=2222' gives an integer and 'enabled=true' a boolean.
+pub fn parse_var_arg(arg: &str) -> Result<(String, ParamValue), String> {
+    let (name, value) = match arg.split_once('=') {
+        Some((name, value)) => (name.trim(), value),
+        None => return Err(format!("expected 'name=value', but got: '{}'", arg)),
+    };
+
+    if !is_valid_identifier(name) || is_reserved_name(name) {
+        return Err(format!("invalid variable name: '{}'", name));
+    }
+
+    let param_value = match YamlLoader::load_from_str(value).ok().and_then(|docs| docs.into_iter().next()) {
+        Some(yaml @ (Yaml::Integer(_) | Yaml::Boolean(_) | Yaml::String(_))) => ParamValue::from(yaml),
+        _ => ParamValue::Str(value.to_string()),
+    };
+
+    Ok((name.to_string(), param_value))
+}
+
+// converts a 'vars' YAML map into variables
+pub fn vars_from_yaml(yaml: &Yaml) -> Result<TemplateVars, String> {
+    let vars = match ParamValue::from(yaml.clone()) {
+        ParamValue::Map(map) => map,
+        _ => return Err("expected a map of variable names to values.".to_string()),
+    };
+
+    for name in vars.keys() {
+        if !is_valid_identifier(name) || is_reserved_name(name) {
+            return Err(format!("invalid variable name: '{}'", name));
+        }
+    }
+
+    Ok(vars)
+}
+
+pub fn load_vars_file(path: &str) -> Result<TemplateVars, FileLoadError> {
+    let mut file = std::fs::File::open(path)?;
+    let mut yaml_content = String::new();
+    file.read_to_string(&mut yaml_content)?;
+
+    let documents = YamlLoader::load_from_str(&yaml_content).map_err(|err| {
+        FileLoadError::CustomError(format!("{}, error: {}", path, err))
+    })?;
+
+    match documents.first() {
+        Some(doc) => vars_from_yaml(doc).map_err(|err| FileLoadError::CustomError(format!("{}, error: {}", path, err))),
+        None => Ok(TemplateVars::new()),
+    }
+}
+
+fn parse_template(value: &str) -> Result<Vec<TemplatePart<'_>>, TemplateError> {
+    let mut parts = Vec::new();
+    let mut remaining = value;
+    let mut offset = 0;
+
+    while let Some(start) = remaining.find("{{") {
+        if start > 0 {
+            parts.push(TemplatePart::Literal(&remaining[..start]));
+        }
+
+        let after_start = &remaining[start + 2..];
+        let end = match find_expression_end(after_start) {
+            Some(end) => end,
+            None => return Err(TemplateError::UnterminatedExpression(value.to_string())),
+        };
+
+        let full_text = &value[offset + start..offset + start + 2 + end + 2];
+        parts.push(TemplatePart::Expression(after_start[..end].trim(), full_text));
+
+        let consumed = start + 2 + end + 2;
+        remaining = &remaining[consumed..];
+        offset += consumed;
+    }
+
+    if !remaining.is_empty() {
+        parts.push(TemplatePart::Literal(remaining));
+    }
+
+    Ok(parts)
+}
+
+// finds the closing '}}' of an expression, ignoring any within quoted literals
+fn find_expression_end(text: &str) -> Option<usize> {
+    let mut quote_char: Option<char> = None;
+    for (index, chr) in text.char_indices() {
+        match quote_char {
+            Some(quote) if chr == quote => quote_char = None,
+            Some(_) => {},
+            None if chr == '\'' || chr == '"' => quote_char = Some(chr),
+            None if text[index..].starts_with("}}") => return Some(index),
+            None => {},
+        }
+    }
+
+    None
+}
+
+fn get_quoted_literal(expr: &str) -> Option<&str> {
+    if expr.len() >= 2 && ((expr.starts_with('\'') && expr.ends_with('\'')) || (expr.starts_with('"') && expr.ends_with('"'))) {
+        return Some(&expr[1..expr.len() - 1]);
+    }
+
+    None
+}
+
+// names which can't be used for variables
+pub fn is_reserved_name(name: &str) -> bool {
+    matches!(name, "env" | "facts" | "item" | "vault")
+}
+
+pub fn is_valid_identifier(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|chr| chr.is_ascii_alphanumeric() || chr == '_' || chr == '-')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_context() -> TemplateContext {
+        let mut vars = TemplateVars::new();
+        vars.insert("user".to_string(), ParamValue::Str("deploy".to_string()));
+        vars.insert("port".to_string(), ParamValue::Int(2222));
+        vars.insert("packages".to_string(), ParamValue::Array(vec![ParamValue::Str("nginx".to_string()),
+                                                                  ParamValue::Str("git".to_string())]));
+        TemplateContext::new(vars)
+    }
+
+    fn as_str(value: ParamValue) -> String {
+        match value {
+            ParamValue::Str(str_val) => str_val,
+            _ => panic!("expected a string value, got: {}", value),
+        }
+    }
+
+    #[test]
+    fn test_render_string() {
+        let context = make_context();
+
+        assert_eq!(as_str(context.render_string("/home/{{ user }}/.ssh").unwrap()), "/home/deploy/.ssh");
+        assert_eq!(as_str(context.render_string("{{user}}:{{ port }}").unwrap()), "deploy:2222");
+        assert_eq!(as_str(context.render_string("{{ packages.1 }}").unwrap()), "git");
+        assert_eq!(as_str(context.render_string("{{ '{{' }} user }}").unwrap()), "{{ user }}");
+        assert_eq!(as_str(context.render_string("no templates").unwrap()), "no templates");
+        assert_eq!(as_str(context.render_string(&escape_template_string("pa{{ss}}")).unwrap()), "pa{{ss}}");
+
+        // whole expressions keep their type
+        assert!(matches!(context.render_string("{{ port }}").unwrap(), ParamValue::Int(2222)));
+        assert!(matches!(context.render_string("{{ packages }}").unwrap(), ParamValue::Array(_)));
+    }
+
+    #[test]
+    fn test_render_errors() {
+        let context = make_context();
+
+        assert_eq!(context.render_string("{{ missing }}").unwrap_err(), TemplateError::UndefinedVariable("missing".to_string()));
+        assert_eq!(context.render_string("{{ user.name }}").unwrap_err(), TemplateError::UndefinedVariable("user.name".to_string()));
+        assert!(matches!(context.render_string("/home/{{ user").unwrap_err(), TemplateError::UnterminatedExpression(_)));
+        assert!(matches!(context.render_string("{{ a b }}").unwrap_err(), TemplateError::InvalidExpression(_)));
+        assert!(matches!(context.render_string("list: {{ packages }}").unwrap_err(), TemplateError::NonScalarValue(_)));
+        assert!(matches!(context.render_string("{{ env.PROD_TEST_UNDEFINED_ENV_VAR }}").unwrap_err(),
+                         TemplateError::UndefinedEnvironmentVariable(_)));
+    }
+
+    #[test]
+    fn test_render_facts() {
+        let mut context = make_context();
+
+        // facts aren't available yet, so should be left as they are
+        assert_eq!(as_str(context.render_string("release-{{ facts.release }}-{{ user }}").unwrap()), "release-{{ facts.release }}-deploy");
+
+        let mut system_details = SystemDetailsResult::new();
+        system_details.distr_id = "Debian".to_string();
+        system_details.release = "12".to_string();
+        context.set_facts(facts_from_system_details(&system_details));
+
+        assert_eq!(as_str(context.render_string("release-{{ facts.release }}-{{ user }}").unwrap()), "release-12-deploy");
+        assert!(matches!(context.render_string("{{ facts.unknown }}").unwrap_err(), TemplateError::UndefinedFact(_)));
+    }
+
+    #[test]
+    fn test_render_loop_item() {
+        let mut context = make_context();
+        assert_eq!(context.render_string("{{ item }}").unwrap_err(), TemplateError::UndefinedVariable("item".to_string()));
+
+        let mut item = TemplateVars::new();
+        item.insert("name".to_string(), ParamValue::Str("bob".to_string()));
+        item.insert("shell".to_string(), ParamValue::Str("/bin/zsh".to_string()));
+        context.set_loop_item(Some(ParamValue::Map(item)));
+
+        assert_eq!(as_str(context.render_string("{{ item.name }}:{{ item.shell }}:{{ user }}").unwrap()), "bob:/bin/zsh:deploy");
+        assert!(parse_var_arg("item=1").is_err());
+    }
+
+    #[test]
+    fn test_render_vault() {
+        let mut context = make_context();
+
+        // the vault isn't loaded for dry-runs, so should be left as it is
+        assert_eq!(as_str(context.render_string("{{ user }}:{{ vault.db_password }}").unwrap()), "deploy:{{ vault.db_password }}");
+
+        context.set_vault(&Vault::from_contents("db_password: hunter2").unwrap());
+        assert_eq!(as_str(context.render_string("{{ user }}:{{ vault.db_password }}").unwrap()), "deploy:hunter2");
+        assert_eq!(context.render_string("{{ vault.api_key }}").unwrap_err(), TemplateError::UndefinedVaultSecret("api_key".to_string()));
+        assert_eq!(context.get_vault_secret_values(), vec!["hunter2".to_string()]);
+        assert!(parse_var_arg("vault=1").is_err());
+    }
+
+    #[test]
+    fn test_render_registered() {
+        let mut context = make_context();
+        context.set_pending_vars(BTreeSet::from(["token".to_string()]));
+
+        // not registered yet, so should be left as it is
+        assert_eq!(as_str(context.render_string("key={{ token.stdout }}").unwrap()), "key={{ token.stdout }}");
+
+        context.set_var("token", create_registered_result("abc123\n", None, Some(0), true, false));
+        assert_eq!(as_str(context.render_string("key={{ token.stdout }}").unwrap()), "key=abc123");
+        assert!(matches!(context.render_string("{{ token.exitCode }}").unwrap(), ParamValue::Int(0)));
+        assert_eq!(as_str(context.render_string("{{ token.stderr }}").unwrap()), "");
+    }
+
+    #[test]
+    fn test_parse_var_arg() {
+        assert!(matches!(parse_var_arg("port=2222").unwrap(), (name, ParamValue::Int(2222)) if name == "port"));
+        assert!(matches!(parse_var_arg("enabled=true").unwrap().1, ParamValue::Bool(true)));
+        assert_eq!(as_str(parse_var_arg("user=deploy").unwrap().1), "deploy");
+        assert_eq!(as_str(parse_var_arg("cmd=a=b").unwrap().1), "a=b");
+        assert!(parse_var_arg("novalue").is_err());
+        assert!(parse_var_arg("env=1").is_err());
+    }
+}
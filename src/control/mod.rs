@@ -21,8 +21,16 @@ pub mod action_provider_linux_debian;
 pub mod action_provider_linux_fedora;
 
 pub mod control_actions;
+pub mod control_action_validation;
 pub mod control_common;
+pub mod control_conditions;
 pub mod control_connection;
+pub mod control_facts;
+
+pub mod control_inventory;
+pub mod control_journal;
+pub mod control_known_hosts;
+pub mod control_ssh_config;
 
 #[cfg(feature = "openssh")]
 pub mod control_connection_openssh;
@@ -31,8 +39,11 @@ pub mod control_connection_openssh;
 pub mod control_connection_sshrs;
 
 pub mod control_manager;
+pub mod control_output;
+pub mod control_report;
 
 pub mod control_system_validation;
+pub mod control_templating;
 
 pub mod file_modifier_helpers;
 
@@ -81,6 +81,19 @@ pub fn extract_details_from_stat_output(output: &str) -> Option<StatDetails> {
     None
 }
 
+// returns the file type from the stat output, i.e. "regular file", "symbolic link" or "directory"
+pub fn extract_file_type_from_stat_output(output: &str) -> Option<String> {
+    let line = output.lines().find(|line| line.contains("IO Block: "))?;
+    let after_io_block = &line[line.find("IO Block: ").unwrap() + 10..];
+    let file_type = after_io_block.trim_start().trim_start_matches(|c: char| c.is_ascii_digit()).trim();
+
+    if file_type.is_empty() {
+        return None;
+    }
+
+    Some(file_type.to_string())
+}
+
 fn extract_contents_from_brackets(string: &str) -> Option<Vec<String>> {
     // make sure we have matched pairs first.
     let count_open = string.matches('(').count();
@@ -149,4 +162,13 @@ Change: 2021-10-18 23:26:34.827018980 +1300
 
         assert_eq!(extracted, Some(expected_details));
     }
+
+    #[test]
+    fn test_extract_file_type_from_stat_output() {
+        assert_eq!(extract_file_type_from_stat_output("  File: 11.tif\n  Size: 71231369  \tBlocks: 139128     IO Block: 4096   regular file"),
+                   Some("regular file".to_string()));
+        assert_eq!(extract_file_type_from_stat_output("  File: /etc/localtime -> /usr/share/zoneinfo/UTC\n  Size: 25  \tBlocks: 0    IO Block: 4096   symbolic link"),
+                   Some("symbolic link".to_string()));
+        assert_eq!(extract_file_type_from_stat_output("stat: cannot statx '/tmp/none': No such file or directory"), None);
+    }
 }
\ No newline at end of file
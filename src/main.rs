@@ -20,17 +20,25 @@ mod control;
 
 mod params;
 
+mod vault;
+
 mod provision;
 
 mod column_list_printer;
 
 use control::control_manager::{ControlManager, CommandResult, ControlGeneralParams};
-use control::control_actions::ControlActions;
+use control::control_actions::{ActionSelection, ControlActions};
+use control::control_inventory::Inventory;
+use control::control_journal::is_valid_run_id;
+use control::control_templating::{get_value_vault_reference_names, load_vars_file, parse_var_arg};
 
 use provision::provision_common::ProvisionActionType;
 use provision::provision_manager::{ProvisionManager, ListType};
 use provision::provision_params::{ProvisionParams, ParamValue};
 
+use vault::{edit_contents, get_vault_file_path, load_vault_for_references, read_passphrase, read_vault_file, write_vault_file,
+            Vault};
+
 /*
 enum MainType {
     Unknown,
@@ -44,11 +52,47 @@ fn print_help() {
     eprintln!();
     eprintln!("prod provision list <provider> <plans/regions/oss>         : list available provision items");
     eprintln!("prod provision <provision_file>                            : run provision script");
+    eprintln!("prod provision --vault-file <vault_file> <provision_file>  : run provision script, using the vault file for any");
+    eprintln!("                                                             '{{{{ vault.name }}}}' secrets in it");
     eprintln!("prod provision deleteInstance <provider> <instance_id>     : delete instance");
     
     eprintln!();
 
+    eprintln!("prod vault create/edit/view [vault_file]                   : create, edit or view the encrypted secrets vault file");
+    eprintln!("                                                             (default $PROD_VAULT_FILE or ~/.prod_vault), whose secrets");
+    eprintln!("                                                             can be used with '{{{{ vault.name }}}}' in control scripts and");
+    eprintln!("                                                             provision files");
+    
+    eprintln!();
+
     eprintln!("prod control [-retry] <control_script_file>     : Run control script file");
+    eprintln!("prod control --dry-run <control_script_file>    : Print the commands and file transfers the control script would perform,");
+    eprintln!("                                                  without connecting to the host");
+    eprintln!("prod control -i <inventory_file> [--limit <hosts/groups>] <control_script_file>");
+    eprintln!("                                                : Run control script file on each of the hosts in the inventory file,");
+    eprintln!("                                                  optionally limited to a comma-separated list of host and group names");
+    eprintln!("                                                  Use '--forks <N>' to set the max number of hosts to run on at once (default 5)");
+    eprintln!("prod control [--var <name=value>] [--vars-file <file>] <control_script_file>");
+    eprintln!("                                                : Set variables used by '{{{{ name }}}}' expressions in the control script,");
+    eprintln!("                                                  overriding any in its 'vars' section. Both can be specified multiple times");
+    eprintln!("prod control --report-json <report_file> <control_script_file>");
+    eprintln!("                                                : Write a JSON report of the results of running the actions on each host");
+    eprintln!("prod control [--start-at <index/name>] [--only <indices>] <control_script_file>");
+    eprintln!("                                                : Only run some of the actions, starting at the action with the (1-based) index");
+    eprintln!("                                                  or name, and/or only those in a comma-separated list of indices and ranges,");
+    eprintln!("                                                  i.e. '--only 1,4,6-8'");
+    eprintln!("prod control --facts <[user@]host[:port]> [control_script_file]");
+    eprintln!("                                                : Print the facts about the host (CPUs, memory, disks, network interfaces, etc),");
+    eprintln!("                                                  using the control script's connection settings if specified. With '-i', the");
+    eprintln!("                                                  host can be a comma-separated list of host and group names in the inventory");
+    eprintln!("prod control [--tags <tags>] [--skip-tags <tags>] <control_script_file>");
+    eprintln!("                                                : Only run the actions with any of the comma-separated tags, and/or skip those");
+    eprintln!("                                                  with any of them. Use '--list-tags' to print the actions each tag selects");
+    eprintln!("prod control --rollback <run_id> [-i <inventory_file> [--limit <hosts/groups>]] <control_script_file>");
+    eprintln!("                                                : Restore the files changed by the run with the ID to their previous state,");
+    eprintln!("                                                  from the journal on each host. Use '--no-journal' to not journal changes");
+    eprintln!("prod control --vault-file <vault_file> <control_script_file>");
+    eprintln!("                                                : Use the vault file for any '{{{{ vault.name }}}}' secrets in the control script");
 }
 
 fn main() {
@@ -93,6 +137,10 @@ fn main() {
         handle_control_command(&args);
         return;
     }
+    else if first_command == "vault" && args.len() >= 3 {
+        handle_vault_command(&args);
+        return;
+    }
     else if first_command.contains("help") {
         print_help();
         return;
@@ -105,13 +153,34 @@ fn main() {
 // return value indicates whether function handled input or not. If true it did,
 // if false, it fell through...
 pub fn handle_provision_command(args: &Vec<String>) -> bool {
+    // the vault file for any '{{ vault.name }}' secrets can be specified before the provision file
+    let mut args = args.clone();
+    let mut vault_file_path = None;
+    if args.len() >= 4 && args[2] == "--vault-file" {
+        vault_file_path = Some(args[3].clone());
+        args.drain(2..4);
+    }
+    if args.len() < 3 {
+        eprintln!("Error: expected a provision file after the '--vault-file' arg.");
+        return true;
+    }
+
     let next_arg = &args[2];
-    let provision_manager = ProvisionManager::new();
+    let mut provision_manager = ProvisionManager::new();
     let dry_run = false;
     if next_arg.contains('.') && args.len() == 3 {
         // likely a provision file
         // TODO: error handling!
-        let provision_params = ProvisionParams::from_file(next_arg).unwrap();
+        let mut provision_params = ProvisionParams::from_file(next_arg).unwrap();
+
+        match load_vault_for_references(vault_file_path.as_deref(), &provision_params.get_vault_reference_names()) {
+            Ok(Some(vault)) => provision_params.substitute_vault_references(&vault),
+            Ok(None) => {},
+            Err(err) => {
+                eprintln!("Error loading vault: {}", err);
+                return true;
+            }
+        }
 
         if provision_params.provider.is_empty() {
             eprintln!("Error: no provider was specified in file: {}", next_arg);
@@ -123,6 +192,15 @@ pub fn handle_provision_command(args: &Vec<String>) -> bool {
             return true;
         }
 
+        // the API key/token can be specified in the file (i.e. from the vault), instead of the provider's env variable
+        if provision_params.has_param("apiKey") {
+            let api_key = provision_params.get_string_value("apiKey", "");
+            if !provision_manager.set_provider_api_key(&provision_params.provider, &api_key) {
+                eprintln!("Error: the '{}' provider doesn't support the 'apiKey' param.", provision_params.provider);
+                return true;
+            }
+        }
+
         let _response = provision_manager.perform_action(&provision_params, dry_run);
 
         return true;
@@ -171,6 +249,7 @@ pub fn handle_control_command(args: &[String]) -> bool {
     let control_manager = ControlManager::new();
 
     let mut general_params = ControlGeneralParams::new();
+    general_params.command_args = args.to_vec();
 
     enum ControlType {
         Unknown,
@@ -180,6 +259,12 @@ pub fn handle_control_command(args: &[String]) -> bool {
 
     let mut run_kind = ControlType::Unknown;
 
+    let mut inventory_file_path: Option<String> = None;
+    let mut host_limit: Option<String> = None;
+    let mut facts_host: Option<String> = None;
+    let mut rollback_run_id: Option<String> = None;
+    let mut vault_file_path: Option<String> = None;
+
     let mut arg_iter = args.iter().skip(2).enumerate().peekable();
     while let Some((_idx, arg)) = arg_iter.next() {
         if arg == "--command" {
@@ -202,11 +287,174 @@ pub fn handle_control_command(args: &[String]) -> bool {
                 return false;
             }
         }
+        else if arg == "--facts" {
+            if let Some((_idx, host)) = arg_iter.next() {
+                facts_host = Some(host.to_string());
+            }
+            else {
+                eprintln!("Error: expected a host arg after the '--facts' arg.");
+                return false;
+            }
+        }
+        else if arg == "--rollback" {
+            match arg_iter.next() {
+                Some((_idx, run_id)) if is_valid_run_id(run_id) => {
+                    rollback_run_id = Some(run_id.to_string());
+                },
+                Some((_idx, run_id)) => {
+                    eprintln!("Error: invalid '--rollback' run ID: '{}'.", run_id);
+                    return false;
+                },
+                None => {
+                    eprintln!("Error: expected a run ID arg after the '--rollback' arg.");
+                    return false;
+                }
+            }
+        }
+        else if arg == "--vault-file" {
+            if let Some((_idx, vault_file)) = arg_iter.next() {
+                vault_file_path = Some(vault_file.to_string());
+            }
+            else {
+                eprintln!("Error: expected a vault file path arg after the '--vault-file' arg.");
+                return false;
+            }
+        }
+        else if arg == "-i" || arg == "--inventory" {
+            if let Some((_idx, inventory_file)) = arg_iter.next() {
+                inventory_file_path = Some(inventory_file.to_string());
+            }
+            else {
+                eprintln!("Error: expected an inventory file path arg after the '{}' arg.", arg);
+                return false;
+            }
+        }
+        else if arg == "--forks" {
+            let forks = arg_iter.next().and_then(|(_idx, forks_string)| forks_string.parse::<usize>().ok());
+            match forks {
+                Some(forks) if forks > 0 => {
+                    general_params.forks = forks;
+                },
+                _ => {
+                    eprintln!("Error: expected a number greater than 0 after the '--forks' arg.");
+                    return false;
+                }
+            }
+        }
+        else if arg == "--var" {
+            if let Some((_idx, var_string)) = arg_iter.next() {
+                match parse_var_arg(var_string) {
+                    Ok((name, value)) => {
+                        general_params.vars.insert(name, value);
+                    },
+                    Err(err) => {
+                        eprintln!("Error: invalid '--var' arg: {}", err);
+                        return false;
+                    }
+                }
+            }
+            else {
+                eprintln!("Error: expected a 'name=value' arg after the '--var' arg.");
+                return false;
+            }
+        }
+        else if arg == "--vars-file" {
+            if let Some((_idx, vars_file)) = arg_iter.next() {
+                match load_vars_file(vars_file) {
+                    Ok(vars) => {
+                        general_params.vars.extend(vars);
+                    },
+                    Err(err) => {
+                        eprintln!("Error loading vars file: {}", err);
+                        return false;
+                    }
+                }
+            }
+            else {
+                eprintln!("Error: expected a vars file path arg after the '--vars-file' arg.");
+                return false;
+            }
+        }
+        else if arg == "--report-json" {
+            if let Some((_idx, report_file)) = arg_iter.next() {
+                general_params.report_json_path = Some(report_file.to_string());
+            }
+            else {
+                eprintln!("Error: expected a report file path arg after the '--report-json' arg.");
+                return false;
+            }
+        }
+        else if arg == "--start-at" {
+            if let Some((_idx, start_at)) = arg_iter.next() {
+                general_params.action_selection.start_at = Some(start_at.to_string());
+            }
+            else {
+                eprintln!("Error: expected an action index or name after the '--start-at' arg.");
+                return false;
+            }
+        }
+        else if arg == "--only" {
+            if let Some((_idx, only_string)) = arg_iter.next() {
+                match ActionSelection::parse_ranges(only_string) {
+                    Ok(ranges) => {
+                        general_params.action_selection.only = Some(ranges);
+                    },
+                    Err(err) => {
+                        eprintln!("Error: invalid '--only' arg: {}", err);
+                        return false;
+                    }
+                }
+            }
+            else {
+                eprintln!("Error: expected a comma-separated list of action indices and ranges after the '--only' arg.");
+                return false;
+            }
+        }
+        else if arg == "--tags" || arg == "--skip-tags" {
+            if let Some((_idx, tags_string)) = arg_iter.next() {
+                match ActionSelection::parse_tags(tags_string) {
+                    Ok(tags) if arg == "--tags" => {
+                        general_params.action_selection.tags.get_or_insert_with(Vec::new).extend(tags);
+                    },
+                    Ok(tags) => {
+                        general_params.action_selection.skip_tags.extend(tags);
+                    },
+                    Err(err) => {
+                        eprintln!("Error: invalid '{}' arg: {}", arg, err);
+                        return false;
+                    }
+                }
+            }
+            else {
+                eprintln!("Error: expected a comma-separated list of tags after the '{}' arg.", arg);
+                return false;
+            }
+        }
+        else if arg == "--limit" {
+            if let Some((_idx, limit_string)) = arg_iter.next() {
+                host_limit = Some(limit_string.to_string());
+            }
+            else {
+                eprintln!("Error: expected a comma-separated list of host and/or group names after the '--limit' arg.");
+                return false;
+            }
+        }
         else if let Some(flag_string) = arg.strip_prefix('-') {
+            // support both '-' and '--' prefixes for flags
+            let flag_string = flag_string.strip_prefix('-').unwrap_or(flag_string);
             match flag_string {
                 "retry"    => {
                     general_params.retry = true;
                 },
+                "dry-run"  => {
+                    general_params.dry_run = true;
+                },
+                "list-tags"  => {
+                    general_params.list_tags = true;
+                },
+                "no-journal"  => {
+                    general_params.journal_changes = false;
+                },
                 _  => {
                     eprintln!("Warning: unrecognised command flag: {}", arg);
                 }
@@ -220,6 +468,24 @@ pub fn handle_control_command(args: &[String]) -> bool {
         }
     }
 
+    if let Some(facts_host) = facts_host {
+        let script_file = match &run_kind {
+            ControlType::ActionsScript(script_file) => Some(script_file.as_str()),
+            _ => None,
+        };
+        return print_host_facts(&control_manager, &facts_host, script_file, inventory_file_path.as_deref(), vault_file_path.as_deref(),
+                                &mut general_params);
+    }
+
+    if let Some(run_id) = rollback_run_id {
+        let ControlType::ActionsScript(script_file) = &run_kind else {
+            eprintln!("Error: the '--rollback' arg needs the control script file the run used, for the connection settings.");
+            return false;
+        };
+        return rollback_run(&control_manager, &run_id, script_file, inventory_file_path.as_deref(), host_limit.as_deref(),
+                            vault_file_path.as_deref(), &mut general_params);
+    }
+
     match run_kind {
         ControlType::ManualCommand(hostname, command_str) => {
             // run the single manual command on the host requested...
@@ -244,13 +510,44 @@ pub fn handle_control_command(args: &[String]) -> bool {
             // run the actual script...
 
             let file_read_res = ControlActions::from_file(&script_file);
-            if let Ok(control_actions) = file_read_res {
-                control_manager.perform_actions(&control_actions, general_params);
-            }
-            else {
+            if file_read_res.is_err() {
                 eprintln!("Error loading Actions file.");
                 return false;
             }
+            let mut control_actions = file_read_res.unwrap();
+
+            if let Some(inventory_file_path) = inventory_file_path {
+                // run the script against multiple hosts from the inventory...
+                let inventory = Inventory::from_file(&inventory_file_path);
+                if let Err(err) = inventory {
+                    eprintln!("Error loading inventory file: {}", err);
+                    return false;
+                }
+                let mut inventory = inventory.unwrap();
+
+                if !load_control_vault(&mut control_actions, Some(&mut inventory), vault_file_path.as_deref(), &mut general_params) {
+                    return false;
+                }
+
+                match inventory.select_hosts(host_limit.as_deref()) {
+                    Ok(hosts) => {
+                        control_manager.perform_actions_on_hosts(&control_actions, &hosts, general_params);
+                    },
+                    Err(err) => {
+                        eprintln!("Error selecting hosts from inventory: {}", err);
+                        return false;
+                    }
+                }
+            }
+            else {
+                if host_limit.is_some() {
+                    eprintln!("Warning: the '--limit' arg is only used with an inventory file.");
+                }
+                if !load_control_vault(&mut control_actions, None, vault_file_path.as_deref(), &mut general_params) {
+                    return false;
+                }
+                control_manager.perform_actions(&control_actions, general_params);
+            }
 
             return true;
         },
@@ -261,4 +558,218 @@ pub fn handle_control_command(args: &[String]) -> bool {
     }
 
     false
-}
\ No newline at end of file
+}
+
+// loads the vault if the control script, inventory or vars reference any secrets in it, and substitutes the secrets
+// into the connection settings, with the vault being set in the general params for rendering the actions.
+// Returns false if the vault couldn't be loaded. It isn't loaded at all for dry runs, so the passphrase isn't needed.
+fn load_control_vault(control_actions: &mut ControlActions, inventory: Option<&mut Inventory>, vault_file_path: Option<&str>,
+                      general_params: &mut ControlGeneralParams) -> bool {
+    if general_params.dry_run || general_params.list_tags {
+        return true;
+    }
+
+    let mut names = control_actions.get_vault_reference_names();
+    if let Some(inventory) = &inventory {
+        names.extend(inventory.get_vault_reference_names());
+    }
+    for value in general_params.vars.values() {
+        get_value_vault_reference_names(value, &mut names);
+    }
+
+    let vault = match load_vault_for_references(vault_file_path, &names) {
+        Ok(Some(vault)) => vault,
+        Ok(None) => return true,
+        Err(err) => {
+            eprintln!("Error loading vault: {}", err);
+            return false;
+        }
+    };
+
+    control_actions.substitute_vault_references(&vault);
+    if let Some(inventory) = inventory {
+        inventory.substitute_vault_references(&vault);
+    }
+    general_params.vault = Some(vault);
+
+    true
+}
+
+// return value indicates whether function handled input or not.
+pub fn handle_vault_command(args: &[String]) -> bool {
+    let command = args[2].as_str();
+    let path = get_vault_file_path(args.get(3).map(|arg| arg.as_str()));
+
+    match command {
+        "create" => {
+            if std::path::Path::new(&path).exists() {
+                eprintln!("Error: vault file: {} already exists, use 'prod vault edit' to edit it.", path);
+                return true;
+            }
+
+            let passphrase = read_passphrase(&format!("Enter new passphrase for vault: {}", path));
+            if std::env::var("PROD_VAULT_PASSPHRASE").is_err() &&
+                    read_passphrase("Enter the passphrase again:") != passphrase {
+                eprintln!("Error: the passphrases don't match.");
+                return true;
+            }
+            if passphrase.is_empty() {
+                eprintln!("Error: the passphrase can't be empty.");
+                return true;
+            }
+
+            let template = "# secrets, as 'name: value' lines, which can be used as '{{ vault.name }}'\n";
+            edit_and_write_vault(&path, template, &passphrase);
+        },
+        "edit" | "view" => {
+            let passphrase = read_passphrase(&format!("Enter passphrase for vault: {}", path));
+            let contents = match read_vault_file(&path, &passphrase) {
+                Ok(contents) => contents,
+                Err(err) => {
+                    eprintln!("Error loading vault: {}", err);
+                    return true;
+                }
+            };
+
+            if command == "view" {
+                print!("{}", contents);
+            }
+            else {
+                edit_and_write_vault(&path, &contents, &passphrase);
+            }
+        },
+        _ => {
+            eprintln!("Error: unknown vault command: '{}', expected 'create', 'edit' or 'view'.", command);
+        }
+    }
+
+    true
+}
+
+fn edit_and_write_vault(path: &str, contents: &str, passphrase: &str) {
+    let new_contents = match edit_contents(contents) {
+        Ok(new_contents) => new_contents,
+        Err(err) => {
+            eprintln!("Error editing vault: {}", err);
+            return;
+        }
+    };
+
+    // make sure the contents are valid before saving them, otherwise they'd only be found to be invalid when used
+    if let Err(err) = Vault::from_contents(&new_contents) {
+        eprintln!("Error: the vault wasn't saved, as the contents were invalid: {}", err);
+        return;
+    }
+
+    match write_vault_file(path, &new_contents, passphrase) {
+        Ok(_) => println!("Saved vault: {}", path),
+        Err(err) => eprintln!("Error saving vault: {}", err),
+    }
+}
+
+// prints the facts about the host (or inventory hosts), using the control script's provider and connection settings
+// if there is one.
+fn print_host_facts(control_manager: &ControlManager, host: &str, script_file: Option<&str>, inventory_file_path: Option<&str>,
+                    vault_file_path: Option<&str>, general_params: &mut ControlGeneralParams) -> bool {
+    let mut control_actions = match script_file {
+        Some(script_file) => {
+            match ControlActions::from_file(script_file) {
+                Ok(control_actions) => control_actions,
+                Err(_err) => {
+                    eprintln!("Error loading Actions file.");
+                    return false;
+                }
+            }
+        },
+        None => ControlActions::new(),
+    };
+    if control_actions.provider.is_empty() {
+        // the fact gathering is the same for all the Linux providers
+        control_actions.provider = "linux_debian".to_string();
+    }
+
+    let host_actions = if let Some(inventory_file_path) = inventory_file_path {
+        match get_inventory_host_actions(&mut control_actions, inventory_file_path, Some(host), vault_file_path, general_params) {
+            Some(host_actions) => host_actions,
+            None => return false,
+        }
+    }
+    else {
+        if !load_control_vault(&mut control_actions, None, vault_file_path, general_params) {
+            return false;
+        }
+        if let Err(err) = control_actions.set_target_host(host) {
+            eprintln!("Error: invalid '--facts' host: '{}': {}", host, err);
+            return false;
+        }
+        vec![(control_actions.hostname.clone(), control_actions)]
+    };
+
+    control_manager.print_host_facts(&host_actions, general_params);
+    true
+}
+
+// rolls back the changes to files made by the run on the script's host (or inventory hosts).
+fn rollback_run(control_manager: &ControlManager, run_id: &str, script_file: &str, inventory_file_path: Option<&str>,
+                host_limit: Option<&str>, vault_file_path: Option<&str>, general_params: &mut ControlGeneralParams) -> bool {
+    let mut control_actions = match ControlActions::from_file(script_file) {
+        Ok(control_actions) => control_actions,
+        Err(_err) => {
+            eprintln!("Error loading Actions file.");
+            return false;
+        }
+    };
+
+    let host_actions = if let Some(inventory_file_path) = inventory_file_path {
+        match get_inventory_host_actions(&mut control_actions, inventory_file_path, host_limit, vault_file_path, general_params) {
+            Some(host_actions) => host_actions,
+            None => return false,
+        }
+    }
+    else {
+        if !load_control_vault(&mut control_actions, None, vault_file_path, general_params) {
+            return false;
+        }
+        vec![(control_actions.hostname.clone(), control_actions)]
+    };
+
+    control_manager.rollback_run(&host_actions, run_id, general_params);
+    true
+}
+
+// the actions with each of the selected inventory hosts' settings applied (and any vault secrets), printing any errors.
+fn get_inventory_host_actions(control_actions: &mut ControlActions, inventory_file_path: &str, host_limit: Option<&str>,
+                              vault_file_path: Option<&str>, general_params: &mut ControlGeneralParams) -> Option<Vec<(String, ControlActions)>> {
+    let mut inventory = match Inventory::from_file(inventory_file_path) {
+        Ok(inventory) => inventory,
+        Err(err) => {
+            eprintln!("Error loading inventory file: {}", err);
+            return None;
+        }
+    };
+
+    if !load_control_vault(control_actions, Some(&mut inventory), vault_file_path, general_params) {
+        return None;
+    }
+
+    let hosts = match inventory.select_hosts(host_limit) {
+        Ok(hosts) => hosts,
+        Err(err) => {
+            eprintln!("Error selecting hosts from inventory: {}", err);
+            return None;
+        }
+    };
+
+    let mut host_actions = Vec::with_capacity(hosts.len());
+    for inventory_host in hosts {
+        match inventory_host.apply_to_actions(control_actions) {
+            Ok(actions) => host_actions.push((inventory_host.name.clone(), actions)),
+            Err(err) => {
+                eprintln!("Error: invalid inventory host settings for {}.", err);
+                return None;
+            }
+        }
+    }
+
+    Some(host_actions)
+}
@@ -194,6 +194,11 @@ impl ProvisionProvider for ProviderBinaryLane {
         !self.binary_lane_api_token.is_empty()
     }
 
+    fn set_api_key(&mut self, api_key: &str) -> bool {
+        self.binary_lane_api_token = api_key.trim().to_string();
+        true
+    }
+
     // actual commands
 
     fn list_available(&self, list_type: ListType) -> bool {
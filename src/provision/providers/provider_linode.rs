@@ -136,6 +136,11 @@ impl ProvisionProvider for ProviderLinode {
         !self.linode_api_key.is_empty()
     }
 
+    fn set_api_key(&mut self, api_key: &str) -> bool {
+        self.linode_api_key = api_key.trim().to_string();
+        true
+    }
+
     // actual commands
 
     fn list_available(&self, list_type: ListType) -> bool {
@@ -141,6 +141,11 @@ impl ProvisionProvider for ProviderVultr {
         !self.vultr_api_key.is_empty()
     }
 
+    fn set_api_key(&mut self, api_key: &str) -> bool {
+        self.vultr_api_key = api_key.trim().to_string();
+        true
+    }
+
     // actual commands
 
     fn list_available(&self, list_type: ListType) -> bool {
@@ -27,6 +27,7 @@ use super::providers::provider_vultr::ProviderVultr;
 use super::provision_params::ProvisionParams;
 
 use crate::column_list_printer::ColumnListPrinter;
+use crate::control::control_known_hosts::{get_known_hosts_file_path, remove_host_keys};
 
 pub struct ProvisionManager {
     registered_providers: Vec<Box<dyn ProvisionProvider> >
@@ -72,6 +73,15 @@ impl ProvisionManager {
         manager
     }
 
+    // sets the provider's API key, overriding any from its env variable, returning false if the provider
+    // doesn't exist or doesn't support it.
+    pub fn set_provider_api_key(&mut self, provider: &str, api_key: &str) -> bool {
+        match self.registered_providers.iter_mut().find(|prov| prov.name() == provider) {
+            Some(prov) => prov.set_api_key(api_key),
+            None => false,
+        }
+    }
+
     fn find_provider(&self, provider: &str) -> Option<&dyn ProvisionProvider> {
         for prov in &self.registered_providers {
             if prov.name() == provider {
@@ -143,6 +153,18 @@ impl ProvisionManager {
                             clp.add_row_strings(&[&format!("{}:", key), val.as_str()]);
                         }
                         println!("{}", clp);
+
+                        // any host keys we have for the IP address will be from a previous instance, so they'd stop
+                        // us connecting to the new one
+                        if let Some(ip) = res_values.values.get("ip") {
+                            let known_hosts_path = get_known_hosts_file_path();
+                            match remove_host_keys(&known_hosts_path, ip) {
+                                Ok(0) => {},
+                                Ok(_) => println!("Removed the old host keys for {} from: {}", ip, known_hosts_path),
+                                Err(err) => eprintln!("Warning: couldn't remove the old host keys for {} from: {}, error: {}",
+                                                      ip, known_hosts_path, err),
+                            }
+                        }
                     },
                     _ => {           
                     }
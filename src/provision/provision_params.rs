@@ -13,12 +13,13 @@
  ---------
 */
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt;
 use std::io::{BufRead, BufReader};
 use std::path::Path;
 
 use crate::common::FileLoadError;
+use crate::vault::{get_vault_reference_names, Vault};
 
 use super::provision_common::{ProvisionActionType, ProvisionResponseWaitType};
 
@@ -169,6 +170,27 @@ impl ProvisionParams {
         }
     }
 
+    // the names of the secrets referenced with '{{ vault.name }}' expressions in the param values
+    pub fn get_vault_reference_names(&self) -> BTreeSet<String> {
+        let mut names = BTreeSet::new();
+        for value in self.values.values() {
+            match value {
+                ParamValue::StringVal(str) => get_vault_reference_names(str, &mut names),
+                ParamValue::StringArray(array) => array.iter().for_each(|str| get_vault_reference_names(str, &mut names)),
+            }
+        }
+        names
+    }
+
+    pub fn substitute_vault_references(&mut self, vault: &Vault) {
+        for value in self.values.values_mut() {
+            match value {
+                ParamValue::StringVal(str) => *str = vault.substitute_references(str),
+                ParamValue::StringArray(array) => array.iter_mut().for_each(|str| *str = vault.substitute_references(str)),
+            }
+        }
+    }
+
     pub fn has_param(&self, key: &str) -> bool {
         self.values.contains_key(key)
     }
@@ -46,6 +46,11 @@ pub trait ProvisionProvider {
         false
     }
 
+    // sets the API key/token from the 'apiKey' provision param (i.e. from the vault), instead of the env variable
+    fn set_api_key(&mut self, _api_key: &str) -> bool {
+        false
+    }
+
     // actual API items
 
     fn list_available(&self, _list_type: ListType) -> bool {
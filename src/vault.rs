@@ -0,0 +1,382 @@
+/*
+ Prod
+ Copyright 2021-2025 Peter Pearson.
+ Licensed under the Apache License, Version 2.0 (the "License");
+ You may not use this file except in compliance with the License.
+ You may obtain a copy of the License at
+ http://www.apache.org/licenses/LICENSE-2.0
+ Unless required by applicable law or agreed to in writing, software
+ distributed under the License is distributed on an "AS IS" BASIS,
+ WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ See the License for the specific language governing permissions and
+ limitations under the License.
+ ---------
+*/
+
+// Encrypted local file of secrets (i.e. passwords and API keys), which control scripts and provision files can
+// reference with '{{ vault.name }}' expressions instead of containing the values in plaintext.
+// The secrets are a YAML map of names to string values, encrypted with ChaCha20-Poly1305 using a key derived
+// from a passphrase with Argon2id. The file is text (hex-encoded), so it can be kept with the scripts:
+//   $PROD_VAULT;1;CHACHA20POLY1305;ARGON2ID
+//   <salt>
+//   <nonce>
+//   <ciphertext, over multiple lines>
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use argon2::Argon2;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use yaml_rust::{Yaml, YamlLoader};
+
+const VAULT_HEADER: &str = "$PROD_VAULT;1;CHACHA20POLY1305;ARGON2ID";
+const SALT_LENGTH: usize = 16;
+const CIPHERTEXT_LINE_LENGTH: usize = 80;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum VaultError {
+    IOError(String),
+    InvalidFormat(String),
+    // the passphrase was wrong, or the file has been modified
+    DecryptionFailed,
+    EncryptionFailed(String),
+    InvalidContents(String),
+    UndefinedSecrets(Vec<String>),
+}
+
+impl fmt::Display for VaultError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VaultError::IOError(err) => write!(f, "{}", err),
+            VaultError::InvalidFormat(err) => write!(f, "invalid vault file: {}", err),
+            VaultError::DecryptionFailed => write!(f, "couldn't decrypt the vault: the passphrase is incorrect, or the file is corrupt"),
+            VaultError::EncryptionFailed(err) => write!(f, "couldn't encrypt the vault: {}", err),
+            VaultError::InvalidContents(err) => write!(f, "invalid vault contents: {}", err),
+            VaultError::UndefinedSecrets(names) => write!(f, "the vault doesn't contain the secrets: {}", names.join(", ")),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Vault {
+    secrets:    BTreeMap<String, String>,
+}
+
+impl Vault {
+    // parses the decrypted contents of the vault, which must be a YAML map of names to (scalar) values.
+    pub fn from_contents(contents: &str) -> Result<Vault, VaultError> {
+        let docs = YamlLoader::load_from_str(contents).map_err(|err| VaultError::InvalidContents(err.to_string()))?;
+
+        let mut secrets = BTreeMap::new();
+        let map = match docs.first() {
+            Some(Yaml::Hash(map)) => map,
+            // an empty (or all commented) vault
+            None | Some(Yaml::Null) => return Ok(Vault { secrets }),
+            Some(_) => return Err(VaultError::InvalidContents("expected a map of secret names to values".to_string())),
+        };
+
+        for (key, value) in map {
+            let name = key.as_str().ok_or_else(|| VaultError::InvalidContents("secret names must be strings".to_string()))?;
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+                return Err(VaultError::InvalidContents(format!("invalid secret name: '{}'", name)));
+            }
+
+            let value = match value {
+                Yaml::String(str_val) => str_val.clone(),
+                Yaml::Integer(int_val) => int_val.to_string(),
+                Yaml::Real(real_val) => real_val.clone(),
+                Yaml::Boolean(bool_val) => bool_val.to_string(),
+                _ => return Err(VaultError::InvalidContents(format!("the value of secret: '{}' must be a string", name))),
+            };
+            secrets.insert(name.to_string(), value);
+        }
+
+        Ok(Vault { secrets })
+    }
+
+    pub fn load(path: &str, passphrase: &str) -> Result<Vault, VaultError> {
+        Vault::from_contents(&read_vault_file(path, passphrase)?)
+    }
+
+    pub fn get_secrets(&self) -> &BTreeMap<String, String> {
+        &self.secrets
+    }
+
+    // checks that all the names are defined in the vault
+    pub fn check_defined<'a>(&self, names: impl IntoIterator<Item = &'a String>) -> Result<(), VaultError> {
+        let undefined: Vec<String> = names.into_iter().filter(|name| !self.secrets.contains_key(*name)).cloned().collect();
+        if !undefined.is_empty() {
+            return Err(VaultError::UndefinedSecrets(undefined));
+        }
+        Ok(())
+    }
+
+    // substitutes any '{{ vault.name }}' expressions in the value with the secrets, leaving any other expressions
+    // (and any undefined secrets) as they are.
+    pub fn substitute_references(&self, value: &str) -> String {
+        let mut result = String::with_capacity(value.len());
+        let mut remaining = value;
+        while let Some((before, name, after)) = find_vault_reference(remaining) {
+            result.push_str(before);
+            match self.secrets.get(name) {
+                Some(secret) => result.push_str(secret),
+                None => result.push_str(&remaining[before.len()..remaining.len() - after.len()]),
+            }
+            remaining = after;
+        }
+        result.push_str(remaining);
+        result
+    }
+}
+
+// the names of the secrets referenced with '{{ vault.name }}' expressions in the value
+pub fn get_vault_reference_names(value: &str, names: &mut BTreeSet<String>) {
+    let mut remaining = value;
+    while let Some((_before, name, after)) = find_vault_reference(remaining) {
+        names.insert(name.to_string());
+        remaining = after;
+    }
+}
+
+// finds the first '{{ vault.name }}' expression in the value, returning the text before it, the name, and the
+// text after it.
+fn find_vault_reference(value: &str) -> Option<(&str, &str, &str)> {
+    let mut search_start = 0;
+    while let Some(start) = value[search_start..].find("{{").map(|pos| pos + search_start) {
+        let end = start + value[start..].find("}}")?;
+        let expr = value[start + 2..end].trim();
+        if let Some(name) = expr.strip_prefix("vault.") {
+            return Some((&value[..start], name, &value[end + 2..]));
+        }
+        search_start = end + 2;
+    }
+
+    None
+}
+
+// the path of the vault file: the specified one, or $PROD_VAULT_FILE, or '.prod_vault' in the home directory.
+pub fn get_vault_file_path(path: Option<&str>) -> String {
+    if let Some(path) = path {
+        return path.to_string();
+    }
+
+    if let Ok(path) = std::env::var("PROD_VAULT_FILE") {
+        return path;
+    }
+
+    let home_dir = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
+    format!("{}/.prod_vault", home_dir)
+}
+
+// gets the passphrase from $PROD_VAULT_PASSPHRASE (i.e. for CI), or prompts for it.
+pub fn read_passphrase(prompt: &str) -> String {
+    if let Ok(passphrase) = std::env::var("PROD_VAULT_PASSPHRASE") {
+        return passphrase;
+    }
+
+    eprintln!("{}", prompt);
+    rpassword::read_password().unwrap_or_default()
+}
+
+// loads the vault if any secrets are referenced, prompting for the passphrase, and checks all the referenced
+// secrets exist.
+pub fn load_vault_for_references(path: Option<&str>, names: &BTreeSet<String>) -> Result<Option<Vault>, VaultError> {
+    if names.is_empty() {
+        return Ok(None);
+    }
+
+    let path = get_vault_file_path(path);
+    let passphrase = read_passphrase(&format!("Enter passphrase for vault: {}", path));
+    let vault = Vault::load(&path, &passphrase)?;
+    vault.check_defined(names)?;
+
+    Ok(Some(vault))
+}
+
+// reads and decrypts the vault file, returning the contents.
+pub fn read_vault_file(path: &str, passphrase: &str) -> Result<String, VaultError> {
+    let file_contents = std::fs::read_to_string(path).map_err(|err| VaultError::IOError(format!("couldn't read vault file: {}: {}", path, err)))?;
+    decrypt_contents(&file_contents, passphrase)
+}
+
+// encrypts the contents and writes them to the vault file, only readable by the user. The contents are written to
+// a new file next to it first, which then replaces it, so the existing vault isn't lost if writing fails.
+pub fn write_vault_file(path: &str, contents: &str, passphrase: &str) -> Result<(), VaultError> {
+    let file_contents = encrypt_contents(contents, passphrase)?;
+
+    let temp_path = format!("{}.tmp-{}", path, random_hex_string());
+    let res = write_new_private_file(std::path::Path::new(&temp_path), &file_contents).and_then(|_| std::fs::rename(&temp_path, path));
+    if let Err(err) = res {
+        let _ = std::fs::remove_file(&temp_path);
+        return Err(VaultError::IOError(format!("couldn't write vault file: {}: {}", path, err)));
+    }
+
+    Ok(())
+}
+
+// lets the user edit the contents with $VISUAL or $EDITOR (or vi), returning the new contents. The contents have to be
+// written to a temporary file for the editor, which is created within a new directory only accessible by the user,
+// and both are removed afterwards.
+pub fn edit_contents(contents: &str) -> Result<String, VaultError> {
+    let temp_dir = std::env::temp_dir().join(format!("prod_vault_{}", random_hex_string()));
+    let mut dir_builder = std::fs::DirBuilder::new();
+#[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut dir_builder, 0o700);
+    dir_builder.create(&temp_dir)
+               .map_err(|err| VaultError::IOError(format!("couldn't create temporary directory: {}: {}", temp_dir.display(), err)))?;
+
+    let temp_path = temp_dir.join("vault.yaml");
+    let res = edit_file_contents(&temp_path, contents);
+
+    let _ = std::fs::remove_file(&temp_path);
+    let _ = std::fs::remove_dir(&temp_dir);
+
+    res
+}
+
+fn edit_file_contents(temp_path: &std::path::Path, contents: &str) -> Result<String, VaultError> {
+    write_new_private_file(temp_path, contents)
+        .map_err(|err| VaultError::IOError(format!("couldn't write temporary file: {}: {}", temp_path.display(), err)))?;
+
+    let editor = std::env::var("VISUAL").or_else(|_| std::env::var("EDITOR")).unwrap_or_else(|_| "vi".to_string());
+    // run it via the shell, so that editors with args (i.e. 'code --wait') work
+    let status = std::process::Command::new("sh").arg("-c").arg(format!("{} \"$1\"", editor)).arg("sh").arg(temp_path).status();
+
+    match status {
+        Ok(status) if status.success() => {},
+        Ok(status) => return Err(VaultError::IOError(format!("editor: '{}' exited with: {}", editor, status))),
+        Err(err) => return Err(VaultError::IOError(format!("couldn't run editor: '{}': {}", editor, err))),
+    }
+
+    std::fs::read_to_string(temp_path)
+        .map_err(|err| VaultError::IOError(format!("couldn't read temporary file: {}: {}", temp_path.display(), err)))
+}
+
+// creates a new file only readable by the user, failing if something already exists at the path (so that it can't
+// be a symlink someone else has created).
+fn write_new_private_file(path: &std::path::Path, contents: &str) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+#[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = options.open(path)?;
+    std::io::Write::write_all(&mut file, contents.as_bytes())?;
+    file.sync_all()
+}
+
+fn random_hex_string() -> String {
+    let mut bytes = [0u8; 8];
+    OsRng.fill_bytes(&mut bytes);
+    to_hex(&bytes)
+}
+
+pub fn encrypt_contents(contents: &str, passphrase: &str) -> Result<String, VaultError> {
+    let mut salt = [0u8; SALT_LENGTH];
+    OsRng.fill_bytes(&mut salt);
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+    let cipher = ChaCha20Poly1305::new(&derive_key(passphrase, &salt)?);
+    let ciphertext = cipher.encrypt(&nonce, contents.as_bytes()).map_err(|err| VaultError::EncryptionFailed(err.to_string()))?;
+
+    let mut file_contents = format!("{}\n{}\n{}\n", VAULT_HEADER, to_hex(&salt), to_hex(&nonce));
+    for chunk in to_hex(&ciphertext).as_bytes().chunks(CIPHERTEXT_LINE_LENGTH) {
+        file_contents.push_str(std::str::from_utf8(chunk).unwrap());
+        file_contents.push('\n');
+    }
+
+    Ok(file_contents)
+}
+
+pub fn decrypt_contents(file_contents: &str, passphrase: &str) -> Result<String, VaultError> {
+    let mut lines = file_contents.lines().map(|line| line.trim()).filter(|line| !line.is_empty());
+    if lines.next() != Some(VAULT_HEADER) {
+        return Err(VaultError::InvalidFormat("missing or unsupported header".to_string()));
+    }
+
+    let salt = lines.next().and_then(from_hex).filter(|salt| salt.len() == SALT_LENGTH)
+                    .ok_or_else(|| VaultError::InvalidFormat("invalid salt".to_string()))?;
+    let nonce = lines.next().and_then(from_hex).filter(|nonce| nonce.len() == 12)
+                     .ok_or_else(|| VaultError::InvalidFormat("invalid nonce".to_string()))?;
+    let ciphertext = from_hex(&lines.collect::<String>()).ok_or_else(|| VaultError::InvalidFormat("invalid ciphertext".to_string()))?;
+
+    let cipher = ChaCha20Poly1305::new(&derive_key(passphrase, &salt)?);
+    let contents = cipher.decrypt(Nonce::from_slice(&nonce), ciphertext.as_slice()).map_err(|_| VaultError::DecryptionFailed)?;
+
+    String::from_utf8(contents).map_err(|_| VaultError::DecryptionFailed)
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<Key, VaultError> {
+    let mut key = Key::default();
+    Argon2::default().hash_password_into(passphrase.as_bytes(), salt, &mut key)
+                     .map_err(|err| VaultError::EncryptionFailed(err.to_string()))?;
+    Ok(key)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(string: &str) -> Option<Vec<u8>> {
+    if !string.len().is_multiple_of(2) || !string.is_ascii() {
+        return None;
+    }
+
+    (0..string.len()).step_by(2).map(|index| u8::from_str_radix(&string[index..index + 2], 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let contents = "db_password: hunter2\napi_key: 'abc:123'\n";
+        let file_contents = encrypt_contents(contents, "correct horse").unwrap();
+        assert!(file_contents.starts_with(VAULT_HEADER));
+        assert!(!file_contents.contains("hunter2"));
+
+        assert_eq!(decrypt_contents(&file_contents, "correct horse").as_deref(), Ok(contents));
+        assert_eq!(decrypt_contents(&file_contents, "wrong horse"), Err(VaultError::DecryptionFailed));
+        assert!(matches!(decrypt_contents("db_password: hunter2", "correct horse"), Err(VaultError::InvalidFormat(_))));
+
+        let vault = Vault::from_contents(contents).unwrap();
+        assert_eq!(vault.get_secrets().get("api_key").map(|value| value.as_str()), Some("abc:123"));
+        assert!(Vault::from_contents("- one\n- two\n").is_err());
+        assert!(Vault::from_contents("# nothing yet\n").unwrap().get_secrets().is_empty());
+    }
+
+    #[test]
+    fn test_references() {
+        let vault = Vault::from_contents("db_password: hunter2\nuser: deploy").unwrap();
+
+        assert_eq!(vault.substitute_references("{{ vault.user }}:{{vault.db_password}} {{ other }} {{ vault.unknown }}"),
+                   "deploy:hunter2 {{ other }} {{ vault.unknown }}");
+        assert_eq!(vault.substitute_references("no references"), "no references");
+
+        let mut names = BTreeSet::new();
+        get_vault_reference_names("{{ user }} {{ vault.db_password }}-{{ vault.api_key }}", &mut names);
+        assert_eq!(names.iter().map(|name| name.as_str()).collect::<Vec<&str>>(), vec!["api_key", "db_password"]);
+
+        assert_eq!(vault.check_defined(&names), Err(VaultError::UndefinedSecrets(vec!["api_key".to_string()])));
+    }
+
+    #[test]
+    fn test_write_vault_file() {
+        let path = std::env::temp_dir().join(format!("prod_vault_test_{}", random_hex_string()));
+        let path = path.to_string_lossy().to_string();
+
+        write_vault_file(&path, "user: deploy\n", "passphrase").unwrap();
+        // replacing the existing file
+        write_vault_file(&path, "user: admin\n", "passphrase").unwrap();
+        assert_eq!(read_vault_file(&path, "passphrase").as_deref(), Ok("user: admin\n"));
+#[cfg(unix)]
+        assert_eq!(std::os::unix::fs::PermissionsExt::mode(&std::fs::metadata(&path).unwrap().permissions()) & 0o777, 0o600);
+
+        // the temporary files can never be created over existing files (or symlinks)
+        assert!(write_new_private_file(std::path::Path::new(&path), "").is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}